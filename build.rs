@@ -31,7 +31,10 @@ fn main() {
                                 &[PathBuf::from("proto/")])
         .expect("Failed to generate protobuf wrappers ./proto/*.proto");
 
-    prost_build::compile_protos(&get_proto_files("proto/criu"),
-                                &[PathBuf::from("proto/criu")])
+    // The descriptors of the CRIU protobufs are used to convert images to JSON.
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    prost_build::Config::new()
+        .file_descriptor_set_path(out_dir.join("criu_descriptors.bin"))
+        .compile_protos(&get_proto_files("proto/criu"), &[PathBuf::from("proto/criu")])
         .expect("Failed to generate protobuf wrappers for ./proto/criu/*.proto");
 }
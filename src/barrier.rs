@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Named barriers at which applications wait for the other members of their
+//! group, for their own logic around checkpoints.
+//!
+//! A member waits at a barrier until its dependencies have reached the
+//! barrier of the same name in the current checkpoint epoch of the group, so
+//! that the group passes the barrier together. A member that reaches the
+//! barrier again, e.g. after a restart, waits for the group to reach it again.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    str,
+};
+use json::{object, JsonValue};
+
+use crate::constants::*;
+
+const BUFFER_SIZE: usize = 32768;
+
+/// A member of a group that waits at named barriers.
+pub struct Barrier {
+    address: String,
+    port: u16,
+    id: String,
+    dependencies: String,
+    group: String,
+    timeout: Option<u64>,
+}
+
+impl Barrier {
+    /// `dependencies` is a colon-separated list of the IDs of the members
+    /// to wait for, as for the hooks of CRIU. If it is empty, the member
+    /// waits for the members of its group known to the server.
+    pub fn new(address: &str, port: u16, id: &str, dependencies: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            port,
+            id: id.to_string(),
+            dependencies: dependencies.to_string(),
+            group: DEFAULT_GROUP.to_string(),
+            timeout: None,
+        }
+    }
+
+    pub fn set_group(&mut self, group: &str) {
+        self.group = group.to_string();
+    }
+
+    /// Seconds to wait for the dependencies, overriding the server.
+    pub fn set_timeout(&mut self, timeout: Option<u64>) {
+        self.timeout = timeout;
+    }
+
+    /// Wait until the dependencies have reached the barrier `name`. Returns
+    /// the reply of the server if they did not, e.g. `timeout`.
+    pub fn wait(&self, name: &str) -> Result<(), String> {
+        let mut tcp_stream = TcpStream::connect(format!("{}:{}", self.address, self.port))
+            .map_err(|e| format!("Failed to connect to the server: {e}"))?;
+        let mut cmd = object!{
+            id: self.id.as_str(),
+            action: ACTION_BARRIER,
+            dependencies: self.dependencies.as_str(),
+            group: self.group.as_str(),
+            name: name,
+        };
+        if let Some(timeout) = self.timeout {
+            cmd["timeouts"] = JsonValue::new_object();
+            cmd["timeouts"][ACTION_BARRIER] = timeout.into();
+        }
+        tcp_stream.write_all(cmd.dump().as_bytes()).map_err(|e| e.to_string())?;
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let size = tcp_stream.read(&mut buffer).map_err(|e| e.to_string())?;
+        let response = str::from_utf8(&buffer[..size]).map_err(|e| e.to_string())?;
+        // With a timeout, the reply holds the deadline as well.
+        let reply = json::parse(response).unwrap_or(JsonValue::Null);
+        match reply["reply"].as_str().unwrap_or(response) {
+            MESSAGE_ACK => Ok(()),
+            response => Err(response.to_string()),
+        }
+    }
+}
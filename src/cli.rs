@@ -19,6 +19,9 @@
 
 use clap::Parser;
 
+use crate::constants::DEFAULT_GROUP;
+use crate::phase::HookMode;
+
 pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
 pub const DEFAULT_PORT: &str = "8080";
 
@@ -33,6 +36,20 @@ pub struct Opts {
     pub mode: Mode,
 }
 
+/// Parse a timeout of the form `ACTION=SECONDS`.
+fn parse_timeout(value: &str) -> Result<(String, u64), String> {
+    let (action, seconds) = value.split_once('=').ok_or_else(|| format!("expected ACTION=SECONDS, got {value:?}"))?;
+    let seconds = seconds.parse().map_err(|_| format!("invalid number of seconds {seconds:?}"))?;
+    Ok((action.to_string(), seconds))
+}
+
+/// Parse the mode of a hook of the form `ACTION=MODE`.
+fn parse_hook(value: &str) -> Result<(String, HookMode), String> {
+    let (action, mode) = value.split_once('=').ok_or_else(|| format!("expected ACTION=MODE, got {value:?}"))?;
+    let mode = HookMode::parse(mode).ok_or_else(|| format!("invalid mode {mode:?}, expected barrier, notify or ignore"))?;
+    Ok((action.to_string(), mode))
+}
+
 #[derive(Parser)]
 pub enum Mode {
     #[clap(about = "Run as client", aliases = ["c"])]
@@ -46,7 +63,7 @@ pub enum Mode {
         #[clap(short, long, help = "Unique client ID")]
         id: String,
 
-        #[clap(short, long, help = "A colon-separated list of dependency IDs")]
+        #[clap(short, long, help = "A colon-separated list of dependency IDs, where IDs ending with '?' are optional")]
         deps: String,
 
         #[clap(short, long, default_value = "pre-dump", help = "Action name indicating the stage of checkpoint/restore")]
@@ -60,6 +77,66 @@ pub enum Mode {
 
         #[clap(short = 'o', long, default_value = "-", hide_default_value = true, help = "Log file name")]
         log_file: String,
+
+        #[clap(short, long, default_value = DEFAULT_GROUP, help = "Group the client belongs to")]
+        group: String,
+
+        #[clap(short, long, help = "File with the group key used to encrypt checkpoint images")]
+        key_file: Option<String>,
+
+        #[clap(long, help = "Cgroup v2 to freeze with the group before the dump, or \"auto\" for the cgroup of the dumped process")]
+        freeze_cgroup: Option<String>,
+
+        #[clap(long, help = "Send heartbeats to the server until the checkpoint or restore completes")]
+        heartbeat: bool,
+
+        #[clap(short = 't', long, value_name = "ACTION=SECONDS", value_parser = parse_timeout, help = "Seconds to wait for the dependencies at an action, overriding the server")]
+        timeout: Vec<(String, u64)>,
+
+        #[clap(short = 'q', long, help = "Proceed once this many dependencies have reached the same state")]
+        quorum: Option<usize>,
+
+        #[clap(long, value_name = "ACTION=MODE", value_parser = parse_hook, help = "Coordinate a hook outside the checkpoint and restore flows at a barrier, by notifying the server, or not at all")]
+        hook: Vec<(String, HookMode)>,
+    },
+
+    #[clap(about = "Wait until the dependencies have reached a named barrier")]
+    Barrier {
+        #[clap(long, default_value = DEFAULT_ADDRESS, help = "Address of the server")]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT, help = "Port of the server")]
+        port: u16,
+
+        #[clap(short, long, help = "Name of the barrier")]
+        name: String,
+
+        #[clap(short, long, help = "Unique client ID")]
+        id: String,
+
+        #[clap(short, long, default_value = "", hide_default_value = true, help = "A colon-separated list of dependency IDs, where IDs ending with '?' are optional. Defaults to the members of the group known to the server")]
+        deps: String,
+
+        #[clap(short, long, default_value = DEFAULT_GROUP, help = "Group the client belongs to")]
+        group: String,
+
+        #[clap(short, long, help = "Seconds to wait for the dependencies, overriding the server")]
+        timeout: Option<u64>,
+    },
+
+    #[clap(about = "Send heartbeats for a client", hide = true)]
+    Heartbeat {
+        #[clap(long, default_value = DEFAULT_ADDRESS)]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT)]
+        port: String,
+
+        #[clap(long)]
+        id: String,
+
+        #[clap(long, default_value = DEFAULT_GROUP)]
+        group: String,
     },
 
     #[clap(about = "Run as server", aliases = ["s"])]
@@ -73,8 +150,152 @@ pub enum Mode {
         #[clap(short = 'o', long, default_value = "-", hide_default_value = true, help = "Log file name")]
         log_file: String,
 
-        #[clap(long, short='w', default_value = "30", help = "Number of seconds to wait for peer clients to connect")]
+        #[clap(long, short='w', default_value = "30", help = "Number of seconds to wait for peer clients at actions without a configured timeout")]
         wait_timeout: u16,
+
+        #[clap(short, long, help = "Server configuration file")]
+        config: Option<String>,
+    },
+
+    #[clap(about = "List the committed checkpoints", aliases = ["ls"])]
+    List {
+        #[clap(long, default_value = DEFAULT_ADDRESS, help = "Address of the server")]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT, help = "Port of the server")]
+        port: u16,
+
+        #[clap(short, long, help = "Only list the checkpoints of this group")]
+        group: Option<String>,
+
+        #[clap(short, long, help = "Only list the checkpoints with this tag")]
+        tag: Option<String>,
+    },
+
+    #[clap(about = "Show the members and manifests of a checkpoint")]
+    Show {
+        #[clap(long, default_value = DEFAULT_ADDRESS, help = "Address of the server")]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT, help = "Port of the server")]
+        port: u16,
+
+        #[clap(help = "Checkpoint epoch")]
+        epoch: String,
+    },
+
+    #[clap(about = "Add tags to a checkpoint or remove them")]
+    Tag {
+        #[clap(long, default_value = DEFAULT_ADDRESS, help = "Address of the server")]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT, help = "Port of the server")]
+        port: u16,
+
+        #[clap(short, long, help = "Remove the tags instead of adding them")]
+        remove: bool,
+
+        #[clap(help = "Checkpoint epoch")]
+        epoch: String,
+
+        #[clap(required = true, help = "Tags to add or remove")]
+        tags: Vec<String>,
+    },
+
+    #[clap(about = "Delete a checkpoint and its images", aliases = ["rm"])]
+    Delete {
+        #[clap(long, default_value = DEFAULT_ADDRESS, help = "Address of the server")]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT, help = "Port of the server")]
+        port: u16,
+
+        #[clap(help = "Checkpoint epoch")]
+        epoch: String,
+    },
+
+    #[clap(about = "Show the phase timeline of a checkpoint or restore")]
+    Timeline {
+        #[clap(long, default_value = DEFAULT_ADDRESS, help = "Address of the server")]
+        address: String,
+
+        #[clap(long, default_value = DEFAULT_PORT, help = "Port of the server")]
+        port: u16,
+
+        #[clap(long, requires = "epoch", help = "Write the timeline as a Chrome trace to this file, or - for stdout")]
+        trace: Option<String>,
+
+        #[clap(help = "Checkpoint epoch [default: list the recorded timelines]")]
+        epoch: Option<String>,
+    },
+
+    #[clap(about = "Summarize the CRIU images in a directory")]
+    Inspect {
+        #[clap(help = "Images directory")]
+        images_dir: String,
+    },
+
+    #[clap(about = "Compare two checkpoints of the same application")]
+    Diff {
+        #[clap(help = "Images directory, or directory of a global checkpoint")]
+        dir_a: String,
+
+        #[clap(help = "Images directory, or directory of a global checkpoint, to compare with")]
+        dir_b: String,
+    },
+
+    #[clap(about = "Check that the TCP connections of a checkpoint were dumped consistently")]
+    Verify {
+        #[clap(help = "Directory of a global checkpoint, or images directory")]
+        dir: String,
+    },
+
+    #[clap(about = "Rewrite the socket addresses of a checkpoint for a restore on other hosts")]
+    Rewrite {
+        #[clap(long, help = "JSON file that maps old IP addresses to new ones")]
+        map: String,
+
+        #[clap(help = "Directory of a global checkpoint, or images directory")]
+        dir: String,
+    },
+
+    #[clap(about = "Convert a CRIU image to JSON, like crit decode")]
+    Decode {
+        #[clap(short, long, default_value = "-", hide_default_value = true, help = "Image file [default: stdin]")]
+        input: String,
+
+        #[clap(short, long, default_value = "-", hide_default_value = true, help = "JSON file [default: stdout]")]
+        output: String,
+
+        #[clap(long, help = "Indent the JSON output and show fields in the format of crit --pretty")]
+        pretty: bool,
+    },
+
+    #[clap(about = "Convert JSON back to a CRIU image, like crit encode")]
+    Encode {
+        #[clap(short, long, default_value = "-", hide_default_value = true, help = "JSON file [default: stdin]")]
+        input: String,
+
+        #[clap(short, long, default_value = "-", hide_default_value = true, help = "Image file [default: stdout]")]
+        output: String,
+    },
+
+    #[clap(about = "Store the images of a directory in a marker stream archive")]
+    Pack {
+        #[clap(help = "Images directory")]
+        images_dir: String,
+
+        #[clap(help = "Archive file")]
+        archive: String,
+    },
+
+    #[clap(about = "Extract a marker stream archive into an images directory")]
+    Unpack {
+        #[clap(help = "Archive file")]
+        archive: String,
+
+        #[clap(help = "Images directory")]
+        images_dir: String,
     },
 
     #[clap(about = "Generate shell completions")]
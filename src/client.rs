@@ -17,17 +17,30 @@
  *
  */
 
-use std::io::{Read, Write};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
 use std::net::{TcpStream, Shutdown};
 use std::path::Path;
-use std::process::exit;
+use std::process::{exit, Command, Stdio};
+use std::thread;
+use std::time::Duration;
 use std::{fs, str};
-use json::object;
+use json::{object, JsonValue};
 use log::*;
 
 use crate::cli::{DEFAULT_ADDRESS, DEFAULT_PORT};
 use crate::constants::*;
-use crate::pipeline::streamer::streamer;
+use crate::images::{
+    compatibility::check_host,
+    connections::{self, Connection},
+    diff::is_images_dir,
+};
+use crate::freezer::Cgroup;
+use crate::quiesce::{Notifier, Quiesce, DEFAULT_QUIESCE_TIMEOUT};
+use crate::phase::{self, HookMode, Operation};
+use crate::timeline::{self, unix_time, Timeline};
+use crate::pipeline::crypto::ImageKey;
+use crate::pipeline::streamer::{restore_images, streamer};
 use std::{collections::HashMap, env, path::PathBuf};
 
 use config::Config;
@@ -35,22 +48,56 @@ use config::Config;
 const BUFFER_SIZE: usize = 32768 * 4;
 
 
+/// User scripts that run for an action before and after it is coordinated.
+#[derive(Clone, Default)]
+pub struct ActionScripts {
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+    /// Whether a failing script aborts the group, and not only the client.
+    pub abort_group: bool,
+}
+
+/// When the scripts of an action run.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ScriptStage {
+    Before,
+    After,
+}
+
 pub struct ClientConfig {
     log_file: String,
     address: String,
     port: String,
     id: String,
     dependencies: String,
+    group: String,
+    key_file: Option<String>,
+    freeze_cgroup: Option<String>,
+    quiesce: Option<Quiesce>,
+    heartbeat: bool,
+    timeouts: HashMap<String, u64>,
+    quorum: Option<usize>,
+    hooks: HashMap<String, HookMode>,
+    scripts: HashMap<String, ActionScripts>,
 }
 
 impl ClientConfig {
-    fn new(log_file: String, address: String, port: String, id: String, dependencies: String) -> Self {
+    pub fn new(log_file: String, address: String, port: String, id: String, dependencies: String) -> Self {
         ClientConfig {
             log_file,
             address,
             port,
             id,
             dependencies,
+            group: DEFAULT_GROUP.to_string(),
+            key_file: None,
+            freeze_cgroup: None,
+            quiesce: None,
+            heartbeat: false,
+            timeouts: HashMap::new(),
+            quorum: None,
+            hooks: HashMap::new(),
+            scripts: HashMap::new(),
         }
     }
 
@@ -73,6 +120,91 @@ impl ClientConfig {
     pub fn get_dependencies(&self) -> &str {
         &self.dependencies
     }
+
+    pub fn get_group(&self) -> &str {
+        &self.group
+    }
+
+    pub fn set_group(&mut self, group: String) {
+        self.group = group;
+    }
+
+    pub fn get_key_file(&self) -> Option<&str> {
+        self.key_file.as_deref()
+    }
+
+    pub fn set_key_file(&mut self, key_file: Option<String>) {
+        self.key_file = key_file;
+    }
+
+    /// Cgroup v2 to freeze together with the group before the dump, or
+    /// `auto` for the cgroup of the checkpointed process.
+    pub fn get_freeze_cgroup(&self) -> Option<&str> {
+        self.freeze_cgroup.as_deref()
+    }
+
+    pub fn set_freeze_cgroup(&mut self, freeze_cgroup: Option<String>) {
+        self.freeze_cgroup = freeze_cgroup;
+    }
+
+    /// How the application is told that a checkpoint is imminent, and that
+    /// it was resumed.
+    pub fn get_quiesce(&self) -> Option<&Quiesce> {
+        self.quiesce.as_ref()
+    }
+
+    pub fn set_quiesce(&mut self, quiesce: Option<Quiesce>) {
+        self.quiesce = quiesce;
+    }
+
+    /// Whether the client sends heartbeats to the server for the duration
+    /// of a checkpoint or restore.
+    pub fn get_heartbeat(&self) -> bool {
+        self.heartbeat
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: bool) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Seconds to wait for the dependencies at each action, which override
+    /// the timeouts of the server.
+    pub fn get_timeouts(&self) -> &HashMap<String, u64> {
+        &self.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: HashMap<String, u64>) {
+        self.timeouts = timeouts;
+    }
+
+    /// Number of dependencies that are enough for the client to proceed
+    /// when the others do not reach the same state in time.
+    pub fn get_quorum(&self) -> Option<usize> {
+        self.quorum
+    }
+
+    pub fn set_quorum(&mut self, quorum: Option<usize>) {
+        self.quorum = quorum;
+    }
+
+    /// How the client coordinates `action`, if it is one of the hooks of
+    /// CRIU outside the checkpoint and restore flows.
+    pub fn get_hook_mode(&self, action: &str) -> Option<HookMode> {
+        phase::default_hook_mode(action).map(|mode| self.hooks.get(action).copied().unwrap_or(mode))
+    }
+
+    pub fn set_hooks(&mut self, hooks: HashMap<String, HookMode>) {
+        self.hooks = hooks;
+    }
+
+    /// User scripts that run for `action`.
+    pub fn get_scripts(&self, action: &str) -> Option<&ActionScripts> {
+        self.scripts.get(action)
+    }
+
+    pub fn set_scripts(&mut self, scripts: HashMap<String, ActionScripts>) {
+        self.scripts = scripts;
+    }
 }
 
 const CONFIG_KEY_ID: &str = "id";
@@ -80,6 +212,15 @@ const CONFIG_KEY_DEPS: &str = "dependencies";
 const CONFIG_KEY_ADDR: &str = "address";
 const CONFIG_KEY_PORT: &str = "port";
 const CONFIG_KEY_LOG: &str = "log-file";
+const CONFIG_KEY_GROUP: &str = "group";
+const CONFIG_KEY_KEY_FILE: &str = "key-file";
+const CONFIG_KEY_FREEZE_CGROUP: &str = "freeze-cgroup";
+const CONFIG_KEY_HEARTBEAT: &str = "heartbeat";
+const CONFIG_KEY_TIMEOUTS: &str = "timeouts";
+const CONFIG_KEY_QUORUM: &str = "quorum";
+const CONFIG_KEY_HOOKS: &str = "hooks";
+const CONFIG_KEY_SCRIPTS: &str = "scripts";
+const CONFIG_KEY_QUIESCE: &str = "quiesce";
 
 pub fn load_config_file<P: AsRef<Path>>(images_dir: P, action: &str) -> ClientConfig {
     let images_dir = images_dir.as_ref();
@@ -90,21 +231,45 @@ pub fn load_config_file<P: AsRef<Path>>(images_dir: P, action: &str) -> ClientCo
         // Example of per-process config file:
         // {
         //    "id": "A",
-        //    "dependencies": "B:C",
+        //    "dependencies": "B:C:metrics?",
         //    "address": "127.0.0.1",
         //    "port": "8080",
-        //    "log-file": "/var/log/criu-coordinator.log"
+        //    "log-file": "/var/log/criu-coordinator.log",
+        //    "group": "default",
+        //    "key-file": "/etc/criu/group.key",
+        //    "freeze-cgroup": "auto",
+        //    "heartbeat": true,
+        //    "timeouts": { "post-dump": 600 },
+        //    "quorum": 1,
+        //    "hooks": { "post-setup-namespaces": "barrier" },
+        //    "scripts": { "pre-dump": { "before": ["/etc/criu/flush.sh"], "abort-group": true } },
+        //    "quiesce": { "socket": "/run/app/checkpoint.sock", "timeout": 30 }
         // }
         let settings = Config::builder().add_source(config::File::from(local_config_file)).build().unwrap();
-        let settings_map = settings.try_deserialize::<HashMap<String, String>>().unwrap();
+        let settings_values = settings.try_deserialize::<HashMap<String, config::Value>>().unwrap();
+        let settings_map: HashMap<String, String> = settings_values.iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.clone().into_string().ok()?)))
+            .collect();
 
-        return ClientConfig::new(
+        let mut client_config = ClientConfig::new(
             settings_map.get(CONFIG_KEY_LOG).cloned().unwrap_or_else(|| "-".to_string()),
             settings_map.get(CONFIG_KEY_ADDR).cloned().unwrap_or_else(|| DEFAULT_ADDRESS.to_string()),
             settings_map.get(CONFIG_KEY_PORT).cloned().unwrap_or_else(|| DEFAULT_PORT.to_string()),
             settings_map.get(CONFIG_KEY_ID).unwrap().clone(),
             settings_map.get(CONFIG_KEY_DEPS).cloned().unwrap_or_default(),
         );
+        if let Some(group) = settings_map.get(CONFIG_KEY_GROUP) {
+            client_config.set_group(group.clone());
+        }
+        client_config.set_key_file(settings_map.get(CONFIG_KEY_KEY_FILE).cloned());
+        client_config.set_freeze_cgroup(settings_map.get(CONFIG_KEY_FREEZE_CGROUP).cloned());
+        client_config.set_heartbeat(settings_map.get(CONFIG_KEY_HEARTBEAT).is_some_and(|heartbeat| heartbeat == "true"));
+        client_config.set_timeouts(parse_timeouts(settings_values.get(CONFIG_KEY_TIMEOUTS)).unwrap_or_else(config_error));
+        client_config.set_quorum(parse_quorum(settings_values.get(CONFIG_KEY_QUORUM)).unwrap_or_else(config_error));
+        client_config.set_hooks(parse_hooks(settings_values.get(CONFIG_KEY_HOOKS)).unwrap_or_else(config_error));
+        client_config.set_scripts(parse_scripts(settings_values.get(CONFIG_KEY_SCRIPTS)).unwrap_or_else(config_error));
+        client_config.set_quiesce(parse_quiesce(settings_values.get(CONFIG_KEY_QUIESCE)).unwrap_or_else(config_error));
+        return client_config;
     }
 
     // The following allows us to load global config files from /etc/criu.
@@ -115,8 +280,17 @@ pub fn load_config_file<P: AsRef<Path>>(images_dir: P, action: &str) -> ClientCo
     //    "address": "127.0.0.1",
     //    "port": "8080",
     //    "log-file": "/var/log/criu-coordinator.log",
+    //    "group": "default",
+    //    "key-file": "/etc/criu/group.key",
+    //    "freeze-cgroup": "auto",
+    //    "heartbeat": true,
+    //    "timeouts": { "post-dump": 600 },
+    //    "quorum": 1,
+    //    "hooks": { "post-setup-namespaces": "barrier" },
+    //    "scripts": { "pre-dump": { "before": ["/etc/criu/flush.sh"], "abort-group": true } },
+    //    "quiesce": { "signal": "SIGUSR1", "resumed-signal": "SIGUSR2", "ack-file": "/run/app/quiesced" },
     //    "dependencies": {
-    //        "A": ["B", "C"],
+    //        "A": ["B", "C", "metrics?"],
     //        "B": ["C", "A"],
     //        "C": ["A"]
     //    }
@@ -134,6 +308,15 @@ pub fn load_config_file<P: AsRef<Path>>(images_dir: P, action: &str) -> ClientCo
     let address = global_map.get(CONFIG_KEY_ADDR).map(|v| v.clone().into_string().unwrap()).unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
     let port = global_map.get(CONFIG_KEY_PORT).map(|v| v.clone().into_string().unwrap()).unwrap_or_else(|| DEFAULT_PORT.to_string());
     let log_file = global_map.get(CONFIG_KEY_LOG).map(|v| v.clone().into_string().unwrap()).unwrap_or_else(|| "-".to_string());
+    let group = global_map.get(CONFIG_KEY_GROUP).map(|v| v.clone().into_string().unwrap());
+    let key_file = global_map.get(CONFIG_KEY_KEY_FILE).map(|v| v.clone().into_string().unwrap());
+    let freeze_cgroup = global_map.get(CONFIG_KEY_FREEZE_CGROUP).map(|v| v.clone().into_string().unwrap());
+    let heartbeat = global_map.get(CONFIG_KEY_HEARTBEAT).is_some_and(|v| v.clone().into_bool().unwrap());
+    let timeouts = parse_timeouts(global_map.get(CONFIG_KEY_TIMEOUTS)).unwrap_or_else(config_error);
+    let quorum = parse_quorum(global_map.get(CONFIG_KEY_QUORUM)).unwrap_or_else(config_error);
+    let hooks = parse_hooks(global_map.get(CONFIG_KEY_HOOKS)).unwrap_or_else(config_error);
+    let scripts = parse_scripts(global_map.get(CONFIG_KEY_SCRIPTS)).unwrap_or_else(config_error);
+    let quiesce = parse_quiesce(global_map.get(CONFIG_KEY_QUIESCE)).unwrap_or_else(config_error);
 
     if is_dump_action(action) {
         let pid_str = env::var(ENV_INIT_PID)
@@ -173,13 +356,25 @@ pub fn load_config_file<P: AsRef<Path>>(images_dir: P, action: &str) -> ClientCo
             write_checkpoint_config(images_dir, &id, &dependencies);
         }
 
-        ClientConfig::new(
+        let mut client_config = ClientConfig::new(
             log_file,
             address,
             port,
             id,
             dependencies,
-        )
+        );
+        if let Some(group) = group {
+            client_config.set_group(group);
+        }
+        client_config.set_key_file(key_file);
+        client_config.set_freeze_cgroup(freeze_cgroup);
+        client_config.set_heartbeat(heartbeat);
+        client_config.set_timeouts(timeouts);
+        client_config.set_quorum(quorum);
+        client_config.set_hooks(hooks.clone());
+        client_config.set_scripts(scripts.clone());
+        client_config.set_quiesce(quiesce.clone());
+        client_config
     } else { // Restore action
         if !local_config_file.is_file() {
             panic!("Restore action initiated, but no {CONFIG_FILE} found in the image directory {:?}", images_dir);
@@ -188,16 +383,135 @@ pub fn load_config_file<P: AsRef<Path>>(images_dir: P, action: &str) -> ClientCo
         let local_settings = Config::builder().add_source(config::File::from(local_config_file)).build().unwrap();
         let local_map = local_settings.try_deserialize::<HashMap<String, String>>().unwrap();
 
-        ClientConfig::new(
+        let mut client_config = ClientConfig::new(
             log_file,
             address,
             port,
             local_map.get(CONFIG_KEY_ID).unwrap().clone(),
             local_map.get(CONFIG_KEY_DEPS).cloned().unwrap_or_default(),
-        )
+        );
+        if let Some(group) = group {
+            client_config.set_group(group);
+        }
+        client_config.set_key_file(key_file);
+        client_config.set_heartbeat(heartbeat);
+        client_config.set_timeouts(timeouts);
+        client_config.set_quorum(quorum);
+        client_config.set_hooks(hooks.clone());
+        client_config.set_scripts(scripts.clone());
+        client_config.set_quiesce(quiesce.clone());
+        client_config
     }
 }
 
+/// Report an invalid configuration and exit. The logger is not set up yet,
+/// as it is configured by the same file.
+fn config_error<T>(error: String) -> T {
+    eprintln!("Invalid configuration: {error}");
+    exit(1);
+}
+
+/// Parse a map of actions to timeouts in seconds.
+fn parse_timeouts(timeouts: Option<&config::Value>) -> Result<HashMap<String, u64>, String> {
+    let timeouts = match timeouts {
+        Some(timeouts) => timeouts.clone().into_table().map_err(|_| "timeouts must map actions to seconds".to_string())?,
+        None => return Ok(HashMap::new()),
+    };
+    timeouts.into_iter()
+        .map(|(action, timeout)| {
+            let timeout = timeout.into_uint().map_err(|_| format!("Invalid timeout for {:?}", action))?;
+            Ok((action, timeout))
+        })
+        .collect()
+}
+
+/// Parse the number of dependencies to wait for at a barrier.
+fn parse_quorum(quorum: Option<&config::Value>) -> Result<Option<usize>, String> {
+    quorum.map(|quorum| {
+        quorum.clone().into_uint().ok()
+            .and_then(|quorum| usize::try_from(quorum).ok())
+            .ok_or_else(|| format!("Invalid quorum {quorum}"))
+    }).transpose()
+}
+
+/// Parse a map of hooks to the modes in which they are coordinated.
+fn parse_hooks(hooks: Option<&config::Value>) -> Result<HashMap<String, HookMode>, String> {
+    let hooks = match hooks {
+        Some(hooks) => hooks.clone().into_table().map_err(|_| "hooks must map actions to modes".to_string())?,
+        None => return Ok(HashMap::new()),
+    };
+    hooks.into_iter()
+        .map(|(action, mode)| {
+            let mode = mode.into_string().ok()
+                .and_then(|mode| HookMode::parse(&mode))
+                .ok_or_else(|| format!("Invalid mode for hook {:?}", action))?;
+            Ok((action, mode))
+        })
+        .collect()
+}
+
+/// Parse a map of actions to the user scripts that run before and after them.
+fn parse_scripts(scripts: Option<&config::Value>) -> Result<HashMap<String, ActionScripts>, String> {
+    let scripts = match scripts {
+        Some(scripts) => scripts.clone().into_table().map_err(|_| "scripts must map actions to their scripts".to_string())?,
+        None => return Ok(HashMap::new()),
+    };
+    let commands = |action: &str, value: Option<config::Value>| -> Result<Vec<String>, String> {
+        let invalid = || format!("Invalid scripts for {:?}, expected a list of commands", action);
+        match value {
+            Some(value) => value.into_array().map_err(|_| invalid())?
+                .into_iter().map(|script| script.into_string().map_err(|_| invalid())).collect(),
+            None => Ok(Vec::new()),
+        }
+    };
+    scripts.into_iter()
+        .map(|(action, value)| {
+            let mut table = value.into_table().map_err(|_| format!("Invalid scripts for {:?}", action))?;
+            let abort_group = match table.remove("abort-group") {
+                Some(value) => value.into_bool().map_err(|_| format!("Invalid abort-group for {:?}", action))?,
+                None => false,
+            };
+            let scripts = ActionScripts {
+                before: commands(&action, table.remove("before"))?,
+                after: commands(&action, table.remove("after"))?,
+                abort_group,
+            };
+            Ok((action, scripts))
+        })
+        .collect()
+}
+
+/// Parse how the application is notified of checkpoints, over a Unix socket
+/// or with signals.
+fn parse_quiesce(quiesce: Option<&config::Value>) -> Result<Option<Quiesce>, String> {
+    let mut table = match quiesce {
+        Some(quiesce) => quiesce.clone().into_table().map_err(|_| "quiesce must be a table".to_string())?,
+        None => return Ok(None),
+    };
+    let mut string = |key: &str| -> Result<Option<String>, String> {
+        table.remove(key).map(|value| value.into_string().map_err(|_| format!("Invalid quiesce {key}"))).transpose()
+    };
+    let signal = |name: String| name.parse().map_err(|_| format!("Invalid signal {:?}", name));
+    let notifier = match (string("socket")?, string("signal")?) {
+        (Some(socket), None) => Notifier::Socket(PathBuf::from(socket)),
+        (None, Some(quiesce)) => Notifier::Signal {
+            quiesce: signal(quiesce)?,
+            resumed: string("resumed-signal")?.map(signal).transpose()?,
+            ack_file: string("ack-file")?.map(PathBuf::from),
+        },
+        _ => return Err("quiesce needs either a socket or a signal".to_string()),
+    };
+    let timeout = match table.remove("timeout") {
+        Some(timeout) => Duration::from_secs(timeout.into_uint().map_err(|_| "Invalid quiesce timeout".to_string())?),
+        None => DEFAULT_QUIESCE_TIMEOUT,
+    };
+    let leave_running = match table.remove("leave-running") {
+        Some(leave_running) => leave_running.into_bool().map_err(|_| "Invalid quiesce leave-running".to_string())?,
+        None => false,
+    };
+    Ok(Some(Quiesce { notifier, timeout, leave_running }))
+}
+
  /// Find containers dependencies by matching the discovered ID as a prefix of a key in the map
 fn find_dependencies_in_global_config(
     deps_map: &HashMap<String, Vec<String>>,
@@ -253,30 +567,86 @@ fn write_checkpoint_config(img_dir: &Path, id: &str, dependencies: &str) {
 
 
 pub fn is_dump_action(action: &str) -> bool {
-    matches!(action, ACTION_PRE_DUMP | ACTION_NETWORK_LOCK | ACTION_POST_DUMP | ACTION_PRE_STREAM)
+    matches!(action, ACTION_PRE_DUMP | ACTION_NETWORK_LOCK | ACTION_POST_DUMP | ACTION_PRE_STREAM | ACTION_POST_STREAM)
+        || phase::hook_operation(action) == Some(Operation::Dump)
 }
 
 pub fn is_restore_action(action: &str) -> bool {
     matches!(action, ACTION_PRE_RESTORE | ACTION_POST_RESTORE | ACTION_NETWORK_UNLOCK | ACTION_POST_RESUME)
+        || phase::hook_operation(action) == Some(Operation::Restore)
 }
 
-pub fn run_client(address: &str, port: u16, id: &str, deps: &str, action: &str, images_dir: &Path, enable_streaming: bool) {
-    let server_address = format!("{address}:{port}");
+/// Coordinate `action` with the dependencies of the client. Returns false
+/// if the action failed, and CRIU must abort the dump or restore.
+pub fn run_client(config: &ClientConfig, action: &str, images_dir: &Path, enable_streaming: bool) -> bool {
+    if config.get_hook_mode(action) == Some(HookMode::Ignore) {
+        return true;
+    }
+    let server_address = format!("{}:{}", config.get_address(), config.get_port());
+
+    let image_key = match config.get_key_file() {
+        Some(key_file) => match ImageKey::load(Path::new(key_file)) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                error!("Failed to load key file {key_file}: {e}");
+                return false;
+            }
+        },
+        None => None,
+    };
+
+    if action == ACTION_PRE_RESTORE {
+        if let Some(key) = &image_key {
+            if let Err(e) = restore_images(images_dir, key) {
+                error!("Failed to decrypt checkpoint images: {e}");
+                return false;
+            }
+        }
+    }
+
+    let freeze_cgroup = find_freeze_cgroup(config);
+    // CRIU runs network-unlock as well when the dump fails or leaves the
+    // processes running.
+    let thaw_frozen_cgroup = || {
+        if let Some(cgroup) = &freeze_cgroup {
+            thaw(cgroup);
+        }
+    };
+    if matches!(action, ACTION_POST_DUMP | ACTION_POST_STREAM | ACTION_NETWORK_UNLOCK) {
+        thaw_frozen_cgroup();
+    }
 
     info!("Connecting to {server_address} using action {action}");
     match TcpStream::connect(&server_address) {
         Ok(mut tcp_stream) => {
             info!("Connected to server at {server_address}");
 
-            let cmd = object!{
-                id: id,
+            let mut cmd = object!{
+                id: config.get_id(),
                 action: action,
-                dependencies: deps,
+                dependencies: config.get_dependencies(),
+                group: config.get_group(),
             };
+            // The server replies in JSON to clients that send timeouts.
+            for (action, timeout) in config.get_timeouts() {
+                cmd["timeouts"][action.as_str()] = (*timeout).into();
+            }
+            if let Some(quorum) = config.get_quorum() {
+                cmd["quorum"] = quorum.into();
+            }
+            if let Some(mode) = config.get_hook_mode(action) {
+                cmd["hook"] = mode.as_str().into();
+            }
+            if let Some(connections) = find_connections(action, images_dir) {
+                cmd["connections"] = connections.into_iter().map(Connection::to_json).collect::<Vec<_>>().into();
+            }
+            if action == ACTION_PRE_RESTORE {
+                cmd["problems"] = check_restore(images_dir).into();
+            }
 
             if let Err(e) = tcp_stream.write_all(cmd.dump().as_bytes()) {
                 error!("Failed to send ID: {e}");
-                return;
+                return true;
             }
 
             let mut buffer = [0; BUFFER_SIZE];
@@ -287,9 +657,36 @@ pub fn run_client(address: &str, port: u16, id: &str, deps: &str, action: &str,
 
             match response {
                 Ok(response_str) => {
-                    info!("Server responded with: {response_str}");
+                    // The reply holds the deadline of the action.
+                    let reply = json::parse(response_str).unwrap_or(JsonValue::Null);
+                    let response_str = reply["reply"].as_str().unwrap_or(response_str);
+                    match reply["timeout"].as_u64() {
+                        Some(timeout) => info!("Server responded with: {response_str} (timeout {timeout}s, deadline {})", reply["deadline"]),
+                        None => info!("Server responded with: {response_str}"),
+                    }
+                    // The processes keep running after a failed dump, and
+                    // after a restore.
+                    let is_resumed = match action {
+                        ACTION_NETWORK_LOCK => response_str != MESSAGE_ACK,
+                        ACTION_POST_DUMP | ACTION_POST_STREAM => {
+                            response_str != MESSAGE_ACK || config.get_quiesce().is_some_and(|quiesce| quiesce.leave_running)
+                        }
+                        ACTION_POST_RESUME => true,
+                        _ => false,
+                    };
+                    if is_resumed {
+                        notify_resumed(config);
+                    }
                     if response_str != MESSAGE_ACK {
-                        exit(1);
+                        // CRIU resumes the processes when post-dump fails,
+                        // and kills them otherwise.
+                        if matches!(action, ACTION_POST_DUMP | ACTION_POST_STREAM) {
+                            info!("The dump of group {} failed, resuming the processes", config.get_group());
+                        }
+                        if is_dump_action(action) {
+                            thaw_frozen_cgroup();
+                        }
+                        return false;
                     }
                 }
                 Err(e) => {
@@ -297,8 +694,31 @@ pub fn run_client(address: &str, port: u16, id: &str, deps: &str, action: &str,
                 }
             }
 
+            if config.get_heartbeat() && matches!(action, ACTION_PRE_DUMP | ACTION_PRE_STREAM | ACTION_PRE_RESTORE) {
+                spawn_heartbeat(config);
+            }
+
+            if action == ACTION_PRE_DUMP || action == ACTION_PRE_STREAM {
+                if let Some(quiesce) = config.get_quiesce() {
+                    if !quiesce_with_group(config, &server_address, quiesce) {
+                        notify_resumed(config);
+                        return false;
+                    }
+                }
+                if let Some(cgroup) = &freeze_cgroup {
+                    if !freeze_with_group(config, &server_address, cgroup) {
+                        notify_resumed(config);
+                        return false;
+                    }
+                }
+            }
+
             if enable_streaming {
-                streamer(&mut tcp_stream, images_dir).expect("Failed to start streamer");
+                if let Err(e) = streamer(&mut tcp_stream, images_dir, config.get_id(), config.get_group(), image_key) {
+                    error!("Failed to start streamer: {e}");
+                    thaw_frozen_cgroup();
+                    return false;
+                }
             }
 
             if let Err(e) = tcp_stream.shutdown(Shutdown::Both) {
@@ -309,4 +729,403 @@ pub fn run_client(address: &str, port: u16, id: &str, deps: &str, action: &str,
             error!("Failed to connect to the server: {e}");
         }
     }
+    true
+}
+
+/// Run the user scripts of `action`. CRIU runs a single action script, which
+/// is criu-coordinator, so the scripts that would otherwise be action scripts
+/// are run from here, with the same environment and output. A failing script
+/// fails the action, and aborts the group if configured to.
+pub fn run_action_scripts(config: &ClientConfig, action: &str, stage: ScriptStage) {
+    let scripts = match config.get_scripts(action) {
+        Some(scripts) => scripts,
+        None => return,
+    };
+    let commands = if stage == ScriptStage::Before { &scripts.before } else { &scripts.after };
+    for command in commands {
+        info!("Running {command} for {action}");
+        // CRIU runs action scripts with the shell as well. It reads the
+        // standard output of query-ext-files, so other scripts write to
+        // standard error.
+        let mut script = Command::new("sh");
+        script.arg("-c").arg(command);
+        if action != ACTION_QUERY_EXT_FILES {
+            script.stdout(io::stderr());
+        }
+        let status = script.status();
+        let error = match status {
+            Ok(status) if status.success() => continue,
+            Ok(status) => format!("{command} failed with {status}"),
+            Err(e) => format!("Failed to run {command}: {e}"),
+        };
+        error!("{error}");
+        if scripts.abort_group {
+            abort_group(config, action, &error);
+        }
+        exit(1);
+    }
+}
+
+/// Tell the server that the client failed `action`, so that the clients of
+/// its group that wait for it fail as well.
+fn abort_group(config: &ClientConfig, action: &str, reason: &str) {
+    let server_address = format!("{}:{}", config.get_address(), config.get_port());
+    let cmd = object!{
+        id: config.get_id(),
+        action: ACTION_ABORT,
+        dependencies: "",
+        group: config.get_group(),
+        "failed-action": action,
+        reason: reason,
+    };
+    let result = TcpStream::connect(&server_address).and_then(|mut tcp_stream| {
+        tcp_stream.write_all(cmd.dump().as_bytes())?;
+        let mut buffer = [0; BUFFER_SIZE];
+        let size = tcp_stream.read(&mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer[..size]).to_string())
+    });
+    match result {
+        Ok(response) if response == MESSAGE_ACK => info!("Aborted group {}", config.get_group()),
+        Ok(response) => error!("Failed to abort group {}: {response}", config.get_group()),
+        Err(e) => error!("Failed to abort group {}: {e}", config.get_group()),
+    }
+}
+
+/// Start a process that sends heartbeats for the client until its operation
+/// completes. CRIU runs a new client for every action, so the heartbeats
+/// cannot be sent by the client itself.
+fn spawn_heartbeat(config: &ClientConfig) {
+    let result = env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            .args(["heartbeat", "--address", config.get_address(), "--port", config.get_port()])
+            .args(["--id", config.get_id(), "--group", config.get_group()])
+            // Otherwise the heartbeat process would run as a CRIU action hook.
+            .env_remove(ENV_ACTION)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+    });
+    if let Err(e) = result {
+        error!("Failed to start sending heartbeats: {e}");
+    }
+}
+
+/// Send heartbeats for client `id` at the interval given by the server,
+/// until the server closes the connection.
+pub fn run_heartbeat(address: &str, port: &str, id: &str, group: &str) {
+    let server_address = format!("{address}:{port}");
+    let mut tcp_stream = match TcpStream::connect(&server_address) {
+        Ok(tcp_stream) => tcp_stream,
+        Err(_) => exit(1),
+    };
+    let cmd = object!{ id: id, action: ACTION_HEARTBEAT, dependencies: "", group: group };
+    if tcp_stream.write_all(cmd.dump().as_bytes()).is_err() {
+        exit(1);
+    }
+
+    let mut buffer = [0; BUFFER_SIZE];
+    let interval = match tcp_stream.read(&mut buffer) {
+        Ok(size) => str::from_utf8(&buffer[..size]).ok()
+            .and_then(|reply| json::parse(reply).ok())
+            .and_then(|reply| reply["interval"].as_u64()),
+        Err(_) => None,
+    };
+    let interval = match interval {
+        Some(interval) => Duration::from_millis(interval),
+        None => exit(1),
+    };
+    while tcp_stream.write_all(b".").is_ok() {
+        thread::sleep(interval);
+    }
+}
+
+/// The cgroup to freeze before the dump, if any.
+fn find_freeze_cgroup(config: &ClientConfig) -> Option<Cgroup> {
+    match config.get_freeze_cgroup()? {
+        "auto" => {
+            let pid = match env::var(ENV_INIT_PID).ok().and_then(|pid| pid.parse().ok()) {
+                Some(pid) => pid,
+                None => {
+                    error!("Cannot find the cgroup to freeze: {ENV_INIT_PID} is not set");
+                    return None;
+                }
+            };
+            Cgroup::of_process(pid)
+                .map_err(|e| error!("Cannot find the cgroup of process {pid}: {e}"))
+                .ok()
+        }
+        path => Some(Cgroup::new(Path::new(path))),
+    }
+}
+
+/// Tell the application that a checkpoint is imminent, then wait until the
+/// applications of all dependencies have quiesced.
+fn quiesce_with_group(config: &ClientConfig, server_address: &str, quiesce: &Quiesce) -> bool {
+    match quiesce_at_barrier(config, server_address, quiesce) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Coordinated quiesce failed: {e}");
+            false
+        }
+    }
+}
+
+fn quiesce_at_barrier(config: &ClientConfig, server_address: &str, quiesce: &Quiesce) -> Result<(), String> {
+    quiesce.quiesce(init_pid()).map_err(|e| format!("The application did not quiesce: {e}"))?;
+    info!("The application has quiesced");
+    let mut tcp_stream = TcpStream::connect(server_address).map_err(|e| e.to_string())?;
+    let cmd = object!{
+        id: config.get_id(),
+        action: ACTION_QUIESCE,
+        dependencies: config.get_dependencies(),
+        group: config.get_group(),
+    };
+    tcp_stream.write_all(cmd.dump().as_bytes()).map_err(|e| e.to_string())?;
+    expect_ack(&mut tcp_stream)
+}
+
+/// Tell the application that its processes keep running, after a restore
+/// or a dump that did not stop them.
+fn notify_resumed(config: &ClientConfig) {
+    if let Some(quiesce) = config.get_quiesce() {
+        match quiesce.resumed(init_pid()) {
+            Ok(()) => info!("Notified the application that it was resumed"),
+            Err(e) => error!("Failed to notify the application that it was resumed: {e}"),
+        }
+    }
+}
+
+/// PID of the process that CRIU checkpoints or restores.
+fn init_pid() -> Option<i32> {
+    env::var(ENV_INIT_PID).ok()?.parse().ok()
+}
+
+/// Freeze `cgroup` together with the dependencies of the client. The
+/// cgroup is thawed if the group could not be frozen.
+fn freeze_with_group(config: &ClientConfig, server_address: &str, cgroup: &Cgroup) -> bool {
+    match freeze_at_barrier(config, server_address, cgroup) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Coordinated freeze failed: {e}");
+            thaw(cgroup);
+            false
+        }
+    }
+}
+
+/// The server replies once all dependencies have reached the freeze
+/// barrier, then again once all of them report that they are frozen.
+fn freeze_at_barrier(config: &ClientConfig, server_address: &str, cgroup: &Cgroup) -> Result<(), String> {
+    let mut tcp_stream = TcpStream::connect(server_address).map_err(|e| e.to_string())?;
+    let cmd = object!{
+        id: config.get_id(),
+        action: ACTION_FREEZE,
+        dependencies: config.get_dependencies(),
+        group: config.get_group(),
+    };
+    tcp_stream.write_all(cmd.dump().as_bytes()).map_err(|e| e.to_string())?;
+    expect_ack(&mut tcp_stream)?;
+
+    cgroup.freeze().map_err(|e| format!("Failed to freeze {:?}: {e}", cgroup.path()))?;
+    let frozen_at = timeline::now();
+    info!("Froze {:?}", cgroup.path());
+    tcp_stream.write_all(object!{ frozen_at: frozen_at }.dump().as_bytes()).map_err(|e| e.to_string())?;
+    expect_ack(&mut tcp_stream)
+}
+
+fn thaw(cgroup: &Cgroup) {
+    match cgroup.thaw() {
+        Ok(()) => info!("Thawed {:?}", cgroup.path()),
+        Err(e) => error!("Failed to thaw {:?}: {e}", cgroup.path()),
+    }
+}
+
+fn expect_ack(tcp_stream: &mut TcpStream) -> Result<(), String> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let size = tcp_stream.read(&mut buffer).map_err(|e| e.to_string())?;
+    match str::from_utf8(&buffer[..size]) {
+        Ok(MESSAGE_ACK) => Ok(()),
+        Ok(response) => Err(format!("Server responded with: {response}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Find the established TCP connections of the checkpointed processes, which
+/// the server uses to discover missing dependencies. They are read from
+/// /proc before the dump and from the images after the dump.
+fn find_connections(action: &str, images_dir: &Path) -> Option<Vec<Connection>> {
+    let connections = match action {
+        ACTION_PRE_DUMP | ACTION_PRE_STREAM => {
+            let pid = env::var(ENV_INIT_PID).ok()?.parse().ok()?;
+            connections::from_proc(pid)
+        }
+        ACTION_POST_DUMP => connections::from_images(images_dir),
+        _ => return None,
+    };
+    match connections {
+        Ok(connections) if !connections.is_empty() => Some(connections),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Failed to find TCP connections: {e}");
+            None
+        }
+    }
+}
+
+/// Check the images against this host before they are restored. The
+/// problems found are reported to the server, which aborts the restore of
+/// the whole group.
+fn check_restore(images_dir: &Path) -> Vec<String> {
+    if !is_images_dir(images_dir) {
+        return Vec::new();
+    }
+    match check_host(images_dir) {
+        Ok(problems) => {
+            for problem in problems.iter() {
+                error!("{problem}");
+            }
+            problems
+        }
+        Err(e) => vec![format!("Failed to check the images: {e}")],
+    }
+}
+
+/// Send a request of the `list`, `show`, `tag` or `delete` commands to the
+/// server and print its reply. Exits with status 1 if the request failed.
+pub fn run_catalog_command(address: &str, port: u16, action: &str, params: JsonValue) {
+    let response = query_server(address, port, action, params);
+
+    match action {
+        ACTION_CATALOG_LIST => {
+            println!("{:<20} {:<16} {:>10} {:>10}  {:<24} TAGS", "EPOCH", "GROUP", "SIZE", "AGE", "MEMBERS");
+            for entry in response["checkpoints"].members() {
+                println!(
+                    "{:<20} {:<16} {:>10} {:>10}  {:<24} {}",
+                    entry["epoch"],
+                    entry["group"],
+                    format_size(entry["size"].as_u64().unwrap_or(0)),
+                    format_age(entry["committed"].as_u64().unwrap_or(0)),
+                    join(&entry["members"]),
+                    tags(entry),
+                );
+            }
+        }
+        ACTION_CATALOG_SHOW => println!("{}", response.pretty(4)),
+        ACTION_CATALOG_TAG => println!("Tags of {}: {}", response["epoch"], join(&response["tags"])),
+        _ => println!("Deleted checkpoint {}", response["epoch"]),
+    }
+}
+
+/// Send a catalog request to the server and return its reply. Exits with
+/// status 1 if the request failed.
+fn query_server(address: &str, port: u16, action: &str, params: JsonValue) -> JsonValue {
+    let mut request = params;
+    request["id"] = "catalog".into();
+    request["action"] = action.into();
+    request["dependencies"] = "".into();
+
+    let response = TcpStream::connect((address, port))
+        .and_then(|mut tcp_stream| {
+            tcp_stream.write_all(request.dump().as_bytes())?;
+            let mut response = String::new();
+            tcp_stream.read_to_string(&mut response)?;
+            Ok(response)
+        })
+        .map_err(|e| format!("Failed to query the server at {address}:{port}: {e}"))
+        .and_then(|response| json::parse(&response).map_err(|_| format!("Invalid response from server: {response}")));
+
+    match response {
+        Ok(response) if response["error"].is_null() => response,
+        Ok(response) => {
+            eprintln!("{}", response["error"]);
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            exit(1);
+        }
+    }
+}
+
+/// List the timelines recorded by the server, or print the timeline of
+/// `epoch`. With `trace`, the timeline is written as a Chrome trace instead.
+pub fn run_timeline_command(address: &str, port: u16, epoch: Option<&str>, trace: Option<&str>) {
+    let epoch = match epoch {
+        Some(epoch) => epoch,
+        None => {
+            let response = query_server(address, port, ACTION_CATALOG_TIMELINE, object!{});
+            println!("{:<20} {:<16} {:>10}  CLIENTS", "EPOCH", "GROUP", "AGE");
+            for timeline in response["timelines"].members() {
+                println!(
+                    "{:<20} {:<16} {:>10}  {}",
+                    timeline["id"],
+                    timeline["group"],
+                    format_age(timeline["started"].as_u64().unwrap_or(0) / 1_000_000),
+                    join(&timeline["clients"]),
+                );
+            }
+            return;
+        }
+    };
+
+    let response = query_server(address, port, ACTION_CATALOG_TIMELINE, object!{ epoch: epoch });
+    let timeline = match Timeline::from_json(&response) {
+        Some(timeline) => timeline,
+        None => {
+            eprintln!("Invalid timeline received from server");
+            exit(1);
+        }
+    };
+    let result = match trace {
+        None => {
+            print!("{}", timeline.report());
+            Ok(())
+        }
+        Some("-") => writeln!(io::stdout(), "{}", timeline.to_chrome_trace().dump()),
+        Some(path) => fs::write(path, timeline.to_chrome_trace().dump()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to write the trace of {epoch}: {e}");
+        exit(1);
+    }
+}
+
+fn join(list: &JsonValue) -> String {
+    list.members().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{size} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// Tags of a catalog entry, marking checkpoints that failed or missed
+/// verification.
+fn tags(entry: &JsonValue) -> String {
+    match entry["consistent"].as_bool() {
+        Some(false) => format!("{} (inconsistent)", join(&entry["tags"])).trim_start().to_string(),
+        Some(true) => join(&entry["tags"]),
+        None => format!("{} (unverified)", join(&entry["tags"])).trim_start().to_string(),
+    }
+}
+
+/// Format the time elapsed since the Unix time `time`, e.g. `5m ago`.
+fn format_age(time: u64) -> String {
+    let age = unix_time().saturating_sub(time);
+    match age {
+        0..=59 => format!("{age}s ago"),
+        60..=3599 => format!("{}m ago", age / 60),
+        3600..=86399 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    }
 }
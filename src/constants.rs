@@ -26,7 +26,37 @@ pub const ACTION_NETWORK_UNLOCK: &str = "network-unlock";
 pub const ACTION_PRE_STREAM: &str = "pre-stream";
 pub const ACTION_POST_STREAM: &str = "post-stream";
 pub const ACTION_POST_RESUME: &str = "post-resume";
+pub const ACTION_SETUP_NAMESPACES: &str = "setup-namespaces";
+pub const ACTION_POST_SETUP_NAMESPACES: &str = "post-setup-namespaces";
+pub const ACTION_PRE_RESUME: &str = "pre-resume";
+pub const ACTION_ORPHAN_PTS_MASTER: &str = "orphan-pts-master";
+pub const ACTION_QUERY_EXT_FILES: &str = "query-ext-files";
 pub const ACTION_ADD_DEPENDENCIES: &str = "add-dependencies";
+/// Action used by applications that wait for their group at a named barrier.
+pub const ACTION_BARRIER: &str = "barrier";
+/// Action used by clients that have quiesced their application, to wait
+/// until the group has quiesced before the dump.
+pub const ACTION_QUIESCE: &str = "quiesce";
+/// Action used by clients that freeze their cgroup together with the group
+/// before the dump.
+pub const ACTION_FREEZE: &str = "freeze";
+/// Action used by clients that send heartbeats during an operation.
+pub const ACTION_HEARTBEAT: &str = "heartbeat";
+/// Action used by clients whose action scripts failed, to abort their group.
+pub const ACTION_ABORT: &str = "abort";
+/// Action used by the streamer to open a data connection for image uploads.
+pub const ACTION_UPLOAD_IMAGE: &str = "upload-image";
+/// Action used by the streamer to open a data connection for the marker
+/// stream of images produced while CRIU is running.
+pub const ACTION_STREAM_IMAGE: &str = "stream-image";
+/// Actions used by the `list`, `show`, `tag` and `delete` commands
+/// to query and update the catalog of committed checkpoints.
+pub const ACTION_CATALOG_LIST: &str = "catalog-list";
+pub const ACTION_CATALOG_SHOW: &str = "catalog-show";
+pub const ACTION_CATALOG_TAG: &str = "catalog-tag";
+pub const ACTION_CATALOG_DELETE: &str = "catalog-delete";
+/// Action used by the `timeline` command to query the recorded timelines.
+pub const ACTION_CATALOG_TIMELINE: &str = "catalog-timeline";
 
 /// ENV_ACTION specifies the CRIU hook that is currently being used.
 pub const ENV_ACTION: &str = "CRTOOLS_SCRIPT_ACTION";
@@ -38,8 +68,17 @@ pub const ENV_INIT_PID: &str = "CRTOOLS_INIT_PID";
 /// Unix socket used for "criu dump".
 pub const IMG_STREAMER_CAPTURE_SOCKET_NAME: &str = "streamer-capture.sock";
 
+/// Suffix of a dependency that the client does not fail without.
+pub const OPTIONAL_DEPENDENCY_SUFFIX: char = '?';
+/// Prefix of a dependency that is restored and resumed before the client.
+pub const ORDERED_DEPENDENCY_PREFIX: char = '^';
+
 /// CONFIG_FILE is used to load checkpoint/restore parameters.
 pub const CONFIG_FILE: &str = "criu-coordinator.json";
+/// Group of the clients that do not name one.
+pub const DEFAULT_GROUP: &str = "default";
+/// MANIFEST_FILE lists the verified image files of a checkpoint stored by the server.
+pub const MANIFEST_FILE: &str = "MANIFEST.json";
 
 /// Acknowledgment message sent to clients when an operation is successful.
 pub const MESSAGE_ACK: &str = "ACK";
@@ -47,11 +86,26 @@ pub const MESSAGE_ACK: &str = "ACK";
 pub const MESSAGE_SYN: &str = "SYN";
 /// Acknowledgment message for successful receipt of an image chunk.
 pub const MESSAGE_IMG_ACK: &str = "IMG_ACK";
+/// Error message when a received image file does not match its size or digest.
+pub const MESSAGE_IMG_CORRUPTED: &str = "image verification failed";
+/// MESSAGE_STORAGE_FAILED is sent when a verified checkpoint could not be stored.
+pub const MESSAGE_STORAGE_FAILED: &str = "failed to store checkpoint";
 /// Error message to signal a timed out during connection or readiness check.
 pub const MESSAGE_TIMEOUT: &str = "timeout";
 /// Error message when a client dependency is not connected.
 pub const MESSAGE_NOT_CONNECTED: &str = "not connected";
 /// Message indicating that a checkpoint is already created.
 pub const MESSAGE_CHECKPOINT_EXISTS: &str = "checkpoint is already created";
-/// Message indicating that a client is already connected.
-pub const MESSAGE_ALREADY_CONNECTED: &str = "client already connected";
+/// Error message when a client sends an action out of order.
+pub const MESSAGE_INVALID_ACTION: &str = "invalid action";
+/// Error message when a client ID cannot be used as a directory name.
+pub const MESSAGE_INVALID_ID: &str = "invalid client ID";
+/// Error message when a dependency stopped sending heartbeats.
+pub const MESSAGE_DEPENDENCY_LOST: &str = "dependency lost";
+/// Error message when a member of the group failed its pre-restore checks.
+pub const MESSAGE_RESTORE_ABORTED: &str = "restore aborted";
+/// Error message when the ordered dependencies of a group form a cycle.
+pub const MESSAGE_DEPENDENCY_CYCLE: &str = "dependency cycle";
+/// Reply to post-dump when a member of the group failed its dump, so that
+/// the processes are resumed instead of killed.
+pub const MESSAGE_RESUME: &str = "resume";
@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Freezing of a cgroup v2 with its `cgroup.freeze` interface.
+//!
+//! Writing `1` to `cgroup.freeze` asks the kernel to stop all processes of
+//! the cgroup and its descendants. The cgroup is frozen once `cgroup.events`
+//! reports `frozen 1`.
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Mount point of the cgroup v2 hierarchy.
+const CGROUP2_ROOT: &str = "/sys/fs/cgroup";
+/// Time allowed for the processes of a cgroup to stop.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    /// The cgroup v2 of process `pid`, from the `0::<path>` line of
+    /// `/proc/<pid>/cgroup`.
+    pub fn of_process(pid: u32) -> Result<Self> {
+        let content = fs::read_to_string(format!("/proc/{pid}/cgroup"))?;
+        let path = content.lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Process {pid} is not in a cgroup v2")))?;
+        Ok(Self::new(&Path::new(CGROUP2_ROOT).join(path.trim_start_matches('/'))))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Freeze the cgroup and wait until all its processes have stopped.
+    pub fn freeze(&self) -> Result<()> {
+        fs::write(self.path.join("cgroup.freeze"), "1")?;
+        let start = Instant::now();
+        while !self.is_frozen()? {
+            if start.elapsed() > FREEZE_TIMEOUT {
+                return Err(Error::new(ErrorKind::TimedOut, format!("{:?} did not freeze", self.path)));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    pub fn thaw(&self) -> Result<()> {
+        fs::write(self.path.join("cgroup.freeze"), "0")
+    }
+
+    fn is_frozen(&self) -> Result<bool> {
+        let events = fs::read_to_string(self.path.join("cgroup.events"))?;
+        Ok(events.lines().any(|line| line == "frozen 1"))
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Readers for the image files that CRIU writes into an images directory.
+//!
+//! Most images start with two little-endian u32 magic numbers, a common
+//! magic (`IMG_COMMON` or `IMG_SERVICE`) and the magic of the image type,
+//! followed by length-prefixed protobuf entries. Raw images, such as
+//! `pages-*.img`, have no magic and are not read here.
+
+use std::{
+    fs::File,
+    io::{BufReader, Error, Read, Result, Write},
+    mem::size_of,
+    path::Path,
+};
+use prost::Message;
+
+use crate::pipeline::frame::{read_frame_with_limit, write_frame};
+use crate::pipeline::protobuf::{invalid_data, MB};
+
+pub mod magic;
+pub mod checkpoint;
+pub mod inspect;
+pub mod diff;
+pub mod connections;
+pub mod consistency;
+pub mod rewrite;
+pub mod compatibility;
+pub mod pb2json;
+pub mod crit;
+
+/// Upper bound for a single image entry. Entries can be much larger than
+/// the frames of the coordination protocol, e.g. the core of a process.
+const MAX_ENTRY_SIZE: usize = 64 * MB;
+
+/// ImageReader reads the entries of a CRIU image.
+pub struct ImageReader<R: Read> {
+    src: R,
+    magic: u32,
+}
+
+impl<R: Read> ImageReader<R> {
+    /// Read the magic of the image.
+    pub fn new(mut src: R) -> Result<Self> {
+        let mut magic = read_u32(&mut src)?;
+        if magic == magic::IMG_COMMON || magic == magic::IMG_SERVICE {
+            magic = read_u32(&mut src)?;
+        }
+        if magic::name(magic).is_none() {
+            return Err(invalid_data(format!("Unknown image magic {magic:#x}")));
+        }
+        Ok(Self { src, magic })
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    /// Read the next entry, or `None` at the end of the image.
+    pub fn next_entry(&mut self) -> Result<Option<Vec<u8>>> {
+        read_frame_with_limit(&mut self.src, MAX_ENTRY_SIZE)
+    }
+
+    /// Read `len` bytes of data that follow an entry.
+    pub fn read_payload(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut payload = vec![0u8; len];
+        self.src.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Read the rest of the image as data.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        self.src.read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+/// ImageWriter writes the magic and entries of a CRIU image.
+pub struct ImageWriter<W: Write> {
+    dst: W,
+}
+
+impl<W: Write> ImageWriter<W> {
+    pub fn new(mut dst: W, magic: u32) -> Result<Self> {
+        let common = if magic::is_service_image(magic) { magic::IMG_SERVICE } else { magic::IMG_COMMON };
+        dst.write_all(&common.to_le_bytes())?;
+        dst.write_all(&magic.to_le_bytes())?;
+        Ok(Self { dst })
+    }
+
+    pub fn write_entry(&mut self, entry: &[u8]) -> Result<()> {
+        write_frame(&mut self.dst, entry)
+    }
+
+    pub fn write_payload(&mut self, payload: &[u8]) -> Result<()> {
+        self.dst.write_all(payload)
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.dst.flush()
+    }
+}
+
+/// Decode all entries of the image at `path`, which must have magic `expected`.
+pub fn read_entries<T: Message + Default>(path: &Path, expected: u32) -> Result<Vec<T>> {
+    decode_entries(BufReader::new(File::open(path)?), expected)
+        .map_err(|e| Error::new(e.kind(), format!("{path:?}: {e}")))
+}
+
+/// Decode all entries of an image read from `src`, which must have magic `expected`.
+pub fn decode_entries<T: Message + Default, R: Read>(src: R, expected: u32) -> Result<Vec<T>> {
+    read_raw_entries(src, expected)?
+        .iter()
+        .map(|entry| T::decode(&entry[..]).map_err(invalid_data))
+        .collect()
+}
+
+/// Read the encoded entries of an image from `src`, which must have magic `expected`.
+pub fn read_raw_entries<R: Read>(src: R, expected: u32) -> Result<Vec<Vec<u8>>> {
+    let mut reader = ImageReader::new(src)?;
+    if reader.magic() != expected {
+        return Err(invalid_data(format!(
+            "Found a {} image, expected {}",
+            magic::name(reader.magic()).unwrap_or("?"),
+            magic::name(expected).unwrap_or("?"),
+        )));
+    }
+
+    let mut entries = Vec::new();
+    while let Some(entry) = reader.next_entry()? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn read_u32<R: Read>(src: &mut R) -> Result<u32> {
+    let mut buf = [0u8; size_of::<u32>()];
+    src.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
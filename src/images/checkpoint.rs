@@ -0,0 +1,365 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Summary of the processes and resources stored in an images directory.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs,
+    io::{ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+use criu_coordinator::criu::{
+    CoreEntry, FdTypes, FdinfoEntry, FileEntry, Fstype, InetSkEntry, InventoryEntry, MmEntry,
+    MntEntry, PagemapEntry, PagemapHead, PstreeEntry, TaskKobjIdsEntry, UnixSkEntry, VmaEntry,
+};
+use json::JsonValue;
+use prost::Message;
+
+use crate::constants::CONFIG_FILE;
+use super::{magic, read_entries, ImageReader};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Flags of pagemap entries.
+const PE_PARENT: u32 = 1 << 0;
+const PE_LAZY: u32 = 1 << 1;
+
+/// Process is a task of the dumped process tree.
+pub struct Process {
+    pub pid: u32,
+    pub ppid: u32,
+    pub pgid: u32,
+    pub sid: u32,
+    pub threads: Vec<u32>,
+    pub comm: String,
+    /// Namespaces and other kernel objects the process belongs to.
+    pub ids: Option<TaskKobjIdsEntry>,
+    pub vmas: Vec<VmaEntry>,
+    pub pages: PageCounts,
+    pub fds: Vec<FdinfoEntry>,
+}
+
+/// Number of memory pages dumped for a process.
+#[derive(Default, Clone, Copy, PartialEq)]
+pub struct PageCounts {
+    /// Pages stored in this images directory.
+    pub dumped: u64,
+    /// Pages stored in the images of the parent (pre-dump) checkpoint.
+    pub in_parent: u64,
+    /// Pages left in memory for lazy migration.
+    pub lazy: u64,
+}
+
+impl Process {
+    /// PID and command name of the process.
+    pub fn label(&self) -> String {
+        format!("{} ({})", self.pid, self.comm)
+    }
+
+    pub fn mapped_size(&self) -> u64 {
+        self.vmas.iter().map(|vma| vma.end - vma.start).sum()
+    }
+}
+
+/// Checkpoint holds the decoded images of an images directory.
+pub struct Checkpoint {
+    /// Content of `criu-coordinator.json`, if present.
+    pub coordinator: Option<JsonValue>,
+    pub inventory: Option<InventoryEntry>,
+    /// Processes in the order of `pstree.img`, parents first.
+    pub processes: Vec<Process>,
+    /// Entries of `files.img` by file ID.
+    pub files: BTreeMap<u32, FileEntry>,
+    /// Mounts of all mount namespaces.
+    pub mounts: Vec<MntEntry>,
+}
+
+impl Checkpoint {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let coordinator = match fs::read_to_string(dir.join(CONFIG_FILE)) {
+            Ok(content) => json::parse(&content).ok(),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let inventory = optional(read_entries(&dir.join("inventory.img"), magic::INVENTORY))?
+            .and_then(|entries: Vec<InventoryEntry>| entries.into_iter().next());
+        let files = optional(read_entries(&dir.join("files.img"), magic::FILES))?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|file: FileEntry| (file.id, file))
+            .collect();
+
+        let mut processes = Vec::new();
+        for entry in read_entries::<PstreeEntry>(&dir.join("pstree.img"), magic::PSTREE)? {
+            processes.push(load_process(dir, entry)?);
+        }
+
+        // Mounts are stored per mount namespace in `mountpoints-<id>.img`.
+        let mut mounts = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("mountpoints-") && name.ends_with(".img") {
+                mounts.extend(read_entries::<MntEntry>(&dir.join(name.as_ref()), magic::MNTS)?);
+            }
+        }
+
+        Ok(Self { coordinator, inventory, processes, files, mounts })
+    }
+
+    /// Children of `pid`, or the root processes if `pid` is zero.
+    pub fn children(&self, pid: u32) -> impl Iterator<Item = &Process> {
+        self.processes.iter().filter(move |process| process.ppid == pid)
+    }
+
+    pub fn describe_fd(&self, fd: &FdinfoEntry) -> String {
+        match self.files.get(&fd.id) {
+            Some(file) => describe_file(file),
+            None => fd_type_name(fd.r#type),
+        }
+    }
+
+    /// Describe the file of a descriptor without its kernel object IDs,
+    /// which differ between checkpoints.
+    pub fn fd_name(&self, fd: &FdinfoEntry) -> String {
+        match self.files.get(&fd.id) {
+            Some(file) => file_name(file),
+            None => fd_type_name(fd.r#type),
+        }
+    }
+
+    pub fn inet_sockets(&self) -> impl Iterator<Item = &InetSkEntry> {
+        self.files.values().filter_map(|file| file.isk.as_ref())
+    }
+
+    pub fn unix_sockets(&self) -> impl Iterator<Item = &UnixSkEntry> {
+        self.files.values().filter_map(|file| file.usk.as_ref())
+    }
+}
+
+fn load_process(dir: &Path, entry: PstreeEntry) -> Result<Process> {
+    let pid = entry.pid;
+    let core = optional(read_entries::<CoreEntry>(&dir.join(format!("core-{pid}.img")), magic::CORE))?
+        .and_then(|entries| entries.into_iter().next());
+    let comm = core.as_ref().and_then(|core| core.tc.as_ref()).map(|tc| tc.comm.clone()).unwrap_or_default();
+
+    let ids = optional(read_entries::<TaskKobjIdsEntry>(&dir.join(format!("ids-{pid}.img")), magic::IDS))?
+        .and_then(|entries| entries.into_iter().next())
+        .or_else(|| core.and_then(|core| core.ids));
+
+    let vmas = optional(read_entries::<MmEntry>(&dir.join(format!("mm-{pid}.img")), magic::MM))?
+        .and_then(|entries| entries.into_iter().next())
+        .map(|mm| mm.vmas)
+        .unwrap_or_default();
+
+    // Descriptors are stored per file table, or per process in old images.
+    let fdinfo_path = match ids.as_ref().map(|ids| dir.join(format!("fdinfo-{}.img", ids.files_id))) {
+        Some(path) if path.exists() => path,
+        _ => dir.join(format!("fdinfo-{pid}.img")),
+    };
+    let fds = optional(read_entries::<FdinfoEntry>(&fdinfo_path, magic::FDINFO))?.unwrap_or_default();
+
+    Ok(Process {
+        pid,
+        ppid: entry.ppid,
+        pgid: entry.pgid,
+        sid: entry.sid,
+        threads: entry.threads,
+        comm,
+        ids,
+        vmas,
+        pages: read_page_counts(&dir.join(format!("pagemap-{pid}.img")))?,
+        fds,
+    })
+}
+
+/// Count the pages listed in a pagemap image. The first entry of the image
+/// is a `PagemapHead`, the others are `PagemapEntry`s.
+fn read_page_counts(path: &Path) -> Result<PageCounts> {
+    let mut counts = PageCounts::default();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(counts),
+        Err(e) => return Err(e),
+    };
+    let mut reader = ImageReader::new(std::io::BufReader::new(file))?;
+    if let Some(head) = reader.next_entry()? {
+        PagemapHead::decode(&head[..])?;
+    }
+    while let Some(entry) = reader.next_entry()? {
+        let entry = PagemapEntry::decode(&entry[..])?;
+        let flags = entry.flags.unwrap_or(0);
+        let pages = entry.nr_pages as u64;
+        if flags & PE_PARENT != 0 || entry.in_parent == Some(true) {
+            counts.in_parent += pages;
+        } else if flags & PE_LAZY != 0 {
+            counts.lazy += pages;
+        } else {
+            counts.dumped += pages;
+        }
+    }
+    Ok(counts)
+}
+
+/// Treat a missing image as absent.
+fn optional<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn fd_type_name(fd_type: i32) -> String {
+    FdTypes::from_i32(fd_type)
+        .map(|fd_type| fd_type.as_str_name().to_lowercase())
+        .unwrap_or_else(|| format!("type {fd_type}"))
+}
+
+pub fn describe_file(file: &FileEntry) -> String {
+    if let Some(usk) = &file.usk {
+        return describe_unix_socket(usk);
+    }
+    if let Some(pipe) = &file.pipe {
+        return format!("pipe:[{}]", pipe.pipe_id);
+    }
+    if let Some(fifo) = &file.fifo {
+        return format!("fifo:[{}]", fifo.pipe_id);
+    }
+    if let Some(tty) = &file.tty {
+        return format!("tty:[{}]", tty.tty_info_id);
+    }
+    if let Some(nsf) = &file.nsf {
+        return format!("ns:[{}]", nsf.ns_id);
+    }
+    if let Some(memfd) = &file.memfd {
+        return format!("memfd:[{}]", memfd.inode_id);
+    }
+    file_name(file)
+}
+
+/// Name of a file that does not change between checkpoints.
+pub fn file_name(file: &FileEntry) -> String {
+    if let Some(reg) = &file.reg {
+        return reg.name.clone();
+    }
+    if let Some(isk) = &file.isk {
+        return describe_inet_socket(isk);
+    }
+    if let Some(usk) = &file.usk {
+        return unix_socket_name(usk);
+    }
+    fd_type_name(file.r#type)
+}
+
+pub fn describe_inet_socket(isk: &InetSkEntry) -> String {
+    let proto = match isk.proto {
+        6 => "tcp",
+        17 => "udp",
+        136 => "udplite",
+        _ => "inet",
+    };
+    let proto = if isk.family == AF_INET6 { format!("{proto}6") } else { proto.to_string() };
+    let local = format_address(isk.family, &isk.src_addr, isk.src_port);
+    if isk.state == TCP_LISTEN {
+        return format!("{proto} {local} (LISTEN)");
+    }
+    let remote = format_address(isk.family, &isk.dst_addr, isk.dst_port);
+    format!("{proto} {local} -> {remote} ({})", tcp_state_name(isk.state))
+}
+
+pub fn describe_unix_socket(usk: &UnixSkEntry) -> String {
+    format!("{} (ino {}, peer {})", unix_socket_name(usk), usk.ino, usk.peer)
+}
+
+/// Type and name of a unix socket, which do not change between checkpoints.
+pub fn unix_socket_name(usk: &UnixSkEntry) -> String {
+    let name = match usk.name.split_first() {
+        None => String::new(),
+        // Abstract socket names start with a null byte.
+        Some((0, rest)) => format!(" @{}", String::from_utf8_lossy(rest)),
+        Some(_) => format!(" {}", String::from_utf8_lossy(&usk.name)),
+    };
+    let kind = match usk.r#type {
+        1 => "stream",
+        2 => "dgram",
+        5 => "seqpacket",
+        _ => "unix",
+    };
+    format!("unix {kind}{name}")
+}
+
+/// Describe a mount as `source on mountpoint type fstype (options)`.
+pub fn describe_mount(mnt: &MntEntry) -> String {
+    // Mounts of unsupported filesystems are dumped with their name.
+    let fstype = mnt.fsname.clone().unwrap_or_else(|| {
+        Fstype::from_i32(mnt.fstype as i32)
+            .map(|fstype| fstype.as_str_name().to_lowercase())
+            .unwrap_or_else(|| format!("fstype {}", mnt.fstype))
+    });
+    let root = if mnt.root == "/" { String::new() } else { format!("[{}]", mnt.root) };
+    format!("{}{} on {} type {} ({})", mnt.source, root, mnt.mountpoint, fstype, mnt.options)
+}
+
+const AF_INET6: u32 = 10;
+const TCP_LISTEN: u32 = 10;
+
+/// Format an address stored by CRIU as u32 words in network byte order.
+pub fn format_address(family: u32, addr: &[u32], port: u32) -> String {
+    match socket_address(family, addr, port) {
+        Some(address) => address.to_string(),
+        None => format!("*:{port}"),
+    }
+}
+
+pub fn socket_address(family: u32, addr: &[u32], port: u32) -> Option<SocketAddr> {
+    let bytes: Vec<u8> = addr.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    let port = port as u16;
+    match (family, bytes.len()) {
+        (AF_INET6, 16) => {
+            let octets: [u8; 16] = bytes[..].try_into().unwrap();
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        (_, 4) => {
+            let octets: [u8; 4] = bytes[..].try_into().unwrap();
+            Some(SocketAddr::new(Ipv4Addr::from(octets).into(), port))
+        }
+        _ => None,
+    }
+}
+
+pub fn tcp_state_name(state: u32) -> &'static str {
+    match state {
+        1 => "ESTABLISHED",
+        2 => "SYN_SENT",
+        3 => "SYN_RECV",
+        4 => "FIN_WAIT1",
+        5 => "FIN_WAIT2",
+        6 => "TIME_WAIT",
+        7 => "CLOSE",
+        8 => "CLOSE_WAIT",
+        9 => "LAST_ACK",
+        10 => "LISTEN",
+        11 => "CLOSING",
+        _ => "UNKNOWN",
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Checks of a checkpoint against the host it is about to be restored on.
+//!
+//! The client runs them at `pre-restore` and reports the problems found to
+//! the server, which aborts the restore of the whole group. Processes and
+//! sockets that are restored into their own namespaces cannot conflict with
+//! the host, so PIDs are only checked if the process tree was dumped without
+//! a PID namespace, and ports if it was dumped without a network namespace.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{ErrorKind, Result},
+    net::TcpListener,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+use criu_coordinator::criu::{CpuinfoEntry, RemapFilePathEntry};
+
+use super::{
+    checkpoint::{socket_address, Checkpoint},
+    magic, read_entries,
+};
+
+const IPPROTO_TCP: u32 = 6;
+const TCP_LISTEN: u32 = 10;
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+
+/// Check the images in `dir` against this host and return the problems
+/// found.
+pub fn check_host(dir: &Path) -> Result<Vec<String>> {
+    let checkpoint = Checkpoint::load(dir)?;
+    let mut problems = check_cpu(dir)?;
+    problems.extend(check_pids(&checkpoint));
+    problems.extend(check_files(dir, &checkpoint)?);
+    if !has_images(dir, "netns-")? {
+        problems.extend(check_ports(&checkpoint));
+    }
+    Ok(problems)
+}
+
+fn has_images(dir: &Path, prefix: &str) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if entry?.file_name().to_string_lossy().starts_with(prefix) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// CPUID registers stored in the capability words of `cpuinfo.img`, as
+/// `(word, leaf, subleaf, register)`. The other words hold features that
+/// the kernel defines and cannot be read from the host.
+#[cfg(target_arch = "x86_64")]
+const CPUID_WORDS: [(usize, u32, u32, &str); 8] = [
+    (0, 0x1, 0, "edx"),
+    (1, 0x8000_0001, 0, "edx"),
+    (4, 0x1, 0, "ecx"),
+    (6, 0x8000_0001, 0, "ecx"),
+    (9, 0x7, 0, "ebx"),
+    (10, 0xd, 1, "eax"),
+    (16, 0x7, 0, "ecx"),
+    (18, 0x7, 0, "edx"),
+];
+
+/// Bits of CPUID 1 ECX that depend on the kernel or hypervisor rather than
+/// on the CPU: OSXSAVE and HYPERVISOR.
+#[cfg(target_arch = "x86_64")]
+const CPUID_1_ECX_IGNORED: u32 = 1 << 27 | 1 << 31;
+
+#[cfg(target_arch = "x86_64")]
+fn cpuid(leaf: u32, subleaf: u32, register: &str) -> u32 {
+    use std::arch::x86_64::{__cpuid, __cpuid_count};
+
+    // Leaves above the highest one supported by the CPU are not defined.
+    let max_leaf = __cpuid(leaf & 0x8000_0000).eax;
+    if leaf > max_leaf {
+        return 0;
+    }
+    let result = __cpuid_count(leaf, subleaf);
+    match register {
+        "eax" => result.eax,
+        "ebx" => result.ebx,
+        "ecx" => result.ecx,
+        _ => result.edx,
+    }
+}
+
+/// Check that the CPU has the features and extended states that the
+/// processes may use.
+#[cfg(target_arch = "x86_64")]
+fn check_cpu(dir: &Path) -> Result<Vec<String>> {
+    let entries = match read_entries::<CpuinfoEntry>(&dir.join("cpuinfo.img"), magic::CPUINFO) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut problems = Vec::new();
+    for cpu in entries.iter().flat_map(|entry| entry.x86_entry.iter()) {
+        for (word, leaf, subleaf, register) in CPUID_WORDS {
+            let mut required = cpu.capability.get(word).copied().unwrap_or(0);
+            if word == 4 {
+                required &= !CPUID_1_ECX_IGNORED;
+            }
+            let missing = required & !cpuid(leaf, subleaf, register);
+            if missing != 0 {
+                problems.push(format!("CPU lacks features of the checkpoint: CPUID {leaf:#x}.{subleaf} {register} bits {missing:#x}"));
+            }
+        }
+
+        let required = cpu.xfeatures_mask.unwrap_or(0);
+        let supported = cpuid(0xd, 0, "eax") as u64 | (cpuid(0xd, 0, "edx") as u64) << 32;
+        if required & !supported != 0 {
+            problems.push(format!("CPU does not support the extended states of the checkpoint: {:#x}", required & !supported));
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn check_cpu(_dir: &Path) -> Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+/// Check that the PIDs and thread IDs of the process tree are free. A tree
+/// whose root has PID 1 is restored into a new PID namespace.
+fn check_pids(checkpoint: &Checkpoint) -> Vec<String> {
+    if checkpoint.processes.first().is_some_and(|root| root.pid == 1) {
+        return Vec::new();
+    }
+    let mut problems = Vec::new();
+    for process in checkpoint.processes.iter() {
+        let ids = std::iter::once(process.pid).chain(process.threads.iter().copied().filter(|tid| *tid != process.pid));
+        for id in ids {
+            if Path::new("/proc").join(id.to_string()).exists() {
+                problems.push(format!("PID {} of {} ({}) is in use", id, process.pid, process.comm));
+            }
+        }
+    }
+    problems
+}
+
+/// Check that the regular files opened or mapped by the processes exist
+/// with the same size and mode. Files that were deleted or unlinked are
+/// restored from the images, and are not checked.
+fn check_files(dir: &Path, checkpoint: &Checkpoint) -> Result<Vec<String>> {
+    let remapped: HashSet<u32> = match read_entries::<RemapFilePathEntry>(&dir.join("remap-fpath.img"), magic::REMAP_FPATH) {
+        Ok(entries) => entries.iter().map(|entry| entry.orig_id).collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut problems = Vec::new();
+    let files = checkpoint.files.values()
+        .filter_map(|file| file.reg.as_ref())
+        .filter(|reg| !remapped.contains(&reg.id) && !reg.ext.unwrap_or(false) && reg.name.starts_with('/'));
+    for reg in files {
+        let metadata = match fs::metadata(&reg.name) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                problems.push(format!("File {} does not exist", reg.name));
+                continue;
+            }
+            Err(e) => {
+                problems.push(format!("File {}: {}", reg.name, e));
+                continue;
+            }
+        };
+        if let Some(mode) = reg.mode {
+            if mode != metadata.mode() {
+                problems.push(format!("File {} has mode {:o}, expected {:o}", reg.name, metadata.mode(), mode));
+                continue;
+            }
+        }
+        // The size of other files, such as directories, depends on the file system.
+        if let Some(size) = reg.size.filter(|_| metadata.mode() & S_IFMT == S_IFREG) {
+            if size != metadata.size() {
+                problems.push(format!("File {} has size {}, expected {}", reg.name, metadata.size(), size));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Check that the addresses of the listening TCP sockets can be bound.
+fn check_ports(checkpoint: &Checkpoint) -> Vec<String> {
+    checkpoint.inet_sockets()
+        .filter(|isk| isk.proto == IPPROTO_TCP && isk.state == TCP_LISTEN)
+        .filter_map(|isk| socket_address(isk.family, &isk.src_addr, isk.src_port))
+        .filter_map(|address| match TcpListener::bind(address) {
+            Ok(_) => None,
+            Err(e) => Some(format!("Port {address} is not available: {e}")),
+        })
+        .collect()
+}
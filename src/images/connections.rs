@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Established TCP connections of a checkpoint.
+//!
+//! Connections are read from the `sk-inet` entries of `files.img` after a
+//! dump, or from `/proc/<pid>/net/tcp` and `tcp6` before the dump. Two
+//! clients share a connection if the local endpoint of one is the remote
+//! endpoint of the other.
+
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    fmt,
+    fs,
+    io::{ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+use criu_coordinator::criu::{FileEntry, InetSkEntry};
+use json::{object, JsonValue};
+
+use super::{checkpoint::socket_address, magic, read_entries};
+
+const IPPROTO_TCP: u32 = 6;
+const TCP_ESTABLISHED: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Connection {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+}
+
+impl Connection {
+    pub fn new(local: SocketAddr, remote: SocketAddr) -> Self {
+        Self { local: canonical(local), remote: canonical(remote) }
+    }
+
+    /// The same connection as seen from the other end.
+    pub fn reversed(self) -> Self {
+        Self { local: self.remote, remote: self.local }
+    }
+
+    pub fn to_json(self) -> JsonValue {
+        object!{ local: self.local.to_string(), remote: self.remote.to_string() }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Option<Self> {
+        let local = value["local"].as_str()?.parse().ok()?;
+        let remote = value["remote"].as_str()?.parse().ok()?;
+        Some(Self::new(local, remote))
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.local, self.remote)
+    }
+}
+
+/// IPv4 peers of an IPv6 socket are seen as IPv4-mapped addresses.
+fn canonical(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), address.port()),
+            None => address,
+        },
+        IpAddr::V4(_) => address,
+    }
+}
+
+/// The connection of an established TCP socket.
+pub fn inet_socket_connection(isk: &InetSkEntry) -> Option<Connection> {
+    if isk.proto != IPPROTO_TCP || isk.state != TCP_ESTABLISHED {
+        return None;
+    }
+    let local = socket_address(isk.family, &isk.src_addr, isk.src_port)?;
+    let remote = socket_address(isk.family, &isk.dst_addr, isk.dst_port)?;
+    Some(Connection::new(local, remote))
+}
+
+/// Established TCP connections stored in the images directory `dir`.
+/// Returns an empty list if the directory has no `files.img`.
+pub fn from_images(dir: &Path) -> Result<Vec<Connection>> {
+    let files = match read_entries::<FileEntry>(&dir.join("files.img"), magic::FILES) {
+        Ok(files) => files,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(files.iter().filter_map(|file| file.isk.as_ref().and_then(inet_socket_connection)).collect())
+}
+
+/// Established TCP connections of process `pid` and its descendants.
+/// `/proc/<pid>/net/tcp` lists all sockets of the network namespace, so
+/// only the sockets that are open in the process tree are kept.
+pub fn from_proc(pid: u32) -> Result<Vec<Connection>> {
+    let mut inodes = HashSet::new();
+    socket_inodes(pid, &mut inodes)?;
+
+    let mut connections = Vec::new();
+    for table in ["tcp", "tcp6"] {
+        match fs::read_to_string(format!("/proc/{pid}/net/{table}")) {
+            Ok(content) => connections.extend(parse_proc_net_tcp(&content, &inodes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(connections)
+}
+
+/// Collect the inodes of the sockets open in process `pid` and its descendants.
+fn socket_inodes(pid: u32, inodes: &mut HashSet<u64>) -> Result<()> {
+    for fd in fs::read_dir(format!("/proc/{pid}/fd"))? {
+        // The descriptor may have been closed since the directory was read.
+        if let Ok(target) = fs::read_link(fd?.path()) {
+            let target = target.to_string_lossy();
+            if let Some(inode) = target.strip_prefix("socket:[").and_then(|inode| inode.strip_suffix(']')) {
+                inodes.extend(inode.parse::<u64>().ok());
+            }
+        }
+    }
+    for task in fs::read_dir(format!("/proc/{pid}/task"))? {
+        let children = fs::read_to_string(task?.path().join("children")).unwrap_or_default();
+        for child in children.split_whitespace().filter_map(|child| child.parse().ok()) {
+            // Children may exit while the tree is walked.
+            match socket_inodes(child, inodes) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse the established connections of `/proc/net/tcp` or `/proc/net/tcp6`
+/// whose socket inode is in `inodes`. The lines are
+/// `sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ...`,
+/// where addresses are `ADDR:PORT` in hex and the address is printed as
+/// u32 words in host byte order.
+fn parse_proc_net_tcp(content: &str, inodes: &HashSet<u64>) -> Vec<Connection> {
+    content.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || u32::from_str_radix(fields[3], 16).ok()? != TCP_ESTABLISHED {
+                return None;
+            }
+            if !inodes.contains(&fields[9].parse().ok()?) {
+                return None;
+            }
+            Some(Connection::new(parse_proc_address(fields[1])?, parse_proc_address(fields[2])?))
+        })
+        .collect()
+}
+
+fn parse_proc_address(field: &str) -> Option<SocketAddr> {
+    let (addr, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::new();
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip: IpAddr = match bytes.len() {
+        4 => Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into(),
+        16 => {
+            let octets: [u8; 16] = bytes[..].try_into().ok()?;
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Connections of `connections` whose other end is in `others`.
+pub fn shared_connections<'a>(connections: &'a [Connection], others: &'a [Connection]) -> impl Iterator<Item = &'a Connection> {
+    connections.iter().filter(move |connection| others.contains(&connection.reversed()))
+}
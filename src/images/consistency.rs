@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Consistency of the TCP connections between the members of a global
+//! checkpoint.
+//!
+//! CRIU stores the queues of an established connection in
+//! `tcp-stream-<inode>.img`: `outq_seq` is the sequence number of the next
+//! byte written, the `outq_len` bytes before it have not been acknowledged
+//! and the last `unsq_len` of them have not been sent. `inq_seq` is the
+//! next sequence number expected, and the `inq_len` bytes before it have
+//! been received but not read. With the network locked during the dump,
+//! each end must have received exactly what the other end has sent, up to
+//! the data that is still unacknowledged:
+//!
+//! ```text
+//! outq_seq - outq_len <= peer inq_seq <= outq_seq - unsq_len
+//! ```
+//!
+//! Data that is both unacknowledged and received must also be identical
+//! in the send queue of one end and the receive queue of the other.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{ErrorKind, Result},
+    path::Path,
+};
+use criu_coordinator::criu::{FileEntry, TcpStreamEntry};
+use prost::Message;
+
+use crate::pipeline::protobuf::invalid_data;
+use super::{
+    connections::{inet_socket_connection, Connection},
+    diff::{client_dirs, is_images_dir},
+    decode_entries, magic, ImageReader,
+};
+
+/// Read the image `name` of a member of a global checkpoint.
+pub type ReadImage<'a> = &'a dyn Fn(&str, &str) -> Result<Vec<u8>>;
+
+/// TcpReport is the result of the verification of a global checkpoint.
+pub struct TcpReport {
+    /// Number of connections between members that were checked.
+    pub connections: usize,
+    pub problems: Vec<String>,
+}
+
+/// Queues of an established TCP connection.
+struct TcpQueues {
+    entry: TcpStreamEntry,
+    inq: Vec<u8>,
+    outq: Vec<u8>,
+}
+
+/// Established TCP socket of a member.
+struct Socket<'a> {
+    member: &'a str,
+    ino: u32,
+    queues: Option<TcpQueues>,
+}
+
+/// Check the connections between `members`, whose images are read with `read`.
+pub fn verify_tcp(members: &[String], read: ReadImage) -> Result<TcpReport> {
+    let mut sockets = HashMap::new();
+    for member in members {
+        for (connection, socket) in read_sockets(member, read)? {
+            sockets.insert(connection, socket);
+        }
+    }
+
+    // Check every connection once, from the end whose connection sorts first.
+    let mut pairs = BTreeMap::new();
+    for (connection, socket) in sockets.iter() {
+        if let Some(peer) = sockets.get(&connection.reversed()) {
+            if *connection < connection.reversed() {
+                pairs.insert(*connection, (socket, peer));
+            }
+        }
+    }
+
+    let mut report = TcpReport { connections: pairs.len(), problems: Vec::new() };
+    for (connection, (a, b)) in pairs {
+        let (qa, qb) = match (&a.queues, &b.queues) {
+            (Some(qa), Some(qb)) => (qa, qb),
+            _ => {
+                for socket in [a, b].iter().filter(|socket| socket.queues.is_none()) {
+                    report.problems.push(format!(
+                        "{}: {} has no {}", connection, socket.member, tcp_stream_image(socket.ino)));
+                }
+                continue;
+            }
+        };
+        report.problems.extend(check_direction(&connection, b.member, qb, a.member, qa));
+        report.problems.extend(check_direction(&connection.reversed(), a.member, qa, b.member, qb));
+    }
+    Ok(report)
+}
+
+fn tcp_stream_image(ino: u32) -> String {
+    format!("tcp-stream-{ino:x}.img")
+}
+
+/// Established TCP sockets of `member` with their queues.
+fn read_sockets<'a>(member: &'a str, read: ReadImage) -> Result<Vec<(Connection, Socket<'a>)>> {
+    let files = match read(member, "files.img") {
+        Ok(data) => decode_entries::<FileEntry, _>(&data[..], magic::FILES)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut sockets = Vec::new();
+    for isk in files.iter().filter_map(|file| file.isk.as_ref()) {
+        let connection = match inet_socket_connection(isk) {
+            Some(connection) => connection,
+            None => continue,
+        };
+        let queues = match read(member, &tcp_stream_image(isk.ino)) {
+            Ok(data) => Some(decode_queues(&data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        sockets.push((connection, Socket { member, ino: isk.ino, queues }));
+    }
+    Ok(sockets)
+}
+
+/// The queued data follows the entry: first the receive queue, then the send queue.
+fn decode_queues(data: &[u8]) -> Result<TcpQueues> {
+    let mut reader = ImageReader::new(data)?;
+    if reader.magic() != magic::TCP_STREAM {
+        return Err(invalid_data("Expected a TCP_STREAM image"));
+    }
+    let entry = reader.next_entry()?
+        .ok_or_else(|| invalid_data("Empty TCP_STREAM image"))?;
+    let entry = TcpStreamEntry::decode(&entry[..]).map_err(invalid_data)?;
+    let inq = reader.read_payload(entry.inq_len as usize)?;
+    let outq = reader.read_payload(entry.outq_len as usize)?;
+    Ok(TcpQueues { entry, inq, outq })
+}
+
+/// Distance from sequence number `b` to `a`, which may wrap around.
+fn seq_diff(a: u32, b: u32) -> i64 {
+    a.wrapping_sub(b) as i32 as i64
+}
+
+/// Check the data sent by `sender` and received by `receiver` over
+/// `connection`, as seen by the receiver.
+fn check_direction(
+    connection: &Connection,
+    sender: &str,
+    sent: &TcpQueues,
+    receiver: &str,
+    received: &TcpQueues,
+) -> Option<String> {
+    let write_seq = sent.entry.outq_seq;
+    let snd_una = write_seq.wrapping_sub(sent.entry.outq_len);
+    let snd_nxt = write_seq.wrapping_sub(sent.entry.unsq_len.unwrap_or(0));
+    let rcv_nxt = received.entry.inq_seq;
+
+    let ahead = seq_diff(rcv_nxt, snd_nxt);
+    if ahead > 0 {
+        return Some(format!("{connection}: {receiver} has received {ahead} bytes that {sender} has not sent"));
+    }
+    let missing = seq_diff(snd_una, rcv_nxt);
+    if missing > 0 {
+        return Some(format!("{connection}: {receiver} is missing {missing} bytes that {sender} has seen acknowledged"));
+    }
+
+    // Data that the sender still holds and the receiver has not read yet.
+    let inq_start = rcv_nxt.wrapping_sub(received.entry.inq_len);
+    let start = if seq_diff(inq_start, snd_una) > 0 { inq_start } else { snd_una };
+    let len = seq_diff(rcv_nxt, start) as usize;
+    let in_outq = sent.outq.get(seq_diff(start, snd_una) as usize..).and_then(|data| data.get(..len));
+    let in_inq = received.inq.get(seq_diff(start, inq_start) as usize..).and_then(|data| data.get(..len));
+    match in_outq == in_inq {
+        true => None,
+        false => Some(format!("{connection}: data received by {receiver} differs from the data sent by {sender}")),
+    }
+}
+
+/// Verify the connections between the clients of the global checkpoint in
+/// `dir`, or within a single images directory, and print the problems
+/// found. Returns whether the checkpoint is consistent.
+pub fn verify(dir: &Path) -> Result<bool> {
+    let clients = match is_images_dir(dir) {
+        true => BTreeMap::from([(dir.display().to_string(), dir.to_path_buf())]),
+        false => client_dirs(dir)?,
+    };
+    let members: Vec<String> = clients.keys().cloned().collect();
+    let report = verify_tcp(&members, &|member, name| fs::read(clients[member].join(name)))?;
+
+    for problem in report.problems.iter() {
+        println!("{problem}");
+    }
+    println!(
+        "Checked {} connection{}: {}",
+        report.connections,
+        if report.connections == 1 { "" } else { "s" },
+        if report.problems.is_empty() { "consistent" } else { "inconsistent" },
+    );
+    Ok(report.problems.is_empty())
+}
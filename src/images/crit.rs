@@ -0,0 +1,388 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Conversion of CRIU images to JSON and back, in the format used by crit:
+//!
+//! ```text
+//! {"magic": "PSTREE", "entries": [{"pid": 1, "ppid": 0, ...}, ...]}
+//! ```
+//!
+//! Data that some images store after an entry, such as the contents of
+//! pipes or TCP queues, is kept in the `extra` field of the entry.
+
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Result, Write},
+};
+use json::{object, JsonValue};
+
+use crate::pipeline::protobuf::invalid_data;
+use super::{
+    magic,
+    pb2json::{decode_base64, encode_base64, parse_int, Descriptors},
+    ImageReader, ImageWriter,
+};
+
+/// Data that follows the entries of an image.
+#[derive(Clone, Copy)]
+enum Extra {
+    /// `bytes` bytes of pipe data.
+    PipeData,
+    /// `length` bytes of a queued socket packet.
+    SkQueue,
+    /// The input and output queues of a TCP connection.
+    TcpStream,
+    /// `keys_bytes + values_bytes` bytes of a BPF map.
+    BpfmapData,
+    /// `size` bytes of a SysV shared memory segment, padded to 4 bytes.
+    IpcShm,
+    /// `nsems` u16 semaphore values, padded to 8 bytes.
+    IpcSem,
+    /// `qnum` `ipc_msg` entries, each followed by its data padded to 8 bytes.
+    IpcMsg,
+}
+
+enum Handler {
+    Entries(&'static str, Option<Extra>),
+    /// A `pagemap_head` followed by `pagemap_entry`s.
+    Pagemap,
+    /// A `ghost_file_entry` followed by the file contents, either in one
+    /// piece or as `ghost_chunk_entry`s each followed by its data.
+    GhostFile,
+}
+
+fn handler(name: &str) -> Option<Handler> {
+    use Extra::*;
+    use Handler::*;
+
+    Some(match name {
+        "PAGEMAP" => Pagemap,
+        "GHOST_FILE" => GhostFile,
+        "PIPES_DATA" => Entries("pipe_data_entry", Some(PipeData)),
+        "SK_QUEUES" => Entries("sk_packet_entry", Some(SkQueue)),
+        "TCP_STREAM" => Entries("tcp_stream_entry", Some(TcpStream)),
+        "BPFMAP_DATA" => Entries("bpfmap_data_entry", Some(BpfmapData)),
+        "IPCNS_SHM" => Entries("ipc_shm_entry", Some(IpcShm)),
+        "IPCNS_SEM" => Entries("ipc_sem_entry", Some(IpcSem)),
+        "IPCNS_MSG" => Entries("ipc_msg_entry", Some(IpcMsg)),
+        name => Entries(entry_message(name)?, None),
+    })
+}
+
+fn entry_message(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "INVENTORY" => "inventory_entry",
+        "CORE" => "core_entry",
+        "IDS" => "task_kobj_ids_entry",
+        "CREDS" => "creds_entry",
+        "UTSNS" => "utsns_entry",
+        "TIMENS" => "timens_entry",
+        "PIDNS" => "pidns_entry",
+        "IPC_VAR" => "ipc_var_entry",
+        "FS" => "fs_entry",
+        "MM" => "mm_entry",
+        "CGROUP" => "cgroup_entry",
+        "STATS" => "stats_entry",
+        "PSTREE" => "pstree_entry",
+        "REG_FILES" => "reg_file_entry",
+        "NS_FILES" => "ns_file_entry",
+        "EVENTFD_FILE" => "eventfd_file_entry",
+        "EVENTPOLL_FILE" => "eventpoll_file_entry",
+        "EVENTPOLL_TFD" => "eventpoll_tfd_entry",
+        "SIGNALFD" => "signalfd_entry",
+        "TIMERFD" => "timerfd_entry",
+        "INOTIFY_FILE" => "inotify_file_entry",
+        "INOTIFY_WD" => "inotify_wd_entry",
+        "FANOTIFY_FILE" => "fanotify_file_entry",
+        "FANOTIFY_MARK" => "fanotify_mark_entry",
+        "VMAS" => "vma_entry",
+        "PIPES" => "pipe_entry",
+        "FIFO" => "fifo_entry",
+        "SIGACT" => "sa_entry",
+        "NETLINK_SK" => "netlink_sk_entry",
+        "REMAP_FPATH" => "remap_file_path_entry",
+        "MNTS" => "mnt_entry",
+        "TTY_FILES" => "tty_file_entry",
+        "TTY_INFO" => "tty_info_entry",
+        "TTY_DATA" => "tty_data_entry",
+        "RLIMIT" => "rlimit_entry",
+        "TUNFILE" => "tunfile_entry",
+        "EXT_FILES" => "ext_file_entry",
+        "IRMAP_CACHE" => "irmap_cache_entry",
+        "FILE_LOCKS" => "file_lock_entry",
+        "FDINFO" => "fdinfo_entry",
+        "UNIXSK" => "unix_sk_entry",
+        "INETSK" => "inet_sk_entry",
+        "PACKETSK" => "packet_sock_entry",
+        "ITIMERS" => "itimer_entry",
+        "POSIX_TIMERS" => "posix_timer_entry",
+        "NETDEV" => "net_device_entry",
+        "NETNS" => "netns_entry",
+        "USERNS" => "userns_entry",
+        "SECCOMP" => "seccomp_entry",
+        "AUTOFS" => "autofs_entry",
+        "FILES" => "file_entry",
+        "CPUINFO" => "cpuinfo_entry",
+        "MEMFD_INODE" => "memfd_inode_entry",
+        "BPFMAP_FILE" => "bpfmap_file_entry",
+        "APPARMOR" => "apparmor_entry",
+        "SIGNAL" => "signal_queue_entry",
+        "BINFMT_MISC" => "binfmt_misc_entry",
+        _ => return None,
+    })
+}
+
+/// Convert the image file `input` to JSON in `output`.
+/// `-` stands for standard input or output.
+pub fn decode_file(input: &str, output: &str, pretty: bool) -> Result<()> {
+    let image = decode_image(BufReader::new(open_input(input)?), pretty)?;
+    let mut text = if pretty { image.pretty(4) } else { image.dump() };
+    text.push('\n');
+    open_output(output)?.write_all(text.as_bytes())
+}
+
+/// Convert the JSON file `input` to the image file `output`.
+pub fn encode_file(input: &str, output: &str) -> Result<()> {
+    let mut text = String::new();
+    open_input(input)?.read_to_string(&mut text)?;
+    let image = json::parse(&text).map_err(|e| invalid_data(format!("Invalid JSON: {e}")))?;
+    encode_image(&image, BufWriter::new(open_output(output)?))
+}
+
+fn open_input(path: &str) -> Result<Box<dyn Read>> {
+    Ok(match path {
+        "-" => Box::new(io::stdin()),
+        path => Box::new(File::open(path)?),
+    })
+}
+
+fn open_output(path: &str) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        "-" => Box::new(io::stdout()),
+        path => Box::new(File::create(path)?),
+    })
+}
+
+/// Convert a CRIU image to JSON. With `pretty`, fields are shown in the
+/// format of their `(criu)` options, like `crit decode --pretty`.
+pub fn decode_image<R: Read>(src: R, pretty: bool) -> Result<JsonValue> {
+    let mut descriptors = Descriptors::criu();
+    descriptors.set_pretty(pretty);
+    let mut reader = ImageReader::new(src)?;
+    let name = magic::name(reader.magic())
+        .ok_or_else(|| invalid_data(format!("Unknown image magic {:#x}", reader.magic())))?;
+    let handler = handler(name).ok_or_else(|| invalid_data(format!("{name} images are not supported")))?;
+
+    let mut entries = JsonValue::new_array();
+    match handler {
+        Handler::Pagemap => {
+            let mut message = "pagemap_head";
+            while let Some(entry) = reader.next_entry()? {
+                entries.push(descriptors.decode(message, &entry)?).unwrap();
+                message = "pagemap_entry";
+            }
+        }
+        Handler::GhostFile => {
+            let entry = reader.next_entry()?.ok_or_else(|| invalid_data("Ghost file image is empty"))?;
+            let mut ghost_file = descriptors.decode("ghost_file_entry", &entry)?;
+            if ghost_file["chunks"].as_bool() == Some(true) {
+                entries.push(ghost_file).unwrap();
+                while let Some(entry) = reader.next_entry()? {
+                    let mut chunk = descriptors.decode("ghost_chunk_entry", &entry)?;
+                    chunk["extra"] = encode_base64(&reader.read_payload(get_len(&chunk, "len")?)?);
+                    entries.push(chunk).unwrap();
+                }
+            } else {
+                ghost_file["extra"] = encode_base64(&reader.read_to_end()?);
+                entries.push(ghost_file).unwrap();
+            }
+        }
+        Handler::Entries(message, extra) => {
+            while let Some(entry) = reader.next_entry()? {
+                let mut entry = descriptors.decode(message, &entry)?;
+                if let Some(extra) = extra {
+                    if let Some(data) = read_extra(extra, &entry, &mut reader, &descriptors)? {
+                        entry["extra"] = data;
+                    }
+                }
+                entries.push(entry).unwrap();
+            }
+        }
+    }
+    Ok(object!{ magic: name, entries: entries })
+}
+
+/// Convert JSON produced by `decode_image` (or crit) back to a CRIU image.
+pub fn encode_image<W: Write>(image: &JsonValue, dst: W) -> Result<()> {
+    let descriptors = Descriptors::criu();
+    let name = image["magic"].as_str().ok_or_else(|| invalid_data("Image has no magic"))?;
+    let magic = magic::by_name(name).ok_or_else(|| invalid_data(format!("Unknown magic {name}")))?;
+    // Aliases such as crit's names resolve to the name of the magic.
+    let name = magic::name(magic).ok_or_else(|| invalid_data(format!("Unknown magic {name}")))?;
+    let handler = handler(name).ok_or_else(|| invalid_data(format!("{name} images are not supported")))?;
+    if !image["entries"].is_array() {
+        return Err(invalid_data("Image has no entries"));
+    }
+    let mut entries = image["entries"].members();
+
+    let mut writer = ImageWriter::new(dst, magic)?;
+    match handler {
+        Handler::Pagemap => {
+            let mut message = "pagemap_head";
+            for entry in entries {
+                writer.write_entry(&descriptors.encode(message, entry)?)?;
+                message = "pagemap_entry";
+            }
+        }
+        Handler::GhostFile => {
+            let ghost_file = entries.next().ok_or_else(|| invalid_data("Ghost file image is empty"))?;
+            let (ghost_file, data) = split_extra(ghost_file);
+            writer.write_entry(&descriptors.encode("ghost_file_entry", &ghost_file)?)?;
+            if ghost_file["chunks"].as_bool() == Some(true) {
+                for chunk in entries {
+                    let (chunk, data) = split_extra(chunk);
+                    writer.write_entry(&descriptors.encode("ghost_chunk_entry", &chunk)?)?;
+                    writer.write_payload(&decode_extra(&data)?)?;
+                }
+            } else {
+                writer.write_payload(&decode_extra(&data)?)?;
+            }
+        }
+        Handler::Entries(message, extra) => {
+            for entry in entries {
+                let (entry, data) = split_extra(entry);
+                writer.write_entry(&descriptors.encode(message, &entry)?)?;
+                if let Some(extra) = extra {
+                    write_extra(extra, &entry, &data, &mut writer, &descriptors)?;
+                }
+            }
+        }
+    }
+    writer.finish()
+}
+
+fn read_extra<R: Read>(extra: Extra, entry: &JsonValue, reader: &mut ImageReader<R>, descriptors: &Descriptors) -> Result<Option<JsonValue>> {
+    Ok(Some(match extra {
+        Extra::PipeData => encode_base64(&reader.read_payload(get_len(entry, "bytes")?)?),
+        Extra::SkQueue => encode_base64(&reader.read_payload(get_len(entry, "length")?)?),
+        Extra::BpfmapData => {
+            let len = get_len(entry, "keys_bytes")? + get_len(entry, "values_bytes")?;
+            encode_base64(&reader.read_payload(len)?)
+        }
+        Extra::TcpStream => {
+            let inq = reader.read_payload(get_len(entry, "inq_len")?)?;
+            let outq = reader.read_payload(get_len(entry, "outq_len")?)?;
+            object!{ inq: encode_base64(&inq), outq: encode_base64(&outq) }
+        }
+        Extra::IpcShm => {
+            // Newer images store the segment in the pagemap images.
+            if entry["in_pagemaps"].as_bool() == Some(true) {
+                return Ok(None);
+            }
+            let size = get_len(entry, "size")?;
+            let data = reader.read_payload(size)?;
+            reader.read_payload(padding(size, 4))?;
+            encode_base64(&data)
+        }
+        Extra::IpcSem => {
+            let size = get_len(entry, "nsems")? * 2;
+            let data = reader.read_payload(size)?;
+            reader.read_payload(padding(size, 8))?;
+            data.chunks(2).map(|value| u16::from_le_bytes([value[0], value[1]])).collect::<Vec<_>>().into()
+        }
+        Extra::IpcMsg => {
+            let mut messages = JsonValue::new_array();
+            for _ in 0..get_len(entry, "qnum")? {
+                let msg = reader.next_entry()?.ok_or_else(|| invalid_data("Message queue is truncated"))?;
+                let msg = descriptors.decode("ipc_msg", &msg)?;
+                let size = get_len(&msg, "msize")?;
+                let data = reader.read_payload(size)?;
+                reader.read_payload(padding(size, 8))?;
+                messages.push(msg).unwrap();
+                messages.push(encode_base64(&data)).unwrap();
+            }
+            messages
+        }
+    }))
+}
+
+fn write_extra<W: Write>(extra: Extra, entry: &JsonValue, data: &JsonValue, writer: &mut ImageWriter<W>, descriptors: &Descriptors) -> Result<()> {
+    match extra {
+        Extra::PipeData | Extra::SkQueue | Extra::BpfmapData => writer.write_payload(&decode_extra(data)?),
+        Extra::TcpStream => {
+            writer.write_payload(&decode_extra(&data["inq"])?)?;
+            writer.write_payload(&decode_extra(&data["outq"])?)
+        }
+        Extra::IpcShm => {
+            if entry["in_pagemaps"].as_bool() == Some(true) {
+                return Ok(());
+            }
+            let data = decode_extra(data)?;
+            writer.write_payload(&data)?;
+            writer.write_payload(&vec![0u8; padding(data.len(), 4)])
+        }
+        Extra::IpcSem => {
+            let values = data.members()
+                .map(|value| value.as_u16().map(u16::to_le_bytes))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid_data("Semaphore values must be u16 numbers"))?
+                .concat();
+            writer.write_payload(&values)?;
+            writer.write_payload(&vec![0u8; padding(values.len(), 8)])
+        }
+        Extra::IpcMsg => {
+            let messages = data.members().collect::<Vec<_>>();
+            for pair in messages.chunks(2) {
+                let (msg, data) = match pair {
+                    [msg, data] => (msg, decode_extra(data)?),
+                    _ => return Err(invalid_data("Message queue must alternate messages and data")),
+                };
+                writer.write_entry(&descriptors.encode("ipc_msg", msg)?)?;
+                writer.write_payload(&data)?;
+                writer.write_payload(&vec![0u8; padding(data.len(), 8)])?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Separate the `extra` field from the fields of the protobuf entry.
+fn split_extra(entry: &JsonValue) -> (JsonValue, JsonValue) {
+    let mut entry = entry.clone();
+    let extra = entry.remove("extra");
+    (entry, extra)
+}
+
+fn decode_extra(data: &JsonValue) -> Result<Vec<u8>> {
+    decode_base64(data).ok_or_else(|| invalid_data("Extra data must be base64"))
+}
+
+fn get_len(entry: &JsonValue, field: &str) -> Result<usize> {
+    let len = match entry[field].as_str() {
+        Some(text) => parse_int(text).and_then(|len| usize::try_from(len).ok()),
+        None => entry[field].as_usize(),
+    };
+    len.ok_or_else(|| invalid_data(format!("Entry has no {field}")))
+}
+
+fn padding(size: usize, align: usize) -> usize {
+    (align - size % align) % align
+}
+
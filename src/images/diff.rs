@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Comparison of two checkpoints, printed by `diff`.
+//!
+//! An images directory holds the checkpoint of a single client. A directory
+//! whose subdirectories are images directories holds a global checkpoint,
+//! such as `<images-dir>/<epoch>` on the server, and its clients are
+//! compared by ID.
+//!
+//! Kernel object IDs, such as socket inodes, differ between any two
+//! checkpoints, so objects are compared by what they refer to.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::client::format_size;
+use super::checkpoint::{describe_inet_socket, describe_mount, unix_socket_name, Checkpoint};
+
+/// Print the differences between the checkpoints in `a` and `b`.
+/// Returns whether any difference was found.
+pub fn diff(a: &Path, b: &Path) -> Result<bool> {
+    println!("--- {}", a.display());
+    println!("+++ {}", b.display());
+
+    let differ = match (is_images_dir(a), is_images_dir(b)) {
+        (true, true) => diff_clients(a, b, None)?,
+        (false, false) => diff_global(a, b)?,
+        (true, false) => return Err(not_comparable(a, b)),
+        (false, true) => return Err(not_comparable(b, a)),
+    };
+    if !differ {
+        println!("No differences");
+    }
+    Ok(differ)
+}
+
+fn not_comparable(client: &Path, global: &Path) -> Error {
+    Error::new(ErrorKind::InvalidInput,
+        format!("{} is an images directory, but {} is not", client.display(), global.display()))
+}
+
+pub fn is_images_dir(dir: &Path) -> bool {
+    dir.join("pstree.img").is_file()
+}
+
+/// Images directories of the clients of a global checkpoint, by client ID.
+pub fn client_dirs(dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut clients = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_images_dir(&path) {
+            clients.insert(path.file_name().unwrap().to_string_lossy().to_string(), path);
+        }
+    }
+    if clients.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("No images found in {}", dir.display())));
+    }
+    Ok(clients)
+}
+
+fn diff_global(a: &Path, b: &Path) -> Result<bool> {
+    let clients_a = client_dirs(a)?;
+    let clients_b = client_dirs(b)?;
+    let mut differ = false;
+
+    for (id, dir_a) in clients_a.iter() {
+        match clients_b.get(id) {
+            Some(dir_b) => differ |= diff_clients(dir_a, dir_b, Some(id))?,
+            None => {
+                println!("\n- Client {id}");
+                differ = true;
+            }
+        }
+    }
+    for id in clients_b.keys().filter(|id| !clients_a.contains_key(*id)) {
+        println!("\n+ Client {id}");
+        differ = true;
+    }
+    Ok(differ)
+}
+
+fn diff_clients(a: &Path, b: &Path, id: Option<&str>) -> Result<bool> {
+    let a = Checkpoint::load(a)?;
+    let b = Checkpoint::load(b)?;
+
+    let sections = [
+        ("Coordinator", compare(coordinator(&a), coordinator(&b))),
+        ("Process tree", compare(processes(&a), processes(&b))),
+        ("Open files", compare(open_files(&a), open_files(&b))),
+        ("Sockets", compare(sockets(&a), sockets(&b))),
+        ("Mounts", compare(mounts(&a), mounts(&b))),
+        ("Mappings", compare(mappings(&a), mappings(&b))),
+        ("Pages", compare(pages(&a), pages(&b))),
+    ];
+    if sections.iter().all(|(_, lines)| lines.is_empty()) {
+        return Ok(false);
+    }
+
+    let indent = match id {
+        Some(id) => {
+            println!("\nClient {id}:");
+            "  "
+        }
+        None => "",
+    };
+    for (title, lines) in sections.iter().filter(|(_, lines)| !lines.is_empty()) {
+        println!("\n{indent}{title}:");
+        for line in lines {
+            println!("{indent}  {line}");
+        }
+    }
+    Ok(true)
+}
+
+/// Labelled value of an object that is compared, keyed so that objects are
+/// listed in a natural order, e.g. processes by PID.
+type Entries<K> = BTreeMap<K, (String, String)>;
+
+/// Compare the objects of two checkpoints: `-` marks an object that is only
+/// in `a`, `+` an object that is only in `b` and `~` a value that has changed.
+fn compare<K: Ord>(a: Entries<K>, b: Entries<K>) -> Vec<String> {
+    let entry = |label: &str, value: &str| match value {
+        "" => label.to_string(),
+        _ => format!("{label}: {value}"),
+    };
+
+    let mut lines = Vec::new();
+    for (key, (label, value_a)) in a.iter() {
+        match b.get(key) {
+            None => lines.push(format!("- {}", entry(label, value_a))),
+            Some((_, value_b)) if value_b != value_a => lines.push(format!("~ {label}: {value_a} -> {value_b}")),
+            Some(_) => {}
+        }
+    }
+    for (label, value_b) in b.iter().filter(|(key, _)| !a.contains_key(*key)).map(|(_, entry)| entry) {
+        lines.push(format!("+ {}", entry(label, value_b)));
+    }
+    lines
+}
+
+fn coordinator(checkpoint: &Checkpoint) -> Entries<String> {
+    checkpoint.coordinator.iter()
+        .flat_map(|config| config.entries())
+        .map(|(key, value)| (key.to_string(), (key.to_string(), value.to_string())))
+        .collect()
+}
+
+fn processes(checkpoint: &Checkpoint) -> Entries<u32> {
+    checkpoint.processes.iter()
+        .map(|process| {
+            let threads = process.threads.len();
+            let value = format!(
+                "{}, ppid {}, sid {}, pgid {}, {} thread{}",
+                process.comm,
+                process.ppid,
+                process.sid,
+                process.pgid,
+                threads,
+                if threads == 1 { "" } else { "s" },
+            );
+            (process.pid, (process.label(), value))
+        })
+        .collect()
+}
+
+fn open_files(checkpoint: &Checkpoint) -> Entries<(u32, u32)> {
+    checkpoint.processes.iter()
+        .flat_map(|process| process.fds.iter().map(move |fd| {
+            ((process.pid, fd.fd), (format!("{} fd {}", process.label(), fd.fd), checkpoint.fd_name(fd)))
+        }))
+        .collect()
+}
+
+/// Sockets by description, with the number of sockets that match it.
+fn sockets(checkpoint: &Checkpoint) -> Entries<String> {
+    let descriptions = checkpoint.inet_sockets().map(describe_inet_socket)
+        .chain(checkpoint.unix_sockets().map(unix_socket_name));
+    count(descriptions)
+}
+
+fn mounts(checkpoint: &Checkpoint) -> Entries<String> {
+    count(checkpoint.mounts.iter().map(describe_mount))
+}
+
+/// Count equal descriptions. A description that occurs once has no value.
+fn count(descriptions: impl Iterator<Item = String>) -> Entries<String> {
+    let mut counts = BTreeMap::new();
+    for description in descriptions {
+        *counts.entry(description).or_insert(0) += 1;
+    }
+    counts.into_iter()
+        .map(|(description, count)| match count {
+            1 => (description.clone(), (description, String::new())),
+            _ => (description.clone(), (description, format!("{count} times"))),
+        })
+        .collect()
+}
+
+fn mappings(checkpoint: &Checkpoint) -> Entries<u32> {
+    checkpoint.processes.iter()
+        .map(|process| {
+            let value = format!("{} mappings, {} mapped", process.vmas.len(), format_size(process.mapped_size()));
+            (process.pid, (process.label(), value))
+        })
+        .collect()
+}
+
+fn pages(checkpoint: &Checkpoint) -> Entries<u32> {
+    checkpoint.processes.iter()
+        .map(|process| {
+            let pages = process.pages;
+            let value = format!("{} dumped, {} in parent, {} lazy", pages.dumped, pages.in_parent, pages.lazy);
+            (process.pid, (process.label(), value))
+        })
+        .collect()
+}
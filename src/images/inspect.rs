@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Human-readable summary of a checkpoint, printed by `inspect`.
+
+use std::{io::Result, path::Path};
+use criu_coordinator::criu::TaskKobjIdsEntry;
+
+use crate::client::format_size;
+use super::checkpoint::{describe_inet_socket, describe_unix_socket, Checkpoint, PAGE_SIZE};
+
+pub fn inspect(dir: &Path) -> Result<()> {
+    let checkpoint = Checkpoint::load(dir)?;
+
+    println!("Images directory: {}", dir.display());
+    match &checkpoint.coordinator {
+        Some(config) => {
+            println!("Coordinator:");
+            for (key, value) in config.entries() {
+                println!("  {key}: {value}");
+            }
+        }
+        None => println!("Coordinator: no {}", crate::constants::CONFIG_FILE),
+    }
+    if let Some(inventory) = &checkpoint.inventory {
+        println!("Image version: {}", inventory.img_version);
+    }
+
+    println!("\nProcess tree:");
+    print_tree(&checkpoint, 0, 1);
+
+    println!("\nMemory:");
+    for process in checkpoint.processes.iter() {
+        let pages = process.pages;
+        let mut line = format!(
+            "  {}: {} mappings, {} mapped, {} pages dumped ({})",
+            process.label(),
+            process.vmas.len(),
+            format_size(process.mapped_size()),
+            pages.dumped,
+            format_size(pages.dumped * PAGE_SIZE),
+        );
+        if pages.in_parent > 0 {
+            line += &format!(", {} in parent", pages.in_parent);
+        }
+        if pages.lazy > 0 {
+            line += &format!(", {} lazy", pages.lazy);
+        }
+        println!("{line}");
+    }
+
+    println!("\nOpen files:");
+    for process in checkpoint.processes.iter() {
+        println!("  {}:", process.label());
+        for fd in process.fds.iter() {
+            println!("    {:>4}  {}", fd.fd, checkpoint.describe_fd(fd));
+        }
+    }
+
+    println!("\nSockets:");
+    for isk in checkpoint.inet_sockets() {
+        println!("  {}", describe_inet_socket(isk));
+    }
+    for usk in checkpoint.unix_sockets() {
+        println!("  {}", describe_unix_socket(usk));
+    }
+
+    println!("\nNamespaces:");
+    for process in checkpoint.processes.iter() {
+        if let Some(ids) = &process.ids {
+            println!("  {}: {}", process.label(), format_namespaces(ids));
+        }
+    }
+    Ok(())
+}
+
+fn print_tree(checkpoint: &Checkpoint, ppid: u32, depth: usize) {
+    for process in checkpoint.children(ppid) {
+        let threads = process.threads.len();
+        println!(
+            "{:indent$}{} sid {} pgid {}, {} thread{}",
+            "",
+            process.label(),
+            process.sid,
+            process.pgid,
+            threads,
+            if threads == 1 { "" } else { "s" },
+            indent = depth * 2,
+        );
+        print_tree(checkpoint, process.pid, depth + 1);
+    }
+}
+
+pub fn format_namespaces(ids: &TaskKobjIdsEntry) -> String {
+    let namespaces = [
+        ("pid", ids.pid_ns_id),
+        ("net", ids.net_ns_id),
+        ("ipc", ids.ipc_ns_id),
+        ("uts", ids.uts_ns_id),
+        ("mnt", ids.mnt_ns_id),
+        ("user", ids.user_ns_id),
+        ("cgroup", ids.cgroup_ns_id),
+        ("time", ids.time_ns_id),
+    ];
+    namespaces.iter()
+        .filter_map(|(name, id)| id.map(|id| format!("{name} {id}")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
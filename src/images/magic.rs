@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Magic numbers of CRIU images, as defined in CRIU's `criu/include/magic.h`.
+
+/// First magic of every image, except the service images below.
+pub const IMG_COMMON: u32 = 0x54564319;
+/// First magic of `inventory.img`, `stats-*` and `irmap-cache`.
+pub const IMG_SERVICE: u32 = 0x55105940;
+
+pub const INVENTORY: u32 = 0x58313116;
+pub const PSTREE: u32 = 0x50273030;
+pub const FDINFO: u32 = 0x56213732;
+pub const PAGEMAP: u32 = 0x56084025;
+pub const CORE: u32 = 0x55053847;
+pub const IDS: u32 = 0x54432030;
+pub const MM: u32 = 0x57492820;
+pub const FILES: u32 = 0x56303138;
+pub const MNTS: u32 = 0x55563928;
+pub const TCP_STREAM: u32 = 0x51465506;
+pub const REMAP_FPATH: u32 = 0x59133954;
+pub const CPUINFO: u32 = 0x61404013;
+pub const STATS: u32 = 0x57093306;
+pub const IRMAP_CACHE: u32 = 0x57004059;
+
+/// Names of all image types, as used by crit.
+pub const MAGICS: &[(&str, u32)] = &[
+    ("INVENTORY", INVENTORY),
+    ("PSTREE", PSTREE),
+    ("FDINFO", FDINFO),
+    ("PAGEMAP", PAGEMAP),
+    ("CORE", CORE),
+    ("IDS", IDS),
+    ("VMAS", 0x54123737),
+    ("PIPES", 0x56513555),
+    ("PIPES_DATA", 0x56453709),
+    ("FIFO", 0x58364939),
+    ("SIGACT", 0x55344201),
+    ("UNIXSK", 0x54373943),
+    ("INETSK", 0x56443851),
+    ("PACKETSK", 0x60454618),
+    ("ITIMERS", 0x57464056),
+    ("POSIX_TIMERS", 0x52603957),
+    ("SK_QUEUES", 0x56264026),
+    ("UTSNS", 0x54473203),
+    ("CREDS", 0x54023547),
+    ("IPC_VAR", 0x53115007),
+    ("IPCNS_SHM", 0x46283044),
+    ("IPCNS_MSG", 0x55453737),
+    ("IPCNS_SEM", 0x59573019),
+    ("REG_FILES", 0x50363636),
+    ("EXT_FILES", 0x59255641),
+    ("FS", 0x51403912),
+    ("MM", MM),
+    ("REMAP_FPATH", REMAP_FPATH),
+    ("GHOST_FILE", 0x52583605),
+    ("TCP_STREAM", TCP_STREAM),
+    ("EVENTFD_FILE", 0x44523722),
+    ("EVENTPOLL_FILE", 0x45023858),
+    ("EVENTPOLL_TFD", 0x44433746),
+    ("SIGNALFD", 0x57323820),
+    ("INOTIFY_FILE", 0x48424431),
+    ("INOTIFY_WD", 0x54562009),
+    ("MNTS", MNTS),
+    ("NETDEV", 0x57373951),
+    ("NETNS", 0x55933752),
+    ("TTY_FILES", 0x59433025),
+    ("TTY_INFO", 0x59453036),
+    ("TTY_DATA", 0x59413026),
+    ("FILE_LOCKS", 0x54323616),
+    ("RLIMIT", 0x57113925),
+    ("FANOTIFY_FILE", 0x55096122),
+    ("FANOTIFY_MARK", 0x56506035),
+    ("SIGNAL", 0x59255647),
+    ("NETLINK_SK", 0x58005614),
+    ("NS_FILES", 0x61394011),
+    ("TUNFILE", 0x57143751),
+    ("CGROUP", 0x59383330),
+    ("TIMERFD", 0x50493712),
+    ("CPUINFO", CPUINFO),
+    ("USERNS", 0x55474906),
+    ("SECCOMP", 0x64413049),
+    ("BINFMT_MISC", 0x67343323),
+    ("AUTOFS", 0x49353943),
+    ("FILES", FILES),
+    ("MEMFD_INODE", 0x48453499),
+    ("TIMENS", 0x43114433),
+    ("PIDNS", 0x61157326),
+    ("BPFMAP_FILE", 0x57506142),
+    ("BPFMAP_DATA", 0x64324033),
+    ("APPARMOR", 0x59423047),
+    ("STATS", STATS),
+    ("IRMAP_CACHE", IRMAP_CACHE),
+];
+
+/// Other names crit uses for some image types.
+const ALIASES: &[(&str, &str)] = &[
+    ("FIFO_DATA", "PIPES_DATA"),
+    ("SHMEM_PAGEMAP", "PAGEMAP"),
+    ("PSIG", "SIGNAL"),
+];
+
+pub fn name(magic: u32) -> Option<&'static str> {
+    MAGICS.iter().find(|(_, value)| *value == magic).map(|(name, _)| *name)
+}
+
+pub fn by_name(name: &str) -> Option<u32> {
+    let name = ALIASES.iter().find(|(alias, _)| *alias == name).map_or(name, |(_, name)| *name);
+    MAGICS.iter().find(|(n, _)| *n == name).map(|(_, value)| *value)
+}
+
+/// Service images start with `IMG_SERVICE` instead of `IMG_COMMON`.
+pub fn is_service_image(magic: u32) -> bool {
+    matches!(magic, INVENTORY | STATS | IRMAP_CACHE)
+}
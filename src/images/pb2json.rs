@@ -0,0 +1,693 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+
+//! Conversion between protobuf messages and JSON, driven by the descriptors
+//! of the CRIU protobufs, like crit's `pb2dict`.
+//!
+//! Fields are named as in the `.proto` files and only fields present in
+//! the message are converted, so a message converted to JSON and back is
+//! unchanged. Enums are converted to their value names and `bytes` fields
+//! to base64. Fields that the descriptors do not know, e.g. fields added by
+//! a newer CRIU, are kept encoded as base64 in `unknown_fields`.
+//!
+//! In pretty mode, as with `crit decode --pretty`, integer fields are shown
+//! in the format of their `(criu)` options in `opts.proto`: hex numbers, IP
+//! addresses, flag names, `major:minor` device numbers or value names. Both
+//! forms are accepted when converting JSON back.
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    io::Result,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use criu_coordinator::criu::{CriuOptsPb, FILE_DESCRIPTOR_SET};
+use json::JsonValue;
+use prost::Message;
+use prost_types::{
+    field_descriptor_proto::{Label, Type},
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorSet,
+};
+
+use crate::pipeline::protobuf::invalid_data;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// Number of the `(criu)` extension of the field options.
+const CRIU_OPTIONS: i32 = 1018;
+
+/// JSON key of the encoded fields that the descriptors do not know.
+pub const UNKNOWN_FIELDS: &str = "unknown_fields";
+
+/// Names of the bits of a flags field.
+type FlagNames = &'static [(&'static str, u64)];
+/// Names of the values of a dict field.
+type DictNames = &'static [(u64, &'static str)];
+
+/// Bits of the fields with `(criu).flags`, as in crit.
+const FLAGS: &[(&str, FlagNames)] = &[
+    ("mmap.prot", &[("PROT_READ", 0x1), ("PROT_WRITE", 0x2), ("PROT_EXEC", 0x4)]),
+    ("mmap.flags", &[("MAP_SHARED", 0x1), ("MAP_PRIVATE", 0x2), ("MAP_ANON", 0x20), ("MAP_GROWSDOWN", 0x100)]),
+    ("mmap.status", &[
+        ("VMA_AREA_NONE", 0), ("VMA_AREA_REGULAR", 1 << 0), ("VMA_AREA_STACK", 1 << 1),
+        ("VMA_AREA_VSYSCALL", 1 << 2), ("VMA_AREA_VDSO", 1 << 3), ("VMA_AREA_HEAP", 1 << 5),
+        ("VMA_FILE_PRIVATE", 1 << 6), ("VMA_FILE_SHARED", 1 << 7), ("VMA_ANON_SHARED", 1 << 8),
+        ("VMA_ANON_PRIVATE", 1 << 9), ("VMA_AREA_SYSVIPC", 1 << 10), ("VMA_AREA_SOCKET", 1 << 11),
+        ("VMA_AREA_VVAR", 1 << 12), ("VMA_AREA_AIORING", 1 << 13), ("VMA_AREA_MEMFD", 1 << 14),
+        ("VMA_UNSUPP", 1 << 31),
+    ]),
+    ("rfile.flags", &[("O_WRONLY", 0o1), ("O_RDWR", 0o2), ("O_APPEND", 0o2000), ("O_DIRECT", 0o40000), ("O_LARGEFILE", 0o100000)]),
+    ("pmap.flags", &[("PE_PARENT", 1 << 0), ("PE_LAZY", 1 << 1), ("PE_PRESENT", 1 << 2)]),
+    ("seals.flags", &[("F_SEAL_SEAL", 0x1), ("F_SEAL_SHRINK", 0x2), ("F_SEAL_GROW", 0x4), ("F_SEAL_WRITE", 0x8), ("F_SEAL_FUTURE_WRITE", 0x10)]),
+];
+
+/// Value names of the fields with `(criu).dict`, by dictionary and field
+/// name, as in crit.
+const DICTS: &[(&str, &str, DictNames)] = &[
+    ("gen", "task_state", &[(1, "Alive"), (3, "Zombie"), (6, "Stopped")]),
+    ("sk", "family", &[(1, "UNIX"), (2, "INET"), (10, "INET6"), (16, "NETLINK"), (17, "PACKET")]),
+    ("sk", "type", &[(1, "STREAM"), (2, "DGRAM"), (3, "RAW"), (5, "SEQPACKET"), (10, "PACKET")]),
+    ("sk", "state", &[
+        (1, "ESTABLISHED"), (2, "SYN_SENT"), (3, "SYN_RECV"), (4, "FIN_WAIT1"), (5, "FIN_WAIT2"),
+        (6, "TIME_WAIT"), (7, "CLOSE"), (8, "CLOSE_WAIT"), (9, "LAST_ACK"), (10, "LISTEN"),
+    ]),
+    ("sk", "proto", &[(0, "IP"), (6, "TCP"), (17, "UDP"), (136, "UDPLITE")]),
+];
+
+/// Bits of the minor number in a device number of the kernel.
+const KERN_MINORBITS: u64 = 20;
+
+/// MessageType is the descriptor of a message with the `(criu)` options
+/// of its fields.
+struct MessageType {
+    desc: DescriptorProto,
+    options: HashMap<i32, CriuOptsPb>,
+}
+
+/// Descriptors holds the message and enum types of the CRIU protobufs.
+pub struct Descriptors {
+    messages: HashMap<String, MessageType>,
+    enums: HashMap<String, EnumDescriptorProto>,
+    pretty: bool,
+}
+
+impl Descriptors {
+    pub fn criu() -> Self {
+        let set = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).expect("Invalid CRIU protobuf descriptors");
+        // prost drops the extensions of the field options, so the (criu)
+        // options are read from the encoded descriptors.
+        let mut options = criu_options(FILE_DESCRIPTOR_SET).expect("Invalid CRIU protobuf descriptors");
+        let mut descriptors = Self { messages: HashMap::new(), enums: HashMap::new(), pretty: false };
+        for file in set.file {
+            let scope = format!(".{}", file.package());
+            descriptors.add_types(&scope, file.message_type, file.enum_type, &mut options);
+        }
+        descriptors
+    }
+
+    /// Convert integer fields to the format of their `(criu)` options.
+    pub fn set_pretty(&mut self, pretty: bool) {
+        self.pretty = pretty;
+    }
+
+    fn add_types(
+        &mut self,
+        scope: &str,
+        messages: Vec<DescriptorProto>,
+        enums: Vec<EnumDescriptorProto>,
+        options: &mut HashMap<String, HashMap<i32, CriuOptsPb>>,
+    ) {
+        for enum_type in enums {
+            self.enums.insert(format!("{scope}.{}", enum_type.name()), enum_type);
+        }
+        for mut message in messages {
+            let name = format!("{scope}.{}", message.name());
+            let nested_messages = std::mem::take(&mut message.nested_type);
+            let nested_enums = std::mem::take(&mut message.enum_type);
+            self.add_types(&name, nested_messages, nested_enums, options);
+            let options = options.remove(&name).unwrap_or_default();
+            self.messages.insert(name, MessageType { desc: message, options });
+        }
+    }
+
+    fn message(&self, full_name: &str) -> Result<&MessageType> {
+        self.messages.get(full_name).ok_or_else(|| invalid_data(format!("Unknown message type {full_name}")))
+    }
+
+    /// Convert the encoded CRIU message `name`, e.g. `pstree_entry`, to JSON.
+    pub fn decode(&self, name: &str, data: &[u8]) -> Result<JsonValue> {
+        self.decode_message(self.message(&format!(".criu.{name}"))?, data, false)
+    }
+
+    /// Convert JSON to the encoded CRIU message `name`.
+    pub fn encode(&self, name: &str, value: &JsonValue) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.encode_message(self.message(&format!(".criu.{name}"))?, value, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// `hex` is set within a field marked as hex, as crit shows all the
+    /// integers of such a message in hex.
+    fn decode_message(&self, message: &MessageType, mut data: &[u8], hex: bool) -> Result<JsonValue> {
+        let desc = &message.desc;
+        let mut values: HashMap<i32, Vec<JsonValue>> = HashMap::new();
+        let mut unknown = Vec::new();
+        while !data.is_empty() {
+            let start = data;
+            let key = read_varint(&mut data)?;
+            let number = (key >> 3) as i32;
+            let wire_type = key & 7;
+            let field = match desc.field.iter().find(|field| field.number() == number) {
+                Some(field) => field,
+                None => {
+                    skip_value(wire_type, &mut data)?;
+                    unknown.extend_from_slice(&start[..start.len() - data.len()]);
+                    continue;
+                }
+            };
+            let options = message.options.get(&number);
+            let hex = hex || options.is_some_and(CriuOptsPb::hex);
+            let field_values = values.entry(number).or_default();
+
+            if wire_type == WIRE_LEN && wire_type_of(field.r#type()) != WIRE_LEN {
+                // Packed repeated scalars.
+                let mut packed = read_len(&mut data)?;
+                while !packed.is_empty() {
+                    field_values.push(self.decode_value(field, options, hex, &mut packed)?);
+                }
+            } else if wire_type == wire_type_of(field.r#type()) {
+                field_values.push(self.decode_value(field, options, hex, &mut data)?);
+            } else {
+                return Err(invalid_data(format!("Wire type {wire_type} does not match field {}", field.name())));
+            }
+        }
+
+        // Fields are listed in the order of the descriptor.
+        let mut object = JsonValue::new_object();
+        for field in desc.field.iter() {
+            if let Some(mut field_values) = values.remove(&field.number()) {
+                let is_ip = self.pretty && message.options.get(&field.number()).is_some_and(CriuOptsPb::ipadd);
+                object[field.name()] = match field.label() {
+                    Label::Repeated if is_ip => format_ip(&field_values).unwrap_or(JsonValue::Array(field_values)),
+                    Label::Repeated => JsonValue::Array(field_values),
+                    _ => field_values.pop().unwrap(),
+                };
+            }
+        }
+        if !unknown.is_empty() {
+            object[UNKNOWN_FIELDS] = encode_base64(&unknown);
+        }
+        Ok(object)
+    }
+
+    fn decode_value(&self, field: &FieldDescriptorProto, options: Option<&CriuOptsPb>, hex: bool, data: &mut &[u8]) -> Result<JsonValue> {
+        let value = match field.r#type() {
+            Type::Int64 => read_varint(data)? as i64 as i128,
+            Type::Uint64 => read_varint(data)? as i128,
+            Type::Int32 => read_varint(data)? as i32 as i128,
+            Type::Uint32 => read_varint(data)? as u32 as i128,
+            Type::Fixed64 => u64::from_le_bytes(read_fixed(data)?) as i128,
+            Type::Fixed32 => u32::from_le_bytes(read_fixed(data)?) as i128,
+            Type::Sfixed32 => i32::from_le_bytes(read_fixed(data)?) as i128,
+            Type::Sfixed64 => i64::from_le_bytes(read_fixed(data)?) as i128,
+            Type::Sint32 => zigzag_decode(read_varint(data)?) as i32 as i128,
+            Type::Sint64 => zigzag_decode(read_varint(data)?) as i128,
+            Type::Double => return Ok(f64::from_le_bytes(read_fixed(data)?).into()),
+            Type::Float => return Ok((f32::from_le_bytes(read_fixed(data)?) as f64).into()),
+            Type::Bool => return Ok((read_varint(data)? != 0).into()),
+            Type::String => return Ok(String::from_utf8(read_len(data)?.to_vec())
+                .map_err(|_| invalid_data(format!("Field {} is not valid UTF-8", field.name())))?
+                .into()),
+            Type::Message => return self.decode_message(self.message(field.type_name())?, read_len(data)?, hex),
+            Type::Bytes => return Ok(STANDARD.encode(read_len(data)?).into()),
+            Type::Enum => {
+                let number = read_varint(data)? as i32;
+                return Ok(match self.enums.get(field.type_name()).and_then(|e| e.value.iter().find(|v| v.number() == number)) {
+                    Some(value) => value.name().into(),
+                    None => number.into(),
+                });
+            }
+            Type::Group => return Err(invalid_data(format!("Field {} is a group", field.name()))),
+        };
+
+        if !self.pretty || options.is_some_and(CriuOptsPb::ipadd) {
+            // Addresses are converted as a whole.
+            return Ok(integer_json(value));
+        }
+        if hex {
+            return Ok(format_hex(value).into());
+        }
+        Ok(match options {
+            Some(options) => format_integer(field, options, value),
+            None => integer_json(value),
+        })
+    }
+
+    fn encode_message(&self, message: &MessageType, value: &JsonValue, buf: &mut Vec<u8>) -> Result<()> {
+        let desc = &message.desc;
+        if !value.is_object() {
+            return Err(invalid_data(format!("Expected an object for {}", desc.name())));
+        }
+        let is_known = |key: &str| key == UNKNOWN_FIELDS || desc.field.iter().any(|field| field.name() == key);
+        if let Some((key, _)) = value.entries().find(|(key, _)| !is_known(key)) {
+            return Err(invalid_data(format!("Unknown field {key} in {}", desc.name())));
+        }
+
+        for field in desc.field.iter() {
+            let field_value = &value[field.name()];
+            if field_value.is_null() {
+                continue;
+            }
+            let options = message.options.get(&field.number());
+            if field.label() != Label::Repeated {
+                self.encode_field(field, options, field_value, buf)?;
+                continue;
+            }
+            if !field_value.is_array() {
+                return Err(invalid_data(format!("Expected an array for {}", field.name())));
+            }
+            let items = match options.is_some_and(CriuOptsPb::ipadd) {
+                true => parse_ip(field_value).unwrap_or_else(|| field_value.members().cloned().collect()),
+                false => field_value.members().cloned().collect(),
+            };
+            if field.options.as_ref().and_then(|options| options.packed) == Some(true) {
+                let mut packed = Vec::new();
+                for item in items.iter() {
+                    self.encode_value(field, options, item, &mut packed)?;
+                }
+                write_varint(buf, (field.number() as u64) << 3 | WIRE_LEN);
+                write_varint(buf, packed.len() as u64);
+                buf.extend_from_slice(&packed);
+            } else {
+                for item in items.iter() {
+                    self.encode_field(field, options, item, buf)?;
+                }
+            }
+        }
+
+        if !value[UNKNOWN_FIELDS].is_null() {
+            let unknown = decode_base64(&value[UNKNOWN_FIELDS])
+                .ok_or_else(|| invalid_data(format!("{UNKNOWN_FIELDS} of {} must be base64", desc.name())))?;
+            buf.extend_from_slice(&unknown);
+        }
+        Ok(())
+    }
+
+    fn encode_field(&self, field: &FieldDescriptorProto, options: Option<&CriuOptsPb>, value: &JsonValue, buf: &mut Vec<u8>) -> Result<()> {
+        write_varint(buf, (field.number() as u64) << 3 | wire_type_of(field.r#type()));
+        self.encode_value(field, options, value, buf)
+    }
+
+    fn encode_value(&self, field: &FieldDescriptorProto, options: Option<&CriuOptsPb>, value: &JsonValue, buf: &mut Vec<u8>) -> Result<()> {
+        let mismatch = || invalid_data(format!("Invalid value {} for field {}", value.dump(), field.name()));
+        let integer = || parse_integer(field, options, value).ok_or_else(mismatch);
+        let unsigned = || integer().and_then(|value| u64::try_from(value).map_err(|_| mismatch()));
+        let signed = || integer().and_then(|value| i64::try_from(value).map_err(|_| mismatch()));
+        let float = || value.as_f64().ok_or_else(mismatch);
+
+        match field.r#type() {
+            Type::Double => buf.extend_from_slice(&float()?.to_le_bytes()),
+            Type::Float => buf.extend_from_slice(&(float()? as f32).to_le_bytes()),
+            Type::Int64 | Type::Int32 => write_varint(buf, signed()? as u64),
+            Type::Uint64 | Type::Uint32 => write_varint(buf, unsigned()?),
+            Type::Fixed64 => buf.extend_from_slice(&unsigned()?.to_le_bytes()),
+            Type::Fixed32 => buf.extend_from_slice(&(unsigned()? as u32).to_le_bytes()),
+            Type::Sfixed32 => buf.extend_from_slice(&(signed()? as i32).to_le_bytes()),
+            Type::Sfixed64 => buf.extend_from_slice(&signed()?.to_le_bytes()),
+            Type::Sint32 | Type::Sint64 => write_varint(buf, zigzag_encode(signed()?)),
+            Type::Bool => write_varint(buf, value.as_bool().ok_or_else(mismatch)? as u64),
+            Type::Enum => {
+                let number = match value.as_str() {
+                    Some(name) => self.enums.get(field.type_name())
+                        .and_then(|e| e.value.iter().find(|v| v.name() == name))
+                        .map(|v| v.number())
+                        .ok_or_else(mismatch)?,
+                    None => value.as_i64().ok_or_else(mismatch)? as i32,
+                };
+                write_varint(buf, number as i64 as u64);
+            }
+            Type::String => write_bytes(buf, value.as_str().ok_or_else(mismatch)?.as_bytes()),
+            Type::Bytes => write_bytes(buf, &decode_base64(value).ok_or_else(mismatch)?),
+            Type::Message => {
+                let mut nested = Vec::new();
+                self.encode_message(self.message(field.type_name())?, value, &mut nested)?;
+                write_bytes(buf, &nested);
+            }
+            Type::Group => return Err(invalid_data(format!("Field {} is a group", field.name()))),
+        }
+        Ok(())
+    }
+}
+
+/// Read the `(criu)` options of the fields in an encoded `FileDescriptorSet`,
+/// by full message name and field number.
+fn criu_options(set: &[u8]) -> Result<HashMap<String, HashMap<i32, CriuOptsPb>>> {
+    let mut options = HashMap::new();
+    for (_, file) in split_fields(set)?.into_iter().filter(|(number, _)| *number == 1) {
+        let fields = split_fields(file)?;
+        let package = fields.iter().rev().find(|(number, _)| *number == 2).map_or(&[][..], |(_, package)| package);
+        let scope = format!(".{}", String::from_utf8_lossy(package));
+        for (_, message) in fields.iter().filter(|(number, _)| *number == 4) {
+            add_message_options(&scope, message, &mut options)?;
+        }
+    }
+    Ok(options)
+}
+
+fn add_message_options(scope: &str, message: &[u8], options: &mut HashMap<String, HashMap<i32, CriuOptsPb>>) -> Result<()> {
+    let fields = split_fields(message)?;
+    let name = fields.iter().rev().find(|(number, _)| *number == 1).map_or(&[][..], |(_, name)| name);
+    let name = format!("{scope}.{}", String::from_utf8_lossy(name));
+    for (_, nested) in fields.iter().filter(|(number, _)| *number == 3) {
+        add_message_options(&name, nested, options)?;
+    }
+    for (_, field) in fields.iter().filter(|(number, _)| *number == 2) {
+        let field = split_fields(field)?;
+        let number = field.iter().rev().find(|(number, _)| *number == 3)
+            .map(|(_, mut value)| read_varint(&mut value))
+            .transpose()?;
+        let criu = field.iter().filter(|(number, _)| *number == 8)
+            .map(|(_, field_options)| split_fields(field_options))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .find(|(number, _)| *number == CRIU_OPTIONS);
+        if let (Some(number), Some((_, criu))) = (number, criu) {
+            let criu = CriuOptsPb::decode(criu).map_err(invalid_data)?;
+            options.entry(name.clone()).or_default().insert(number as i32, criu);
+        }
+    }
+    Ok(())
+}
+
+/// Split an encoded message into its field numbers and values. The value
+/// of a length-delimited field is its content, others are kept encoded.
+fn split_fields(mut data: &[u8]) -> Result<Vec<(i32, &[u8])>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let value = match key & 7 {
+            WIRE_LEN => read_len(&mut data)?,
+            wire_type => skip_value(wire_type, &mut data)?,
+        };
+        fields.push(((key >> 3) as i32, value));
+    }
+    Ok(fields)
+}
+
+fn integer_json(value: i128) -> JsonValue {
+    match u64::try_from(value) {
+        Ok(value) => value.into(),
+        Err(_) => (value as i64).into(),
+    }
+}
+
+fn format_hex(value: i128) -> String {
+    match value < 0 {
+        true => format!("-0x{:x}", -value),
+        false => format!("0x{value:x}"),
+    }
+}
+
+fn format_integer(field: &FieldDescriptorProto, options: &CriuOptsPb, value: i128) -> JsonValue {
+    if options.dev() {
+        return format_dev(options.odev(), value as u64).into();
+    }
+    if let Some(flags) = options.flags.as_deref() {
+        return match flag_names(flags) {
+            Some(names) => format_flags(value as u64, names).into(),
+            // Flags are better seen as hex anyway.
+            None => format_hex(value).into(),
+        };
+    }
+    let name = options.dict.as_deref()
+        .and_then(|dict| dict_names(dict, field.name()))
+        .and_then(|names| names.iter().find(|(number, _)| *number as i128 == value));
+    match name {
+        Some((_, name)) => (*name).into(),
+        None => integer_json(value),
+    }
+}
+
+/// Parse an integer field that may be in the format of its `(criu)`
+/// options, or in hex, octal or binary like Python's `int(value, 0)`.
+fn parse_integer(field: &FieldDescriptorProto, options: Option<&CriuOptsPb>, value: &JsonValue) -> Option<i128> {
+    let text = match value.as_str() {
+        Some(text) => text,
+        None => return value.as_u64().map(i128::from).or_else(|| value.as_i64().map(i128::from)),
+    };
+    if let Some(options) = options {
+        if options.dev() {
+            return parse_dev(options.odev(), text);
+        }
+        if let Some(names) = options.flags.as_deref().and_then(flag_names) {
+            return parse_flags(text, names);
+        }
+        let number = options.dict.as_deref()
+            .and_then(|dict| dict_names(dict, field.name()))
+            .and_then(|names| names.iter().find(|(_, name)| *name == text));
+        if let Some((number, _)) = number {
+            return Some(*number as i128);
+        }
+    }
+    parse_int(text)
+}
+
+/// Parse an integer like Python's `int(text, 0)`.
+pub fn parse_int(text: &str) -> Option<i128> {
+    let text = text.trim().replace('_', "");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(&text)),
+    };
+    let digits = digits.to_ascii_lowercase();
+    let (radix, digits) = [("0x", 16), ("0o", 8), ("0b", 2)].iter()
+        .find_map(|(prefix, radix)| digits.strip_prefix(prefix).map(|digits| (*radix, digits)))
+        .unwrap_or((10, &digits));
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+    let value = i128::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+fn flag_names(flags: &str) -> Option<FlagNames> {
+    FLAGS.iter().find(|(name, _)| *name == flags).map(|(_, names)| *names)
+}
+
+fn dict_names(dict: &str, field: &str) -> Option<DictNames> {
+    DICTS.iter().find(|(name, field_name, _)| *name == dict && *field_name == field).map(|(_, _, names)| *names)
+}
+
+/// Names of the bits set in `value`, followed by the unknown bits in hex.
+fn format_flags(value: u64, names: &[(&str, u64)]) -> String {
+    let mut parts: Vec<String> = names.iter()
+        .filter(|(_, bit)| value & bit != 0)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let unknown = value & !names.iter().fold(0, |all, (_, bit)| all | bit);
+    if unknown != 0 {
+        parts.push(format!("0x{unknown:x}"));
+    }
+    parts.join(" | ")
+}
+
+fn parse_flags(text: &str, names: &[(&str, u64)]) -> Option<i128> {
+    text.split('|')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| match names.iter().find(|(name, _)| *name == part) {
+            Some((_, bit)) => Some(*bit as i128),
+            None => parse_int(part),
+        })
+        .sum()
+}
+
+/// Format a device number as `major:minor`. `old` devices are encoded like
+/// the `dev_t` of glibc, others like the kernel does internally.
+fn format_dev(old: bool, dev: u64) -> String {
+    let (major, minor) = match old {
+        true => (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff), (dev & 0xff) | ((dev >> 12) & !0xff)),
+        false => (dev >> KERN_MINORBITS, dev & ((1 << KERN_MINORBITS) - 1)),
+    };
+    format!("{major}:{minor}")
+}
+
+fn parse_dev(old: bool, text: &str) -> Option<i128> {
+    let (major, minor) = text.split_once(':')?;
+    let (major, minor): (u64, u64) = (major.trim().parse().ok()?, minor.trim().parse().ok()?);
+    let dev = match old {
+        true => (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12) | ((major & !0xfff) << 32),
+        false => major << KERN_MINORBITS | minor,
+    };
+    Some(dev as i128)
+}
+
+/// Format the words of an IPv4 or IPv6 address, stored in network byte
+/// order, as a list with the address, as crit does.
+fn format_ip(values: &[JsonValue]) -> Option<JsonValue> {
+    let bytes: Vec<u8> = values.iter()
+        .map(|value| value.as_u32().map(u32::to_ne_bytes))
+        .collect::<Option<Vec<_>>>()?
+        .concat();
+    let address = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some(json::array![address.to_string()])
+}
+
+fn parse_ip(value: &JsonValue) -> Option<Vec<JsonValue>> {
+    if value.len() != 1 {
+        return None;
+    }
+    let bytes = match value[0].as_str()?.parse::<IpAddr>().ok()? {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    };
+    Some(bytes.chunks(4).map(|word| u32::from_ne_bytes(word.try_into().unwrap()).into()).collect())
+}
+
+/// Decode base64, ignoring the line breaks that crit inserts.
+pub fn decode_base64(value: &JsonValue) -> Option<Vec<u8>> {
+    let text: String = value.as_str()?.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    STANDARD.decode(text).ok()
+}
+
+pub fn encode_base64(data: &[u8]) -> JsonValue {
+    STANDARD.encode(data).into()
+}
+
+fn wire_type_of(field_type: Type) -> u64 {
+    match field_type {
+        Type::Double | Type::Fixed64 | Type::Sfixed64 => WIRE_FIXED64,
+        Type::Float | Type::Fixed32 | Type::Sfixed32 => WIRE_FIXED32,
+        Type::String | Type::Bytes | Type::Message | Type::Group => WIRE_LEN,
+        _ => WIRE_VARINT,
+    }
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or_else(|| invalid_data("Truncated varint"))?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("Varint is too long"))
+}
+
+fn read_fixed<const N: usize>(data: &mut &[u8]) -> Result<[u8; N]> {
+    if data.len() < N {
+        return Err(invalid_data("Truncated fixed-size field"));
+    }
+    let (bytes, rest) = data.split_at(N);
+    *data = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn read_len<'a>(data: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = read_varint(data)? as usize;
+    if data.len() < len {
+        return Err(invalid_data("Truncated length-delimited field"));
+    }
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Ok(bytes)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+
+/// Split an encoded message into its fields, as the field number with the
+/// encoded key and value, so that they can be copied unchanged.
+pub fn raw_fields(mut data: &[u8]) -> Result<Vec<(i32, &[u8])>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let start = data;
+        let key = read_varint(&mut data)?;
+        skip_value(key & 7, &mut data)?;
+        fields.push(((key >> 3) as i32, &start[..start.len() - data.len()]));
+    }
+    Ok(fields)
+}
+
+/// Content of a length-delimited field returned by `raw_fields`.
+pub fn raw_field_content(mut field: &[u8]) -> Result<&[u8]> {
+    let key = read_varint(&mut field)?;
+    if key & 7 != WIRE_LEN {
+        return Err(invalid_data(format!("Field {} is not length-delimited", key >> 3)));
+    }
+    read_len(&mut field)
+}
+
+/// Append a length-delimited field with `content`.
+pub fn write_raw_field(buf: &mut Vec<u8>, number: i32, content: &[u8]) {
+    write_varint(buf, (number as u64) << 3 | WIRE_LEN);
+    write_bytes(buf, content);
+}
+
+/// Skip the value of a field of `wire_type` and return its encoded bytes.
+fn skip_value<'a>(wire_type: u64, data: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let start = *data;
+    match wire_type {
+        WIRE_VARINT => {
+            read_varint(data)?;
+        }
+        WIRE_FIXED64 => {
+            read_fixed::<8>(data)?;
+        }
+        WIRE_LEN => {
+            read_len(data)?;
+        }
+        WIRE_FIXED32 => {
+            read_fixed::<4>(data)?;
+        }
+        _ => return Err(invalid_data(format!("Unsupported wire type {wire_type}"))),
+    }
+    Ok(&start[..start.len() - data.len()])
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Rewrite of the socket addresses of a checkpoint, so that it can be
+//! restored on hosts with other IP addresses.
+//!
+//! The addresses of inet sockets are stored in the `sk-inet` entries of
+//! `files.img`. The queues of an established connection are stored in
+//! `tcp-stream-<inode>.img`, which refers to its socket by inode and holds
+//! no address, so it stays valid as long as both ends of the connection
+//! are rewritten together. All members of a global checkpoint are
+//! therefore rewritten with the same mapping, and nothing is written
+//! unless every member can be rewritten.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Result},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use criu_coordinator::criu::{FileEntry, InetSkEntry};
+use json::JsonValue;
+use prost::{encoding::uint32, Message};
+
+use crate::constants::MANIFEST_FILE;
+use crate::pipeline::digest::ImageDigest;
+use crate::pipeline::protobuf::invalid_data;
+use super::{
+    checkpoint::socket_address,
+    connections::{inet_socket_connection, Connection},
+    diff::{client_dirs, is_images_dir},
+    magic,
+    pb2json::{raw_field_content, raw_fields, write_raw_field},
+    read_raw_entries, ImageWriter,
+};
+
+/// Number of `isk` in `file_entry`.
+const FILE_ENTRY_ISK: i32 = 4;
+/// Numbers of `src_addr` and `dst_addr` in `inet_sk_entry`.
+const INET_SK_SRC_ADDR: u32 = 11;
+const INET_SK_DST_ADDR: u32 = 12;
+
+/// AddressMap maps the IP addresses of the old hosts to the new ones.
+pub struct AddressMap(HashMap<IpAddr, IpAddr>);
+
+impl AddressMap {
+    /// Load a JSON object that maps old addresses to new ones:
+    /// `{"10.0.0.1": "10.1.0.1", ...}`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let value = json::parse(&content).map_err(|e| invalid_data(format!("{path:?}: {e}")))?;
+        Self::from_json(&value)
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<Self> {
+        if !value.is_object() {
+            return Err(invalid_data("Expected an object that maps old addresses to new ones".to_string()));
+        }
+        let mut map = HashMap::new();
+        let mut targets = HashMap::new();
+        for (old, new) in value.entries() {
+            let old: IpAddr = old.parse().map_err(|_| invalid_data(format!("Invalid address {old:?}")))?;
+            let new: IpAddr = match new.as_str().map(str::parse) {
+                Some(Ok(new)) => new,
+                _ => return Err(invalid_data(format!("Invalid address {} for {old}", new.dump()))),
+            };
+            if old.is_ipv4() != new.is_ipv4() {
+                return Err(invalid_data(format!("{old} and {new} are not of the same address family")));
+            }
+            // Two hosts mapped to one would merge the ends of distinct connections.
+            if let Some(other) = targets.insert(new, old) {
+                return Err(invalid_data(format!("Both {other} and {old} are mapped to {new}")));
+            }
+            map.insert(old, new);
+        }
+        Ok(Self(map))
+    }
+
+    /// New address of `ip`. IPv4 peers of IPv6 sockets are matched by their
+    /// IPv4-mapped address.
+    fn map(&self, ip: IpAddr) -> Option<IpAddr> {
+        if let Some(new) = self.0.get(&ip) {
+            return Some(*new);
+        }
+        match ip {
+            IpAddr::V6(ip) => match self.0.get(&ip.to_ipv4_mapped()?.into())? {
+                IpAddr::V4(new) => Some(new.to_ipv6_mapped().into()),
+                IpAddr::V6(_) => None,
+            },
+            IpAddr::V4(_) => None,
+        }
+    }
+}
+
+
+/// Address of an `sk-inet` entry as u32 words in host byte order.
+fn address_words(ip: IpAddr) -> Vec<u32> {
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    octets.chunks(4).map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]])).collect()
+}
+
+/// Rewrite the source and destination addresses of `isk`. Returns whether
+/// any of them has changed.
+fn rewrite_socket(isk: &mut InetSkEntry, map: &AddressMap) -> bool {
+    let mut changed = false;
+    for addr in [&mut isk.src_addr, &mut isk.dst_addr] {
+        let new = socket_address(isk.family, addr, 0).and_then(|address| map.map(address.ip()));
+        if let Some(new) = new {
+            *addr = address_words(new);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Rewritten `files.img` of a member.
+struct RewrittenFiles {
+    dir: PathBuf,
+    files: Vec<FileEntry>,
+    /// Encoded entries, in which only the rewritten addresses have changed.
+    entries: Vec<Vec<u8>>,
+    sockets: usize,
+}
+
+/// Rewrite the socket addresses of every client of the global checkpoint
+/// in `dir`, or of a single images directory, and print the number of
+/// sockets rewritten per client.
+pub fn rewrite(dir: &Path, map: &AddressMap) -> Result<()> {
+    let clients = match is_images_dir(dir) {
+        true => BTreeMap::from([(dir.display().to_string(), dir.to_path_buf())]),
+        false => client_dirs(dir)?,
+    };
+
+    let mut rewritten = BTreeMap::new();
+    for (id, client_dir) in clients {
+        let path = client_dir.join("files.img");
+        let mut entries = match File::open(&path).and_then(|file| read_raw_entries(BufReader::new(file), magic::FILES)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(invalid_data(format!("{path:?}: {e}"))),
+        };
+        let mut files = Vec::new();
+        let mut sockets = 0;
+        for entry in entries.iter_mut() {
+            let mut file = FileEntry::decode(&entry[..]).map_err(|e| invalid_data(format!("{path:?}: {e}")))?;
+            if let Some(isk) = file.isk.as_mut() {
+                if rewrite_socket(isk, map) {
+                    *entry = rewrite_entry(entry, isk)?;
+                    sockets += 1;
+                }
+            }
+            files.push(file);
+        }
+        rewritten.insert(id, RewrittenFiles { dir: client_dir, files, entries, sockets });
+    }
+    check_connections(&rewritten)?;
+
+    // Write all images before replacing any of them.
+    let mut staged = Vec::new();
+    for member in rewritten.values().filter(|member| member.sockets > 0) {
+        match stage_files(&member.dir, &member.entries) {
+            Ok(files) => staged.extend(files),
+            Err(e) => {
+                let partial = tmp_paths(&member.dir);
+                for tmp_path in staged.into_iter().map(|(tmp_path, _)| tmp_path).chain(partial) {
+                    let _ = fs::remove_file(tmp_path);
+                }
+                return Err(e);
+            }
+        }
+    }
+    for (tmp_path, path) in staged {
+        fs::rename(&tmp_path, &path)?;
+    }
+    for (id, member) in rewritten.iter() {
+        println!("{id}: rewrote {} socket{}", member.sockets, if member.sockets == 1 { "" } else { "s" });
+    }
+    Ok(())
+}
+
+/// Replace the addresses in the encoded `files.img` entry `entry` with the
+/// ones of `isk`. The other fields are copied, including the ones that this
+/// version does not know.
+fn rewrite_entry(entry: &[u8], isk: &InetSkEntry) -> Result<Vec<u8>> {
+    let mut rewritten = Vec::new();
+    for (number, field) in raw_fields(entry)? {
+        if number != FILE_ENTRY_ISK {
+            rewritten.extend_from_slice(field);
+            continue;
+        }
+        let mut content = Vec::new();
+        let mut addresses_written = false;
+        for (number, field) in raw_fields(raw_field_content(field)?)? {
+            if ![INET_SK_SRC_ADDR, INET_SK_DST_ADDR].contains(&(number as u32)) {
+                content.extend_from_slice(field);
+            } else if !addresses_written {
+                uint32::encode_repeated(INET_SK_SRC_ADDR, &isk.src_addr, &mut content);
+                uint32::encode_repeated(INET_SK_DST_ADDR, &isk.dst_addr, &mut content);
+                addresses_written = true;
+            }
+        }
+        write_raw_field(&mut rewritten, FILE_ENTRY_ISK, &content);
+    }
+    Ok(rewritten)
+}
+
+/// Check that no two established sockets of the checkpoint have the same
+/// connection after the rewrite, which happens when a new address is
+/// already used by a host that is not rewritten.
+fn check_connections(rewritten: &BTreeMap<String, RewrittenFiles>) -> Result<()> {
+    let mut owners: HashMap<Connection, &str> = HashMap::new();
+    for (id, member) in rewritten.iter() {
+        let connections = member.files.iter().filter_map(|file| file.isk.as_ref().and_then(inet_socket_connection));
+        for connection in connections {
+            if let Some(other) = owners.insert(connection, id) {
+                return Err(invalid_data(format!("Connection {connection} would be used by both {other} and {id}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the new `files.img` of `dir` and its manifest, if there is one,
+/// to temporary files. Returns the temporary files with the files that
+/// they replace.
+fn stage_files(dir: &Path, entries: &[Vec<u8>]) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let path = dir.join("files.img");
+    let [tmp_path, tmp_manifest_path] = tmp_paths(dir);
+    let mut writer = ImageWriter::new(BufWriter::new(File::create(&tmp_path)?), magic::FILES)?;
+    for entry in entries {
+        writer.write_entry(entry)?;
+    }
+    writer.finish()?;
+    let mut staged = vec![(tmp_path.clone(), path)];
+
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let mut manifest = match fs::read_to_string(&manifest_path) {
+        Ok(content) => json::parse(&content).map_err(|e| invalid_data(format!("{manifest_path:?}: {e}")))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(staged),
+        Err(e) => return Err(e),
+    };
+    let data = fs::read(&tmp_path)?;
+    for entry in manifest["files"].members_mut().filter(|entry| entry["name"] == "files.img") {
+        let mut digest = ImageDigest::new();
+        digest.update(&data);
+        entry["size"] = data.len().into();
+        entry["digest"] = digest.finalize().into();
+    }
+    fs::write(&tmp_manifest_path, manifest.pretty(4))?;
+    staged.push((tmp_manifest_path, manifest_path));
+    Ok(staged)
+}
+
+/// Temporary files of `files.img` and the manifest in `dir`.
+fn tmp_paths(dir: &Path) -> [PathBuf; 2] {
+    [dir.join(".files.img.tmp"), dir.join(format!(".{MANIFEST_FILE}.tmp"))]
+}
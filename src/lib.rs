@@ -20,6 +20,9 @@
 #[allow(clippy::all)]
 pub mod criu {
     include!(concat!(env!("OUT_DIR"), "/criu.rs"));
+
+    /// Encoded `FileDescriptorSet` of the CRIU image protobufs.
+    pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/criu_descriptors.bin"));
 }
 
 #[allow(clippy::all)]
@@ -27,4 +30,6 @@ pub mod image {
     include!(concat!(env!("OUT_DIR"), "/image.rs"));
 }
 
-pub mod constants;
\ No newline at end of file
+pub mod barrier;
+pub mod constants;
+pub mod phase;
@@ -53,8 +53,8 @@ impl log::Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            if self.log_file.is_some() {
-                if let Err(error) = writeln!(self.log_file.as_ref().unwrap(), "{} - {}", record.level(), record.args()) {
+            if let Some(mut log_file) = self.log_file.as_ref() {
+                if let Err(error) = writeln!(log_file, "{} - {}", record.level(), record.args()) {
                     eprintln!("Error writing to log file: {error}");
                 }
             } else {
@@ -17,27 +17,35 @@
  *
  */
 
+mod barrier;
 mod cli;
 mod client;
 mod server;
 mod constants;
 mod pipeline;
 mod logger;
+mod images;
+mod freezer;
+mod quiesce;
+mod timeline;
+mod phase;
 
 use constants::*;
 
-use std::{env, path::PathBuf, process::exit, fs, os::unix::prelude::FileTypeExt};
+use std::{env, path::{Path, PathBuf}, process::exit, fs, os::unix::prelude::FileTypeExt};
 
 use clap::{CommandFactory, Parser};
 use clap_complete::{generate, Shell};
+use json::object;
 use std::io;
 
+use barrier::Barrier;
 use cli::{Opts, Mode};
-use client::run_client;
-use server::run_server;
+use client::{run_client, run_catalog_command, run_heartbeat, run_timeline_command};
+use server::{run_server, config::ServerConfig};
 use logger::init_logger;
 
-use crate::client::{load_config_file, is_dump_action, is_restore_action};
+use crate::client::{load_config_file, is_dump_action, is_restore_action, run_action_scripts, ClientConfig, ScriptStage};
 
 
 fn main() {
@@ -51,7 +59,7 @@ fn main() {
 
         let client_config = load_config_file(&images_dir, &action);
 
-        // Ignore all action hooks other than "pre-stream", "pre-dump" and "pre-restore".
+        // Images are streamed with "pre-stream", and "pre-dump" is ignored when the stream socket exists.
         let enable_streaming = match action.as_str() {
             ACTION_PRE_STREAM => true,
             ACTION_PRE_DUMP => {
@@ -71,16 +79,11 @@ fn main() {
 
         init_logger(Some(&images_dir), client_config.get_log_file().to_string());
 
-        run_client(
-            client_config.get_address(),
-            client_config.get_port().parse().unwrap(),
-            client_config.get_id(),
-            client_config.get_dependencies(),
-            &action,
-            &images_dir,
-            enable_streaming
-        );
-        exit(0);
+        run_action_scripts(&client_config, &action, ScriptStage::Before);
+        // The scripts run after the coordination even if it failed.
+        let succeeded = run_client(&client_config, &action, &images_dir, enable_streaming);
+        run_action_scripts(&client_config, &action, ScriptStage::After);
+        exit(if succeeded { 0 } else { 1 });
     }
 
     let opts = Opts::parse();
@@ -92,13 +95,123 @@ fn main() {
             generate(shell, &mut cmd, "criu-coordinator", &mut io::stdout());
         }
 
-        Mode::Client { address, port, id, deps, action, images_dir, stream, log_file} => {
-            init_logger(Some(&PathBuf::from(&images_dir)), log_file);
-            run_client(&address, port, &id, &deps, &action, &PathBuf::from(images_dir), stream);
+        Mode::Client { address, port, id, deps, action, images_dir, stream, log_file, group, key_file, freeze_cgroup, heartbeat, timeout, quorum, hook } => {
+            init_logger(Some(&PathBuf::from(&images_dir)), log_file.clone());
+            let mut client_config = ClientConfig::new(log_file, address, port.to_string(), id, deps);
+            client_config.set_group(group);
+            client_config.set_key_file(key_file);
+            client_config.set_freeze_cgroup(freeze_cgroup);
+            client_config.set_heartbeat(heartbeat);
+            client_config.set_timeouts(timeout.into_iter().collect());
+            client_config.set_quorum(quorum);
+            client_config.set_hooks(hook.into_iter().collect());
+            if !run_client(&client_config, &action, &PathBuf::from(images_dir), stream) {
+                exit(1);
+            }
         },
-        Mode::Server { address, port , wait_timeout, log_file} => {
+        Mode::Barrier { address, port, name, id, deps, group, timeout } => {
+            let mut barrier = Barrier::new(&address, port, &id, &deps);
+            barrier.set_group(&group);
+            barrier.set_timeout(timeout);
+            if let Err(e) = barrier.wait(&name) {
+                eprintln!("Failed to pass barrier {name}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Heartbeat { address, port, id, group } => {
+            run_heartbeat(&address, &port, &id, &group);
+        }
+        Mode::List { address, port, group, tag } => {
+            let mut params = object!{};
+            if let Some(group) = group {
+                params["group"] = group.into();
+            }
+            if let Some(tag) = tag {
+                params["tag"] = tag.into();
+            }
+            run_catalog_command(&address, port, ACTION_CATALOG_LIST, params);
+        }
+        Mode::Show { address, port, epoch } => {
+            run_catalog_command(&address, port, ACTION_CATALOG_SHOW, object!{ epoch: epoch });
+        }
+        Mode::Tag { address, port, remove, epoch, tags } => {
+            let params = match remove {
+                true => object!{ epoch: epoch, remove: tags },
+                false => object!{ epoch: epoch, add: tags },
+            };
+            run_catalog_command(&address, port, ACTION_CATALOG_TAG, params);
+        }
+        Mode::Delete { address, port, epoch } => {
+            run_catalog_command(&address, port, ACTION_CATALOG_DELETE, object!{ epoch: epoch });
+        }
+        Mode::Timeline { address, port, trace, epoch } => {
+            run_timeline_command(&address, port, epoch.as_deref(), trace.as_deref());
+        }
+        Mode::Inspect { images_dir } => {
+            if let Err(e) = images::inspect::inspect(Path::new(&images_dir)) {
+                eprintln!("Failed to inspect {images_dir}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Diff { dir_a, dir_b } => {
+            // Exit like diff(1): 0 if the checkpoints match, 1 if they differ.
+            match images::diff::diff(Path::new(&dir_a), Path::new(&dir_b)) {
+                Ok(differ) => exit(differ as i32),
+                Err(e) => {
+                    eprintln!("Failed to compare {dir_a} and {dir_b}: {e}");
+                    exit(2);
+                }
+            }
+        }
+        Mode::Verify { dir } => {
+            match images::consistency::verify(Path::new(&dir)) {
+                Ok(consistent) => exit(!consistent as i32),
+                Err(e) => {
+                    eprintln!("Failed to verify {dir}: {e}");
+                    exit(2);
+                }
+            }
+        }
+        Mode::Rewrite { map, dir } => {
+            let result = images::rewrite::AddressMap::load(Path::new(&map))
+                .and_then(|map| images::rewrite::rewrite(Path::new(&dir), &map));
+            if let Err(e) = result {
+                eprintln!("Failed to rewrite {dir}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Decode { input, output, pretty } => {
+            if let Err(e) = images::crit::decode_file(&input, &output, pretty) {
+                eprintln!("Failed to decode {input}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Encode { input, output } => {
+            if let Err(e) = images::crit::encode_file(&input, &output) {
+                eprintln!("Failed to encode {input}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Pack { images_dir, archive } => {
+            let result = fs::File::create(&archive)
+                .and_then(|file| pipeline::marker::write_archive(Path::new(&images_dir), file));
+            if let Err(e) = result {
+                eprintln!("Failed to pack {images_dir}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Unpack { archive, images_dir } => {
+            let result = fs::File::open(&archive)
+                .and_then(|file| pipeline::marker::read_archive(file, Path::new(&images_dir)));
+            if let Err(e) = result {
+                eprintln!("Failed to unpack {archive}: {e}");
+                exit(1);
+            }
+        }
+        Mode::Server { address, port , wait_timeout, log_file, config} => {
             init_logger(None, log_file);
-            run_server(&address, port, wait_timeout);
+            let server_config = ServerConfig::load(config.as_deref().map(Path::new));
+            run_server(&address, port, wait_timeout, server_config);
         }
     };
 }
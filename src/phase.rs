@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+//! Phases of a client during a checkpoint or a restore.
+//!
+//! A client starts an operation with `pre-dump`, `pre-stream` or
+//! `pre-restore`, and each further action moves it to the next phase of the
+//! operation. Actions that are out of order are rejected with the reason.
+//! The other hooks of CRIU leave the phase as it is, and are coordinated as
+//! configured for each hook.
+
+use std::fmt;
+
+use crate::constants::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Dump,
+    Restore,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Dump => "dump",
+            Operation::Restore => "restore",
+        })
+    }
+}
+
+/// Phases in the order in which a client goes through them. The phases after
+/// `Ready` belong to one operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// The operation started, and the client waits for its dependencies.
+    Connected,
+    /// The dependencies of the client are connected.
+    Ready,
+    /// The application of the client has quiesced.
+    Quiesced,
+    /// The client has reached the freeze barrier.
+    Freezing,
+    /// The client has frozen its cgroup.
+    Frozen,
+    NetworkLocked,
+    /// The images of the client are streamed to the server.
+    Streaming,
+    /// The client has completed its local checkpoint.
+    Checkpointed,
+    Restored,
+    NetworkUnlocked,
+    Resumed,
+}
+
+impl Phase {
+    /// Operation the phase belongs to, if only one.
+    pub fn operation(self) -> Option<Operation> {
+        match self {
+            Phase::Connected | Phase::Ready => None,
+            Phase::Quiesced | Phase::Freezing | Phase::Frozen | Phase::NetworkLocked | Phase::Streaming | Phase::Checkpointed => Some(Operation::Dump),
+            Phase::Restored | Phase::NetworkUnlocked | Phase::Resumed => Some(Operation::Restore),
+        }
+    }
+
+    /// Whether the operation of the client is complete.
+    pub fn is_final(self) -> bool {
+        matches!(self, Phase::Checkpointed | Phase::Resumed)
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Connected => "connected",
+            Phase::Ready => "ready",
+            Phase::Quiesced => "quiesced",
+            Phase::Freezing => "freezing",
+            Phase::Frozen => "frozen",
+            Phase::NetworkLocked => "network locked",
+            Phase::Streaming => "streaming",
+            Phase::Checkpointed => "checkpointed",
+            Phase::Restored => "restored",
+            Phase::NetworkUnlocked => "network unlocked",
+            Phase::Resumed => "resumed",
+        })
+    }
+}
+
+/// Actions that a client may send in the phases of an operation, and the
+/// phase it enters with them.
+const TRANSITIONS: &[(Operation, &[Phase], &str, Phase)] = &[
+    (Operation::Dump, &[Phase::Ready], ACTION_QUIESCE, Phase::Quiesced),
+    (Operation::Dump, &[Phase::Ready, Phase::Quiesced], ACTION_FREEZE, Phase::Freezing),
+    (Operation::Dump, &[Phase::Ready, Phase::Quiesced, Phase::Frozen], ACTION_NETWORK_LOCK, Phase::NetworkLocked),
+    (
+        Operation::Dump,
+        &[Phase::Ready, Phase::Quiesced, Phase::Frozen, Phase::NetworkLocked, Phase::Streaming],
+        ACTION_POST_DUMP,
+        Phase::Checkpointed,
+    ),
+    (Operation::Dump, &[Phase::Streaming], ACTION_POST_STREAM, Phase::Checkpointed),
+    (Operation::Restore, &[Phase::Ready], ACTION_POST_RESTORE, Phase::Restored),
+    (Operation::Restore, &[Phase::Ready, Phase::Restored], ACTION_NETWORK_UNLOCK, Phase::NetworkUnlocked),
+    (
+        Operation::Restore,
+        &[Phase::Ready, Phase::Restored, Phase::NetworkUnlocked],
+        ACTION_POST_RESUME,
+        Phase::Resumed,
+    ),
+];
+
+/// How a client coordinates a hook of CRIU outside the flows of the
+/// transition table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookMode {
+    /// Wait until the dependencies have reached the same hook.
+    Barrier,
+    /// Tell the server that the hook was reached, without waiting.
+    Notify,
+    /// Do not contact the server.
+    Ignore,
+}
+
+impl HookMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "barrier" => Some(HookMode::Barrier),
+            "notify" => Some(HookMode::Notify),
+            "ignore" => Some(HookMode::Ignore),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HookMode::Barrier => "barrier",
+            HookMode::Notify => "notify",
+            HookMode::Ignore => "ignore",
+        }
+    }
+}
+
+/// Hooks outside the flows of the transition table, the operation they are
+/// run in, and how they are coordinated unless configured otherwise. CRIU
+/// reads the output of `query-ext-files`, and passes a file descriptor to
+/// `orphan-pts-master`, so these are ignored by default.
+const HOOKS: &[(&str, Operation, HookMode)] = &[
+    (ACTION_QUERY_EXT_FILES, Operation::Dump, HookMode::Ignore),
+    (ACTION_SETUP_NAMESPACES, Operation::Restore, HookMode::Notify),
+    (ACTION_POST_SETUP_NAMESPACES, Operation::Restore, HookMode::Notify),
+    (ACTION_ORPHAN_PTS_MASTER, Operation::Restore, HookMode::Ignore),
+    (ACTION_PRE_RESUME, Operation::Restore, HookMode::Notify),
+];
+
+/// Operation in which CRIU runs `action`, if it is one of the other hooks.
+pub fn hook_operation(action: &str) -> Option<Operation> {
+    HOOKS.iter().find(|(hook, _, _)| *hook == action).map(|(_, operation, _)| *operation)
+}
+
+/// How `action` is coordinated unless configured otherwise, if it is one of
+/// the other hooks.
+pub fn default_hook_mode(action: &str) -> Option<HookMode> {
+    HOOKS.iter().find(|(hook, _, _)| *hook == action).map(|(_, _, mode)| *mode)
+}
+
+/// Operation started by `action`, which resets the phase of the client.
+pub fn starts_operation(action: &str) -> Option<Operation> {
+    match action {
+        ACTION_PRE_DUMP | ACTION_PRE_STREAM => Some(Operation::Dump),
+        ACTION_PRE_RESTORE => Some(Operation::Restore),
+        _ => None,
+    }
+}
+
+/// Whether `action` is checked against the phase of the client that sends it.
+pub fn is_phase_action(action: &str) -> bool {
+    starts_operation(action).is_some()
+        || hook_operation(action).is_some()
+        || TRANSITIONS.iter().any(|(_, _, a, _)| *a == action)
+}
+
+/// Operation and phase that a client enters when it sends `action` during
+/// `current`, which is `None` for a client without an operation in progress.
+/// Returns the reply to the client if the action is out of order.
+pub fn transition(current: Option<(Operation, Phase)>, action: &str) -> Result<(Operation, Phase), String> {
+    if let Some(operation) = starts_operation(action) {
+        return Ok((operation, Phase::Connected));
+    }
+    let (operation, phase) = match current {
+        Some(current) => current,
+        None => return Err(format!("{MESSAGE_NOT_CONNECTED}: {action} without a dump or restore in progress")),
+    };
+    if hook_operation(action) == Some(operation) {
+        return Ok((operation, phase));
+    }
+    if action == ACTION_POST_DUMP && phase == Phase::Checkpointed {
+        return Err(MESSAGE_CHECKPOINT_EXISTS.to_string());
+    }
+    TRANSITIONS.iter()
+        .find(|(op, from, a, _)| *op == operation && *a == action && from.contains(&phase))
+        .map(|(_, _, _, to)| (operation, *to))
+        .ok_or_else(|| format!("{MESSAGE_INVALID_ACTION}: {action} in phase {phase} of {operation}"))
+}
@@ -22,4 +22,9 @@ pub mod unix_pipe;
 pub mod streamer;
 pub mod monitor;
 pub mod criu;
-pub mod ord_by;
\ No newline at end of file
+pub mod ord_by;
+pub mod frame;
+pub mod digest;
+pub mod crypto;
+pub mod upload;
+pub mod marker;
\ No newline at end of file
@@ -0,0 +1,238 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Authenticated encryption of checkpoint images.
+//!
+//! An encrypted image starts with a fixed-size header followed by a sequence
+//! of ChaCha20-Poly1305 chunks:
+//!
+//! ```text
+//! magic (8) | key fingerprint (32) | nonce prefix (7) | reserved (1) | chunk...
+//! ```
+//!
+//! Every chunk except the last holds `CHUNK_SIZE` bytes of plaintext. The last
+//! chunk is always shorter (possibly empty), so truncation at a chunk boundary
+//! is detected. The nonce of a chunk is the nonce prefix, the big-endian chunk
+//! counter and a flag marking the last chunk. The image name is used as
+//! associated data, so an encrypted image cannot be swapped for another one.
+
+use std::{
+    convert::TryInto,
+    fs,
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::Path,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, OsRng, Payload, rand_core::RngCore},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+
+use super::digest::{decode_hex, encode_hex};
+use super::protobuf::{invalid_data, KB};
+
+pub const KEY_SIZE: usize = 32;
+pub const ENCRYPTED_IMAGE_MAGIC: &[u8; 8] = b"CRCOENC1";
+
+const FINGERPRINT_SIZE: usize = 32;
+const NONCE_PREFIX_SIZE: usize = 7;
+pub const HEADER_SIZE: usize = ENCRYPTED_IMAGE_MAGIC.len() + FINGERPRINT_SIZE + NONCE_PREFIX_SIZE + 1;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * KB;
+
+/// ImageKey is the symmetric key shared by all members of a group.
+#[derive(Clone)]
+pub struct ImageKey {
+    cipher: ChaCha20Poly1305,
+    fingerprint: [u8; FINGERPRINT_SIZE],
+}
+
+impl ImageKey {
+    /// Load a key file containing 32 bytes encoded as 64 hex characters,
+    /// e.g. generated with `openssl rand -hex 32`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let key = decode_hex(content.trim())
+            .filter(|key| key.len() == KEY_SIZE)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData,
+                format!("{path:?} must contain {KEY_SIZE} bytes encoded as hex")))?;
+        Ok(Self::new(&key))
+    }
+
+    pub fn new(key: &[u8]) -> Self {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let fingerprint = Sha256::digest(key).into();
+        Self { cipher, fingerprint }
+    }
+
+    /// Hex-encoded fingerprint, which identifies the key without revealing it.
+    pub fn fingerprint(&self) -> String {
+        encode_hex(&self.fingerprint)
+    }
+
+    fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+        nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+        nonce[11] = last as u8;
+        *Nonce::from_slice(&nonce)
+    }
+
+    /// Create a sealer that encrypts the image `name` as its content
+    /// becomes available.
+    pub fn sealer(&self, name: &str) -> Sealer {
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        Sealer {
+            key: self.clone(),
+            name: name.to_string(),
+            prefix,
+            counter: 0,
+            pending: Vec::with_capacity(CHUNK_SIZE),
+            header_written: false,
+        }
+    }
+
+    /// Decrypt `src` into `dst`. Fails if the image was encrypted with
+    /// another key, was modified or was truncated.
+    pub fn open<R: Read, W: Write>(&self, name: &str, src: &mut R, dst: &mut W) -> Result<u64> {
+        let mut header = [0u8; HEADER_SIZE];
+        src.read_exact(&mut header)?;
+        let prefix = self.check_header(&header)?;
+
+        let mut buffer = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+        let mut counter: u32 = 0;
+        let mut written = 0;
+        loop {
+            let len = read_full(src, &mut buffer)?;
+            let last = len < buffer.len();
+            let nonce = Self::chunk_nonce(&prefix, counter, last);
+            let chunk = self.cipher
+                .decrypt(&nonce, Payload { msg: &buffer[..len], aad: name.as_bytes() })
+                .map_err(|_| invalid_data(format!("{name} failed authentication")))?;
+            dst.write_all(&chunk)?;
+            written += chunk.len() as u64;
+
+            if last {
+                return Ok(written);
+            }
+            counter = counter.checked_add(1)
+                .ok_or_else(|| invalid_data(format!("{name} has too many chunks")))?;
+        }
+    }
+
+    /// Check that `header` belongs to an image encrypted with this key
+    /// and return its nonce prefix.
+    pub fn check_header(&self, header: &[u8]) -> Result<[u8; NONCE_PREFIX_SIZE]> {
+        match parse_header(header) {
+            Some((fingerprint, prefix)) if fingerprint == self.fingerprint => Ok(prefix),
+            Some(_) => Err(invalid_data("Image was encrypted with a different key")),
+            None => Err(invalid_data("Image is not encrypted")),
+        }
+    }
+}
+
+/// Sealer encrypts an image incrementally. Plaintext is held back until a
+/// full chunk is available, and a full chunk is only sealed once more data
+/// follows, because the last chunk must be shorter than `CHUNK_SIZE`.
+pub struct Sealer {
+    key: ImageKey,
+    name: String,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    pending: Vec<u8>,
+    header_written: bool,
+}
+
+impl Sealer {
+    /// Encrypt `data` and append the encrypted output to `sealed`.
+    pub fn update(&mut self, mut data: &[u8], sealed: &mut Vec<u8>) -> Result<()> {
+        self.write_header(sealed);
+        while !data.is_empty() {
+            if self.pending.len() == CHUNK_SIZE {
+                self.seal_pending(false, sealed)?;
+            }
+            let len = std::cmp::min(CHUNK_SIZE - self.pending.len(), data.len());
+            self.pending.extend_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Encrypt the remaining data as the last chunk.
+    pub fn finish(mut self, sealed: &mut Vec<u8>) -> Result<()> {
+        self.write_header(sealed);
+        if self.pending.len() == CHUNK_SIZE {
+            self.seal_pending(false, sealed)?;
+        }
+        self.seal_pending(true, sealed)
+    }
+
+    fn write_header(&mut self, sealed: &mut Vec<u8>) {
+        if !self.header_written {
+            sealed.extend_from_slice(ENCRYPTED_IMAGE_MAGIC);
+            sealed.extend_from_slice(&self.key.fingerprint);
+            sealed.extend_from_slice(&self.prefix);
+            sealed.push(0);
+            self.header_written = true;
+        }
+    }
+
+    fn seal_pending(&mut self, last: bool, sealed: &mut Vec<u8>) -> Result<()> {
+        let nonce = ImageKey::chunk_nonce(&self.prefix, self.counter, last);
+        let chunk = self.key.cipher
+            .encrypt(&nonce, Payload { msg: &self.pending, aad: self.name.as_bytes() })
+            .map_err(|_| Error::other(format!("Failed to encrypt {}", self.name)))?;
+        sealed.extend_from_slice(&chunk);
+        self.pending.clear();
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is too large to encrypt", self.name)))?;
+        Ok(())
+    }
+}
+
+/// Return the key fingerprint stored in the header of an encrypted image,
+/// or `None` if `header` does not start with an encrypted image header.
+pub fn header_fingerprint(header: &[u8]) -> Option<String> {
+    parse_header(header).map(|(fingerprint, _)| encode_hex(&fingerprint))
+}
+
+fn parse_header(header: &[u8]) -> Option<([u8; FINGERPRINT_SIZE], [u8; NONCE_PREFIX_SIZE])> {
+    if header.len() < HEADER_SIZE || !header.starts_with(ENCRYPTED_IMAGE_MAGIC) {
+        return None;
+    }
+    let magic_len = ENCRYPTED_IMAGE_MAGIC.len();
+    let fingerprint = header[magic_len..magic_len + FINGERPRINT_SIZE].try_into().ok()?;
+    let prefix = header[magic_len + FINGERPRINT_SIZE..HEADER_SIZE - 1].try_into().ok()?;
+    Some((fingerprint, prefix))
+}
+
+/// Like `read_exact`, but stops at end of file and returns the number of bytes read.
+fn read_full<R: Read>(src: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match src.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
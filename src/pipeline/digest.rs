@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Digests used to verify the integrity of transferred checkpoint images.
+
+use std::{
+    fs::File,
+    io::Result,
+    os::unix::fs::FileExt,
+};
+use sha2::{Digest, Sha256};
+
+use super::protobuf::KB;
+
+/// Name of the digest algorithm, recorded in the manifest next to every digest.
+pub const DIGEST_ALGORITHM: &str = "sha256";
+
+/// ImageDigest accumulates the digest of an image file as its content
+/// is received.
+#[derive(Default, Clone)]
+pub struct ImageDigest {
+    hasher: Sha256,
+}
+
+impl ImageDigest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Read `len` bytes of `file` starting at `offset` into the digest.
+    /// This is used after data has been spliced into a file, because the
+    /// content never passes through user space on the way in.
+    pub fn update_from_file(&mut self, file: &File, offset: u64, len: usize) -> Result<()> {
+        let mut buffer = vec![0u8; std::cmp::min(len, 64 * KB)];
+        let mut done = 0;
+        while done < len {
+            let chunk = std::cmp::min(buffer.len(), len - done);
+            file.read_exact_at(&mut buffer[..chunk], offset + done as u64)?;
+            self.hasher.update(&buffer[..chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Return the digest as a lowercase hex string.
+    pub fn finalize(self) -> String {
+        encode_hex(&self.hasher.finalize())
+    }
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
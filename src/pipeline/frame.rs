@@ -0,0 +1,64 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Length-prefixed messages exchanged between the streamer and the server.
+//!
+//! Each frame is a little-endian u32 length followed by the payload, which
+//! matches the framing CRIU uses for protobuf entries in its image files.
+
+use std::{
+    io::{ErrorKind, Read, Result, Write},
+    mem::size_of,
+};
+
+use super::protobuf::{invalid_data, MB};
+
+/// Upper bound for a single frame. Image data is not sent in frames, only
+/// the headers that describe it, so anything bigger indicates a bug.
+const MAX_FRAME_SIZE: usize = MB;
+
+pub fn write_frame<S: Write>(dst: &mut S, payload: &[u8]) -> Result<()> {
+    dst.write_all(&(payload.len() as u32).to_le_bytes())?;
+    dst.write_all(payload)
+}
+
+/// Read the next frame. Returns `None` if the peer closed the connection
+/// before a new frame started.
+pub fn read_frame<S: Read>(src: &mut S) -> Result<Option<Vec<u8>>> {
+    read_frame_with_limit(src, MAX_FRAME_SIZE)
+}
+
+/// Read the next frame of at most `max_size` bytes.
+pub fn read_frame_with_limit<S: Read>(src: &mut S, max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut size_buf = [0u8; size_of::<u32>()];
+    match src.read_exact(&mut size_buf) {
+        Ok(()) => {},
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let size = u32::from_le_bytes(size_buf) as usize;
+    if size > max_size {
+        return Err(invalid_data(format!("Frame of size {size} exceeds limit")));
+    }
+
+    let mut payload = vec![0u8; size];
+    src.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
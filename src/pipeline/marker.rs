@@ -0,0 +1,251 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Marker stream of CRIU images, as defined by `proto/image.proto`.
+//!
+//! A stream is a sequence of length-prefixed `marker` messages. A
+//! `file_data` marker is followed by the number of raw bytes it announces:
+//!
+//! ```text
+//! len (u32 LE) | marker { seq, filename }
+//! len (u32 LE) | marker { seq, file_data: n } | n bytes
+//! len (u32 LE) | marker { seq, file_eof }
+//! ...
+//! len (u32 LE) | marker { seq, image_eof }
+//! ```
+//!
+//! A `filename` marker selects the file that the following data belongs to,
+//! so chunks of several files can be interleaved. `seq` starts at zero and
+//! increases by one with every marker. The stream does not depend on the
+//! transport, so it is also stored as a file to archive an images directory,
+//! see `write_archive` and `read_archive`.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Result, Write},
+    path::Path,
+};
+
+use criu_coordinator::image::{marker::Body, Marker};
+use prost::Message;
+
+use super::frame::{read_frame, write_frame};
+use super::protobuf::{invalid_data, MB};
+
+/// Upper bound for the data following a single `file_data` marker.
+pub const MAX_DATA_SIZE: usize = MB;
+
+/// MarkerEvent is an item read from a marker stream.
+#[derive(Debug, PartialEq)]
+pub enum MarkerEvent {
+    /// The following data belongs to this file.
+    File(String),
+    Data(Vec<u8>),
+    /// The named file is complete.
+    FileEof(String),
+    /// The stream is complete.
+    ImageEof,
+}
+
+/// MarkerWriter serializes image files into a marker stream.
+pub struct MarkerWriter<W: Write> {
+    dst: W,
+    seq: u64,
+    current_file: Option<String>,
+}
+
+impl<W: Write> MarkerWriter<W> {
+    pub fn new(dst: W) -> Self {
+        Self { dst, seq: 0, current_file: None }
+    }
+
+    fn write_marker(&mut self, body: Body) -> Result<()> {
+        let marker = Marker { seq: self.seq, body: Some(body) };
+        write_frame(&mut self.dst, &marker.encode_to_vec())?;
+        self.seq += 1;
+        Ok(())
+    }
+
+    /// Select the file that the following data belongs to. The `filename`
+    /// marker is only written when the file changes.
+    fn select_file(&mut self, filename: &str) -> Result<()> {
+        if self.current_file.as_deref() != Some(filename) {
+            self.write_marker(Body::Filename(filename.to_string()))?;
+            self.current_file = Some(filename.to_string());
+        }
+        Ok(())
+    }
+
+    /// Append `data` to `filename`.
+    pub fn write_data(&mut self, filename: &str, data: &[u8]) -> Result<()> {
+        self.select_file(filename)?;
+        for chunk in data.chunks(MAX_DATA_SIZE) {
+            self.write_marker(Body::FileData(chunk.len() as u32))?;
+            self.dst.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Mark `filename` as complete.
+    pub fn write_file_eof(&mut self, filename: &str) -> Result<()> {
+        self.select_file(filename)?;
+        self.write_marker(Body::FileEof(true))?;
+        self.current_file = None;
+        Ok(())
+    }
+
+    /// Mark the end of the stream.
+    pub fn write_image_eof(&mut self) -> Result<()> {
+        self.write_marker(Body::ImageEof(true))?;
+        self.dst.flush()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.dst
+    }
+}
+
+/// MarkerReader parses a marker stream.
+pub struct MarkerReader<R: Read> {
+    src: R,
+    seq: u64,
+    current_file: Option<String>,
+}
+
+impl<R: Read> MarkerReader<R> {
+    pub fn new(src: R) -> Self {
+        Self { src, seq: 0, current_file: None }
+    }
+
+    /// Name of the file that data is currently appended to.
+    pub fn current_file(&self) -> Option<&str> {
+        self.current_file.as_deref()
+    }
+
+    /// Read the next event. Returns `None` if the stream ended without
+    /// an `image_eof` marker.
+    pub fn next_event(&mut self) -> Result<Option<MarkerEvent>> {
+        let frame = match read_frame(&mut self.src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let marker = Marker::decode(frame.as_slice()).map_err(invalid_data)?;
+        if marker.seq != self.seq {
+            return Err(invalid_data(format!("Expected marker {} but received {}", self.seq, marker.seq)));
+        }
+        self.seq += 1;
+
+        let event = match marker.body {
+            Some(Body::Filename(filename)) => {
+                self.current_file = Some(filename.clone());
+                MarkerEvent::File(filename)
+            }
+            Some(Body::FileData(size)) => {
+                let size = size as usize;
+                if self.current_file.is_none() {
+                    return Err(invalid_data("File data received before a file name".to_string()));
+                }
+                if size > MAX_DATA_SIZE {
+                    return Err(invalid_data(format!("File data of size {size} exceeds limit")));
+                }
+                let mut data = vec![0u8; size];
+                self.src.read_exact(&mut data)?;
+                MarkerEvent::Data(data)
+            }
+            Some(Body::FileEof(_)) => match self.current_file.take() {
+                Some(filename) => MarkerEvent::FileEof(filename),
+                None => return Err(invalid_data("End of file received before a file name".to_string())),
+            },
+            Some(Body::ImageEof(_)) => MarkerEvent::ImageEof,
+            None => return Err(invalid_data(format!("Marker {} has no body", marker.seq))),
+        };
+        Ok(Some(event))
+    }
+}
+
+
+/// Write the image files of `images_dir` to `dst` as a marker stream,
+/// one file after the other.
+pub fn write_archive<W: Write>(images_dir: &Path, dst: W) -> Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(images_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_file() && is_valid_image_name(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    let mut writer = MarkerWriter::new(BufWriter::new(dst));
+    let mut buffer = vec![0u8; MAX_DATA_SIZE];
+    for name in names {
+        let mut src = File::open(images_dir.join(&name))?;
+        loop {
+            let n = src.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_data(&name, &buffer[..n])?;
+        }
+        writer.write_file_eof(&name)?;
+    }
+    writer.write_image_eof()
+}
+
+/// Extract the image files of a marker stream read from `src` into
+/// `images_dir`. Fails if the stream ends before its `image_eof` marker
+/// or with files that are not complete.
+pub fn read_archive<R: Read>(src: R, images_dir: &Path) -> Result<()> {
+    fs::create_dir_all(images_dir)?;
+    let mut reader = MarkerReader::new(BufReader::new(src));
+    let mut files: HashMap<String, BufWriter<File>> = HashMap::new();
+    loop {
+        match reader.next_event()? {
+            Some(MarkerEvent::File(name)) => {
+                if !is_valid_image_name(&name) {
+                    return Err(invalid_data(format!("Invalid image name {name:?}")));
+                }
+                if !files.contains_key(&name) {
+                    files.insert(name.clone(), BufWriter::new(File::create(images_dir.join(&name))?));
+                }
+            }
+            Some(MarkerEvent::Data(data)) => {
+                let name = reader.current_file().unwrap_or_default();
+                match files.get_mut(name) {
+                    Some(file) => file.write_all(&data)?,
+                    None => return Err(invalid_data(format!("Data received for {name} after its end"))),
+                }
+            }
+            Some(MarkerEvent::FileEof(name)) => match files.remove(&name) {
+                Some(mut file) => file.flush()?,
+                None => return Err(invalid_data(format!("End of {name} received twice"))),
+            },
+            Some(MarkerEvent::ImageEof) if files.is_empty() => return Ok(()),
+            Some(MarkerEvent::ImageEof) => return Err(invalid_data("Archive ends with incomplete image files")),
+            None => return Err(invalid_data("Archive ends before the image_eof marker")),
+        }
+    }
+}
+
+/// Image names are created by CRIU and never contain path components.
+pub fn is_valid_image_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
@@ -32,7 +32,7 @@ use nix::{
     unistd::close, errno::Errno,
 };
 use crate::pipeline::protobuf::MB;
-use super::{criu::StreamConnection, unix_pipe::{UnixFile, UnixPipe}};
+use super::{criu::StreamConnection, digest::ImageDigest, unix_pipe::{UnixFile, UnixPipe}};
 
 
 /// CRIU has difficulties if the pipe size is bigger than 4MB.
@@ -49,12 +49,16 @@ pub struct ImageFile {
     pub filename: Rc<str>,
     /// Output file
     pub output_file: File,
+    /// Number of bytes written to the output file so far
+    pub size: u64,
+    /// Digest of the bytes written to the output file so far
+    pub digest: ImageDigest,
 }
 impl ImageFile {
     pub(crate) fn new(filename: String, mut pipe: UnixFile, output_file: File) -> Self {
         let _ = pipe.set_capacity(CRIU_PIPE_DESIRED_CAPACITY);
         let filename = Rc::from(filename);
-        Self { pipe, filename, output_file }
+        Self { pipe, filename, output_file, size: 0, digest: ImageDigest::new() }
     }
 }
 
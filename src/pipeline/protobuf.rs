@@ -22,7 +22,7 @@ use prost::Message;
 use std::{
     mem::size_of,
     process::exit,
-    io::{Read, Result},
+    io::{Error, ErrorKind, Read, Result},
 };
 use bytes::{BytesMut, Buf};
 
@@ -30,6 +30,12 @@ use bytes::{BytesMut, Buf};
 pub const KB: usize = 1024;
 pub const MB: usize = 1024*1024;
 
+/// Error for data that is malformed, e.g. an image or a message that cannot
+/// be decoded.
+pub fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> Error {
+    Error::new(ErrorKind::InvalidData, error)
+}
+
 pub fn read_bytes_next<S: Read>(src: &mut S, len: usize) -> Result<Option<BytesMut>> {
     let mut buf = Vec::with_capacity(len);
     src.take(len as u64).read_to_end(&mut buf)?;
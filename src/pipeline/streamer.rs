@@ -19,30 +19,37 @@
 
  //! This module is responsible for facilitating the transmission of CRIU images.
 
-use json::object;
+use json::{object, JsonValue};
 
 use log::*;
 use std::{
     fs::{self, File},
-    io::{self, Read, Write},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
     io::Error,
+    os::unix::fs::{FileExt, OpenOptionsExt},
     os::fd::AsRawFd,
     path::Path,
     process::exit,
-    net::TcpStream,
+    net::{SocketAddr, TcpStream},
     collections::HashMap,
     rc::Rc,
 };
 use nix::{
-    sys::{epoll::EpollFlags, stat::Mode, sendfile::sendfile},
-    unistd::{lseek, Whence},
+    sys::{epoll::EpollFlags, stat::Mode},
+    unistd::{unlinkat, UnlinkatFlags},
     fcntl::{openat, OFlag}
 };
 
 use super::{
     criu::StreamListener,
+    crypto::{ImageKey, Sealer, ENCRYPTED_IMAGE_MAGIC},
+    digest::{ImageDigest, DIGEST_ALGORITHM},
+    frame::write_frame,
+    marker::MarkerWriter,
     monitor::{Monitor, MonitorType, ImageFile},
+    upload::{open_data_connection, read_response, SavedImage, Uploader},
 };
+use crate::constants::*;
 use crate::pipeline::unix_pipe::UnixPipe;
 
 const BUFFER_SIZE: usize = 32768 * 4;
@@ -128,9 +135,103 @@ fn receive_response(tcp_stream: &mut TcpStream, expected_message: &str) {
     }
 }
 
+/// LiveStream sends images to the server as a marker stream while CRIU is
+/// still producing them. If the connection fails, the stream is abandoned
+/// and the images are uploaded once the local checkpoint is complete.
+struct LiveStream {
+    writer: Option<MarkerWriter<TcpStream>>,
+}
+
+impl LiveStream {
+    fn connect(server_address: SocketAddr, id: &str, group: &str) -> Self {
+        match open_data_connection(server_address, id, group, ACTION_STREAM_IMAGE) {
+            Ok(stream) => Self { writer: Some(MarkerWriter::new(stream)) },
+            Err(e) => {
+                warn!("Failed to open image stream, images will be uploaded after the checkpoint: {e}");
+                Self { writer: None }
+            }
+        }
+    }
+
+    fn send<F>(&mut self, f: F)
+        where
+            F: FnOnce(&mut MarkerWriter<TcpStream>) -> io::Result<()>,
+    {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = f(writer) {
+                warn!("Image stream interrupted, images will be uploaded after the checkpoint: {e}");
+                self.writer = None;
+            }
+        }
+    }
+
+    /// End the stream and wait until the server has stored every file.
+    fn finish(mut self) {
+        self.send(|writer| writer.write_image_eof());
+        if let Some(mut writer) = self.writer {
+            match read_response(writer.get_mut()) {
+                Ok(response) if response == MESSAGE_ACK => info!("Image stream complete"),
+                Ok(response) => warn!("Server rejected image stream: {response}"),
+                Err(e) => warn!("Image stream interrupted: {e}"),
+            }
+        }
+    }
+}
+
+/// SealedImage holds the encrypted form of an image while CRIU writes it.
+/// The encrypted content is kept in an unlinked file in the images
+/// directory, so that it can be uploaded again if the live stream fails.
+struct SealedImage {
+    sealer: Sealer,
+    file: File,
+    size: u64,
+    digest: ImageDigest,
+}
+
+impl SealedImage {
+    fn new(images_dir: &File, name: &str, key: &ImageKey) -> io::Result<Self> {
+        let sealed_name = format!(".{name}.sealed");
+        let file_fd = openat(images_dir.as_raw_fd(), sealed_name.as_str(), OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_TRUNC, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        unlinkat(Some(images_dir.as_raw_fd()), sealed_name.as_str(), UnlinkatFlags::NoRemoveDir)?;
+        Ok(Self {
+            sealer: key.sealer(name),
+            file: fs::File::new(file_fd)?,
+            size: 0,
+            digest: ImageDigest::new(),
+        })
+    }
+
+    /// Encrypt `data` and return the encrypted bytes that became available.
+    fn update(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut sealed = Vec::new();
+        self.sealer.update(data, &mut sealed)?;
+        self.file.write_all(&sealed)?;
+        self.digest.update(&sealed);
+        self.size += sealed.len() as u64;
+        Ok(sealed)
+    }
+
+    /// Encrypt the end of the image. Returns the remaining encrypted bytes
+    /// and the encrypted image.
+    fn finish(self, name: &str) -> io::Result<(Vec<u8>, SavedImage)> {
+        let Self { sealer, mut file, size, mut digest } = self;
+        let mut sealed = Vec::new();
+        sealer.finish(&mut sealed)?;
+        file.write_all(&sealed)?;
+        digest.update(&sealed);
+        let size = size + sealed.len() as u64;
+        Ok((sealed, SavedImage {
+            name: name.to_string(),
+            size,
+            digest: digest.finalize(),
+            file,
+        }))
+    }
+}
+
 /// Create a Unix socket that accepts a connection with CRIU
 /// and run a streamer loop to receive and serialize CRIU images.
-fn run_streamer(tcp_stream: &mut TcpStream, images_dir: &Path) -> io::Result<()> {
+fn run_streamer(tcp_stream: &mut TcpStream, images_dir: &Path, id: &str, group: &str, image_key: Option<ImageKey>) -> io::Result<()> {
     info!("Starting streamer at {}", images_dir.to_str().unwrap());
     fs::create_dir_all(images_dir)?;
     // Create Unix socket to communicate with CRIU
@@ -155,8 +256,14 @@ fn run_streamer(tcp_stream: &mut TcpStream, images_dir: &Path) -> io::Result<()>
     // will persist.
     let images_dir = fs::File::open(images_dir)?;
 
-    let mut saved_images: HashMap<Rc<str>, File> = HashMap::new();
-    let mut image_size: HashMap<Rc<str>, i32> = HashMap::new();
+    let server_address = tcp_stream.peer_addr()?;
+    let mut live_stream = LiveStream::connect(server_address, id, group);
+    if let Some(key) = &image_key {
+        info!("Encrypting images with key {}", key.fingerprint());
+    }
+
+    let mut sealed_images: HashMap<Rc<str>, SealedImage> = HashMap::new();
+    let mut saved_images: Vec<SavedImage> = Vec::new();
 
     let epoll_capacity = 8;
     while let Some((monitor_key, monitor_obj)) = monitor.poll(epoll_capacity)? {
@@ -171,6 +278,10 @@ fn run_streamer(tcp_stream: &mut TcpStream, images_dir: &Path) -> io::Result<()>
 
                         let pipe = criu_connection.recv_pipe()?;
                         let image_file = ImageFile::new(filename, pipe, output_file);
+                        if let Some(key) = &image_key {
+                            let sealed_image = SealedImage::new(&images_dir, &image_file.filename, key)?;
+                            sealed_images.insert(image_file.filename.clone(), sealed_image);
+                        }
                         monitor.add(
                             image_file.pipe.as_raw_fd(),
                             MonitorType::ImageFile(image_file),
@@ -186,66 +297,113 @@ fn run_streamer(tcp_stream: &mut TcpStream, images_dir: &Path) -> io::Result<()>
                 info!("Receiving: {}", img_file.filename);
                 let (eof, file_size) = img_file.pipe.drain_img_file(&img_file.output_file)?;
 
-                let image_size_entry = image_size.entry(Rc::clone(&img_file.filename)).or_insert_with(|| 0);
-                *image_size_entry += file_size;
+                if file_size > 0 {
+                    let mut data = vec![0u8; file_size as usize];
+                    img_file.output_file.read_exact_at(&mut data, img_file.size)?;
+                    img_file.digest.update(&data);
+                    img_file.size += file_size as u64;
+
+                    if let Some(sealed_image) = sealed_images.get_mut(&img_file.filename) {
+                        data = sealed_image.update(&data)?;
+                    }
+                    live_stream.send(|writer| writer.write_data(&img_file.filename, &data));
+                }
 
                 if !eof {
-                    info!("Saved: {} with size {}", img_file.filename, image_size_entry);
-                    let output_file = img_file.output_file.try_clone()?;
-                    saved_images.insert(Rc::clone(&img_file.filename), output_file);
-                    monitor.remove(monitor_key)?;
+                    info!("Saved: {} with size {}", img_file.filename, img_file.size);
+                    if let MonitorType::ImageFile(img_file) = monitor.remove(monitor_key)? {
+                        let filename = img_file.filename.clone();
+                        let saved_image = match sealed_images.remove(&img_file.filename) {
+                            Some(sealed_image) => {
+                                let (data, saved_image) = sealed_image.finish(&img_file.filename)?;
+                                live_stream.send(|writer| writer.write_data(&img_file.filename, &data));
+                                saved_image
+                            }
+                            None => SavedImage {
+                                name: img_file.filename.to_string(),
+                                size: img_file.size,
+                                digest: img_file.digest.finalize(),
+                                file: img_file.output_file,
+                            },
+                        };
+                        live_stream.send(|writer| writer.write_file_eof(&filename));
+                        saved_images.push(saved_image);
+                    }
                 }
             }
         }
     }
+    live_stream.finish();
 
     info!("Local checkpoint complete");
-    send_message(tcp_stream, "SYN");
-
-    // FIXME: Receive ACK message
-    receive_response(tcp_stream, "ACK");
-
-    // FIXME: Transfer local checkpoint to server
-    for (img_name, img_file) in saved_images.iter() {
-        let img_metadata = object!{
-            img_name: img_name.to_string(),
-            img_size: image_size[img_name],
-        };
-
-        send_message(tcp_stream, &img_metadata.dump());
-
-        // Go to the beginning of the file.
-        lseek(img_file.as_raw_fd(), 0, Whence::SeekSet)?;
-
-        // Send file content
-        let mut offset = 0;
-        let mut to_write = image_size[img_name] as usize;
-        while to_write > 0 {
-            let bytes_sent = sendfile(tcp_stream.as_raw_fd(), img_file.as_raw_fd(), Some(&mut offset), to_write)?;
-            info!("bytes_sent: {bytes_sent}");
-            to_write -= bytes_sent;
-        }
-
-        // Wait to receive ACK
-        receive_response(tcp_stream, "IMG_ACK");
+    send_message(tcp_stream, MESSAGE_SYN);
+    receive_response(tcp_stream, MESSAGE_ACK);
+
+    // Announce the image files on the coordination connection.
+    let mut images = JsonValue::new_array();
+    for img in saved_images.iter() {
+        info!("Announcing {} with size {} and {DIGEST_ALGORITHM} {}", img.name, img.size, img.digest);
+        images.push(object!{
+            img_name: img.name.clone(),
+            img_size: img.size,
+            img_digest: img.digest.clone(),
+        }).unwrap();
     }
+    write_frame(tcp_stream, object!{ images: images }.dump().as_bytes())?;
+    receive_response(tcp_stream, MESSAGE_ACK);
 
-    // Send SYN message
-    send_message(tcp_stream, "SYN");
+    // Upload the image files that were not received over the live stream.
+    let uploader = Uploader::new(server_address, id, group);
+    if let Err(e) = uploader.upload_all(&saved_images) {
+        error!("Checkpoint transfer failed: {e}");
+        exit(1);
+    }
 
-    // FIXME: Receive ACK message
+    // Signal the end of the transfer and wait for the server
+    // to confirm that the manifest has been written.
+    write_frame(tcp_stream, MESSAGE_SYN.as_bytes())?;
+    receive_response(tcp_stream, MESSAGE_ACK);
 
     info!("Checkpoint transfer complete");
 
     Ok(())
 }
 
-pub fn streamer(tcp_stream: &mut TcpStream, images_dir: &Path) -> io::Result<()> {
+/// Decrypt the encrypted images in `images_dir` in place before CRIU
+/// restores from them. Images that are not encrypted are left untouched.
+pub fn restore_images(images_dir: &Path, key: &ImageKey) -> io::Result<()> {
+    for entry in fs::read_dir(images_dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let mut header = [0u8; ENCRYPTED_IMAGE_MAGIC.len()];
+        let mut src = File::open(&path)?;
+        if src.read_exact(&mut header).is_err() || &header != ENCRYPTED_IMAGE_MAGIC {
+            continue;
+        }
+        src.seek(SeekFrom::Start(0))?;
+
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let tmp_path = images_dir.join(format!(".{name}.open"));
+        let mut dst = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp_path)?;
+        if let Err(e) = key.open(&name, &mut BufReader::new(src), &mut dst) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        fs::rename(&tmp_path, &path)?;
+        info!("Decrypted {name}");
+    }
+    Ok(())
+}
+
+pub fn streamer(tcp_stream: &mut TcpStream, images_dir: &Path, id: &str, group: &str, image_key: Option<ImageKey>) -> io::Result<()> {
     info!("Detaching from main thread");
     fork_process()?;
     detach_terminal()?;
     change_working_dir()?;
     close_std_file_descriptors()?;
 
-    run_streamer(tcp_stream, images_dir)
+    run_streamer(tcp_stream, images_dir, id, group, image_key)
 }
\ No newline at end of file
@@ -0,0 +1,184 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! This module is responsible for uploading image files to the server
+//! over dedicated data connections. Files that the server has already
+//! received over the live marker stream are skipped.
+
+use json::object;
+use log::*;
+use std::{
+    fs::File,
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::fd::AsRawFd,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+use nix::sys::sendfile::sendfile;
+
+use crate::constants::*;
+use super::frame::write_frame;
+use super::protobuf::invalid_data;
+
+/// Number of image files uploaded concurrently.
+pub const UPLOAD_CONNECTIONS: usize = 4;
+/// Number of times an interrupted upload is resumed before giving up.
+const MAX_UPLOAD_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const BUFFER_SIZE: usize = 1024;
+
+/// SavedImage is a local image file that is ready to be sent to the server.
+pub struct SavedImage {
+    pub name: String,
+    pub size: u64,
+    pub digest: String,
+    pub file: File,
+}
+
+/// Uploader sends image files to the server. Every worker opens its own data
+/// connection. When a connection drops, the worker reconnects and the server
+/// reports how much of the file it has already received.
+pub struct Uploader<'a> {
+    server_address: SocketAddr,
+    id: &'a str,
+    group: &'a str,
+}
+
+impl<'a> Uploader<'a> {
+    pub fn new(server_address: SocketAddr, id: &'a str, group: &'a str) -> Self {
+        Self { server_address, id, group }
+    }
+
+    pub fn upload_all(&self, images: &[SavedImage]) -> io::Result<()> {
+        let queue = Mutex::new(images.iter().collect::<Vec<_>>());
+        let workers = UPLOAD_CONNECTIONS.min(images.len());
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| scope.spawn(|| self.run_worker(&queue)))
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| Err(Error::other("Upload worker panicked"))))
+                .collect::<io::Result<Vec<()>>>()
+        })?;
+        Ok(())
+    }
+
+    fn run_worker(&self, queue: &Mutex<Vec<&SavedImage>>) -> io::Result<()> {
+        let mut connection: Option<TcpStream> = None;
+
+        loop {
+            let img = match queue.lock().unwrap().pop() {
+                Some(img) => img,
+                None => return Ok(()),
+            };
+
+            let mut attempt = 1;
+            loop {
+                let result = match connection.as_mut() {
+                    Some(stream) => self.upload(stream, img),
+                    None => self.connect().and_then(|stream| self.upload(connection.insert(stream), img)),
+                };
+
+                match result {
+                    Ok(()) => break,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        error!("Server rejected {}: {}", img.name, e);
+                        return Err(e);
+                    }
+                    Err(e) if attempt < MAX_UPLOAD_ATTEMPTS => {
+                        warn!("Upload of {} interrupted ({}), resuming (attempt {})", img.name, e, attempt + 1);
+                        connection = None;
+                        attempt += 1;
+                        thread::sleep(RETRY_DELAY);
+                    }
+                    Err(e) => {
+                        error!("Failed to upload {}: {}", img.name, e);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        open_data_connection(self.server_address, self.id, self.group, ACTION_UPLOAD_IMAGE)
+    }
+
+    /// Upload a single image file, starting at the offset reported by the server.
+    fn upload(&self, stream: &mut TcpStream, img: &SavedImage) -> io::Result<()> {
+        write_frame(stream, img.name.as_bytes())?;
+
+        let response = read_response(stream)?;
+        let response = json::parse(&response).ok()
+            .filter(|response| response["offset"].as_u64().is_some_and(|offset| offset <= img.size))
+            .ok_or_else(|| invalid_data(response))?;
+        if response["verified"].as_bool() == Some(true) {
+            info!("{} has already been received by the server", img.name);
+            return Ok(());
+        }
+        let mut offset = response["offset"].as_i64().unwrap();
+
+        info!("Uploading {} from offset {} of {}", img.name, offset, img.size);
+        while (offset as u64) < img.size {
+            let to_write = (img.size - offset as u64) as usize;
+            let bytes_sent = sendfile(stream.as_raw_fd(), img.file.as_raw_fd(), Some(&mut offset), to_write)?;
+            if bytes_sent == 0 {
+                return Err(invalid_data(format!("{} was truncated while being sent", img.name)));
+            }
+        }
+
+        match read_response(stream)?.as_str() {
+            MESSAGE_IMG_ACK => {
+                info!("Uploaded {}", img.name);
+                Ok(())
+            }
+            response => Err(invalid_data(response.to_string())),
+        }
+    }
+}
+
+/// Open a data connection to the server for `action`.
+pub fn open_data_connection(server_address: SocketAddr, id: &str, group: &str, action: &str) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(server_address)?;
+    let cmd = object!{
+        id: id,
+        action: action,
+        dependencies: "",
+        group: group,
+    };
+    stream.write_all(cmd.dump().as_bytes())?;
+
+    match read_response(&mut stream)?.as_str() {
+        MESSAGE_ACK => Ok(stream),
+        response => Err(invalid_data(response.to_string())),
+    }
+}
+
+pub fn read_response(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let size = stream.read(&mut buffer)?;
+    if size == 0 {
+        return Err(Error::new(ErrorKind::ConnectionAborted, "Server closed the connection"));
+    }
+    Ok(String::from_utf8_lossy(&buffer[..size]).to_string())
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Notifications that tell an application that a checkpoint is imminent, so
+//! that it flushes its buffers and pauses intake, and that it was resumed.
+//!
+//! Over a Unix socket, the client writes `quiesce` or `resumed` as a line,
+//! and the application acknowledges `quiesce` with an `ok` line. With
+//! signals, the checkpointed process is sent a signal, and the application
+//! acknowledges by creating the acknowledgement file, if one is configured.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Error, ErrorKind, Result, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use nix::{sys::signal::{kill, Signal}, unistd::Pid};
+
+use crate::pipeline::protobuf::invalid_data;
+
+/// Time allowed for the application to acknowledge, unless configured.
+pub const DEFAULT_QUIESCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MESSAGE_QUIESCE: &str = "quiesce";
+const MESSAGE_RESUMED: &str = "resumed";
+const MESSAGE_OK: &str = "ok";
+
+#[derive(Clone)]
+pub enum Notifier {
+    /// Unix socket on which the application listens.
+    Socket(PathBuf),
+    Signal {
+        quiesce: Signal,
+        resumed: Option<Signal>,
+        /// File that the application creates once it has quiesced.
+        ack_file: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone)]
+pub struct Quiesce {
+    pub notifier: Notifier,
+    pub timeout: Duration,
+    /// Whether the processes keep running after a successful dump, as with
+    /// `--leave-running`, so that the application is resumed after it.
+    pub leave_running: bool,
+}
+
+impl Quiesce {
+    /// Tell the application of process `pid` that a checkpoint is imminent,
+    /// and wait until it acknowledges.
+    pub fn quiesce(&self, pid: Option<i32>) -> Result<()> {
+        match &self.notifier {
+            Notifier::Socket(path) => {
+                let mut stream = self.connect(path)?;
+                stream.write_all(format!("{MESSAGE_QUIESCE}\n").as_bytes())?;
+                let mut reply = String::new();
+                BufReader::new(stream).read_line(&mut reply)?;
+                match reply.trim() {
+                    MESSAGE_OK => Ok(()),
+                    "" => Err(Error::new(ErrorKind::UnexpectedEof, "The application closed the connection")),
+                    reply => Err(invalid_data(format!("The application replied {reply:?}"))),
+                }
+            }
+            Notifier::Signal { quiesce, ack_file, .. } => {
+                if let Some(ack_file) = ack_file {
+                    // An acknowledgement of a previous checkpoint does not count.
+                    let _ = fs::remove_file(ack_file);
+                }
+                signal(pid, *quiesce)?;
+                match ack_file {
+                    Some(ack_file) => self.wait_for_file(ack_file),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Tell the application of process `pid` that it was resumed.
+    pub fn resumed(&self, pid: Option<i32>) -> Result<()> {
+        match &self.notifier {
+            Notifier::Socket(path) => self.connect(path)?.write_all(format!("{MESSAGE_RESUMED}\n").as_bytes()),
+            Notifier::Signal { resumed: Some(resumed), .. } => signal(pid, *resumed),
+            Notifier::Signal { resumed: None, .. } => Ok(()),
+        }
+    }
+
+    fn connect(&self, path: &Path) -> Result<UnixStream> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    fn wait_for_file(&self, path: &Path) -> Result<()> {
+        let start = Instant::now();
+        while !path.exists() {
+            if start.elapsed() > self.timeout {
+                return Err(Error::new(ErrorKind::TimedOut, format!("{:?} was not created", path)));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(path)
+    }
+}
+
+fn signal(pid: Option<i32>, signal: Signal) -> Result<()> {
+    let pid = pid.ok_or_else(|| Error::new(ErrorKind::NotFound, "The PID of the checkpointed process is unknown"))?;
+    kill(Pid::from_raw(pid), signal).map_err(Error::from)
+}
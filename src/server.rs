@@ -18,26 +18,57 @@
  */
 
 use std::{
-    collections::HashMap,
-    fs::{create_dir_all, File},
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    collections::{BTreeSet, HashMap},
+    fs::{create_dir_all, metadata, remove_dir_all, remove_file, rename, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
     path::Path,
     str::from_utf8,
     sync::{Arc, Mutex, Condvar},
-    thread, time::{Duration, Instant},
+    thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use json::JsonValue;
+use json::{object, JsonValue};
 use log::*;
 
+mod catalog;
+use catalog::{new_epoch_id, Catalog, CatalogEntry, Epoch};
 mod client_status;
 use client_status::ClientStatus;
-
-use crate::{constants::*, server::client_status::Operation};
+pub mod config;
+use config::{DependencyDiscovery, ServerConfig};
+mod group_operation;
+use group_operation::GroupOperation;
+mod manifest;
+mod restore_order;
+use restore_order::RestoreOrder;
+pub mod storage;
+use storage::{object_key, Storage};
+mod upload;
+use upload::UploadSession;
+
+use crate::constants::*;
+use crate::phase::{self, HookMode, Operation, Phase};
+use crate::images::{
+    connections::{shared_connections, Connection},
+    consistency::verify_tcp,
+};
+use crate::timeline::{self, Span, Timeline};
+use crate::pipeline::{
+    crypto::{self, HEADER_SIZE as ENCRYPTED_HEADER_SIZE},
+    digest::{ImageDigest, DIGEST_ALGORITHM},
+    frame::read_frame,
+    marker::{self, MarkerEvent, MarkerReader},
+};
 
 const BUFFER_SIZE: usize = 32768 * 4;
-const DEFAULT_IMAGES_DIR: &str = "/tmp/server-images";
+/// Directory below the images directory where uploads are staged
+/// until the checkpoint is committed to the storage.
+const STAGING_DIR: &str = ".incoming";
+/// Number of heartbeats that clients send within the heartbeat timeout.
+const HEARTBEATS_PER_TIMEOUT: u32 = 4;
+/// Time after which a data connection that uploads nothing is dropped.
+const UPLOAD_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub struct Server {
@@ -45,35 +76,100 @@ pub struct Server {
     pub port: u16,
     pub wait_timeout: u16,
     pub images_directory: String,
+    pub config: Arc<ServerConfig>,
+    pub storage: Arc<dyn Storage>,
+    pub catalog: Arc<Catalog>,
+    /// Global checkpoint that is in progress for each group.
+    pub epochs: Arc<Mutex<HashMap<String, Epoch>>>,
     pub clients: Arc<Mutex<HashMap<String, ClientStatus>>>,
     pub container_dependencies: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// TCP connections reported by the clients of each group.
+    pub connections: Arc<Mutex<HashMap<String, GroupConnections>>>,
+    pub uploads: Arc<Mutex<HashMap<String, UploadSession>>>,
+    /// Restore in progress for each group. The restore of the group is
+    /// aborted once a member has failed.
+    pub restores: Arc<Mutex<HashMap<String, GroupOperation>>>,
+    /// Dump in progress for each group. The processes of the group are
+    /// resumed after the dump if a member has failed, and killed otherwise.
+    pub dumps: Arc<Mutex<HashMap<String, GroupOperation>>>,
+    /// Ordered dependencies of the clients of each group, for restore.
+    pub restore_orders: Arc<Mutex<HashMap<String, RestoreOrder>>>,
+    /// Named barriers of each group in its current checkpoint epoch, which
+    /// are reset when the group starts its next dump.
+    pub barriers: Arc<Mutex<HashMap<String, GroupBarriers>>>,
+    /// Clients of each group that have resumed after a restore.
+    pub resumed: Arc<Mutex<HashMap<String, BTreeSet<String>>>>,
+    /// Timeline of the operation in progress for each group.
+    pub timelines: Arc<Mutex<HashMap<String, Timeline>>>,
     pub notifier: Arc<Condvar>,
 }
 
+/// TCP connections reported by the clients of a group, by client ID.
+type GroupConnections = HashMap<String, Vec<Connection>>;
+
+/// Named barriers of a group in a checkpoint epoch, with the clients that
+/// have reached and passed each of them.
+pub struct GroupBarriers {
+    epoch: u64,
+    reached: HashMap<String, BTreeSet<String>>,
+    passed: HashMap<String, BTreeSet<String>>,
+    /// Clients that have reached a barrier of the group in any epoch.
+    members: BTreeSet<String>,
+}
+
+/// Reasons why receiving an image file failed.
+enum UploadError {
+    /// The connection was lost. The partially received file is kept.
+    Interrupted(String),
+    /// The image file is invalid and must be sent again from the start.
+    Invalid(String),
+}
+
 /// Client message representing client ID, action, and dependencies.
+#[derive(Clone)]
 struct ClientMessage {
     id: String,
     action: String,
+    group: String,
     dependencies: Vec<String>,
     dependency_map: JsonValue, // This will store the raw dependencies for kubescr
+    params: JsonValue, // The whole message, for actions that take extra parameters
+    optional: BTreeSet<String>, // Dependencies whose absence does not fail the client
+    ordered: BTreeSet<String>, // Dependencies that must resume before the client is restored
+    quorum: Option<usize>, // Number of dependencies that are enough to proceed
+    timeout: u64, // Seconds the client waits for its dependencies at this action
+    deadline: Instant,
 }
 
 /// Start CRIU coordinator server
-pub fn run_server(address: &str, port: u16, wait_timeout: u16) {
-    let mut server = Server::new(address, port, wait_timeout);
+pub fn run_server(address: &str, port: u16, wait_timeout: u16, config: ServerConfig) {
+    let mut server = Server::new(address, port, wait_timeout, config);
     server.run();
 }
 
 impl Server {
     // Create a new instance of the Server struct.
-    pub fn new(address: &str, port: u16, wait_timeout: u16) -> Self {
+    pub fn new(address: &str, port: u16, wait_timeout: u16, config: ServerConfig) -> Self {
+        let storage = config.open_storage();
         Self {
             address: address.to_string(),
             port,
             wait_timeout,
-            images_directory: DEFAULT_IMAGES_DIR.to_string(),
+            images_directory: config.get_images_dir().to_string(),
+            catalog: Arc::new(Catalog::new(storage.clone())),
+            storage,
+            epochs: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(config),
             clients: Arc::new(Mutex::new(HashMap::new())),
             container_dependencies: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            restores: Arc::new(Mutex::new(HashMap::new())),
+            dumps: Arc::new(Mutex::new(HashMap::new())),
+            restore_orders: Arc::new(Mutex::new(HashMap::new())),
+            barriers: Arc::new(Mutex::new(HashMap::new())),
+            resumed: Arc::new(Mutex::new(HashMap::new())),
+            timelines: Arc::new(Mutex::new(HashMap::new())),
             notifier: Arc::new(Condvar::new()),
         }
     }
@@ -114,7 +210,7 @@ impl Server {
         info!("[>>] Receive client ID, action and dependencies");
 
         // Read the client message from the TCP stream.
-        let client_msg = match self.read_message(&tcp_stream) {
+        let mut client_msg = match self.read_message(&tcp_stream) {
             Some(msg) => msg,
             None => {
                 error!("[!!] Failed to read client message");
@@ -129,14 +225,50 @@ impl Server {
             client_msg.id,
             client_msg.dependencies.join(", ")
         );
+        self.discover_dependencies(&mut client_msg);
+        let start = timeline::now();
+
+        if let Err(reply) = self.enter_phase(&client_msg) {
+            error!("[{}] [!!] {}", client_msg.id, reply);
+            self.send_reply(&client_msg, &reply, &tcp_stream);
+            self.close_client_connection(&client_msg, tcp_stream);
+            return;
+        }
 
         match client_msg.action.as_str() {
             ACTION_ADD_DEPENDENCIES if client_msg.id == "kubescr" => {
                 self.handle_add_kubesrc_dependencies(&client_msg, &tcp_stream);
             }
-            ACTION_POST_DUMP => {
+            ACTION_POST_DUMP | ACTION_POST_STREAM => {
                 self.handle_post_dump(&client_msg, &tcp_stream);
             }
+            ACTION_UPLOAD_IMAGE => {
+                self.handle_upload_image(&client_msg, &tcp_stream);
+            }
+            ACTION_STREAM_IMAGE => {
+                self.handle_stream_image(&client_msg, &tcp_stream);
+            }
+            ACTION_CATALOG_LIST | ACTION_CATALOG_SHOW | ACTION_CATALOG_TAG | ACTION_CATALOG_DELETE | ACTION_CATALOG_TIMELINE => {
+                self.handle_catalog_request(&client_msg, &tcp_stream);
+            }
+            ACTION_PRE_RESTORE => {
+                self.handle_pre_restore(&client_msg, &tcp_stream);
+            }
+            ACTION_QUIESCE => {
+                self.handle_quiesce(&client_msg, &tcp_stream);
+            }
+            ACTION_BARRIER => {
+                self.handle_barrier(&client_msg, &tcp_stream);
+            }
+            ACTION_FREEZE => {
+                self.handle_freeze(&client_msg, &tcp_stream);
+            }
+            ACTION_HEARTBEAT => {
+                self.handle_heartbeat(&client_msg, &tcp_stream);
+            }
+            ACTION_ABORT => {
+                self.handle_abort(&client_msg, &tcp_stream);
+            }
             ACTION_NETWORK_LOCK => {
                 self.handle_network_lock(&client_msg, &tcp_stream);
             }
@@ -145,45 +277,124 @@ impl Server {
             }
             ACTION_POST_RESTORE | ACTION_POST_RESUME => {
                 info!("[{}] [==] {} action received", client_msg.id, client_msg.action);
+                if client_msg.action == ACTION_POST_RESUME {
+                    self.mark_resumed(&client_msg);
+                }
                 // For these actions, we just acknowledge.
-                self.send_response(&client_msg.id, MESSAGE_ACK, &tcp_stream);
+                self.send_reply(&client_msg, MESSAGE_ACK, &tcp_stream);
             }
-            _ => {
-                // Default logic for pre-dump, pre-restore, etc.
-                let mut response_message = self.get_response_message(&client_msg);
+            action if phase::hook_operation(action).is_some() => {
+                self.handle_hook(&client_msg, &tcp_stream);
+            }
+            ACTION_PRE_DUMP | ACTION_PRE_STREAM => {
+                let mut response_message = MESSAGE_ACK;
+                {
+                    // Waiting clients check the dump with the clients locked.
+                    let _clients = self.clients.lock().unwrap();
+                    let mut dumps = self.dumps.lock().unwrap();
+                    let dump = dumps.entry(client_msg.group.clone()).or_default();
+                    if dump.join(&client_msg.id) {
+                        // The dump starts a new checkpoint epoch of the group.
+                        if let Some(barriers) = self.barriers.lock().unwrap().get_mut(&client_msg.group) {
+                            barriers.epoch = dump.number;
+                            barriers.reached.clear();
+                            barriers.passed.clear();
+                        }
+                    }
+                }
                 if response_message == MESSAGE_ACK && !client_msg.dependencies.is_empty() && !self.wait_for_dependencies(&client_msg) {
-                    response_message = MESSAGE_TIMEOUT;
+                    response_message = self.wait_failure(&client_msg);
                 }
 
                 if response_message == MESSAGE_ACK {
                     if let Some(x) = self.clients.lock().unwrap().get_mut(&client_msg.id) {
                         info!("[{}] [==] Client is ready", client_msg.id);
-                        x.set_ready(true);
+                        x.set_phase(Phase::Ready);
                     }
                     self.notifier.notify_all();
                     if !client_msg.dependencies.is_empty() && !self.wait_for_dependencies_readiness(&client_msg) {
-                        response_message = MESSAGE_TIMEOUT;
+                        response_message = self.wait_failure(&client_msg);
                     }
                 }
-                self.send_response(&client_msg.id, response_message, &tcp_stream);
-                if client_msg.action == ACTION_PRE_STREAM && response_message == MESSAGE_ACK {
+                let is_streaming = client_msg.action == ACTION_PRE_STREAM && response_message == MESSAGE_ACK;
+                if is_streaming {
+                    // The streamer may start sending images as soon as it receives the ACK.
+                    self.start_upload_session(&client_msg);
+                }
+                self.send_reply(&client_msg, response_message, &tcp_stream);
+                if is_streaming {
                     if !self.wait_for_syn_response(&client_msg, &tcp_stream) {
+                        self.abandon_upload(&client_msg, None);
+                        self.record_span(&client_msg, start);
                         return;
                     } else {
                          if let Some(x) = self.clients.lock().unwrap().get_mut(&client_msg.id) {
-                            x.set_local_checkpoint();
+                            x.set_phase(Phase::Streaming);
                         }
                         self.notifier.notify_all();
-                        self.handle_pre_stream(&client_msg, &tcp_stream);
+                        self.send_response(&client_msg.id, MESSAGE_ACK, &tcp_stream);
+                        if !self.handle_pre_stream(&client_msg, &tcp_stream) {
+                            error!("[{}] [!!] Checkpoint transfer failed", client_msg.id);
+                        }
                     }
                 }
             }
+            _ => {
+                let reply = format!("{MESSAGE_INVALID_ACTION}: unknown action {}", client_msg.action);
+                error!("[{}] [!!] {}", client_msg.id, reply);
+                self.send_reply(&client_msg, &reply, &tcp_stream);
+            }
         }
 
         // Close TCP connection with client
+        self.record_span(&client_msg, start);
         self.close_client_connection(&client_msg, tcp_stream);
     }
 
+    /// Add the span of the action of a client to the timeline of its group.
+    /// Once every client of the timeline has finished its operation, the
+    /// timeline is stored. A client that starts a new operation while still
+    /// in the timeline ends the previous one, which did not complete.
+    fn record_span(&self, msg: &ClientMessage, start: u64) {
+        let action = msg.action.as_str();
+        if matches!(action, ACTION_ADD_DEPENDENCIES | ACTION_UPLOAD_IMAGE | ACTION_HEARTBEAT | ACTION_ABORT | ACTION_BARRIER)
+            || matches!(action, ACTION_CATALOG_LIST | ACTION_CATALOG_SHOW | ACTION_CATALOG_TAG | ACTION_CATALOG_DELETE | ACTION_CATALOG_TIMELINE)
+        {
+            return;
+        }
+        let is_final = |action: &str| matches!(action, ACTION_POST_DUMP | ACTION_POST_STREAM | ACTION_POST_RESUME);
+
+        let mut timelines = self.timelines.lock().unwrap();
+        let mut finished = Vec::new();
+        if matches!(action, ACTION_PRE_DUMP | ACTION_PRE_STREAM | ACTION_PRE_RESTORE)
+            && timelines.get(&msg.group).is_some_and(|timeline| timeline.clients().contains(msg.id.as_str()))
+        {
+            finished.push(timelines.remove(&msg.group).unwrap());
+        }
+        let timeline = timelines.entry(msg.group.clone()).or_insert_with(|| Timeline::new(&new_epoch_id(), &msg.group));
+        timeline.spans.push(Span { client: msg.id.clone(), action: action.to_string(), start, end: Some(timeline::now()) });
+        let is_complete = timeline.clients().iter()
+            .all(|client| timeline.spans.iter().any(|span| span.client == *client && is_final(&span.action)));
+        if is_complete {
+            finished.push(timelines.remove(&msg.group).unwrap());
+        }
+        drop(timelines);
+
+        for timeline in finished {
+            self.store_timeline(&timeline);
+        }
+    }
+
+    fn store_timeline(&self, timeline: &Timeline) {
+        info!("[==] Timeline {} of group {}: {}", timeline.id, timeline.group, timeline.critical_path());
+        if let Some(skew) = timeline.freeze_skew() {
+            info!("[==] Group {} froze within {} us", timeline.group, skew);
+        }
+        if let Err(e) = self.catalog.add_timeline(timeline) {
+            error!("[!!] Failed to store timeline {}: {}", timeline.id, e);
+        }
+    }
+
     fn read_message(&self, tcp_stream: &Arc<Mutex<TcpStream>>) -> Option<ClientMessage> {
         let mut buffer = [0; 32768 * 4];
         let message_data = match tcp_stream.lock().unwrap().read(&mut buffer) {
@@ -211,7 +422,14 @@ impl Server {
         };
 
         let client_id = message_data["id"].to_string();
+        // The ID names the staging directory of the client.
+        if client_id.contains('/') || storage::check_key(&client_id).is_err() {
+            error!("[!!] Invalid client ID {:?}", client_id);
+            self.send_response(&client_id, MESSAGE_INVALID_ID, tcp_stream);
+            return None;
+        }
         let client_action = message_data["action"].to_string();
+        let client_group = message_data["group"].as_str().unwrap_or(DEFAULT_GROUP).to_string();
         let dependencies_json = &message_data["dependencies"];
 
         let mut dependencies: Vec<String> = Vec::new();
@@ -242,113 +460,186 @@ impl Server {
             }
         }
 
+        // A dependency marked with a trailing '?' is optional, and one marked
+        // with a leading '^' is restored before the client.
+        let mut optional = BTreeSet::new();
+        let mut ordered = BTreeSet::new();
+        for dependency in dependencies.iter_mut() {
+            if let Some(name) = dependency.strip_suffix(OPTIONAL_DEPENDENCY_SUFFIX) {
+                *dependency = name.to_string();
+                optional.insert(dependency.clone());
+            }
+            if let Some(name) = dependency.strip_prefix(ORDERED_DEPENDENCY_PREFIX) {
+                if optional.remove(dependency.as_str()) {
+                    optional.insert(name.to_string());
+                }
+                *dependency = name.to_string();
+                ordered.insert(dependency.clone());
+            }
+        }
+        let quorum = message_data["quorum"].as_usize();
+
+        // A timeout requested by the client takes precedence over the server config.
+        let timeout = message_data["timeouts"][client_action.as_str()].as_u64()
+            .or_else(|| self.config.get_timeout(&client_group, &client_action))
+            .unwrap_or(self.wait_timeout as u64);
+
         let client_msg = ClientMessage {
             id: client_id,
             action: client_action,
+            group: client_group,
             dependencies,
             dependency_map,
+            params: message_data,
+            optional,
+            ordered,
+            quorum,
+            timeout,
+            deadline: Instant::now() + Duration::from_secs(timeout),
         };
         Some(client_msg)
     }
 
-    /// Wait for all dependencies to reach a certain state.
+    /// Record the TCP connections reported by a client and find the other
+    /// clients of its group that share a connection with it, but are not
+    /// its dependencies. Depending on the server config, they are reported
+    /// or added to the dependencies of the client.
+    fn discover_dependencies(&self, msg: &mut ClientMessage) {
+        let discovery = self.config.get_dependency_discovery();
+        if discovery == DependencyDiscovery::Off {
+            return;
+        }
+
+        // Missing dependencies are logged when connections are reported,
+        // usually before and after the dump.
+        let reported = msg.params["connections"].is_array();
+        let mut groups = self.connections.lock().unwrap();
+        let group = groups.entry(msg.group.clone()).or_default();
+        if reported {
+            let connections: Vec<Connection> = msg.params["connections"].members().filter_map(Connection::from_json).collect();
+            info!("[{}] [>>] {} established TCP connections", msg.id, connections.len());
+            group.insert(msg.id.clone(), connections);
+        }
+        let own = match group.get(&msg.id) {
+            Some(own) => own,
+            None => return,
+        };
+
+        let mut peers: Vec<_> = group.iter()
+            .filter(|(peer, _)| **peer != msg.id && !msg.dependencies.contains(peer))
+            .filter_map(|(peer, connections)| shared_connections(own, connections).next().map(|shared| (peer.clone(), *shared)))
+            .collect();
+        peers.sort();
+        for (peer, shared) in peers {
+            if reported {
+                warn!("[{}] [!!] Connection {} is shared with {}, which is not a dependency", msg.id, shared, peer);
+            }
+            if discovery == DependencyDiscovery::Add {
+                if reported {
+                    info!("[{}] [==] Adding dependency {}", msg.id, peer);
+                }
+                msg.dependencies.push(peer);
+            }
+        }
+    }
+
+    /// Wait for all dependencies to reach a certain state. Optional
+    /// dependencies are only waited for once they have connected. With a
+    /// quorum, the wait ends as soon as that many dependencies have reached
+    /// the state. Dependencies left behind are recorded as excluded.
     fn wait_for_dependencies_state<F>(&self, msg: &ClientMessage, check_state: F, state_name: &str) -> bool
         where
-            F: Fn(&ClientStatus) -> bool,
+            F: Fn(Option<&ClientStatus>) -> bool,
+    {
+        self.wait_for_dependencies_where(msg, |dependency, clients| check_state(clients.get(dependency)), state_name)
+    }
+
+    /// Wait until `check` holds for all dependencies, given the state of the
+    /// clients, as in `wait_for_dependencies_state`. The state that `check`
+    /// reads is updated with the clients locked.
+    fn wait_for_dependencies_where<F>(&self, msg: &ClientMessage, check: F, state_name: &str) -> bool
+        where
+            F: Fn(&str, &HashMap<String, ClientStatus>) -> bool,
     {
         info!("[{}] [==] Waiting for all dependencies to be {}", msg.id, state_name);
-        let timeout_duration = Duration::from_secs(self.wait_timeout as u64);
-        let start_time = Instant::now();
 
-        for dependency in msg.dependencies.iter() {
-            if dependency.is_empty() {
-                continue;
+        let dependencies: Vec<&String> = msg.dependencies.iter()
+            .filter(|dependency| !dependency.is_empty())
+            .filter(|dependency| !msg.is_restored_after(dependency))
+            .collect();
+        let pending = |clients: &HashMap<String, ClientStatus>| -> Vec<String> {
+            dependencies.iter()
+                .filter(|dependency| !msg.optional.contains(**dependency) || clients.contains_key(**dependency))
+                .filter(|dependency| !check(dependency, clients))
+                .map(|dependency| dependency.to_string())
+                .collect()
+        };
+        let quorum_met = |clients: &HashMap<String, ClientStatus>| {
+            msg.quorum.is_some_and(|quorum| {
+                dependencies.iter().filter(|dependency| check(dependency, clients)).count() >= quorum
+            })
+        };
+
+        let clients_lock = self.clients.lock().unwrap();
+        let result = self.notifier.wait_timeout_while(
+            clients_lock,
+            msg.deadline.saturating_duration_since(Instant::now()),
+            |clients| {
+                // Lost dependencies will never reach the state.
+                pending(clients).iter().any(|dependency| clients.get(dependency).is_none_or(ClientStatus::is_connected))
+                    && !quorum_met(clients)
+                    && !self.is_restore_aborted(msg)
             }
-            info!("[{}] [==] Checking {} status of dependency: {}", msg.id, state_name, dependency);
+        );
 
-            let clients_lock = self.clients.lock().unwrap();
+        let (pending, quorum_met) = match result {
+            Ok((clients_lock, _)) => (pending(&clients_lock), quorum_met(&clients_lock)),
+            Err(_) => {
+                error!("[{}] [!!] Error waiting for dependencies to be {}", msg.id, state_name);
+                return false;
+            }
+        };
+        if pending.is_empty() {
+            info!("[{}] [==] All dependencies are {}", msg.id, state_name);
+            return true;
+        }
+        if self.is_restore_aborted(msg) {
+            return false;
+        }
 
-            let result = self.notifier.wait_timeout_while(
-                clients_lock,
-                timeout_duration.saturating_sub(start_time.elapsed()),
-                |clients| {
-                    !clients.get(dependency).is_some_and(&check_state)
-                }
+        let required: Vec<&String> = pending.iter()
+            .filter(|dependency| !msg.optional.contains(*dependency))
+            .collect();
+        if !required.is_empty() && !quorum_met {
+            error!(
+                "[{}] [!!] Dependencies {} are not {}",
+                msg.id, required.iter().map(|d| d.as_str()).collect::<Vec<_>>().join(", "), state_name
             );
-
-            match result {
-                Ok((clients_lock, timeout_result)) => {
-                    if timeout_result.timed_out() {
-                        error!("[{}] [!!] Timeout waiting for dependency {} to be {}", msg.id, dependency, state_name);
-                        return false;
-                    }
-                    if clients_lock.get(dependency).is_some_and(&check_state) {
-                        info!("[{}] [==] Dependency {} is {}", msg.id, dependency, state_name);
-                    }
-                }
-                Err(_) => {
-                    error!("[{}] [!!] Error waiting for dependency {} to be {}", msg.id, dependency, state_name);
-                    return false;
-                }
-            }
+            return false;
         }
+
+        warn!("[{}] [!!] Proceeding without dependencies {}, which are not {}", msg.id, pending.join(", "), state_name);
+        self.exclude_dependencies(msg, &pending);
         true
     }
 
+    /// Record in the timeline of the group that `dependencies` were left
+    /// behind by a member of the group.
+    fn exclude_dependencies(&self, msg: &ClientMessage, dependencies: &[String]) {
+        let mut timelines = self.timelines.lock().unwrap();
+        if let Some(timeline) = timelines.get_mut(&msg.group) {
+            timeline.excluded.extend(dependencies.iter().cloned());
+        }
+    }
+
     /// Wait for all dependencies to connect.
     /// Returns true if all dependencies are connected, false if timeout occurs.
     fn wait_for_dependencies(&self, msg: &ClientMessage) -> bool {
-        let timeout_duration = Duration::from_secs(self.wait_timeout as u64);
-        let start_time = Instant::now();
-
-        for dependency in msg.dependencies.iter() {
-            if dependency.is_empty() {
-                continue;
-            }
-            info!(
-                "[{}] [==] Checking connection of dependency: {}",
-                msg.id, dependency
-            );
-
-            let clients_lock = self.clients.lock().unwrap();
-
-            let result = self.notifier.wait_timeout_while(
-                clients_lock,
-                timeout_duration.saturating_sub(start_time.elapsed()),
-                |clients| {
-                    !clients.get(dependency).is_some_and(|status| status.is_connected())
-                }
-            );
-
-            match result {
-                Ok((clients_lock, timeout_result)) => {
-                    if timeout_result.timed_out() {
-                        error!(
-                            "[{}] [!!] Timeout waiting for dependency {} to connect",
-                            msg.id, dependency
-                        );
-                        return false;
-                    }
-                    if let Some(status) = clients_lock.get(dependency) {
-                        if status.is_connected() {
-                            info!("[{}] [==] Dependency {} connected", msg.id, dependency);
-                        }
-                    }
-                }
-                Err(_) => {
-                    error!(
-                        "[{}] [!!] Error waiting for dependency {} to connect",
-                        msg.id, dependency
-                    );
-                    return false;
-                }
-            }
-        }
-        true
+        self.wait_for_dependencies_state(msg, |s| s.is_some_and(ClientStatus::is_connected), "connected")
     }
 
     fn wait_for_dependencies_readiness(&self, msg: &ClientMessage) -> bool {
-        self.wait_for_dependencies_state(msg, |s| s.is_ready(), "ready")
+        self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.has_reached(Phase::Ready)), "ready")
     }
 
     /// Handle adding dependencies for kubesrc client
@@ -382,50 +673,465 @@ impl Server {
         self.close_client_connection(msg, tcp_stream.clone());
     }
 
-    fn handle_network_lock(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
-        let mut response_message = self.get_response_message(msg);
-        if response_message != MESSAGE_ACK {
-            self.send_response(&msg.id, response_message, tcp_stream);
-            self.close_client_connection(msg, tcp_stream.clone());
-            return;
+    /// Handle pre-restore action. The client reports the problems found by
+    /// checking its images against the host. If any client of the restore
+    /// has problems, the other clients of the restore are aborted.
+    fn handle_pre_restore(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        let mut problems: Vec<String> = msg.params["problems"].members().filter_map(JsonValue::as_str).map(str::to_string).collect();
+        let mut response_message = MESSAGE_ACK;
+        {
+            // Waiting clients check the restore with the clients locked.
+            let _clients = self.clients.lock().unwrap();
+            if self.restores.lock().unwrap().entry(msg.group.clone()).or_default().join(&msg.id) {
+                // The order and the resumed clients of the previous restore
+                // do not apply to this one.
+                self.resumed.lock().unwrap().remove(&msg.group);
+                self.restore_orders.lock().unwrap().remove(&msg.group);
+            }
+        }
+        let cycle = self.set_restore_order(msg).err();
+        if let Some(cycle) = &cycle {
+            problems.push(format!("Configuration error: ordered dependencies form a cycle {}", cycle.join(" -> ")));
+        }
+        if !problems.is_empty() {
+            for problem in problems.iter() {
+                error!("[{}] [!!] {}", msg.id, problem);
+            }
+            let _clients = self.clients.lock().unwrap();
+            self.restores.lock().unwrap().entry(msg.group.clone()).or_default().fail(&msg.id);
         }
+        self.notifier.notify_all();
 
-        if !self.wait_for_dependencies(msg) {
-            response_message = MESSAGE_TIMEOUT;
+        if response_message == MESSAGE_ACK && !msg.dependencies.is_empty() && !self.wait_for_dependencies(msg) {
+            response_message = self.wait_failure(msg);
+        }
+        if response_message == MESSAGE_ACK && !self.is_restore_aborted(msg) {
+            if let Some(x) = self.clients.lock().unwrap().get_mut(&msg.id) {
+                info!("[{}] [==] Client is ready", msg.id);
+                x.set_phase(Phase::Ready);
+            }
+            self.notifier.notify_all();
+            if !msg.dependencies.is_empty() && !self.wait_for_dependencies_readiness(msg) {
+                response_message = self.wait_failure(msg);
+            }
+        }
+        if response_message == MESSAGE_ACK && !msg.ordered.is_empty() && !self.wait_for_ordered_dependencies(msg) {
+            response_message = self.wait_failure(msg);
         }
 
-        if response_message != MESSAGE_ACK {
-            self.send_response(&msg.id, response_message, tcp_stream);
-            self.close_client_connection(msg, tcp_stream.clone());
+        {
+            let _clients = self.clients.lock().unwrap();
+            let mut restores = self.restores.lock().unwrap();
+            let restore = restores.entry(msg.group.clone()).or_default();
+            if !restore.failed.is_empty() {
+                error!(
+                    "[{}] [!!] Restore of group {} aborted, {} failed",
+                    msg.id, msg.group, restore.failed.iter().cloned().collect::<Vec<_>>().join(", ")
+                );
+                response_message = MESSAGE_RESTORE_ABORTED;
+            }
+            if cycle.is_some() {
+                response_message = MESSAGE_DEPENDENCY_CYCLE;
+            }
+            // The client does not restore its processes.
+            if response_message != MESSAGE_ACK {
+                restore.finish(&msg.id);
+            }
+        }
+        self.send_reply(msg, response_message, tcp_stream);
+    }
+
+    /// Record the ordered dependencies of the client and log the stage in
+    /// which it is restored. Returns the cycle if the ordered dependencies
+    /// of the group form one.
+    fn set_restore_order(&self, msg: &ClientMessage) -> Result<(), Vec<String>> {
+        let mut orders = self.restore_orders.lock().unwrap();
+        let order = orders.entry(msg.group.clone()).or_default();
+        order.set(&msg.id, msg.ordered.clone());
+        let stages = order.stages()?;
+        if !msg.ordered.is_empty() {
+            info!(
+                "[{}] [==] Restored in stage {} of {} in group {}",
+                msg.id, RestoreOrder::stage_of(&stages, &msg.id), stages.len(), msg.group
+            );
+        }
+        Ok(())
+    }
+
+    /// Wait until the ordered dependencies of the client have been restored
+    /// and resumed. Optional dependencies are only waited for once they have
+    /// connected.
+    fn wait_for_ordered_dependencies(&self, msg: &ClientMessage) -> bool {
+        info!(
+            "[{}] [==] Waiting for {} to resume",
+            msg.id, msg.ordered.iter().cloned().collect::<Vec<_>>().join(", ")
+        );
+        let pending = |clients: &HashMap<String, ClientStatus>| -> Vec<String> {
+            let resumed = self.resumed.lock().unwrap();
+            msg.ordered.iter()
+                .filter(|dependency| !resumed.get(&msg.group).is_some_and(|resumed| resumed.contains(*dependency)))
+                .filter(|dependency| !msg.optional.contains(*dependency) || clients.contains_key(*dependency))
+                .cloned()
+                .collect()
+        };
+
+        let clients_lock = self.clients.lock().unwrap();
+        let result = self.notifier.wait_timeout_while(
+            clients_lock,
+            msg.deadline.saturating_duration_since(Instant::now()),
+            |clients| {
+                !pending(clients).is_empty()
+                    && !self.is_restore_aborted(msg)
+                    && lost_dependency(msg, clients).is_none()
+            }
+        );
+        match result {
+            Ok((clients_lock, _)) => {
+                let pending = pending(&clients_lock);
+                if pending.is_empty() {
+                    info!("[{}] [==] Ordered dependencies have resumed", msg.id);
+                    return true;
+                }
+                if !self.is_restore_aborted(msg) {
+                    error!("[{}] [!!] Dependencies {} have not resumed", msg.id, pending.join(", "));
+                }
+                false
+            }
+            Err(_) => {
+                error!("[{}] [!!] Error waiting for ordered dependencies to resume", msg.id);
+                false
+            }
+        }
+    }
+
+    /// Release the clients that are restored after `msg`.
+    fn mark_resumed(&self, msg: &ClientMessage) {
+        {
+            // Waiting clients check the resumed clients with the clients locked.
+            let _clients = self.clients.lock().unwrap();
+            self.resumed.lock().unwrap().entry(msg.group.clone()).or_default().insert(msg.id.clone());
+            self.restores.lock().unwrap().entry(msg.group.clone()).or_default().finish(&msg.id);
+        }
+        self.notifier.notify_all();
+    }
+
+    /// Whether `msg` is part of a restore that has been aborted.
+    fn is_restore_aborted(&self, msg: &ClientMessage) -> bool {
+        msg.action == ACTION_PRE_RESTORE
+            && self.restores.lock().unwrap().get(&msg.group).is_some_and(|restore| !restore.failed.is_empty())
+    }
+
+    /// Reply to a client whose wait for its dependencies failed. The client
+    /// exits with a failure, so a dump fails.
+    fn wait_failure(&self, msg: &ClientMessage) -> &'static str {
+        let clients = self.clients.lock().unwrap();
+        let is_dump = clients.get(&msg.id).is_some_and(|status| status.get_operation() == Operation::Dump);
+        if is_dump && phase::is_phase_action(&msg.action) {
+            self.fail_dump(msg);
+        }
+        match lost_dependency(msg, &clients) {
+            Some(_) => MESSAGE_DEPENDENCY_LOST,
+            None => MESSAGE_TIMEOUT,
+        }
+    }
+
+    /// Record that the dump of a client failed, so that the processes of its
+    /// group are resumed after the dump. Called with the clients locked.
+    fn fail_dump(&self, msg: &ClientMessage) {
+        self.dumps.lock().unwrap().entry(msg.group.clone()).or_default().fail(&msg.id);
+    }
+
+    /// Handle heartbeat action. The client keeps the connection open for the
+    /// rest of its operation and sends a byte at the interval given in the
+    /// reply. If the heartbeats stop, because the client or its host died,
+    /// the client is marked disconnected and its operation fails.
+    fn handle_heartbeat(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        let started = match self.clients.lock().unwrap().get(&msg.id) {
+            Some(status) => status.get_started(),
+            None => {
+                self.send_response(&msg.id, MESSAGE_NOT_CONNECTED, tcp_stream);
+                return;
+            }
+        };
+        let timeout = Duration::from_secs(self.config.get_heartbeat_timeout());
+        let interval = (timeout / HEARTBEATS_PER_TIMEOUT).as_millis() as u64;
+        self.send_response(&msg.id, &object!{ interval: interval }.dump(), tcp_stream);
+
+        let mut stream = match tcp_stream.lock().unwrap().try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("[{}] [!!] Failed to watch heartbeats: {}", msg.id, e);
+                return;
+            }
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(interval.max(1))));
+        let mut last_seen = Instant::now();
+        let mut buffer = [0; 64];
+        loop {
+            let closed = match stream.read(&mut buffer) {
+                Ok(0) => true,
+                Ok(_) => {
+                    last_seen = Instant::now();
+                    false
+                }
+                Err(e) => !matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut),
+            };
+            // Heartbeats end with the operation, or when the client starts a new one.
+            if self.clients.lock().unwrap().get(&msg.id).is_none_or(|status| status.get_started() != started) {
+                return;
+            }
+            if closed || last_seen.elapsed() > timeout {
+                break;
+            }
+        }
+
+        error!("[{}] [!!] Client stopped sending heartbeats", msg.id);
+        self.fail_client(msg);
+    }
+
+    /// Handle abort action, sent by a client whose action script failed.
+    /// A client that failed the action that starts an operation is failed
+    /// in that operation.
+    fn handle_abort(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        let failed_action = msg.params["failed-action"].as_str().unwrap_or_default();
+        error!("[{}] [!!] {} failed: {}", msg.id, failed_action, msg.params["reason"]);
+        {
+            let mut clients = self.clients.lock().unwrap();
+            if let Some(operation) = phase::starts_operation(failed_action) {
+                clients.insert(msg.id.clone(), ClientStatus::new(operation));
+            }
+            if !clients.contains_key(&msg.id) {
+                drop(clients);
+                self.send_response(&msg.id, MESSAGE_NOT_CONNECTED, tcp_stream);
+                return;
+            }
+        }
+        self.fail_client(msg);
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
+    }
+
+    /// Mark the client as lost, so that the clients that wait for it fail
+    /// instead of waiting out their timeout. The dump of the client is
+    /// missing from its epoch, and its restore aborts the group.
+    fn fail_client(&self, msg: &ClientMessage) {
+        let operation = match self.clients.lock().unwrap().get_mut(&msg.id) {
+            Some(status) => {
+                status.set_disconnected();
+                status.get_operation()
+            }
+            None => return,
+        };
+        match operation {
+            Operation::Dump => {
+                {
+                    let _clients = self.clients.lock().unwrap();
+                    self.fail_dump(msg);
+                }
+                // A member that has already stored its images stays in the epoch.
+                let epoch = self.epochs.lock().unwrap().get(&msg.group)
+                    .filter(|epoch| epoch.members.get(&msg.id) == Some(&None))
+                    .map(|epoch| epoch.id.clone());
+                if let Some(epoch) = epoch {
+                    self.finish_epoch_member(&msg.group, &epoch, &msg.id, None);
+                }
+            }
+            Operation::Restore => {
+                let _clients = self.clients.lock().unwrap();
+                self.restores.lock().unwrap().entry(msg.group.clone()).or_default().fail(&msg.id);
+            }
+        }
+        self.notifier.notify_all();
+    }
+
+    /// Handle quiesce action. The client has told its application that a
+    /// checkpoint is imminent, and is acknowledged once all dependencies
+    /// have quiesced too, so that no application is frozen while the others
+    /// still accept work.
+    fn handle_quiesce(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        info!("[{}] [==] Client has quiesced", msg.id);
+        let mut response_message = MESSAGE_ACK;
+        if !self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.has_reached(Phase::Quiesced)), "quiesced") {
+            response_message = self.wait_failure(msg);
+        }
+        self.send_reply(msg, response_message, tcp_stream);
+    }
+
+    /// Handle barrier action. The client waits until its dependencies have
+    /// reached the barrier in the current checkpoint epoch of the group, so
+    /// that the whole group passes the barrier together. A client that
+    /// reaches the barrier again after passing it starts a new round of it.
+    fn handle_barrier(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        let name = match msg.params["name"].as_str() {
+            Some(name) if !name.is_empty() => name,
+            _ => {
+                self.send_reply(msg, &format!("{MESSAGE_INVALID_ACTION}: barrier without a name"), tcp_stream);
+                return;
+            }
+        };
+        let (epoch, mut members) = {
+            // Waiting clients read the barriers with the clients locked.
+            let _clients = self.clients.lock().unwrap();
+            let mut members = BTreeSet::new();
+            let epoch = match self.dumps.lock().unwrap().get(&msg.group) {
+                Some(dump) => {
+                    members.extend(dump.members.iter().cloned());
+                    dump.number
+                }
+                None => 0,
+            };
+            if let Some(restore) = self.restores.lock().unwrap().get(&msg.group) {
+                members.extend(restore.members.iter().cloned());
+            }
+            let mut barriers = self.barriers.lock().unwrap();
+            let barriers = barriers.entry(msg.group.clone())
+                .or_insert_with(|| GroupBarriers { epoch, reached: HashMap::new(), passed: HashMap::new(), members: BTreeSet::new() });
+            let reached = barriers.reached.entry(name.to_string()).or_default();
+            let passed = barriers.passed.entry(name.to_string()).or_default();
+            if passed.contains(&msg.id) {
+                // The client reaches the barrier again, e.g. after a restart,
+                // so the clients that have passed it have to reach it again.
+                reached.retain(|id| !passed.contains(id));
+                passed.clear();
+            }
+            reached.insert(msg.id.clone());
+            barriers.members.insert(msg.id.clone());
+            members.extend(barriers.members.iter().cloned());
+            (barriers.epoch, members)
+        };
+        self.notifier.notify_all();
+        info!("[{}] [==] Reached barrier {} in epoch {}", msg.id, name, epoch);
+
+        let has_reached = |dependency: &str, _: &HashMap<String, ClientStatus>| {
+            self.barriers.lock().unwrap().get(&msg.group)
+                .filter(|barriers| barriers.epoch == epoch)
+                .and_then(|barriers| barriers.reached.get(name))
+                .is_some_and(|reached| reached.contains(dependency))
+        };
+        // Without dependencies, the client waits for the members of the
+        // group known to the server: the clients of its last dump and
+        // restore, and the clients that have reached its barriers.
+        members.remove(&msg.id);
+        let msg = &ClientMessage {
+            dependencies: match msg.dependencies.is_empty() {
+                true => members.into_iter().collect(),
+                false => msg.dependencies.clone(),
+            },
+            ..msg.clone()
+        };
+        let passed = self.wait_for_dependencies_where(msg, has_reached, &format!("at barrier {name}"));
+        {
+            let _clients = self.clients.lock().unwrap();
+            if let Some(barriers) = self.barriers.lock().unwrap().get_mut(&msg.group).filter(|barriers| barriers.epoch == epoch) {
+                if passed {
+                    barriers.passed.entry(name.to_string()).or_default().insert(msg.id.clone());
+                } else if let Some(reached) = barriers.reached.get_mut(name) {
+                    // The client has not passed the barrier, so its
+                    // dependencies do not pass it without the client either.
+                    reached.remove(&msg.id);
+                }
+            }
+        }
+        let response_message = match passed {
+            true => MESSAGE_ACK,
+            false => self.wait_failure(msg),
+        };
+        self.send_reply(msg, response_message, tcp_stream);
+    }
+
+    /// Handle freeze action. Once all dependencies have reached the freeze
+    /// barrier, the client is told to freeze its cgroup. It replies with the
+    /// time at which it froze, and is acknowledged once all dependencies are
+    /// frozen too, so the dump only proceeds when the whole group is stopped.
+    fn handle_freeze(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        if !self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.has_reached(Phase::Freezing)), "at the freeze barrier") {
+            self.send_response(&msg.id, self.wait_failure(msg), tcp_stream);
             return;
         }
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
 
-        if let Some(x) = self.clients.lock().unwrap().get_mut(&msg.id) {
-            info!("[{}] [==] Client network is locked", msg.id);
-            x.set_network_locked();
+        let mut buffer = [0; BUFFER_SIZE];
+        let reply = match tcp_stream.lock().unwrap().read(&mut buffer) {
+            Ok(size) => from_utf8(&buffer[..size]).ok().and_then(|text| json::parse(text).ok()),
+            Err(_) => None,
+        };
+        let frozen_at = match reply.as_ref().and_then(|reply| reply["frozen_at"].as_u64()) {
+            Some(frozen_at) => frozen_at,
+            None => {
+                error!("[{}] [!!] Client failed to freeze", msg.id);
+                return;
+            }
+        };
+        if let Some(status) = self.clients.lock().unwrap().get_mut(&msg.id) {
+            info!("[{}] [==] Client is frozen", msg.id);
+            status.set_phase(Phase::Frozen);
+            status.set_frozen_at(frozen_at);
         }
+        self.timelines.lock().unwrap()
+            .entry(msg.group.clone())
+            .or_insert_with(|| Timeline::new(&new_epoch_id(), &msg.group))
+            .frozen.insert(msg.id.clone(), frozen_at);
         self.notifier.notify_all();
 
-        if !self.wait_for_dependencies_state(msg, |s| s.is_network_locked(), "network locked") {
-            response_message = MESSAGE_TIMEOUT;
+        if !self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.get_frozen_at().is_some()), "frozen") {
+            self.send_response(&msg.id, self.wait_failure(msg), tcp_stream);
+            return;
         }
 
-        self.send_response(&msg.id, response_message, tcp_stream);
+        // Skew between the first and the last freeze, as reported by the clients.
+        let times: Vec<u64> = {
+            let clients = self.clients.lock().unwrap();
+            std::iter::once(&msg.id).chain(msg.dependencies.iter())
+                .filter_map(|id| clients.get(id).and_then(ClientStatus::get_frozen_at))
+                .collect()
+        };
+        let skew = times.iter().max().unwrap_or(&0) - times.iter().min().unwrap_or(&0);
+        info!("[{}] [==] Frozen with its dependencies within {} us", msg.id, skew);
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
+    }
+
+    fn handle_network_lock(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        info!("[{}] [==] Client network is locked", msg.id);
+        let mut response_message = MESSAGE_ACK;
+        if !self.wait_for_dependencies(msg)
+            || !self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.has_reached(Phase::NetworkLocked)), "network locked")
+        {
+            response_message = self.wait_failure(msg);
+        }
+
+        self.send_reply(msg, response_message, tcp_stream);
     }
 
     fn handle_network_unlock(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
-        if let Some(x) = self.clients.lock().unwrap().get_mut(&msg.id) {
-            info!("[{}] [==] Client network is unlocked", msg.id);
-            x.set_network_unlocked();
+        info!("[{}] [==] Client network is unlocked", msg.id);
+        let mut response_message = MESSAGE_ACK;
+        if !self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.has_reached(Phase::NetworkUnlocked)), "network unlocked") {
+            response_message = self.wait_failure(msg);
+        }
+
+        self.send_reply(msg, response_message, tcp_stream);
+    }
+
+    /// Handle one of the other hooks of CRIU. The client tells how the hook
+    /// is coordinated: at a barrier, it waits until its dependencies have
+    /// reached the same hook, and otherwise it is acknowledged right away.
+    fn handle_hook(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        let mode = msg.params["hook"].as_str()
+            .and_then(HookMode::parse)
+            .or_else(|| phase::default_hook_mode(&msg.action))
+            .unwrap_or(HookMode::Notify);
+        if let Some(status) = self.clients.lock().unwrap().get_mut(&msg.id) {
+            status.set_reached_hook(&msg.action);
         }
         self.notifier.notify_all();
+        info!("[{}] [==] Reached {} ({})", msg.id, msg.action, mode.as_str());
 
         let mut response_message = MESSAGE_ACK;
-        if !self.wait_for_dependencies_state(msg, |s| s.is_network_unlocked(), "network unlocked") {
-            response_message = MESSAGE_TIMEOUT;
+        if mode == HookMode::Barrier
+            && !self.wait_for_dependencies_state(msg, |s| s.is_some_and(|s| s.has_reached_hook(&msg.action)), &format!("at {}", msg.action))
+        {
+            response_message = self.wait_failure(msg);
         }
-
-        self.send_response(&msg.id, response_message, tcp_stream);
+        self.send_reply(msg, response_message, tcp_stream);
     }
 
     /// Handle post-dump action
@@ -437,153 +1143,647 @@ impl Server {
 
         let mut response_message = MESSAGE_ACK;
 
+        // Wait until all dependencies have also completed their local checkpoint.
+        // In post-dump phase, a dependency that is not found might have
+        // already completed and been removed, so we assume it has completed.
+        if !self.wait_for_dependencies_state(
+                msg,
+                |s| s.is_none_or(|s| s.has_reached(Phase::Streaming)),
+                "done with their local checkpoint",
+            )
         {
-            let mut clients_lock = self.clients.lock().unwrap();
-            if let Some(status) = clients_lock.get_mut(&msg.id) {
-                if status.has_local_checkpoint() {
-                    response_message = MESSAGE_CHECKPOINT_EXISTS;
-                } else {
-                    status.set_local_checkpoint();
-                }
-            } else {
-                error!(
-                    "[!!] Client {} not found in clients_set during post-dump",
-                    msg.id
-                );
-                response_message = MESSAGE_NOT_CONNECTED;
-            }
+            response_message = self.wait_failure(msg);
         }
-        self.notifier.notify_all();
 
-        // Wait until all dependencies have also set their local_checkpoint.
         if response_message == MESSAGE_ACK {
-            let timeout_duration = Duration::from_secs(self.wait_timeout as u64);
-            let start_time = Instant::now();
+            response_message = self.wait_for_dump_outcome(msg);
+        }
+        self.send_reply(msg, response_message, tcp_stream);
+        self.close_client_connection(msg, tcp_stream.clone());
+    }
 
-            for dependency in msg.dependencies.iter() {
-                info!(
-                    "[{}] [==] Waiting for dependency {} to complete its local checkpoint",
-                    msg.id, dependency
-                );
+    /// Wait until every member of the dump of the group has reached
+    /// post-dump or failed, so that all of them get the same reply. The
+    /// processes are killed after an ACK, and resumed after any other reply.
+    fn wait_for_dump_outcome(&self, msg: &ClientMessage) -> &'static str {
+        let number = {
+            let _clients = self.clients.lock().unwrap();
+            let mut dumps = self.dumps.lock().unwrap();
+            let dump = dumps.entry(msg.group.clone()).or_default();
+            dump.members.contains(&msg.id).then(|| {
+                dump.finish(&msg.id);
+                dump.number
+            })
+        };
+        self.notifier.notify_all();
+        let number = match number {
+            Some(number) => number,
+            None => {
+                warn!("[{}] [!!] Dump of group {} was started over, resuming the processes", msg.id, msg.group);
+                return MESSAGE_RESUME;
+            }
+        };
 
-                let clients_lock = self.clients.lock().unwrap();
-
-                let result = self.notifier.wait_timeout_while(
-                    clients_lock,
-                    timeout_duration.saturating_sub(start_time.elapsed()),
-                    |clients| {
-                        if let Some(dep_status) = clients.get(dependency) {
-                            !dep_status.has_local_checkpoint()
-                        } else {
-                            // In post-dump phase, dependency not found might have already completed and been removed
-                            // so we assume it has completed.
-                            false
-                        }
-                    }
-                );
+        let outcome = || self.dumps.lock().unwrap().get(&msg.group).and_then(|dump| dump.outcome(number));
+        let clients_lock = self.clients.lock().unwrap();
+        let result = self.notifier.wait_timeout_while(
+            clients_lock,
+            msg.deadline.saturating_duration_since(Instant::now()),
+            |_| outcome().is_none()
+        );
+        let clients_lock = match result {
+            Ok((clients_lock, _)) => clients_lock,
+            Err(_) => {
+                error!("[{}] [!!] Error waiting for the dump of group {}", msg.id, msg.group);
+                return MESSAGE_RESUME;
+            }
+        };
+        match outcome() {
+            Some(false) => MESSAGE_ACK,
+            Some(true) => {
+                let dumps = self.dumps.lock().unwrap();
+                let failed = dumps.get(&msg.group)
+                    .filter(|dump| dump.number == number)
+                    .map(|dump| dump.failed.iter().cloned().collect::<Vec<_>>().join(", "))
+                    .unwrap_or_else(|| "a member that started over".to_string());
+                warn!("[{}] [!!] Dump of group {} failed on {}, resuming the processes", msg.id, msg.group, failed);
+                MESSAGE_RESUME
+            }
+            None => {
+                // The client fails the dump, so that the others are resumed too.
+                let dumps = self.dumps.lock().unwrap();
+                let pending = dumps.get(&msg.group)
+                    .map(|dump| dump.members.difference(&dump.finished).cloned().collect::<Vec<_>>().join(", "))
+                    .unwrap_or_default();
+                drop(dumps);
+                error!("[{}] [!!] {} of group {} did not finish their dump, resuming the processes", msg.id, pending, msg.group);
+                self.fail_dump(msg);
+                drop(clients_lock);
+                self.notifier.notify_all();
+                MESSAGE_TIMEOUT
+            }
+        }
+    }
 
-                match result {
-                    Ok((_, timeout_result)) => {
-                        if timeout_result.timed_out() {
-                            error!(
-                                "[{}] [!!] Timeout waiting for dependency {}",
-                                msg.id, dependency
-                            );
-                            response_message = MESSAGE_TIMEOUT;
-                            break;
-                        }
-                        info!(
-                            "[{}] [==] Dependency {} has completed its local checkpoint",
-                            msg.id, dependency
-                        );
-                    }
-                    Err(_) => {
-                        error!(
-                            "[{}] [!!] Error waiting for dependency {}",
-                            msg.id, dependency
-                        );
-                        response_message = MESSAGE_TIMEOUT;
-                        break;
+    /// Create the upload session that receives the images of a client.
+    /// The client joins the open global checkpoint of its group, even if
+    /// the other members have already committed it, or starts a new one if
+    /// it is a member of the open one already.
+    fn start_upload_session(&self, msg: &ClientMessage) {
+        let epoch = {
+            let mut epochs = self.epochs.lock().unwrap();
+            if epochs.get(&msg.group).is_some_and(|epoch| epoch.members.contains_key(&msg.id)) {
+                epochs.remove(&msg.group);
+            }
+            let epoch = epochs.entry(msg.group.clone()).or_insert_with(Epoch::begin);
+            epoch.members.insert(msg.id.clone(), None);
+            epoch.id.clone()
+        };
+        info!("[{}] [==] Joined global checkpoint {} of group {}", msg.id, epoch, msg.group);
+        // The timeline of the checkpoint is stored under its epoch.
+        self.timelines.lock().unwrap()
+            .entry(msg.group.clone())
+            .or_insert_with(|| Timeline::new(&epoch, &msg.group))
+            .id = epoch.clone();
+
+        let images_dir = Path::new(&self.images_directory).join(STAGING_DIR).join(&msg.id);
+        let _ = remove_dir_all(&images_dir);
+        create_dir_all(&images_dir).unwrap();
+        let key_fingerprint = self.config.get_key_fingerprint(&msg.group);
+        self.uploads.lock().unwrap().insert(msg.id.clone(), UploadSession::new(&epoch, &images_dir, key_fingerprint));
+    }
+
+    /// Discard the images of a client whose checkpoint transfer failed.
+    /// The global checkpoint of its group can no longer be committed.
+    fn abandon_upload(&self, msg: &ClientMessage, session: Option<UploadSession>) {
+        let session = session.or_else(|| self.uploads.lock().unwrap().remove(&msg.id));
+        if let Some(session) = session {
+            session.discard();
+            self.finish_epoch_member(&msg.group, &session.epoch, &msg.id, None);
+        }
+        self.fail_dump(msg);
+    }
+
+    /// Record that a member of a global checkpoint has stored its images
+    /// (`size` is set) or failed. Once every member has finished, the
+    /// global checkpoint is added to the catalog, or its images are removed
+    /// if a member failed. A late member that finishes after the commit is
+    /// added to the catalog entry, and one that fails is left out of it.
+    fn finish_epoch_member(&self, group: &str, epoch_id: &str, id: &str, size: Option<u64>) {
+        let mut epochs = self.epochs.lock().unwrap();
+        let epoch = match epochs.get_mut(group) {
+            Some(epoch) if epoch.id == epoch_id => epoch,
+            _ => return,
+        };
+        match size {
+            Some(size) => {
+                epoch.members.insert(id.to_string(), Some(size));
+            }
+            None => {
+                epoch.members.remove(id);
+                epoch.failed |= !epoch.committed;
+            }
+        }
+        if !epoch.is_complete() {
+            return;
+        }
+
+        if epoch.failed || epoch.members.is_empty() {
+            let epoch = epochs.remove(group).unwrap();
+            drop(epochs);
+            warn!("[==] Global checkpoint {} of group {} failed", epoch.id, group);
+            if let Err(e) = self.catalog.delete_images(&epoch.id) {
+                error!("[!!] Failed to remove images of {}: {}", epoch.id, e);
+            }
+            return;
+        }
+
+        let recommit = epoch.committed;
+        epoch.committed = true;
+        let mut entry = epoch.to_entry(group);
+        drop(epochs);
+        if recommit {
+            // Keep the tags added since the first commit.
+            if let Ok(committed) = self.catalog.get(&entry.epoch) {
+                entry.tags = committed.tags;
+            }
+        }
+        self.verify_epoch(&mut entry);
+        match self.catalog.add(&entry) {
+            Ok(()) => info!("[==] Global checkpoint {} of group {} committed: {}", entry.epoch, group, entry.members.join(", ")),
+            Err(e) => error!("[!!] Failed to add {} to the catalog: {}", entry.epoch, e),
+        }
+    }
+
+    /// Check that the TCP connections between the members of a committed
+    /// global checkpoint form a consistent cut, which fails if the network
+    /// was not locked while they were dumped. The server never decrypts
+    /// images, so encrypted checkpoints are left unverified.
+    fn verify_epoch(&self, entry: &mut CatalogEntry) {
+        if self.config.get_key_fingerprint(&entry.group).is_some() {
+            info!("[==] Global checkpoint {} is encrypted, TCP connections not verified", entry.epoch);
+            return;
+        }
+        let read = |member: &str, name: &str| {
+            let mut data = Vec::new();
+            self.storage.get(&object_key(&entry.epoch, member, name), &mut data)?;
+            Ok(data)
+        };
+        match verify_tcp(&entry.members, &read) {
+            Ok(report) => {
+                for problem in report.problems.iter() {
+                    error!("[!!] Global checkpoint {} is inconsistent: {}", entry.epoch, problem);
+                }
+                info!("[==] Verified {} TCP connections of global checkpoint {}", report.connections, entry.epoch);
+                entry.inconsistencies = Some(report.problems);
+            }
+            Err(e) => warn!("[!!] Failed to verify TCP connections of {}: {}", entry.epoch, e),
+        }
+    }
+
+    /// Handle a request of the `list`, `show`, `tag`, `delete` or `timeline`
+    /// commands.
+    fn handle_catalog_request(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        let params = &msg.params;
+        let epoch = params["epoch"].as_str().unwrap_or_default();
+        let strings = |value: &JsonValue| value.members().filter_map(|v| v.as_str().map(str::to_string)).collect::<Vec<_>>();
+
+        let result = match msg.action.as_str() {
+            ACTION_CATALOG_LIST => self.catalog.list().map(|entries| {
+                let group = params["group"].as_str();
+                let tag = params["tag"].as_str();
+                let mut checkpoints = JsonValue::new_array();
+                for entry in entries.iter() {
+                    if group.is_some_and(|group| entry.group != group) || tag.is_some_and(|tag| !entry.tags.iter().any(|t| t == tag)) {
+                        continue;
                     }
+                    checkpoints.push(entry.to_json()).unwrap();
+                }
+                object!{ checkpoints: checkpoints }
+            }),
+            ACTION_CATALOG_SHOW => self.catalog.get(epoch).and_then(|entry| {
+                let mut response = entry.to_json();
+                response["manifests"] = self.catalog.manifests(&entry)?;
+                Ok(response)
+            }),
+            ACTION_CATALOG_TIMELINE if epoch.is_empty() => self.catalog.timelines().map(|timelines| {
+                let mut summaries = JsonValue::new_array();
+                for timeline in timelines.iter() {
+                    let clients: Vec<&str> = timeline.clients().into_iter().collect();
+                    summaries.push(object!{
+                        id: timeline.id.clone(),
+                        group: timeline.group.clone(),
+                        started: timeline.started(),
+                        finished: timeline.finished(),
+                        clients: clients,
+                    }).unwrap();
                 }
+                object!{ timelines: summaries }
+            }),
+            // The timeline of an operation in progress is returned as recorded so far.
+            ACTION_CATALOG_TIMELINE => {
+                let in_progress = self.timelines.lock().unwrap().values()
+                    .find(|timeline| timeline.id == epoch)
+                    .map(Timeline::to_json);
+                match in_progress {
+                    Some(timeline) => Ok(timeline),
+                    None => self.catalog.timeline(epoch).map(|timeline| timeline.to_json()),
+                }
+            }
+            ACTION_CATALOG_TAG => self.catalog.tag(epoch, &strings(&params["add"]), &strings(&params["remove"]))
+                .map(|entry| entry.to_json()),
+            _ => self.catalog.delete(epoch).map(|entry| {
+                info!("[==] Deleted global checkpoint {}", entry.epoch);
+                entry.to_json()
+            }),
+        };
+
+        let response = result.unwrap_or_else(|e| object!{ error: e.to_string() });
+        self.send_response(&msg.id, &response.dump(), tcp_stream);
+    }
+
+    /// Handle pre-stream action (checkpoint creation and image transfer).
+    /// Images may already have been received over the live stream while
+    /// CRIU was running. The client then announces all its image files on
+    /// the coordination connection, uploads the missing ones over data
+    /// connections and signals the end of the transfer. Returns false if an
+    /// image file was not received or verified.
+    fn handle_pre_stream(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) -> bool {
+        // Receive the name, size and digest of every image file.
+        let plan = match read_frame(&mut *tcp_stream.lock().unwrap()) {
+            Ok(Some(plan)) => from_utf8(&plan).ok().and_then(|data| json::parse(data).ok()),
+            Ok(None) => {
+                error!("[{}] [!!] Client disconnected before announcing image files", msg.id);
+                self.abandon_upload(msg, None);
+                return false;
+            }
+            Err(e) => {
+                error!("[{}] [!!] Failed to read image files: {}", msg.id, e);
+                self.abandon_upload(msg, None);
+                return false;
+            }
+        };
+
+        let result = {
+            let mut uploads = self.uploads.lock().unwrap();
+            match (uploads.get_mut(&msg.id), plan) {
+                (Some(session), Some(plan)) => session.apply_plan(&plan["images"])
+                    .map(|()| (session.files.len(), session.unverified_files().len())),
+                (None, _) => Err("Upload session not found".to_string()),
+                (_, None) => Err("Failed to parse image files".to_string()),
+            }
+        };
+        let (expected, missing) = match result {
+            Ok(counts) => counts,
+            Err(e) => {
+                error!("[{}] [!!] {}", msg.id, e);
+                self.abandon_upload(msg, None);
+                return false;
             }
+        };
+
+        info!("[{}] [==] Expecting {} image files, {} already received", msg.id, expected, expected - missing);
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
+
+        // Wait for the client to finish uploading.
+        let finished = matches!(read_frame(&mut *tcp_stream.lock().unwrap()), Ok(Some(frame)) if frame == MESSAGE_SYN.as_bytes());
+        let session = self.uploads.lock().unwrap().remove(&msg.id).unwrap();
+        if !finished {
+            error!("[{}] [!!] Client disconnected during image transfer", msg.id);
+            self.abandon_upload(msg, Some(session));
+            return false;
         }
 
-        // Respond with ACK to indicate that all local checkpoints have been successful.
-        self.send_response(&msg.id, response_message, tcp_stream);
-        self.close_client_connection(msg, tcp_stream.clone());
+        let unverified = session.unverified_files();
+        if !unverified.is_empty() {
+            error!("[{}] [!!] Image files were not received: {}", msg.id, unverified.join(", "));
+            self.abandon_upload(msg, Some(session));
+            self.send_response(&msg.id, MESSAGE_IMG_CORRUPTED, tcp_stream);
+            return false;
+        }
+
+        match session.commit(&msg.id, self.storage.as_ref()) {
+            Ok(size) => {
+                info!("[{}] [==] All image files verified, stored and recorded in {}", msg.id, MANIFEST_FILE);
+                self.finish_epoch_member(&msg.group, &session.epoch, &msg.id, Some(size));
+            }
+            Err(e) => {
+                error!("[{}] [!!] Failed to store checkpoint: {}", msg.id, e);
+                self.abandon_upload(msg, Some(session));
+                self.send_response(&msg.id, MESSAGE_STORAGE_FAILED, tcp_stream);
+                return false;
+            }
+        }
+
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
+        true
     }
 
-    /// Handle pre-stream action (checkpoint creation and image transfer)
-    fn handle_pre_stream(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
-        create_dir_all(&self.images_directory).unwrap();
+    /// Handle a data connection used to upload image files.
+    /// For each file, the client sends the image name and the server replies
+    /// with the offset to resume from, followed by the remaining content.
+    fn handle_upload_image(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        if !self.uploads.lock().unwrap().contains_key(&msg.id) {
+            self.send_response(&msg.id, MESSAGE_NOT_CONNECTED, tcp_stream);
+            return;
+        }
+        // A client that went away must not hold its files forever.
+        let receiver = {
+            let stream = tcp_stream.lock().unwrap();
+            stream.set_read_timeout(Some(UPLOAD_READ_TIMEOUT)).and_then(|()| stream.try_clone())
+        };
+        let receiver = match receiver {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                error!("[{}] [!!] Failed to set up data connection: {}", msg.id, e);
+                return;
+            }
+        };
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
 
-        // FIXME: Receive image files
-        loop {
-            // Receive image name and size.
-            let mut buffer = [0; 1024];
-            let data_size = tcp_stream.lock().unwrap().read(&mut buffer).unwrap();
+        'files: loop {
+            let img_name = match read_frame(&mut *tcp_stream.lock().unwrap()) {
+                Ok(Some(name)) => String::from_utf8_lossy(&name).to_string(),
+                _ => return,
+            };
 
-            let message_data = match from_utf8(&buffer[..data_size]) {
-                Ok(data) => data.to_string(),
-                Err(err) => {
-                    error!("[{}] [!!] Failed to parse message data: {}", msg.id, err);
-                    break;
+            // Claim the file and find out how much of it has already been received.
+            let (partial_path, img_size, img_digest, key_fingerprint) = loop {
+                let mut uploads = self.uploads.lock().unwrap();
+                let file = uploads.get_mut(&msg.id).and_then(|session| {
+                    let paths = (session.partial_path(&img_name), session.key_fingerprint.clone());
+                    session.files.get_mut(&img_name).map(|file| (paths, file))
+                });
+                match file {
+                    Some((_, file)) if file.verified => {
+                        // The file has already been received over the live stream.
+                        let response = object!{ offset: file.size, verified: true };
+                        drop(uploads);
+                        self.send_response(&msg.id, &response.dump(), tcp_stream);
+                        continue 'files;
+                    }
+                    Some(((partial_path, key_fingerprint), file)) => match &file.receiver {
+                        None => {
+                            file.receiver = receiver.try_clone().ok();
+                            break (partial_path, file.size, file.digest.clone(), key_fingerprint);
+                        }
+                        Some(stalled) => {
+                            // The client has given up on the other connection. Stop it
+                            // and take over once it has released the file.
+                            warn!("[{}] [!!] Taking over the upload of {}", msg.id, img_name);
+                            let _ = stalled.shutdown(Shutdown::Both);
+                        }
+                    },
+                    None => {
+                        error!("[{}] [!!] Unexpected upload of {}", msg.id, img_name);
+                        drop(uploads);
+                        self.send_response(&msg.id, MESSAGE_IMG_CORRUPTED, tcp_stream);
+                        return;
+                    }
                 }
+                drop(uploads);
+                thread::sleep(Duration::from_millis(10));
             };
 
-            if message_data == MESSAGE_SYN {
-                break;
+            let mut offset = metadata(&partial_path).map(|m| m.len()).unwrap_or(0);
+            if offset > img_size || (key_fingerprint.is_some() && offset < ENCRYPTED_HEADER_SIZE as u64) {
+                // The encryption header must be checked as a whole.
+                offset = 0;
+            }
+            if offset > 0 {
+                info!("[{}] [==] Resuming {} at offset {} of {}", msg.id, img_name, offset, img_size);
+            } else {
+                info!("[{}] [==] Receiving {} with size {}", msg.id, img_name, img_size);
             }
+            self.send_response(&msg.id, &object!{ offset: offset }.dump(), tcp_stream);
 
-            let message_data = json::parse(message_data.as_str()).unwrap();
+            let result = self.receive_image_file(&partial_path, offset, img_size, &img_digest, key_fingerprint.as_deref(), tcp_stream);
 
-            if !message_data.has_key("img_name") || !message_data.has_key("img_size") {
-                break;
+            let mut uploads = self.uploads.lock().unwrap();
+            let session = match uploads.get_mut(&msg.id) {
+                Some(session) => session,
+                None => return,
+            };
+            let image_path = session.image_path(&img_name);
+            let file = session.files.get_mut(&img_name).unwrap();
+            file.receiver = None;
+
+            match result {
+                Ok(()) => {
+                    if let Err(e) = rename(&partial_path, &image_path) {
+                        error!("[{}] [!!] Failed to store {}: {}", msg.id, img_name, e);
+                        return;
+                    }
+                    file.verified = true;
+                    drop(uploads);
+                    self.send_response(&msg.id, MESSAGE_IMG_ACK, tcp_stream);
+                }
+                Err(UploadError::Interrupted(e)) => {
+                    // Keep the partially received file so the upload can resume.
+                    warn!("[{}] [!!] Upload of {} interrupted: {}", msg.id, img_name, e);
+                    return;
+                }
+                Err(UploadError::Invalid(e)) => {
+                    error!("[{}] [!!] Failed to receive {}: {}", msg.id, img_name, e);
+                    let _ = remove_file(&partial_path);
+                    drop(uploads);
+                    self.send_response(&msg.id, MESSAGE_IMG_CORRUPTED, tcp_stream);
+                    return;
+                }
             }
+        }
+    }
 
-            let img_name = message_data["img_name"].to_string();
-            let img_size: usize = message_data["img_size"]
-                .to_string()
-                .trim()
-                .parse()
-                .expect("Image size is not u32");
+    /// Receive the content of an image file after `offset` and append it to
+    /// `partial_path`, then check that the digest of the whole file matches
+    /// the one computed by the client.
+    /// If `key_fingerprint` is set, the image must be encrypted with that key.
+    /// The server never decrypts images, so they stay encrypted at rest.
+    fn receive_image_file(
+        &self,
+        partial_path: &Path,
+        offset: u64,
+        img_size: u64,
+        img_digest: &str,
+        key_fingerprint: Option<&str>,
+        tcp_stream: &Arc<Mutex<TcpStream>>,
+    ) -> Result<(), UploadError> {
+        let mut digest = ImageDigest::new();
+
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let mut bytes_read = offset;
+
+        let mut output_file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(partial_path).map_err(|e| UploadError::Invalid(e.to_string()))?;
+        output_file.set_len(offset).map_err(|e| UploadError::Invalid(e.to_string()))?;
+        digest.update_from_file(&output_file, 0, offset as usize).map_err(|e| UploadError::Invalid(e.to_string()))?;
+        output_file.seek(SeekFrom::End(0)).map_err(|e| UploadError::Invalid(e.to_string()))?;
+
+        // Check the encryption header before anything is written to disk.
+        if let (Some(key_fingerprint), 0) = (key_fingerprint, offset) {
+            if img_size < ENCRYPTED_HEADER_SIZE as u64 {
+                return Err(UploadError::Invalid("image is not encrypted".to_string()));
+            }
+            let header = &mut buffer[..ENCRYPTED_HEADER_SIZE];
+            tcp_stream.lock().unwrap().read_exact(header)
+                .map_err(|e| UploadError::Interrupted(format!("truncated encryption header: {e}")))?;
+            check_encryption_header(header, key_fingerprint)?;
+            digest.update(header);
+            output_file.write_all(header).map_err(|e| UploadError::Invalid(e.to_string()))?;
+            bytes_read = ENCRYPTED_HEADER_SIZE as u64;
+        }
 
-            let output_file_path = Path::new(&self.images_directory).join(img_name.clone());
-            let mut output_file = File::create(output_file_path.clone()).unwrap();
+        while bytes_read < img_size {
+            let bytes_to_read = std::cmp::min(buffer.len() as u64, img_size - bytes_read) as usize;
+            let n = tcp_stream
+                .lock()
+                .unwrap()
+                .read(&mut buffer[..bytes_to_read])
+                .map_err(|e| UploadError::Interrupted(e.to_string()))?;
+            if n == 0 {
+                return Err(UploadError::Interrupted(format!("truncated after {bytes_read} of {img_size} bytes")));
+            }
+            digest.update(&buffer[..n]);
+            output_file.write_all(&buffer[..n]).map_err(|e| UploadError::Invalid(e.to_string()))?;
+            bytes_read += n as u64;
+        }
 
-            info!(
-                "[{}] [==] Receiving {} with size {} to {:?}",
-                msg.id,
-                img_name,
-                img_size,
-                output_file_path.to_str()
-            );
+        let received_digest = digest.finalize();
+        if received_digest != img_digest {
+            return Err(UploadError::Invalid(format!("{DIGEST_ALGORITHM} mismatch (expected {img_digest}, got {received_digest})")));
+        }
+        Ok(())
+    }
 
-            let response_message: &str = MESSAGE_IMG_ACK;
+    /// Handle a data connection that carries a marker stream of images
+    /// produced while CRIU is running. Chunks of several files may be
+    /// interleaved. Files that are not complete when the stream ends are
+    /// discarded and uploaded again after the local checkpoint.
+    fn handle_stream_image(&self, msg: &ClientMessage, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        if !self.uploads.lock().unwrap().contains_key(&msg.id) {
+            self.send_response(&msg.id, MESSAGE_NOT_CONNECTED, tcp_stream);
+            return;
+        }
+        self.send_response(&msg.id, MESSAGE_ACK, tcp_stream);
 
-            let mut buffer = [0u8; 1024];
-            let mut bytes_read = 0;
+        let stream = match tcp_stream.lock().unwrap().try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("[{}] [!!] Failed to read image stream: {}", msg.id, e);
+                return;
+            }
+        };
+        let receiver = stream.try_clone().ok();
+        let mut reader = MarkerReader::new(stream);
+        let mut receiving: HashMap<String, (std::fs::File, ImageDigest, u64)> = HashMap::new();
+
+        let result = loop {
+            let event = match reader.next_event() {
+                Ok(Some(event)) => event,
+                Ok(None) => break Err(UploadError::Interrupted("stream ended before the end of image marker".to_string())),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => break Err(UploadError::Invalid(e.to_string())),
+                Err(e) => break Err(UploadError::Interrupted(e.to_string())),
+            };
 
-            while bytes_read < img_size {
-                let bytes_to_read = std::cmp::min(buffer.len(), img_size - bytes_read);
-                let n = tcp_stream
-                    .lock()
-                    .unwrap()
-                    .read(&mut buffer[..bytes_to_read])
-                    .unwrap();
-                if n == 0 {
-                    break;
+            match event {
+                MarkerEvent::File(name) if !receiving.contains_key(&name) => {
+                    match self.start_stream_file(msg, &name, receiver.as_ref()) {
+                        Ok(file) => {
+                            info!("[{}] [==] Streaming {}", msg.id, name);
+                            receiving.insert(name, (file, ImageDigest::new(), 0));
+                        }
+                        Err(e) => break Err(e),
+                    }
+                }
+                MarkerEvent::File(_) => {}
+                MarkerEvent::Data(data) => {
+                    let name = reader.current_file().unwrap_or_default();
+                    let (file, digest, size) = match receiving.get_mut(name) {
+                        Some(receiving) => receiving,
+                        None => break Err(UploadError::Invalid(format!("data received for {name} after its end"))),
+                    };
+                    let key_fingerprint = self.uploads.lock().unwrap().get(&msg.id).and_then(|s| s.key_fingerprint.clone());
+                    if let (Some(key_fingerprint), 0) = (key_fingerprint, *size) {
+                        // The first chunk of an encrypted image holds its header.
+                        if let Err(e) = check_encryption_header(&data, &key_fingerprint) {
+                            break Err(e);
+                        }
+                    }
+                    if let Err(e) = file.write_all(&data) {
+                        break Err(UploadError::Invalid(e.to_string()));
+                    }
+                    digest.update(&data);
+                    *size += data.len() as u64;
+                }
+                MarkerEvent::FileEof(name) => {
+                    let (_, digest, size) = match receiving.remove(&name) {
+                        Some(receiving) => receiving,
+                        None => break Err(UploadError::Invalid(format!("end of {name} received twice"))),
+                    };
+                    let mut uploads = self.uploads.lock().unwrap();
+                    let session = match uploads.get_mut(&msg.id) {
+                        Some(session) => session,
+                        None => break Err(UploadError::Invalid("upload session was closed".to_string())),
+                    };
+                    if let Err(e) = rename(session.partial_path(&name), session.image_path(&name)) {
+                        break Err(UploadError::Invalid(format!("failed to store {name}: {e}")));
+                    }
+                    let file = session.files.get_mut(&name).unwrap();
+                    file.size = size;
+                    file.digest = digest.finalize();
+                    file.verified = true;
+                    file.receiver = None;
+                    info!("[{}] [==] Received {} with size {}", msg.id, name, size);
+                }
+                MarkerEvent::ImageEof => {
+                    if receiving.is_empty() {
+                        break Ok(());
+                    }
+                    break Err(UploadError::Invalid("stream ended with incomplete image files".to_string()));
                 }
-                output_file.write_all(&buffer[..n]).unwrap();
-                bytes_read += n;
             }
+        };
 
-            self.send_response(&msg.id, response_message, tcp_stream);
+        // Forget the files that were not received completely.
+        if let Some(session) = self.uploads.lock().unwrap().get_mut(&msg.id) {
+            for name in receiving.keys() {
+                let _ = remove_file(session.partial_path(name));
+                session.files.remove(name);
+            }
         }
 
-        // FIXME: 7. Wait to receive the image files from all dependencies.
-        // FIXME: 8. Send ACK to confirm that the image files from all checkpoints have been received.
+        match result {
+            Ok(()) => self.send_response(&msg.id, MESSAGE_ACK, tcp_stream),
+            Err(UploadError::Interrupted(e)) => {
+                warn!("[{}] [!!] Image stream interrupted: {}", msg.id, e);
+            }
+            Err(UploadError::Invalid(e)) => {
+                error!("[{}] [!!] Invalid image stream: {}", msg.id, e);
+                self.send_response(&msg.id, MESSAGE_IMG_CORRUPTED, tcp_stream);
+            }
+        }
+    }
+
+    /// Register a file announced on the live stream and open the file
+    /// that receives its content.
+    fn start_stream_file(&self, msg: &ClientMessage, name: &str, receiver: Option<&TcpStream>) -> Result<std::fs::File, UploadError> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let session = uploads.get_mut(&msg.id)
+            .ok_or_else(|| UploadError::Invalid("upload session was closed".to_string()))?;
+        if !marker::is_valid_image_name(name) || session.files.contains_key(name) {
+            return Err(UploadError::Invalid(format!("unexpected image file {name}")));
+        }
+
+        let file = OpenOptions::new().write(true).create(true).truncate(true)
+            .open(session.partial_path(name))
+            .map_err(|e| UploadError::Invalid(e.to_string()))?;
+        session.files.insert(name.to_string(), upload::UploadFile {
+            size: 0,
+            digest: String::new(),
+            verified: false,
+            receiver: receiver.and_then(|stream| stream.try_clone().ok()),
+        });
+        Ok(file)
     }
 
     fn wait_for_syn_response(&self, msg: &ClientMessage, stream: &Arc<Mutex<TcpStream>>) -> bool {
@@ -608,46 +1808,31 @@ impl Server {
         }
     }
 
-    fn get_response_message(&self, client_msg: &ClientMessage) -> &'static str {
-        let mut clients_lock = self.clients.lock().unwrap();
-        let action = client_msg.action.as_str();
-
-        match action {
-            ACTION_PRE_DUMP | ACTION_PRE_STREAM => {
-                info!(
-                    "[{}] [==] Starting new DUMP operation with action '{}', (re)setting state.",
-                    client_msg.id, action
-                );
-                clients_lock.insert(client_msg.id.clone(), ClientStatus::new(Operation::Dump));
-                drop(clients_lock);
-                self.notifier.notify_all();
-                return MESSAGE_ACK;
-            }
-            ACTION_PRE_RESTORE => {
-                info!(
-                    "[{}] [==] Starting new RESTORE operation with action '{}', (re)setting state.",
-                    client_msg.id, action
-                );
-                clients_lock.insert(client_msg.id.clone(), ClientStatus::new(Operation::Restore));
-                drop(clients_lock);
-                self.notifier.notify_all();
-                return MESSAGE_ACK;
-            }
-            _ => {}
+    /// Move the client to the phase entered with its action. An action that
+    /// starts an operation (re)sets the state of the client. Returns the reply
+    /// to the client if the action is out of order.
+    fn enter_phase(&self, msg: &ClientMessage) -> Result<(), String> {
+        let action = msg.action.as_str();
+        if !phase::is_phase_action(action) {
+            return Ok(());
         }
-
-        // For other actions, the client must already be known.
-        if let Some(status) = clients_lock.get(&client_msg.id) {
-            // Check for re-entrant actions that are not allowed.
-            if action == ACTION_NETWORK_LOCK && status.is_network_locked() {
-                 return MESSAGE_ALREADY_CONNECTED;
-            }
-            // We may need to add other state checks for other actions if needed
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(operation) = phase::starts_operation(action) {
+            info!(
+                "[{}] [==] Starting new {:?} operation with action '{}', (re)setting state.",
+                msg.id, operation, action
+            );
+            clients.insert(msg.id.clone(), ClientStatus::new(operation));
         } else {
-            return MESSAGE_NOT_CONNECTED;
+            let status = clients.get_mut(&msg.id);
+            match status {
+                Some(status) => status.apply(action)?,
+                None => return phase::transition(None, action).map(|_| ()),
+            }
         }
-
-        MESSAGE_ACK
+        drop(clients);
+        self.notifier.notify_all();
+        Ok(())
     }
 
     fn send_response(
@@ -664,14 +1849,31 @@ impl Server {
             .expect("Failed to send message");
     }
 
+    /// Send the reply to the action of a client. Clients that send their
+    /// timeouts get the reply as JSON, with the timeout of the action and its
+    /// deadline in milliseconds since the Unix epoch.
+    fn send_reply(&self, msg: &ClientMessage, response_message: &str, tcp_stream: &Arc<Mutex<TcpStream>>) {
+        if !msg.params["timeouts"].is_object() {
+            self.send_response(&msg.id, response_message, tcp_stream);
+            return;
+        }
+        let remaining = msg.deadline.saturating_duration_since(Instant::now());
+        let deadline = SystemTime::now() + remaining;
+        let reply = object!{
+            reply: response_message,
+            timeout: msg.timeout,
+            deadline: deadline.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+        };
+        self.send_response(&msg.id, &reply.dump(), tcp_stream);
+    }
+
     fn close_client_connection(&self, msg: &ClientMessage, tcp_stream: Arc<Mutex<TcpStream>>) {
         if let Some(x) = self.clients.lock().unwrap().get_mut(&msg.id) {
             if x.is_connected() {
-                tcp_stream
-                    .lock()
-                    .unwrap()
-                    .shutdown(std::net::Shutdown::Both)
-                    .expect("Failed to shutdown TCP connection");
+                // The client may have closed the connection already, e.g. after a failed freeze.
+                if let Err(e) = tcp_stream.lock().unwrap().shutdown(std::net::Shutdown::Both) {
+                    warn!("[{}] [!!] Failed to shutdown TCP connection: {}", msg.id, e);
+                }
                 info!("[{}] [==] Client disconnected", msg.id);
             }
         }
@@ -679,21 +1881,11 @@ impl Server {
         let mut clients = self.clients.lock().unwrap();
         let mut op_for_log: Option<Operation> = None;
 
+        // A dump operation is complete after post-dump or post-stream, and a
+        // restore after post-resume, and the state of the client is cleared.
         let is_complete = if let Some(status) = clients.get(&msg.id) {
-            let action = msg.action.as_str();
-            let operation = status.get_operation();
-            op_for_log = Some(operation);
-
-            match operation {
-                Operation::Dump => {
-                    // A dump operation is considered complete and state can be cleared
-                    // only after post-dump or post-stream.
-                    action == ACTION_POST_DUMP || action == ACTION_POST_STREAM
-                }
-                Operation::Restore => {
-                    action == ACTION_POST_RESUME
-                }
-            }
+            op_for_log = Some(status.get_operation());
+            status.get_phase().is_final()
         } else {
             false
         };
@@ -710,3 +1902,28 @@ impl Server {
         self.notifier.notify_all();
     }
 }
+
+impl ClientMessage {
+    /// Whether the client waits for `dependency` to resume instead of
+    /// waiting for it at the barriers of the action.
+    fn is_restored_after(&self, dependency: &str) -> bool {
+        self.action == ACTION_PRE_RESTORE && self.ordered.contains(dependency)
+    }
+}
+
+/// First required dependency of `msg` that stopped sending heartbeats.
+fn lost_dependency<'a>(msg: &'a ClientMessage, clients: &HashMap<String, ClientStatus>) -> Option<&'a str> {
+    msg.dependencies.iter()
+        .filter(|dependency| !msg.optional.contains(*dependency))
+        .find(|dependency| clients.get(*dependency).is_some_and(|status| !status.is_connected()))
+        .map(String::as_str)
+}
+
+/// Check that `header` starts an image encrypted with the key of the group.
+fn check_encryption_header(header: &[u8], key_fingerprint: &str) -> Result<(), UploadError> {
+    match crypto::header_fingerprint(header) {
+        Some(fingerprint) if fingerprint == key_fingerprint => Ok(()),
+        Some(_) => Err(UploadError::Invalid("image is encrypted with a different key".to_string())),
+        None => Err(UploadError::Invalid("image is not encrypted".to_string())),
+    }
+}
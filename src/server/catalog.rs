@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Catalog of the global checkpoints committed to the storage.
+//!
+//! A global checkpoint (epoch) of a group is opened when the first member
+//! starts streaming its images and is committed once every member that
+//! joined it has stored its images. Each committed epoch is recorded as a
+//! `catalog/<epoch>.json` object next to the images, so the catalog is as
+//! durable as the checkpoints it describes. The timeline of the actions of
+//! the members is stored as `timeline/<epoch>.json`.
+
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Result},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use json::{object, JsonValue};
+
+use crate::constants::MANIFEST_FILE;
+use crate::pipeline::protobuf::invalid_data;
+use crate::timeline::{unix_time, Timeline};
+use super::storage::{object_key, Storage};
+
+const CATALOG_PREFIX: &str = "catalog/";
+const TIMELINE_PREFIX: &str = "timeline/";
+
+/// CatalogEntry describes a committed global checkpoint.
+pub struct CatalogEntry {
+    pub epoch: String,
+    pub group: String,
+    pub members: Vec<String>,
+    /// Unix time when the first member started streaming.
+    pub started: u64,
+    /// Unix time when the last member was stored.
+    pub committed: u64,
+    /// Total size of the images of all members.
+    pub size: u64,
+    pub tags: Vec<String>,
+    /// Problems found by the verification of the TCP connections between
+    /// the members, or `None` if the checkpoint was not verified.
+    pub inconsistencies: Option<Vec<String>>,
+}
+
+impl CatalogEntry {
+    pub fn to_json(&self) -> JsonValue {
+        let mut data = object!{
+            epoch: self.epoch.clone(),
+            group: self.group.clone(),
+            members: self.members.clone(),
+            started: self.started,
+            committed: self.committed,
+            size: self.size,
+            tags: self.tags.clone(),
+        };
+        if let Some(inconsistencies) = &self.inconsistencies {
+            data["consistent"] = inconsistencies.is_empty().into();
+            data["inconsistencies"] = inconsistencies.clone().into();
+        }
+        data
+    }
+
+    pub fn from_json(data: &JsonValue) -> Option<Self> {
+        let strings = |value: &JsonValue| value.members().map(|v| v.as_str().map(str::to_string)).collect::<Option<Vec<_>>>();
+        Some(Self {
+            epoch: data["epoch"].as_str()?.to_string(),
+            group: data["group"].as_str()?.to_string(),
+            members: strings(&data["members"])?,
+            started: data["started"].as_u64()?,
+            committed: data["committed"].as_u64()?,
+            size: data["size"].as_u64()?,
+            tags: strings(&data["tags"])?,
+            inconsistencies: match data["inconsistencies"].is_null() {
+                true => None,
+                false => Some(strings(&data["inconsistencies"])?),
+            },
+        })
+    }
+}
+
+/// Epoch tracks a global checkpoint while its members store their images.
+pub struct Epoch {
+    pub id: String,
+    pub started: u64,
+    /// Members that joined the epoch, with the size of their images once stored.
+    pub members: BTreeMap<String, Option<u64>>,
+    /// A member failed, so the epoch is not a consistent global checkpoint.
+    pub failed: bool,
+    /// The epoch is in the catalog. It stays open, so that members that
+    /// start their dump late join it, until a member dumps again.
+    pub committed: bool,
+}
+
+impl Epoch {
+    pub fn begin() -> Self {
+        Self { id: new_epoch_id(), started: unix_time(), members: BTreeMap::new(), failed: false, committed: false }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.members.values().all(Option::is_some)
+    }
+
+    pub fn to_entry(&self, group: &str) -> CatalogEntry {
+        CatalogEntry {
+            epoch: self.id.clone(),
+            group: group.to_string(),
+            members: self.members.keys().cloned().collect(),
+            started: self.started,
+            committed: unix_time(),
+            size: self.members.values().map(|size| size.unwrap_or(0)).sum(),
+            tags: Vec::new(),
+            inconsistencies: None,
+        }
+    }
+}
+
+/// Epoch IDs sort by creation time. The random suffix keeps them unique
+/// when several servers share a storage.
+pub fn new_epoch_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}-{:04x}", now.as_millis(), rand::random::<u16>())
+}
+
+/// Catalog reads and updates the catalog entries in the storage.
+pub struct Catalog {
+    storage: Arc<dyn Storage>,
+    /// Serializes read-modify-write updates of catalog entries.
+    lock: Mutex<()>,
+}
+
+impl Catalog {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage, lock: Mutex::new(()) }
+    }
+
+    fn entry_key(epoch: &str) -> String {
+        format!("{CATALOG_PREFIX}{epoch}.json")
+    }
+
+    fn read_json(&self, key: &str) -> Result<JsonValue> {
+        let mut data = Vec::new();
+        self.storage.get(key, &mut data)?;
+        std::str::from_utf8(&data).ok()
+            .and_then(|data| json::parse(data).ok())
+            .ok_or_else(|| invalid_data(format!("{key} is not valid JSON")))
+    }
+
+    fn write_entry(&self, entry: &CatalogEntry) -> Result<()> {
+        let data = entry.to_json().pretty(4);
+        self.storage.put(&Self::entry_key(&entry.epoch), &mut data.as_bytes(), data.len() as u64)
+    }
+
+    pub fn add(&self, entry: &CatalogEntry) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        self.write_entry(entry)
+    }
+
+    /// Return all committed checkpoints, oldest first.
+    pub fn list(&self) -> Result<Vec<CatalogEntry>> {
+        let mut entries = Vec::new();
+        for key in self.storage.list(CATALOG_PREFIX)? {
+            match self.read_json(&key) {
+                Ok(data) => entries.extend(CatalogEntry::from_json(&data)),
+                // The entry was deleted after it was listed.
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        entries.sort_by(|a, b| (a.committed, &a.epoch).cmp(&(b.committed, &b.epoch)));
+        Ok(entries)
+    }
+
+    pub fn get(&self, epoch: &str) -> Result<CatalogEntry> {
+        if epoch.is_empty() || epoch.contains('/') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid epoch {epoch:?}")));
+        }
+        let data = self.read_json(&Self::entry_key(epoch)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::new(ErrorKind::NotFound, format!("Checkpoint {epoch} does not exist")),
+            _ => e,
+        })?;
+        CatalogEntry::from_json(&data)
+            .ok_or_else(|| invalid_data(format!("Catalog entry of {epoch} is invalid")))
+    }
+
+    fn timeline_key(id: &str) -> String {
+        format!("{TIMELINE_PREFIX}{id}.json")
+    }
+
+    pub fn add_timeline(&self, timeline: &Timeline) -> Result<()> {
+        let data = timeline.to_json().pretty(4);
+        self.storage.put(&Self::timeline_key(&timeline.id), &mut data.as_bytes(), data.len() as u64)
+    }
+
+    /// Return all recorded timelines, oldest first.
+    pub fn timelines(&self) -> Result<Vec<Timeline>> {
+        let mut timelines = Vec::new();
+        for key in self.storage.list(TIMELINE_PREFIX)? {
+            match self.read_json(&key) {
+                Ok(data) => timelines.extend(Timeline::from_json(&data)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        timelines.sort_by(|a, b| (a.started(), &a.id).cmp(&(b.started(), &b.id)));
+        Ok(timelines)
+    }
+
+    pub fn timeline(&self, id: &str) -> Result<Timeline> {
+        if id.is_empty() || id.contains('/') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid epoch {id:?}")));
+        }
+        let data = self.read_json(&Self::timeline_key(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::new(ErrorKind::NotFound, format!("No timeline was recorded for {id}")),
+            _ => e,
+        })?;
+        Timeline::from_json(&data)
+            .ok_or_else(|| invalid_data(format!("Timeline of {id} is invalid")))
+    }
+
+    /// Return the manifest of every member of `epoch`.
+    pub fn manifests(&self, entry: &CatalogEntry) -> Result<JsonValue> {
+        let mut manifests = JsonValue::new_object();
+        for member in entry.members.iter() {
+            manifests[member.as_str()] = self.read_json(&object_key(&entry.epoch, member, MANIFEST_FILE))?;
+        }
+        Ok(manifests)
+    }
+
+    /// Add `add` to the tags of `epoch` and remove `remove` from them.
+    pub fn tag(&self, epoch: &str, add: &[String], remove: &[String]) -> Result<CatalogEntry> {
+        let _lock = self.lock.lock().unwrap();
+        let mut entry = self.get(epoch)?;
+        entry.tags.retain(|tag| !remove.contains(tag));
+        for tag in add {
+            if !entry.tags.contains(tag) {
+                entry.tags.push(tag.clone());
+            }
+        }
+        self.write_entry(&entry)?;
+        Ok(entry)
+    }
+
+    /// Remove a committed checkpoint and all its images.
+    pub fn delete(&self, epoch: &str) -> Result<CatalogEntry> {
+        let _lock = self.lock.lock().unwrap();
+        let entry = self.get(epoch)?;
+        // The entry is removed first, so a partially deleted checkpoint
+        // is never offered for restore.
+        self.storage.delete(&Self::entry_key(epoch))?;
+        self.storage.delete(&Self::timeline_key(epoch))?;
+        self.delete_images(epoch)?;
+        Ok(entry)
+    }
+
+    /// Remove the images stored for `epoch`.
+    pub fn delete_images(&self, epoch: &str) -> Result<()> {
+        for key in self.storage.list(&format!("{epoch}/"))? {
+            self.storage.delete(&key)?;
+        }
+        Ok(())
+    }
+}
@@ -16,32 +16,33 @@
  * limitations under the License.
  *
  */
+use std::{collections::BTreeSet, time::Instant};
 
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Operation {
-    Dump,
-    Restore,
-}
+use crate::phase::{self, Operation, Phase};
 
 pub struct ClientStatus {
+    /// Cleared when the client stops sending heartbeats.
     connected: bool,
-    ready: bool,
-    local_checkpoint: bool,
-    network_locked: bool,
-    network_unlocked: bool,
+    phase: Phase,
+    /// Time at which the client froze its cgroup, in microseconds since the epoch.
+    frozen_at: Option<u64>,
+    /// Hooks outside the flows of the transition table that the client has reached.
+    hooks: BTreeSet<String>,
     operation: Operation,
+    /// Time at which the operation started, which tells operations of the
+    /// same client apart.
+    started: Instant,
 }
 
 impl ClientStatus {
     pub fn new(operation: Operation) -> Self {
         Self {
             connected: true,
-            ready: false,
-            local_checkpoint: false,
-            network_locked: false,
-            network_unlocked: false,
+            phase: Phase::Connected,
+            frozen_at: None,
+            hooks: BTreeSet::new(),
             operation,
+            started: Instant::now(),
         }
     }
 
@@ -49,39 +50,53 @@ impl ClientStatus {
         self.connected
     }
 
-    pub fn is_ready(&self) -> bool {
-        self.ready
+    pub fn set_disconnected(&mut self) {
+        self.connected = false;
+    }
+
+    pub fn get_phase(&self) -> Phase {
+        self.phase
     }
 
-    pub fn set_ready(&mut self, value: bool) {
-        self.ready = value;
+    /// Move to the phase entered with `action`, or return the reply to the
+    /// client if the action is out of order.
+    pub fn apply(&mut self, action: &str) -> Result<(), String> {
+        let (_, phase) = phase::transition(Some((self.operation, self.phase)), action)?;
+        self.phase = phase;
+        Ok(())
     }
 
-    pub fn set_local_checkpoint(&mut self) {
-        self.local_checkpoint = true;
+    /// Move to a phase that the client reaches within an action.
+    pub fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
     }
 
-    pub fn has_local_checkpoint(&self) -> bool {
-        self.local_checkpoint
+    /// Whether the client has gone through `phase` in its operation.
+    pub fn has_reached(&self, phase: Phase) -> bool {
+        self.phase >= phase && phase.operation().is_none_or(|operation| operation == self.operation)
     }
 
-    pub fn is_network_locked(&self) -> bool {
-        self.network_locked
+    pub fn get_frozen_at(&self) -> Option<u64> {
+        self.frozen_at
     }
 
-    pub fn set_network_locked(&mut self) {
-        self.network_locked = true;
+    pub fn set_frozen_at(&mut self, time: u64) {
+        self.frozen_at = Some(time);
     }
 
-    pub fn is_network_unlocked(&self) -> bool {
-        self.network_unlocked
+    pub fn has_reached_hook(&self, hook: &str) -> bool {
+        self.hooks.contains(hook)
     }
 
-    pub fn set_network_unlocked(&mut self) {
-        self.network_unlocked = true;
+    pub fn set_reached_hook(&mut self, hook: &str) {
+        self.hooks.insert(hook.to_string());
     }
 
     pub fn get_operation(&self) -> Operation {
         self.operation
     }
+
+    pub fn get_started(&self) -> Instant {
+        self.started
+    }
 }
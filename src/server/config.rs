@@ -0,0 +1,256 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+use std::{collections::HashMap, env, path::Path, sync::Arc};
+
+use config::Config;
+
+use crate::pipeline::crypto::ImageKey;
+use super::storage::{
+    local::LocalStorage,
+    s3::{S3Config, S3Storage},
+    Storage,
+};
+
+pub const DEFAULT_IMAGES_DIR: &str = "/tmp/server-images";
+
+const CONFIG_KEY_IMAGES_DIR: &str = "images-dir";
+const CONFIG_KEY_ENCRYPTION: &str = "encryption";
+const CONFIG_KEY_STORAGE: &str = "storage";
+const CONFIG_KEY_DEPENDENCY_DISCOVERY: &str = "dependency-discovery";
+const CONFIG_KEY_HEARTBEAT_TIMEOUT: &str = "heartbeat-timeout";
+const CONFIG_KEY_TIMEOUTS: &str = "timeouts";
+
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 10;
+
+/// StorageConfig selects where committed checkpoints are stored.
+pub enum StorageConfig {
+    /// A local directory, which defaults to the images directory.
+    Local { path: Option<String> },
+    S3(S3Config),
+}
+
+/// DependencyDiscovery selects what the server does when clients share
+/// a TCP connection without depending on each other.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DependencyDiscovery {
+    Off,
+    /// Log the missing dependencies.
+    Report,
+    /// Log the missing dependencies and coordinate the clients as if
+    /// they depended on each other.
+    Add,
+}
+
+/// ServerConfig holds the settings loaded from the server configuration file.
+///
+/// Example of server config file:
+/// {
+///    "images-dir": "/var/lib/criu-coordinator",
+///    "encryption": {
+///        "default": "/etc/criu-coordinator/default.key"
+///    },
+///    "storage": {
+///        "type": "s3",
+///        "endpoint": "http://127.0.0.1:9000",
+///        "bucket": "checkpoints",
+///        "region": "us-east-1",
+///        "access-key": "minioadmin",
+///        "secret-key": "minioadmin"
+///    },
+///    "dependency-discovery": "add",
+///    "heartbeat-timeout": 10,
+///    "timeouts": {
+///        "post-dump": 600,
+///        "groups": {
+///            "db": { "post-dump": 1800 }
+///        }
+///    }
+/// }
+/// Where encryption is a map of group names to the key file of the group.
+/// The images directory holds the images that are being received. Once a
+/// checkpoint is verified, it is moved to the storage, which is either
+/// `{"type": "local", "path": ...}` or an S3-compatible object store. The
+/// S3 credentials default to `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+/// Dependency discovery is `off`, `report` (the default) or `add`.
+/// Clients that send heartbeats are considered lost after the heartbeat
+/// timeout, in seconds, without one. Timeouts are the number of seconds that
+/// clients wait for their dependencies at each action, for all groups or for
+/// the groups listed in `groups`. Actions without a timeout use the timeout
+/// given on the command line.
+pub struct ServerConfig {
+    images_dir: String,
+    key_fingerprints: HashMap<String, String>,
+    storage: StorageConfig,
+    dependency_discovery: DependencyDiscovery,
+    heartbeat_timeout: u64,
+    timeouts: Timeouts,
+    group_timeouts: HashMap<String, Timeouts>,
+}
+
+/// Timeouts in seconds, by action.
+type Timeouts = HashMap<String, u64>;
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            images_dir: DEFAULT_IMAGES_DIR.to_string(),
+            key_fingerprints: HashMap::new(),
+            storage: StorageConfig::Local { path: None },
+            dependency_discovery: DependencyDiscovery::Report,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            timeouts: HashMap::new(),
+            group_timeouts: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Load the server configuration. Without a configuration file,
+    /// the default settings are used.
+    pub fn load(config_file: Option<&Path>) -> Self {
+        let mut server_config = Self::default();
+        let config_file = match config_file {
+            Some(config_file) => config_file,
+            None => return server_config,
+        };
+
+        if !config_file.is_file() {
+            panic!("Server config file {:?} is not found", config_file);
+        }
+
+        let settings = Config::builder().add_source(config::File::from(config_file)).build().unwrap();
+        let settings_map = settings.try_deserialize::<HashMap<String, config::Value>>().unwrap();
+
+        if let Some(images_dir) = settings_map.get(CONFIG_KEY_IMAGES_DIR) {
+            server_config.images_dir = images_dir.clone().into_string().unwrap();
+        }
+
+        if let Some(encryption) = settings_map.get(CONFIG_KEY_ENCRYPTION) {
+            for (group, key_file) in encryption.clone().into_table().unwrap() {
+                let key_file = key_file.into_string().unwrap();
+                let key = ImageKey::load(Path::new(&key_file))
+                    .unwrap_or_else(|e| panic!("Failed to load key file {} for group {}: {}", key_file, group, e));
+                server_config.key_fingerprints.insert(group, key.fingerprint());
+            }
+        }
+
+        if let Some(storage) = settings_map.get(CONFIG_KEY_STORAGE) {
+            server_config.storage = parse_storage_config(storage.clone().into_table().unwrap());
+        }
+
+        if let Some(discovery) = settings_map.get(CONFIG_KEY_DEPENDENCY_DISCOVERY) {
+            server_config.dependency_discovery = match discovery.clone().into_string().unwrap().as_str() {
+                "off" => DependencyDiscovery::Off,
+                "report" => DependencyDiscovery::Report,
+                "add" => DependencyDiscovery::Add,
+                other => panic!("Unknown dependency discovery {:?} in server config", other),
+            };
+        }
+
+        if let Some(timeout) = settings_map.get(CONFIG_KEY_HEARTBEAT_TIMEOUT) {
+            server_config.heartbeat_timeout = match timeout.clone().into_uint() {
+                Ok(timeout) if timeout > 0 => timeout,
+                _ => panic!("Invalid heartbeat timeout {} in server config", timeout),
+            };
+        }
+
+        if let Some(timeouts) = settings_map.get(CONFIG_KEY_TIMEOUTS) {
+            for (key, value) in timeouts.clone().into_table().unwrap() {
+                if key == "groups" {
+                    for (group, timeouts) in value.into_table().unwrap() {
+                        server_config.group_timeouts.insert(group, parse_timeouts(timeouts.into_table().unwrap()));
+                    }
+                } else {
+                    server_config.timeouts.extend(parse_timeouts(HashMap::from([(key, value)])));
+                }
+            }
+        }
+
+        server_config
+    }
+
+    pub fn get_images_dir(&self) -> &str {
+        &self.images_dir
+    }
+
+    /// Fingerprint of the key that must be used to encrypt the images of
+    /// `group`, or `None` if the images of the group are not encrypted.
+    pub fn get_key_fingerprint(&self, group: &str) -> Option<&str> {
+        self.key_fingerprints.get(group).map(String::as_str)
+    }
+
+    pub fn get_dependency_discovery(&self) -> DependencyDiscovery {
+        self.dependency_discovery
+    }
+
+    pub fn get_heartbeat_timeout(&self) -> u64 {
+        self.heartbeat_timeout
+    }
+
+    /// Timeout of `action` for the clients of `group`, if one is configured.
+    pub fn get_timeout(&self, group: &str, action: &str) -> Option<u64> {
+        self.group_timeouts.get(group)
+            .and_then(|timeouts| timeouts.get(action))
+            .or_else(|| self.timeouts.get(action))
+            .copied()
+    }
+
+    /// Create the storage backend selected in the configuration.
+    pub fn open_storage(&self) -> Arc<dyn Storage> {
+        match &self.storage {
+            StorageConfig::Local { path } => {
+                let path = path.as_deref().unwrap_or(&self.images_dir);
+                Arc::new(LocalStorage::new(Path::new(path)))
+            }
+            StorageConfig::S3(s3_config) => Arc::new(S3Storage::new(s3_config.clone())),
+        }
+    }
+}
+
+fn parse_timeouts(settings: HashMap<String, config::Value>) -> Timeouts {
+    settings.into_iter()
+        .map(|(action, timeout)| match timeout.clone().into_uint() {
+            Ok(timeout) if timeout > 0 => (action, timeout),
+            _ => panic!("Invalid timeout {} for {:?} in server config", timeout, action),
+        })
+        .collect()
+}
+
+fn parse_storage_config(settings: HashMap<String, config::Value>) -> StorageConfig {
+    let get = |key: &str| settings.get(key).map(|value| value.clone().into_string().unwrap());
+
+    match get("type").as_deref() {
+        None | Some("local") => StorageConfig::Local { path: get("path") },
+        Some("s3") => {
+            let required = |key: &str| get(key).unwrap_or_else(|| panic!("S3 storage requires {:?} in server config", key));
+            let credential = |key: &str, env_var: &str| get(key).or_else(|| env::var(env_var).ok())
+                .unwrap_or_else(|| panic!("S3 storage requires {:?} in server config or {} to be set", key, env_var));
+            StorageConfig::S3(S3Config {
+                endpoint: required("endpoint"),
+                bucket: required("bucket"),
+                region: get("region").unwrap_or_else(|| DEFAULT_S3_REGION.to_string()),
+                access_key: credential("access-key", "AWS_ACCESS_KEY_ID"),
+                secret_key: credential("secret-key", "AWS_SECRET_ACCESS_KEY"),
+            })
+        }
+        Some(other) => panic!("Unknown storage type {:?} in server config", other),
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+//! Dump or restore of the clients of a group, as a whole.
+//!
+//! An operation of a group starts with the first of its clients and ends
+//! once every client that joined it has finished, successfully or not. The
+//! failures of an operation only affect the clients of that operation: a
+//! client that joins after the operation has ended, or a member that starts
+//! over, begins a new one.
+
+use std::collections::BTreeSet;
+
+#[derive(Default)]
+pub struct GroupOperation {
+    /// Number of the operation in its group.
+    pub number: u64,
+    /// Clients that joined the operation.
+    pub members: BTreeSet<String>,
+    /// Members that have finished the operation.
+    pub finished: BTreeSet<String>,
+    /// Members whose operation failed.
+    pub failed: BTreeSet<String>,
+    /// Number of the previous operation, and whether it failed.
+    previous: Option<(u64, bool)>,
+}
+
+impl GroupOperation {
+    /// Add `client` to the operation. Returns whether a new operation was
+    /// started, because the client was already a member or every member
+    /// has finished.
+    pub fn join(&mut self, client: &str) -> bool {
+        let is_new = self.members.contains(client) || self.is_complete();
+        if is_new {
+            // An operation that a member starts over has failed.
+            let previous = (self.number, !self.failed.is_empty() || !self.is_complete());
+            *self = Self { number: self.number + 1, previous: Some(previous), ..Self::default() };
+        }
+        self.members.insert(client.to_string());
+        is_new
+    }
+
+    pub fn finish(&mut self, client: &str) {
+        self.finished.insert(client.to_string());
+    }
+
+    pub fn fail(&mut self, client: &str) {
+        // A complete operation keeps its outcome.
+        if self.is_complete() {
+            self.join(client);
+        }
+        self.members.insert(client.to_string());
+        self.failed.insert(client.to_string());
+        self.finish(client);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.members.is_subset(&self.finished)
+    }
+
+    /// Whether operation `number` failed, once it is complete. The members
+    /// of the previous operation may learn its outcome after the next one
+    /// has started, and older operations are considered failed.
+    pub fn outcome(&self, number: u64) -> Option<bool> {
+        if number == self.number {
+            return self.is_complete().then_some(!self.failed.is_empty());
+        }
+        match self.previous {
+            Some((previous, failed)) if previous == number => Some(failed),
+            _ => Some(true),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+use std::io::Result;
+use json::{object, JsonValue};
+
+use crate::constants::MANIFEST_FILE;
+use crate::pipeline::digest::DIGEST_ALGORITHM;
+use crate::timeline::unix_time;
+use super::storage::{object_key, Storage};
+
+/// ManifestEntry describes a single verified image file.
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub digest: String,
+}
+
+/// Manifest records the image files of a checkpoint after the server
+/// has verified their size and digest.
+pub struct Manifest {
+    id: String,
+    epoch: String,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(id: &str, epoch: &str) -> Self {
+        Self { id: id.to_string(), epoch: epoch.to_string(), entries: Vec::new() }
+    }
+
+    pub fn add(&mut self, entry: ManifestEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let created = unix_time();
+        let mut files = JsonValue::new_array();
+        for entry in self.entries.iter() {
+            files.push(object!{
+                name: entry.name.clone(),
+                size: entry.size,
+                digest: entry.digest.clone(),
+            }).unwrap();
+        }
+
+        object!{
+            id: self.id.clone(),
+            epoch: self.epoch.clone(),
+            algorithm: DIGEST_ALGORITHM,
+            created: created,
+            files: files,
+        }
+    }
+
+    /// Store the manifest next to the images of the checkpoint.
+    pub fn store(&self, storage: &dyn Storage) -> Result<()> {
+        let data = self.to_json().pretty(4);
+        storage.put(&object_key(&self.epoch, &self.id, MANIFEST_FILE), &mut data.as_bytes(), data.len() as u64)
+    }
+}
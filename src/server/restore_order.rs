@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+//! Order in which the clients of a group are restored.
+//!
+//! A client that depends on another with an ordered edge is only released
+//! from `pre-restore` once that dependency has resumed. The clients of a group
+//! are thereby restored in stages, where each stage holds the clients whose
+//! ordered dependencies are all in earlier stages.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Ordered dependencies of each client of a group.
+#[derive(Default)]
+pub struct RestoreOrder {
+    edges: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl RestoreOrder {
+    /// Replace the ordered dependencies of `client`.
+    pub fn set(&mut self, client: &str, dependencies: BTreeSet<String>) {
+        if dependencies.is_empty() {
+            self.edges.remove(client);
+        } else {
+            self.edges.insert(client.to_string(), dependencies);
+        }
+    }
+
+    /// Stages of the group, from the first to be restored to the last.
+    /// A cycle of ordered dependencies cannot be restored, and is returned
+    /// as the error, starting and ending with the same client.
+    pub fn stages(&self) -> Result<Vec<Vec<String>>, Vec<String>> {
+        let mut clients: BTreeSet<&str> = self.edges.keys().map(String::as_str).collect();
+        clients.extend(self.edges.values().flatten().map(String::as_str));
+
+        let mut stages: Vec<Vec<String>> = Vec::new();
+        let mut placed: BTreeSet<&str> = BTreeSet::new();
+        while placed.len() < clients.len() {
+            let stage: Vec<&str> = clients.iter()
+                .filter(|client| !placed.contains(*client))
+                .filter(|client| self.dependencies(client).all(|dependency| placed.contains(dependency)))
+                .copied()
+                .collect();
+            if stage.is_empty() {
+                return Err(self.find_cycle(&placed));
+            }
+            placed.extend(stage.iter().copied());
+            stages.push(stage.into_iter().map(str::to_string).collect());
+        }
+        Ok(stages)
+    }
+
+    /// Stage of `client`, counting from 1.
+    pub fn stage_of(stages: &[Vec<String>], client: &str) -> usize {
+        stages.iter().position(|stage| stage.iter().any(|c| c == client)).map_or(1, |i| i + 1)
+    }
+
+    fn dependencies<'a>(&'a self, client: &str) -> impl Iterator<Item = &'a str> {
+        self.edges.get(client).into_iter().flatten().map(String::as_str)
+    }
+
+    /// Follow the dependencies of the clients that could not be placed in a
+    /// stage until a client repeats.
+    fn find_cycle(&self, placed: &BTreeSet<&str>) -> Vec<String> {
+        let mut path: Vec<&str> = Vec::new();
+        let mut client = self.edges.keys()
+            .map(String::as_str)
+            .find(|client| !placed.contains(client))
+            .unwrap_or_default();
+        while !path.contains(&client) {
+            path.push(client);
+            client = match self.dependencies(client).find(|dependency| !placed.contains(dependency)) {
+                Some(dependency) => dependency,
+                None => break,
+            };
+        }
+        let start = path.iter().position(|c| *c == client).unwrap_or(0);
+        let mut cycle: Vec<String> = path[start..].iter().map(|c| c.to_string()).collect();
+        cycle.push(client.to_string());
+        cycle
+    }
+}
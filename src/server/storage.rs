@@ -0,0 +1,60 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Storage backends for the checkpoint images committed by the server.
+//!
+//! Images are stored as objects named `<epoch>/<client id>/<image name>`,
+//! where the epoch identifies the global checkpoint of a group. The manifest
+//! of a client is stored after its images, and the catalog entry of an
+//! epoch is stored once every member of the group has been stored.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub mod local;
+pub mod s3;
+
+/// Storage is an object store for checkpoint images.
+pub trait Storage: Send + Sync {
+    /// Store the `size` bytes read from `src` as the object `key`,
+    /// replacing any existing object.
+    fn put(&self, key: &str, src: &mut dyn Read, size: u64) -> Result<()>;
+
+    /// Write the content of the object `key` into `dst`.
+    fn get(&self, key: &str, dst: &mut dyn Write) -> Result<u64>;
+
+    /// Return the keys of all objects that start with `prefix`, sorted.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Remove the object `key`. Removing a missing object is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Name of the object that holds the image `name` of client `id`
+/// in the global checkpoint `epoch`.
+pub fn object_key(epoch: &str, id: &str, name: &str) -> String {
+    format!("{epoch}/{id}/{name}")
+}
+
+/// Keys are relative paths without empty, `.` or `..` components.
+pub fn check_key(key: &str) -> Result<()> {
+    if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid object key {key:?}")));
+    }
+    Ok(())
+}
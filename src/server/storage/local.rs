@@ -0,0 +1,113 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Storage of checkpoint images in a local directory.
+
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Result, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{check_key, Storage};
+
+/// LocalStorage stores every object as a file below `root`.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf() }
+    }
+
+    fn object_path(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+
+    fn list_dir(&self, dir: &Path, keys: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            // Hidden entries hold incomplete objects and uploads in progress.
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                self.list_dir(&path, keys)?;
+            } else if let Ok(key) = path.strip_prefix(&self.root) {
+                keys.push(key.to_string_lossy().to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, src: &mut dyn Read, size: u64) -> Result<()> {
+        let path = self.object_path(key)?;
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        // The object is renamed into place once it is complete.
+        let tmp_path = dir.join(format!(".{}.tmp", path.file_name().unwrap().to_string_lossy()));
+        let mut file = File::create(&tmp_path)?;
+        let copied = io::copy(&mut src.take(size), &mut file)?;
+        if copied != size {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("{key} is truncated")));
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn get(&self, key: &str, dst: &mut dyn Write) -> Result<u64> {
+        let mut file = File::open(self.object_path(key)?)?;
+        io::copy(&mut file, dst)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        match self.list_dir(&self.root, &mut keys) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.object_path(key)?;
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Remove directories that no longer hold any object.
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != self.root) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+}
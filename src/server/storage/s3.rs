@@ -0,0 +1,244 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Storage of checkpoint images in an S3-compatible object store.
+//!
+//! Requests use path-style addressing (`<endpoint>/<bucket>/<key>`), which
+//! is supported by AWS S3 as well as MinIO and other self-hosted stores,
+//! and are authenticated with AWS Signature Version 4.
+
+use std::{
+    io::{self, Error, ErrorKind, Read, Result, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::pipeline::digest::encode_hex;
+use super::{check_key, Storage};
+
+const SERVICE: &str = "s3";
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+/// Uploads are streamed, so their content is not part of the signature.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// S3Config holds the settings of an S3-compatible object store.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// Base URL of the object store, e.g. `https://s3.eu-west-1.amazonaws.com`.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// S3Storage stores every object in a bucket of an S3-compatible store.
+pub struct S3Storage {
+    config: S3Config,
+    host: String,
+    agent: ureq::Agent,
+}
+
+impl S3Storage {
+    pub fn new(mut config: S3Config) -> Self {
+        config.endpoint = config.endpoint.trim_end_matches('/').to_string();
+        let authority = config.endpoint.split_once("://").map_or(config.endpoint.as_str(), |(_, rest)| rest);
+        let host = authority.split('/').next().unwrap_or_default().to_string();
+        let agent = ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT).build();
+        Self { config, host, agent }
+    }
+
+    fn object_path(&self, key: &str) -> Result<String> {
+        check_key(key)?;
+        Ok(format!("/{}/{}", uri_encode(&self.config.bucket, false), uri_encode(key, true)))
+    }
+
+    /// Create a request signed with AWS Signature Version 4.
+    fn request(&self, method: &str, path: &str, query: &[(&str, &str)], payload_hash: &str) -> ureq::Request {
+        let (date, timestamp) = format_timestamp(SystemTime::now());
+
+        let mut query: Vec<String> = query.iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\n{SIGNED_HEADERS}\n{payload_hash}",
+            self.host
+        );
+        let scope = format!("{date}/{}/{SERVICE}/aws4_request", self.config.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}", sha256_hex(canonical_request.as_bytes()));
+
+        let mut signing_key = hmac_sha256(format!("AWS4{}", self.config.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.config.region.as_str(), SERVICE, "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = encode_hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={SIGNED_HEADERS}, Signature={signature}",
+            self.config.access_key
+        );
+
+        let mut url = format!("{}{path}", self.config.endpoint);
+        if !query.is_empty() {
+            url = format!("{url}?{query}");
+        }
+        self.agent.request(method, &url)
+            .set("x-amz-date", &timestamp)
+            .set("x-amz-content-sha256", payload_hash)
+            .set("authorization", &authorization)
+    }
+}
+
+impl Storage for S3Storage {
+    fn put(&self, key: &str, src: &mut dyn Read, size: u64) -> Result<()> {
+        let path = self.object_path(key)?;
+        self.request("PUT", &path, &[], UNSIGNED_PAYLOAD)
+            .set("content-length", &size.to_string())
+            .send(src.take(size))
+            .map_err(|e| to_io_error(key, e))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str, dst: &mut dyn Write) -> Result<u64> {
+        let path = self.object_path(key)?;
+        let response = self.request("GET", &path, &[], &sha256_hex(b""))
+            .call()
+            .map_err(|e| to_io_error(key, e))?;
+        io::copy(&mut response.into_reader(), dst)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let path = format!("/{}", uri_encode(&self.config.bucket, false));
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = continuation_token.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            let body = self.request("GET", &path, &query, &sha256_hex(b""))
+                .call()
+                .map_err(|e| to_io_error(&self.config.bucket, e))?
+                .into_string()?;
+
+            keys.extend(xml_elements(&body, "Key"));
+            continuation_token = match xml_elements(&body, "IsTruncated").first().map(String::as_str) {
+                Some("true") => xml_elements(&body, "NextContinuationToken").pop(),
+                _ => None,
+            };
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.object_path(key)?;
+        match self.request("DELETE", &path, &[], &sha256_hex(b"")).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(e) => Err(to_io_error(key, e)),
+        }
+    }
+}
+
+fn to_io_error(key: &str, error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(404, _) => Error::new(ErrorKind::NotFound, format!("{key} does not exist")),
+        ureq::Error::Status(status, response) => {
+            let code = xml_elements(&response.into_string().unwrap_or_default(), "Code").pop().unwrap_or_default();
+            Error::other(format!("Request for {key} failed with status {status} {code}"))
+        }
+        ureq::Error::Transport(e) => Error::new(ErrorKind::ConnectionAborted, format!("Request for {key} failed: {e}")),
+    }
+}
+
+/// Percent-encode `s` as required by Signature Version 4.
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Return the date (`YYYYMMDD`) and timestamp (`YYYYMMDDTHHMMSSZ`) in UTC.
+fn format_timestamp(time: SystemTime) -> (String, String) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert days since the epoch to a civil date (proleptic Gregorian calendar).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!("{date}T{:02}{:02}{:02}Z", secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60);
+    (date, timestamp)
+}
+
+/// Return the text of all `<tag>` elements in an XML response.
+fn xml_elements(body: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut values = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(xml_unescape(&rest[..end]));
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    encode_hex(&Sha256::digest(data))
+}
+
@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    net::TcpStream,
+    path::{Path, PathBuf},
+};
+use json::JsonValue;
+
+use crate::pipeline::marker::is_valid_image_name;
+
+use super::manifest::{Manifest, ManifestEntry};
+use super::storage::{object_key, Storage};
+
+/// UploadFile tracks an image file that the client has announced.
+pub struct UploadFile {
+    pub size: u64,
+    pub digest: String,
+    /// The file has been received completely and its digest matches.
+    /// Files received over the live stream are checked again against the
+    /// digest announced by the client once the plan arrives.
+    pub verified: bool,
+    /// The data connection that is currently receiving the file. A new
+    /// connection for the file shuts it down and takes over, so that a
+    /// stalled upload does not hold the file.
+    pub receiver: Option<TcpStream>,
+}
+
+/// UploadSession tracks the image files of a checkpoint that are uploaded
+/// over data connections. Files are received into a staging directory.
+/// Partially received files are kept there, so an interrupted upload
+/// resumes from the last received byte.
+pub struct UploadSession {
+    /// Global checkpoint that the images belong to.
+    pub epoch: String,
+    pub images_dir: PathBuf,
+    pub key_fingerprint: Option<String>,
+    pub files: BTreeMap<String, UploadFile>,
+}
+
+impl UploadSession {
+    pub fn new(epoch: &str, images_dir: &Path, key_fingerprint: Option<&str>) -> Self {
+        Self {
+            epoch: epoch.to_string(),
+            images_dir: images_dir.to_path_buf(),
+            key_fingerprint: key_fingerprint.map(str::to_string),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Apply the list of images announced by the client:
+    /// `[{"img_name": ..., "img_size": ..., "img_digest": ...}, ...]`
+    /// Files that were already received over the live stream are kept if
+    /// their size and digest match. Any other file must be uploaded.
+    pub fn apply_plan(&mut self, plan: &JsonValue) -> Result<(), String> {
+        let mut files = BTreeMap::new();
+        for image in plan.members() {
+            match (image["img_name"].as_str(), image["img_size"].as_u64(), image["img_digest"].as_str()) {
+                (Some(name), Some(size), Some(digest)) if is_valid_image_name(name) => {
+                    let verified = self.files.get(name)
+                        .is_some_and(|file| file.verified && file.size == size && file.digest == digest);
+                    files.insert(name.to_string(), UploadFile {
+                        size,
+                        digest: digest.to_string(),
+                        verified,
+                        receiver: None,
+                    });
+                }
+                _ => return Err(format!("Invalid image entry: {}", image.dump())),
+            }
+        }
+
+        // Remove the received files that do not match the plan.
+        for (name, file) in self.files.iter() {
+            if file.verified && !files.get(name).is_some_and(|planned| planned.verified) {
+                let _ = fs::remove_file(self.image_path(name));
+            }
+        }
+        self.files = files;
+        Ok(())
+    }
+
+    /// Path where the image is staged once verified.
+    pub fn image_path(&self, name: &str) -> PathBuf {
+        self.images_dir.join(name)
+    }
+
+    /// Path where a partially received image is kept.
+    pub fn partial_path(&self, name: &str) -> PathBuf {
+        self.images_dir.join(format!(".{name}.part"))
+    }
+
+    pub fn unverified_files(&self) -> Vec<&str> {
+        self.files.iter().filter(|(_, file)| !file.verified).map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Remove the staged files of an upload that was abandoned.
+    pub fn discard(&self) {
+        let _ = fs::remove_dir_all(&self.images_dir);
+    }
+
+    /// Move the verified images of client `id` into `storage`, followed by
+    /// the manifest. Returns the total size of the images.
+    pub fn commit(&self, id: &str, storage: &dyn Storage) -> io::Result<u64> {
+        for (name, file) in self.files.iter() {
+            let mut src = File::open(self.image_path(name))?;
+            storage.put(&object_key(&self.epoch, id, name), &mut src, file.size)?;
+        }
+        self.to_manifest(id).store(storage)?;
+        self.discard();
+        Ok(self.files.values().map(|file| file.size).sum())
+    }
+
+    pub fn to_manifest(&self, id: &str) -> Manifest {
+        let mut manifest = Manifest::new(id, &self.epoch);
+        for (name, file) in self.files.iter() {
+            manifest.add(ManifestEntry { name: name.clone(), size: file.size, digest: file.digest.clone() });
+        }
+        manifest
+    }
+}
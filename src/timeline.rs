@@ -0,0 +1,255 @@
+/*
+ * Copyright (c) 2023 University of Oxford.
+ * Copyright (c) 2023 Red Hat, Inc.
+ * All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+//! Timeline of the actions of the clients of a group during a checkpoint or
+//! a restore, recorded by the server.
+//!
+//! Each action is a span from the time the server received it until the
+//! connection was closed. For actions at which clients wait for their
+//! dependencies, the client that arrived last is the straggler that held
+//! back the others. The stragglers of all phases form the critical path.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use json::{object, JsonValue};
+
+use crate::constants::*;
+
+/// Actions at which clients wait for their dependencies.
+const BARRIER_ACTIONS: [&str; 8] = [
+    ACTION_PRE_DUMP,
+    ACTION_PRE_STREAM,
+    ACTION_QUIESCE,
+    ACTION_FREEZE,
+    ACTION_NETWORK_LOCK,
+    ACTION_POST_DUMP,
+    ACTION_PRE_RESTORE,
+    ACTION_NETWORK_UNLOCK,
+];
+
+/// Current time in microseconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+/// Current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Span of an action of a client, in microseconds since the Unix epoch.
+pub struct Span {
+    pub client: String,
+    pub action: String,
+    pub start: u64,
+    /// `None` until the connection of the action is closed.
+    pub end: Option<u64>,
+}
+
+/// Phase groups the spans of an action.
+pub struct Phase<'a> {
+    pub action: &'a str,
+    pub spans: Vec<&'a Span>,
+    /// Last client to reach the barrier of the phase, or to finish it.
+    pub straggler: &'a Span,
+}
+
+pub struct Timeline {
+    /// The epoch of the global checkpoint, if the images were streamed to the
+    /// server, or an ID of the same form.
+    pub id: String,
+    pub group: String,
+    pub spans: Vec<Span>,
+    /// Time at which each client froze its cgroup.
+    pub frozen: BTreeMap<String, u64>,
+    /// Optional dependencies, or members missing a quorum, that the group
+    /// proceeded without.
+    pub excluded: BTreeSet<String>,
+}
+
+impl Timeline {
+    pub fn new(id: &str, group: &str) -> Self {
+        Self { id: id.to_string(), group: group.to_string(), spans: Vec::new(), frozen: BTreeMap::new(), excluded: BTreeSet::new() }
+    }
+
+    pub fn clients(&self) -> BTreeSet<&str> {
+        self.spans.iter().map(|span| span.client.as_str()).collect()
+    }
+
+    pub fn started(&self) -> u64 {
+        self.spans.iter().map(|span| span.start).min().unwrap_or(0)
+    }
+
+    pub fn finished(&self) -> u64 {
+        self.spans.iter().map(|span| span.end.unwrap_or(span.start)).max().unwrap_or(0)
+    }
+
+    /// Time between the first and the last client freezing its cgroup.
+    pub fn freeze_skew(&self) -> Option<u64> {
+        Some(self.frozen.values().max()? - self.frozen.values().min()?)
+    }
+
+    /// Phases in the order in which they started.
+    pub fn phases(&self) -> Vec<Phase<'_>> {
+        let mut actions: Vec<&str> = Vec::new();
+        for span in self.spans.iter() {
+            if !actions.contains(&span.action.as_str()) {
+                actions.push(&span.action);
+            }
+        }
+        let mut phases: Vec<Phase> = actions.into_iter()
+            .map(|action| {
+                let mut spans: Vec<&Span> = self.spans.iter().filter(|span| span.action == action).collect();
+                spans.sort_by_key(|span| (span.start, span.client.clone()));
+                let straggler = match BARRIER_ACTIONS.contains(&action) {
+                    true => *spans.last().unwrap(),
+                    false => *spans.iter().max_by_key(|span| span.end.unwrap_or(u64::MAX)).unwrap(),
+                };
+                Phase { action, spans, straggler }
+            })
+            .collect();
+        phases.sort_by_key(|phase| phase.spans[0].start);
+        phases
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let spans: Vec<JsonValue> = self.spans.iter()
+            .map(|span| object!{ client: span.client.clone(), action: span.action.clone(), start: span.start, end: span.end })
+            .collect();
+        let mut frozen = JsonValue::new_object();
+        for (client, time) in self.frozen.iter() {
+            frozen[client.as_str()] = (*time).into();
+        }
+        object!{
+            id: self.id.clone(),
+            group: self.group.clone(),
+            started: self.started(),
+            finished: self.finished(),
+            spans: spans,
+            frozen: frozen,
+            excluded: self.excluded.iter().cloned().collect::<Vec<_>>(),
+        }
+    }
+
+    pub fn from_json(data: &JsonValue) -> Option<Self> {
+        let mut timeline = Self::new(data["id"].as_str()?, data["group"].as_str()?);
+        for span in data["spans"].members() {
+            timeline.spans.push(Span {
+                client: span["client"].as_str()?.to_string(),
+                action: span["action"].as_str()?.to_string(),
+                start: span["start"].as_u64()?,
+                end: span["end"].as_u64(),
+            });
+        }
+        for (client, time) in data["frozen"].entries() {
+            timeline.frozen.insert(client.to_string(), time.as_u64()?);
+        }
+        for client in data["excluded"].members() {
+            timeline.excluded.insert(client.as_str()?.to_string());
+        }
+        Some(timeline)
+    }
+
+    /// Stragglers of all phases, as `action (client)`.
+    pub fn critical_path(&self) -> String {
+        self.phases().iter()
+            .map(|phase| format!("{} ({})", phase.action, phase.straggler.client))
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    /// Report of the phases of every client, relative to the first action.
+    pub fn report(&self) -> String {
+        let origin = self.started();
+        let mut report = String::new();
+        let _ = writeln!(report, "Timeline {} of group {}", self.id, self.group);
+        let _ = write!(report, "Duration: {}", format_duration(self.finished() - origin));
+        if let Some(skew) = self.freeze_skew() {
+            let _ = write!(report, ", freeze skew: {}", format_duration(skew));
+        }
+        let _ = writeln!(report, "\n");
+
+        let _ = writeln!(report, "{:<16} {:<24} {:>10} {:>10}", "PHASE", "CLIENT", "START", "DURATION");
+        for phase in self.phases() {
+            for span in phase.spans.iter() {
+                let marker = if std::ptr::eq(*span, phase.straggler) { " *" } else { "" };
+                let _ = writeln!(
+                    report,
+                    "{:<16} {:<24} {:>10} {:>10}",
+                    phase.action,
+                    format!("{}{}", span.client, marker),
+                    format!("+{}", format_duration(span.start - origin)),
+                    span.end.map(|end| format_duration(end - span.start)).unwrap_or_else(|| "-".to_string()),
+                );
+            }
+        }
+        let _ = writeln!(report, "\n* Last client to reach the barrier, or to finish the phase");
+        let _ = writeln!(report, "Critical path: {}", self.critical_path());
+        if !self.excluded.is_empty() {
+            let _ = writeln!(report, "Excluded: {}", self.excluded.iter().cloned().collect::<Vec<_>>().join(", "));
+        }
+        report
+    }
+
+    /// Events of the Chrome trace event format, which can be opened in
+    /// chrome://tracing or Perfetto. Every client is shown as a thread and
+    /// the spans on the critical path are highlighted.
+    pub fn to_chrome_trace(&self) -> JsonValue {
+        let clients: Vec<&str> = self.clients().into_iter().collect();
+        let tid = |client: &str| clients.iter().position(|c| *c == client).unwrap_or(0);
+        let phases = self.phases();
+
+        let mut events = Vec::new();
+        events.push(object!{ name: "process_name", ph: "M", pid: 1, args: object!{ name: format!("{} {}", self.group, self.id) } });
+        for (i, client) in clients.iter().enumerate() {
+            events.push(object!{ name: "thread_name", ph: "M", pid: 1, tid: i, args: object!{ name: *client } });
+        }
+        for span in self.spans.iter() {
+            let mut event = object!{
+                name: span.action.clone(),
+                cat: "phase",
+                ph: "X",
+                ts: span.start,
+                dur: span.end.unwrap_or(span.start) - span.start,
+                pid: 1,
+                tid: tid(&span.client),
+            };
+            if phases.iter().any(|phase| std::ptr::eq(phase.straggler, span)) {
+                event["cname"] = "terrible".into();
+                event["args"] = object!{ critical: true };
+            }
+            events.push(event);
+        }
+        for (client, time) in self.frozen.iter() {
+            events.push(object!{ name: "frozen", cat: "freeze", ph: "i", s: "t", ts: *time, pid: 1, tid: tid(client) });
+        }
+        object!{ traceEvents: events, displayTimeUnit: "ms" }
+    }
+}
+
+fn format_duration(micros: u64) -> String {
+    match micros {
+        0..=999 => format!("{micros} us"),
+        1_000..=999_999 => format!("{:.1} ms", micros as f64 / 1e3),
+        _ => format!("{:.3} s", micros as f64 / 1e6),
+    }
+}
@@ -0,0 +1,103 @@
+use criu_coordinator::constants::*;
+use criu_coordinator::phase::{default_hook_mode, transition, HookMode, Operation, Phase};
+
+/// Phase reached by a client that sends `actions` in order.
+fn run(actions: &[&str]) -> Result<(Operation, Phase), String> {
+    let mut current = None;
+    for action in actions {
+        current = Some(transition(current, action)?);
+    }
+    Ok(current.unwrap())
+}
+
+#[test]
+fn dump_stream_and_restore_flows_are_accepted() {
+    assert_eq!(run(&[ACTION_PRE_DUMP]), Ok((Operation::Dump, Phase::Connected)));
+
+    // The ready and frozen phases are reached within pre-dump and freeze.
+    let ready = Some((Operation::Dump, Phase::Ready));
+    assert_eq!(transition(ready, ACTION_FREEZE), Ok((Operation::Dump, Phase::Freezing)));
+    assert_eq!(transition(ready, ACTION_QUIESCE), Ok((Operation::Dump, Phase::Quiesced)));
+    let quiesced = Some((Operation::Dump, Phase::Quiesced));
+    assert_eq!(transition(quiesced, ACTION_FREEZE), Ok((Operation::Dump, Phase::Freezing)));
+    assert_eq!(transition(quiesced, ACTION_POST_DUMP), Ok((Operation::Dump, Phase::Checkpointed)));
+    let frozen = Some((Operation::Dump, Phase::Frozen));
+    assert_eq!(transition(frozen, ACTION_NETWORK_LOCK), Ok((Operation::Dump, Phase::NetworkLocked)));
+    let locked = Some((Operation::Dump, Phase::NetworkLocked));
+    assert_eq!(transition(locked, ACTION_POST_DUMP), Ok((Operation::Dump, Phase::Checkpointed)));
+    assert_eq!(transition(ready, ACTION_POST_DUMP), Ok((Operation::Dump, Phase::Checkpointed)));
+
+    let streaming = Some((Operation::Dump, Phase::Streaming));
+    assert_eq!(transition(streaming, ACTION_POST_STREAM), Ok((Operation::Dump, Phase::Checkpointed)));
+    assert_eq!(transition(streaming, ACTION_POST_DUMP), Ok((Operation::Dump, Phase::Checkpointed)));
+
+    let restoring = Some((Operation::Restore, Phase::Ready));
+    assert_eq!(transition(restoring, ACTION_POST_RESTORE), Ok((Operation::Restore, Phase::Restored)));
+    let restored = Some((Operation::Restore, Phase::Restored));
+    assert_eq!(transition(restored, ACTION_NETWORK_UNLOCK), Ok((Operation::Restore, Phase::NetworkUnlocked)));
+    let unlocked = Some((Operation::Restore, Phase::NetworkUnlocked));
+    assert_eq!(transition(unlocked, ACTION_POST_RESUME), Ok((Operation::Restore, Phase::Resumed)));
+    assert!(Phase::Resumed.is_final() && Phase::Checkpointed.is_final());
+
+    // A new operation can start in any phase.
+    assert_eq!(transition(unlocked, ACTION_PRE_DUMP), Ok((Operation::Dump, Phase::Connected)));
+}
+
+#[test]
+fn out_of_order_actions_are_rejected() {
+    assert_eq!(
+        run(&[ACTION_POST_DUMP]),
+        Err(format!("{MESSAGE_NOT_CONNECTED}: post-dump without a dump or restore in progress"))
+    );
+    // Only the ready phase is entered within pre-dump.
+    assert_eq!(
+        run(&[ACTION_PRE_DUMP, ACTION_POST_DUMP]),
+        Err(format!("{MESSAGE_INVALID_ACTION}: post-dump in phase connected of dump"))
+    );
+
+    let ready = Some((Operation::Dump, Phase::Ready));
+    assert_eq!(
+        transition(ready, ACTION_NETWORK_UNLOCK),
+        Err(format!("{MESSAGE_INVALID_ACTION}: network-unlock in phase ready of dump"))
+    );
+    assert_eq!(
+        transition(ready, ACTION_POST_RESTORE),
+        Err(format!("{MESSAGE_INVALID_ACTION}: post-restore in phase ready of dump"))
+    );
+    let locked = Some((Operation::Dump, Phase::NetworkLocked));
+    assert_eq!(
+        transition(locked, ACTION_NETWORK_LOCK),
+        Err(format!("{MESSAGE_INVALID_ACTION}: network-lock in phase network locked of dump"))
+    );
+    let frozen = Some((Operation::Dump, Phase::Frozen));
+    assert_eq!(
+        transition(frozen, ACTION_QUIESCE),
+        Err(format!("{MESSAGE_INVALID_ACTION}: quiesce in phase frozen of dump"))
+    );
+    let checkpointed = Some((Operation::Dump, Phase::Checkpointed));
+    assert_eq!(transition(checkpointed, ACTION_POST_DUMP), Err(MESSAGE_CHECKPOINT_EXISTS.to_string()));
+
+    let restoring = Some((Operation::Restore, Phase::Ready));
+    assert_eq!(
+        transition(restoring, ACTION_FREEZE),
+        Err(format!("{MESSAGE_INVALID_ACTION}: freeze in phase ready of restore"))
+    );
+}
+
+#[test]
+fn other_hooks_keep_the_phase_of_their_operation() {
+    let restoring = Some((Operation::Restore, Phase::Ready));
+    for hook in [ACTION_SETUP_NAMESPACES, ACTION_POST_SETUP_NAMESPACES, ACTION_ORPHAN_PTS_MASTER, ACTION_PRE_RESUME] {
+        assert_eq!(transition(restoring, hook), Ok((Operation::Restore, Phase::Ready)));
+    }
+    let dumping = Some((Operation::Dump, Phase::NetworkLocked));
+    assert_eq!(transition(dumping, ACTION_QUERY_EXT_FILES), Ok((Operation::Dump, Phase::NetworkLocked)));
+    assert_eq!(
+        transition(dumping, ACTION_PRE_RESUME),
+        Err(format!("{MESSAGE_INVALID_ACTION}: pre-resume in phase network locked of dump"))
+    );
+
+    assert_eq!(default_hook_mode(ACTION_PRE_RESUME), Some(HookMode::Notify));
+    assert_eq!(default_hook_mode(ACTION_QUERY_EXT_FILES), Some(HookMode::Ignore));
+    assert_eq!(default_hook_mode(ACTION_PRE_DUMP), None);
+}
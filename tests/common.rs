@@ -1,10 +1,15 @@
 use std::{
+    fs,
+    io::{Read, Write},
     net::{TcpListener, TcpStream},
+    path::Path,
     process::{Child, Command, Stdio},
     thread,
     time::Duration,
 };
 
+use prost::Message;
+
 pub const CRIU_COORDINATOR_PATH: &str = "target/debug/criu-coordinator";
 
 pub fn pick_port() -> u16 {
@@ -26,6 +31,10 @@ pub fn server_ready(addr: &str, retries: u32) -> bool {
 }
 
 pub fn spawn_server(port: u16) -> Child {
+    spawn_server_with_args(port, &[])
+}
+
+pub fn spawn_server_with_args(port: u16, extra_args: &[&str]) -> Child {
     Command::new(CRIU_COORDINATOR_PATH)
         .args([
             "server",
@@ -36,8 +45,78 @@ pub fn spawn_server(port: u16) -> Child {
             "--wait-timeout",
             "5",
         ])
+        .args(extra_args)
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .spawn()
         .expect("Failed to spawn criu-coordinator server. Did you run `cargo build`?")
 }
+
+/// Spawn a server with the server config `config`, and wait until it has
+/// started and read the config.
+pub fn spawn_server_with_config(port: u16, config: &str) -> Child {
+    let config_path = std::env::temp_dir().join(format!("criu-coordinator-server-{port}.json"));
+    fs::write(&config_path, config).unwrap();
+    let server = spawn_server_with_args(port, &["--config", config_path.to_str().unwrap()]);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+    let _ = fs::remove_file(&config_path);
+    server
+}
+
+/// ClientMessage is a message of a client to the server, like the ones
+/// sent by `criu-coordinator client`.
+pub struct ClientMessage(json::JsonValue);
+
+impl ClientMessage {
+    pub fn new(id: &str, action: &str, dependencies: &str) -> Self {
+        Self(json::object!{ id: id, action: action, dependencies: dependencies })
+    }
+
+    /// Add the field `key`, such as `quorum` or `hook`.
+    pub fn with(mut self, key: &str, value: impl Into<json::JsonValue>) -> Self {
+        self.0[key] = value.into();
+        self
+    }
+
+    /// Send the message to the server on `port` and return the connection.
+    pub fn send(self, port: u16) -> TcpStream {
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+        stream.write_all(self.0.dump().as_bytes()).unwrap();
+        stream
+    }
+}
+
+pub fn read_response(stream: &mut TcpStream) -> String {
+    let mut buffer = [0; 1024];
+    let size = stream.read(&mut buffer).unwrap();
+    String::from_utf8_lossy(&buffer[..size]).to_string()
+}
+
+pub const IMG_COMMON: u32 = 0x54564319;
+pub const IMG_SERVICE: u32 = 0x55105940;
+pub const INVENTORY_MAGIC: u32 = 0x58313116;
+
+/// Encode a CRIU image with the magic `magic` and the given encoded
+/// entries, each followed by its payload.
+pub fn encode_image(magic: u32, entries: &[(Vec<u8>, &[u8])]) -> Vec<u8> {
+    let common = if magic == INVENTORY_MAGIC { IMG_SERVICE } else { IMG_COMMON };
+    let mut data = Vec::new();
+    data.extend_from_slice(&common.to_le_bytes());
+    data.extend_from_slice(&magic.to_le_bytes());
+    for (entry, payload) in entries {
+        data.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        data.extend_from_slice(entry);
+        data.extend_from_slice(payload);
+    }
+    data
+}
+
+/// Write an image with the given encoded entries.
+pub fn write_raw_image(path: &Path, magic: u32, entries: impl IntoIterator<Item = Vec<u8>>) {
+    let entries: Vec<(Vec<u8>, &[u8])> = entries.into_iter().map(|entry| (entry, &[][..])).collect();
+    fs::write(path, encode_image(magic, &entries)).unwrap();
+}
+
+pub fn write_image<M: Message>(path: &Path, magic: u32, entries: &[M]) {
+    write_raw_image(path, magic, entries.iter().map(Message::encode_to_vec));
+}
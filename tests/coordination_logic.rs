@@ -1,12 +1,16 @@
 use std::{
+    fs,
+    io::{Read, Write},
     process::{Child, Command, Stdio},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
     sync::{Arc, Barrier},
 };
 
+use criu_coordinator::barrier;
 use criu_coordinator::constants::*;
-mod common;
+use criu_coordinator::criu::{FdTypes, FileEntry, PstreeEntry, RegFileEntry};
+pub mod common;
 use common::*;
 
 
@@ -267,3 +271,675 @@ fn dump_and_restore_client_server_with_network_hooks() {
         ],
     });
 }
+
+#[test]
+fn dump_adds_dependencies_of_shared_connections() {
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, r#"{"dependency-discovery": "add"}"#);
+
+    // Neither client lists the other as a dependency, but B is connected to A.
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "")
+        .with("connections", json::array![{ local: "10.0.0.1:8080", remote: "10.0.0.2:5432" }])
+        .send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    let mut b = ClientMessage::new("B", ACTION_PRE_DUMP, "")
+        .with("connections", json::array![
+            { local: "10.0.0.2:5432", remote: "10.0.0.1:8080" },
+            { local: "10.0.0.2:5432", remote: "10.0.0.3:9000" },
+        ])
+        .send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+
+    // A waits for B to lock its network.
+    let mut a = ClientMessage::new("A", ACTION_NETWORK_LOCK, "").send(port);
+    a.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    assert!(a.read(&mut [0; 16]).is_err(), "A did not wait for its discovered dependency");
+    a.set_read_timeout(None).unwrap();
+
+    let mut b = ClientMessage::new("B", ACTION_NETWORK_LOCK, "").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn restore_aborts_group_when_checks_fail() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    // The process tree of A uses the PID of this test, and a file that is missing.
+    let images_dir = std::env::temp_dir().join(format!("criu-coordinator-pre-restore-{}", std::process::id()));
+    fs::create_dir_all(&images_dir).unwrap();
+    let pid = std::process::id();
+    write_image(&images_dir.join("pstree.img"), 0x50273030, &[PstreeEntry { pid, threads: vec![pid], ..Default::default() }]);
+    write_image(&images_dir.join("files.img"), 0x56303138, &[FileEntry {
+        id: 1,
+        r#type: FdTypes::Reg as i32,
+        reg: Some(RegFileEntry { id: 1, name: "/nonexistent/data.log".to_string(), ..Default::default() }),
+        ..Default::default()
+    }]);
+
+    // B waits for A, whose checks fail.
+    let mut b = ClientMessage::new("B", ACTION_PRE_RESTORE, "A").send(port);
+    let output = Command::new("target/debug/criu-coordinator")
+        .args(["client", "--id", "A", "--deps", "B", "--action", ACTION_PRE_RESTORE])
+        .args(["--images-dir", images_dir.to_str().unwrap(), "--port", &port.to_string()])
+        .output()
+        .unwrap();
+    let combined = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", combined);
+    assert!(combined.contains(&format!("PID {pid} of {pid} () is in use")), "{}", combined);
+    assert!(combined.contains("File /nonexistent/data.log does not exist"), "{}", combined);
+    assert!(combined.contains(MESSAGE_RESTORE_ABORTED), "{}", combined);
+    assert_eq!(read_response(&mut b), MESSAGE_RESTORE_ABORTED);
+
+    // The restore of A and B has ended, so the next restore starts without its failures.
+    let mut c = ClientMessage::new("C", ACTION_PRE_RESTORE, "").send(port);
+    assert_eq!(read_response(&mut c), MESSAGE_ACK);
+
+    // While C is restoring, D fails its checks, and E, which joins the restore, is aborted.
+    let mut d = ClientMessage::new("D", ACTION_PRE_RESTORE, "").with("problems", json::array!["Test problem"]).send(port);
+    assert_eq!(read_response(&mut d), MESSAGE_RESTORE_ABORTED);
+    let mut e = ClientMessage::new("E", ACTION_PRE_RESTORE, "").send(port);
+    assert_eq!(read_response(&mut e), MESSAGE_RESTORE_ABORTED);
+
+    // Once C has resumed, the restore has ended.
+    let mut c = ClientMessage::new("C", ACTION_POST_RESUME, "").send(port);
+    assert_eq!(read_response(&mut c), MESSAGE_ACK);
+    let mut e = ClientMessage::new("E", ACTION_PRE_RESTORE, "").send(port);
+    assert_eq!(read_response(&mut e), MESSAGE_ACK);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&images_dir);
+}
+
+/// Run a client with a fake cgroup directory to freeze, whose `cgroup.events`
+/// reports it frozen as soon as it is asked to freeze.
+fn spawn_freezing_client(port: u16, id: &str, deps: &str, action: &str, cgroup: &std::path::Path) -> Child {
+    Command::new("target/debug/criu-coordinator")
+        .args(["client", "--id", id, "--deps", deps, "--action", action, "--images-dir", "."])
+        .args(["--port", &port.to_string(), "--freeze-cgroup", cgroup.to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+#[test]
+fn dump_freezes_group_at_barrier() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    let cgroups_dir = std::env::temp_dir().join(format!("criu-coordinator-freeze-{}", std::process::id()));
+    let (cgroup_a, cgroup_b) = (cgroups_dir.join("a"), cgroups_dir.join("b"));
+    for cgroup in [&cgroup_a, &cgroup_b] {
+        fs::create_dir_all(cgroup).unwrap();
+        fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+    }
+
+    let a = spawn_freezing_client(port, "A", "B", ACTION_PRE_DUMP, &cgroup_a);
+    let b = spawn_freezing_client(port, "B", "A", ACTION_PRE_DUMP, &cgroup_b);
+    for child in [a, b] {
+        let output = child.wait_with_output().unwrap();
+        let combined = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}", combined);
+        assert!(combined.contains("Froze"), "{}", combined);
+    }
+    assert_eq!(fs::read_to_string(cgroup_a.join("cgroup.freeze")).unwrap(), "1");
+    assert_eq!(fs::read_to_string(cgroup_b.join("cgroup.freeze")).unwrap(), "1");
+
+    // The cgroups are thawed after the dump.
+    let a = spawn_freezing_client(port, "A", "B", ACTION_POST_DUMP, &cgroup_a);
+    let b = spawn_freezing_client(port, "B", "A", ACTION_POST_DUMP, &cgroup_b);
+    for child in [a, b] {
+        assert!(child.wait_with_output().unwrap().status.success());
+    }
+    assert_eq!(fs::read_to_string(cgroup_a.join("cgroup.freeze")).unwrap(), "0");
+    assert_eq!(fs::read_to_string(cgroup_b.join("cgroup.freeze")).unwrap(), "0");
+
+    // A cgroup that fails to freeze is thawed and fails the dump.
+    fs::remove_file(cgroup_a.join("cgroup.events")).unwrap();
+    let output = spawn_freezing_client(port, "A", "", ACTION_PRE_DUMP, &cgroup_a).wait_with_output().unwrap();
+    let combined = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", combined);
+    assert!(combined.contains("Coordinated freeze failed"), "{}", combined);
+    assert_eq!(fs::read_to_string(cgroup_a.join("cgroup.freeze")).unwrap(), "0");
+
+    // CRIU runs network-unlock when the dump fails after the freeze.
+    fs::write(cgroup_a.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+    let output = spawn_freezing_client(port, "A", "", ACTION_PRE_DUMP, &cgroup_a).wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(cgroup_a.join("cgroup.freeze")).unwrap(), "1");
+    spawn_freezing_client(port, "A", "", ACTION_NETWORK_UNLOCK, &cgroup_a).wait().unwrap();
+    assert_eq!(fs::read_to_string(cgroup_a.join("cgroup.freeze")).unwrap(), "0");
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&cgroups_dir);
+}
+
+#[test]
+fn dump_fails_fast_when_dependency_stops_heartbeats() {
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, r#"{"heartbeat-timeout": 1}"#);
+
+    // A runs a real client, which keeps sending heartbeats after pre-dump.
+    let a = Command::new("target/debug/criu-coordinator")
+        .args(["client", "--id", "A", "--deps", "B", "--action", ACTION_PRE_DUMP, "--images-dir", "."])
+        .stdout(Stdio::null())
+        .args(["--port", &port.to_string(), "--heartbeat"])
+        .spawn()
+        .unwrap();
+    let mut b = ClientMessage::new("B", ACTION_PRE_DUMP, "A").send(port);
+    assert!(a.wait_with_output().unwrap().status.success());
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+
+    // B's host dies after pre-dump: its heartbeat connection stays open, but silent.
+    let mut heartbeat = ClientMessage::new("B", ACTION_HEARTBEAT, "").send(port);
+    let reply = json::parse(&read_response(&mut heartbeat)).unwrap();
+    assert_eq!(reply["interval"].as_u64(), Some(250));
+
+    // A outlives the heartbeat timeout and is not waited out at network-lock.
+    thread::sleep(Duration::from_millis(1500));
+    let start = std::time::Instant::now();
+    let mut a = ClientMessage::new("A", ACTION_NETWORK_LOCK, "B").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_DEPENDENCY_LOST);
+    assert!(start.elapsed() < Duration::from_secs(4), "A waited out the timeout");
+
+    // A is still alive, until its heartbeat process is killed.
+    let mut c = ClientMessage::new("C", ACTION_PRE_DUMP, "A").send(port);
+    assert_eq!(read_response(&mut c), MESSAGE_ACK);
+    let heartbeat_process = format!("criu-coordinator heartbeat --address 127.0.0.1 --port {port} --id A");
+    assert!(Command::new("pkill").args(["-f", &heartbeat_process]).status().unwrap().success());
+    thread::sleep(Duration::from_millis(1500));
+    let mut c = ClientMessage::new("C", ACTION_PRE_DUMP, "A").send(port);
+    assert_eq!(read_response(&mut c), MESSAGE_DEPENDENCY_LOST);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+/// Send pre-dump for a client of `group` that waits for a dependency that
+/// never connects, and return the reply and how long it took.
+fn wait_for_missing_dependency(port: u16, id: &str, group: &str, timeouts: json::JsonValue) -> (json::JsonValue, Duration) {
+    let start = std::time::Instant::now();
+    let mut stream = ClientMessage::new(id, ACTION_PRE_DUMP, "missing").with("group", group).with("timeouts", timeouts).send(port);
+    let reply = json::parse(&read_response(&mut stream)).unwrap();
+    (reply, start.elapsed())
+}
+
+#[test]
+fn timeouts_are_configured_per_action_group_and_client() {
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, r#"{"timeouts": {"pre-dump": 1, "groups": {"db": {"pre-dump": 3}}}}"#);
+
+    let (reply, elapsed) = wait_for_missing_dependency(port, "A", "default", json::object!{});
+    assert_eq!(reply["reply"], MESSAGE_TIMEOUT);
+    assert_eq!(reply["timeout"], 1);
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    assert!(reply["deadline"].as_u64().unwrap() <= now, "{}", reply);
+
+    let (reply, elapsed) = wait_for_missing_dependency(port, "B", "db", json::object!{});
+    assert_eq!(reply["timeout"], 3);
+    assert!(elapsed >= Duration::from_secs(3), "{:?}", elapsed);
+
+    // The timeout of the client takes precedence over the server config.
+    let (reply, elapsed) = wait_for_missing_dependency(port, "C", "db", json::object!{ "pre-dump": 2 });
+    assert_eq!(reply["timeout"], 2);
+    assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3), "{:?}", elapsed);
+
+    let output = Command::new("target/debug/criu-coordinator")
+        .args(["client", "--id", "E", "--deps", "missing", "--action", ACTION_PRE_DUMP, "--images-dir", "."])
+        .args(["--port", &port.to_string(), "--timeout", "pre-dump=1"])
+        .output()
+        .unwrap();
+    let combined = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", combined);
+    assert!(combined.contains("Server responded with: timeout (timeout 1s, deadline"), "{}", combined);
+
+    // Clients without timeouts get a plain reply.
+    let output = Command::new("target/debug/criu-coordinator")
+        .args(["client", "--id", "F", "--deps", "missing", "--action", ACTION_PRE_DUMP, "--images-dir", "."])
+        .args(["--port", &port.to_string()])
+        .output()
+        .unwrap();
+    let combined = String::from_utf8_lossy(&output.stdout).to_string() + &String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", combined);
+    assert!(combined.contains("Server responded with: timeout\n"), "{}", combined);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn dump_proceeds_without_optional_dependencies_and_with_quorum() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    // The metrics sidecar of A is optional and never shows up.
+    let started = Instant::now();
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "B:metrics?").send(port);
+    let mut b = ClientMessage::new("B", ACTION_PRE_DUMP, "A").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+
+    // One of the two dependencies of C is enough.
+    let started = Instant::now();
+    let mut c = ClientMessage::new("C", ACTION_PRE_DUMP, "D:E").with("quorum", 1).send(port);
+    let mut d = ClientMessage::new("D", ACTION_PRE_DUMP, "C").send(port);
+    assert_eq!(read_response(&mut c), MESSAGE_ACK);
+    assert_eq!(read_response(&mut d), MESSAGE_ACK);
+    assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+
+    // Without the quorum, F never becomes ready and G waits for it.
+    let mut f = ClientMessage::new("F", ACTION_PRE_DUMP, "G:H").with("quorum", 2).send(port);
+    let mut g = ClientMessage::new("G", ACTION_PRE_DUMP, "F").send(port);
+    assert_eq!(read_response(&mut f), MESSAGE_TIMEOUT);
+    assert_eq!(read_response(&mut g), MESSAGE_TIMEOUT);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn restore_releases_clients_after_their_ordered_dependencies_resume() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    // The app is restored once the database is restored and running.
+    let mut app = ClientMessage::new("app", ACTION_PRE_RESTORE, "^db").send(port);
+    let mut db = ClientMessage::new("db", ACTION_PRE_RESTORE, "app").send(port);
+    assert_eq!(read_response(&mut db), MESSAGE_ACK);
+    app.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(app.read(&mut [0; 64]).is_err(), "app was released before db resumed");
+
+    let mut db = ClientMessage::new("db", ACTION_POST_RESUME, "app").send(port);
+    assert_eq!(read_response(&mut db), MESSAGE_ACK);
+    app.set_read_timeout(None).unwrap();
+    assert_eq!(read_response(&mut app), MESSAGE_ACK);
+    let mut app = ClientMessage::new("app", ACTION_POST_RESUME, "db").send(port);
+    assert_eq!(read_response(&mut app), MESSAGE_ACK);
+
+    // The next restore waits for the database to resume again.
+    let mut app = ClientMessage::new("app", ACTION_PRE_RESTORE, "^db").send(port);
+    app.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(app.read(&mut [0; 64]).is_err(), "app was released by the previous restore of db");
+    let mut db = ClientMessage::new("db", ACTION_PRE_RESTORE, "app").send(port);
+    assert_eq!(read_response(&mut db), MESSAGE_ACK);
+    let mut db = ClientMessage::new("db", ACTION_POST_RESUME, "app").send(port);
+    assert_eq!(read_response(&mut db), MESSAGE_ACK);
+    app.set_read_timeout(None).unwrap();
+    assert_eq!(read_response(&mut app), MESSAGE_ACK);
+    let mut app = ClientMessage::new("app", ACTION_POST_RESUME, "db").send(port);
+    assert_eq!(read_response(&mut app), MESSAGE_ACK);
+
+    // Ordered dependencies that form a cycle abort the restore of the group.
+    let mut x = ClientMessage::new("X", ACTION_PRE_RESTORE, "^Y").send(port);
+    thread::sleep(Duration::from_millis(200));
+    let mut y = ClientMessage::new("Y", ACTION_PRE_RESTORE, "^X").send(port);
+    assert_eq!(read_response(&mut y), MESSAGE_DEPENDENCY_CYCLE);
+    assert_eq!(read_response(&mut x), MESSAGE_RESTORE_ABORTED);
+
+    // The order of the aborted restore does not apply to the next one.
+    let mut x = ClientMessage::new("X", ACTION_PRE_RESTORE, "^Y").send(port);
+    thread::sleep(Duration::from_millis(200));
+    let mut y = ClientMessage::new("Y", ACTION_PRE_RESTORE, "").send(port);
+    assert_eq!(read_response(&mut y), MESSAGE_ACK);
+    let mut y = ClientMessage::new("Y", ACTION_POST_RESUME, "").send(port);
+    assert_eq!(read_response(&mut y), MESSAGE_ACK);
+    assert_eq!(read_response(&mut x), MESSAGE_ACK);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn out_of_order_actions_are_rejected() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    let mut a = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), format!("{MESSAGE_NOT_CONNECTED}: post-dump without a dump or restore in progress"));
+
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    let mut a = ClientMessage::new("A", ACTION_NETWORK_UNLOCK, "").send(port);
+    assert_eq!(read_response(&mut a), format!("{MESSAGE_INVALID_ACTION}: network-unlock in phase ready of dump"));
+
+    // The rejected action leaves the client in its phase.
+    let mut a = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn restore_hooks_are_coordinated_as_configured() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    let mut a = ClientMessage::new("A", ACTION_PRE_RESTORE, "B").send(port);
+    let mut b = ClientMessage::new("B", ACTION_PRE_RESTORE, "A").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+
+    // A notification is acknowledged without waiting for the dependencies.
+    let mut a = ClientMessage::new("A", ACTION_SETUP_NAMESPACES, "B").with("hook", "notify").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+
+    // At a barrier, A waits until B has set up its namespaces too.
+    let mut a = ClientMessage::new("A", ACTION_POST_SETUP_NAMESPACES, "B").with("hook", "barrier").send(port);
+    a.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(a.read(&mut [0; 64]).is_err(), "A did not wait for B at the barrier");
+    a.set_read_timeout(None).unwrap();
+    let mut b = ClientMessage::new("B", ACTION_POST_SETUP_NAMESPACES, "A").with("hook", "barrier").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+
+    // Restore hooks are not part of a dump.
+    let mut c = ClientMessage::new("C", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut c), MESSAGE_ACK);
+    let mut c = ClientMessage::new("C", ACTION_PRE_RESUME, "").with("hook", "notify").send(port);
+    assert_eq!(read_response(&mut c), format!("{MESSAGE_INVALID_ACTION}: pre-resume in phase ready of dump"));
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    // CRIU reads the output of query-ext-files, which is not sent to the server by default.
+    let output = Command::new("target/debug/criu-coordinator")
+        .args(["client", "--id", "A", "--deps", "", "--action", ACTION_QUERY_EXT_FILES, "--images-dir", "."])
+        .args(["--port", &port.to_string()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+}
+
+/// Run criu-coordinator as the action script of CRIU, with the images in `images_dir`.
+fn run_action_script(action: &str, images_dir: &std::path::Path) -> std::process::Output {
+    Command::new("target/debug/criu-coordinator")
+        .env(ENV_ACTION, action)
+        .env(ENV_IMAGE_DIR, images_dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn action_scripts_run_around_coordination() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    let images_dir = std::env::temp_dir().join(format!("criu-coordinator-scripts-{}", std::process::id()));
+    fs::create_dir_all(&images_dir).unwrap();
+    let config = json::object!{
+        id: "A",
+        port: port.to_string(),
+        "log-file": "coordinator.log",
+        scripts: json::object!{
+            "pre-dump": json::object!{
+                before: ["echo $CRTOOLS_SCRIPT_ACTION > $CRTOOLS_IMAGE_DIR/before"],
+                after: ["echo done > $CRTOOLS_IMAGE_DIR/after"],
+            },
+            "pre-restore": json::object!{ before: ["exit 3"], "abort-group": true },
+            "network-unlock": json::object!{ after: ["echo network-unlock > $CRTOOLS_IMAGE_DIR/after"] },
+            "query-ext-files": json::object!{ before: ["echo ext-file"] },
+        },
+    };
+    fs::write(images_dir.join(CONFIG_FILE), config.dump()).unwrap();
+
+    // The failing script of A fails its restore, and B does not wait for A.
+    let mut b = ClientMessage::new("B", ACTION_PRE_RESTORE, "A").send(port);
+    let start = Instant::now();
+    let output = run_action_script(ACTION_PRE_RESTORE, &images_dir);
+    assert!(!output.status.success());
+    assert_eq!(read_response(&mut b), MESSAGE_RESTORE_ABORTED);
+    assert!(start.elapsed() < Duration::from_secs(3), "B waited out the timeout");
+    let log = fs::read_to_string(images_dir.join("coordinator.log")).unwrap();
+    assert!(log.contains("exit 3 failed with exit status: 3"), "{}", log);
+
+    let output = run_action_script(ACTION_PRE_DUMP, &images_dir);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(images_dir.join("before")).unwrap(), "pre-dump\n");
+    assert_eq!(fs::read_to_string(images_dir.join("after")).unwrap(), "done\n");
+
+    // The after scripts run when the coordination fails too, here because
+    // network-unlock is out of order in a dump.
+    let output = run_action_script(ACTION_NETWORK_UNLOCK, &images_dir);
+    assert!(!output.status.success());
+    assert_eq!(fs::read_to_string(images_dir.join("after")).unwrap(), "network-unlock\n");
+
+    // CRIU reads the output of the scripts of query-ext-files.
+    let output = run_action_script(ACTION_QUERY_EXT_FILES, &images_dir);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ext-file\n");
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&images_dir);
+}
+
+#[test]
+fn invalid_client_config_is_reported() {
+    let images_dir = std::env::temp_dir().join(format!("criu-coordinator-invalid-config-{}", std::process::id()));
+    fs::create_dir_all(&images_dir).unwrap();
+    let configs = [
+        (json::object!{ id: "A", quorum: "many" }, "Invalid quorum many"),
+        (json::object!{ id: "A", hooks: json::object!{ "pre-resume": "wait" } }, "Invalid mode for hook \"pre-resume\""),
+        (json::object!{ id: "A", scripts: json::object!{ "pre-dump": json::object!{ before: "flush" } } }, "Invalid scripts for \"pre-dump\""),
+        (json::object!{ id: "A", quiesce: json::object!{ signal: "SIGNOPE" } }, "Invalid signal \"SIGNOPE\""),
+        (json::object!{ id: "A", quiesce: json::object!{ timeout: 5 } }, "quiesce needs either a socket or a signal"),
+    ];
+    for (config, error) in configs {
+        fs::write(images_dir.join(CONFIG_FILE), config.dump()).unwrap();
+        let output = run_action_script(ACTION_PRE_DUMP, &images_dir);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains(&format!("Invalid configuration: {error}")), "{}", stderr);
+    }
+    let _ = fs::remove_dir_all(&images_dir);
+}
+
+#[test]
+fn post_dump_resumes_the_group_when_a_member_failed() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    // C fails its dump, and A, which does not wait for C, is resumed too.
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    let (reply, _) = wait_for_missing_dependency(port, "C", "default", json::object!{ "pre-dump": 1 });
+    assert_eq!(reply["reply"], MESSAGE_TIMEOUT);
+    let mut a = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_RESUME);
+
+    // The failure of C does not outlast its dump.
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    let mut a = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+
+    // A waits at post-dump until B has dumped or failed, so that the group
+    // is killed or resumed as a whole.
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    let mut b = ClientMessage::new("B", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    let mut a = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    a.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(a.read(&mut [0; 64]).is_err(), "A passed post-dump before B");
+    a.set_read_timeout(None).unwrap();
+    let mut b = ClientMessage::new("B", ACTION_ABORT, "").with("failed-action", ACTION_POST_DUMP).with("reason", "test").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    assert_eq!(read_response(&mut a), MESSAGE_RESUME);
+
+    let mut a = ClientMessage::new("A", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    let mut b = ClientMessage::new("B", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    let mut a = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    let mut b = ClientMessage::new("B", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a), MESSAGE_ACK);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+/// Accept a connection on the socket of an application and return the
+/// notification, after replying with `reply` if one is given.
+fn receive_notification(listener: &std::os::unix::net::UnixListener, reply: Option<&str>) -> String {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut notification = String::new();
+    std::io::BufRead::read_line(&mut std::io::BufReader::new(stream.try_clone().unwrap()), &mut notification).unwrap();
+    if let Some(reply) = reply {
+        stream.write_all(format!("{reply}\n").as_bytes()).unwrap();
+    }
+    notification
+}
+
+#[test]
+fn applications_quiesce_together_before_the_dump() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    let images_dir = std::env::temp_dir().join(format!("criu-coordinator-quiesce-{}", std::process::id()));
+    fs::create_dir_all(&images_dir).unwrap();
+    let socket = images_dir.join("app.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    let config = json::object!{
+        id: "A",
+        dependencies: "B",
+        port: port.to_string(),
+        quiesce: json::object!{ socket: socket.to_str().unwrap(), timeout: 5 },
+    };
+    fs::write(images_dir.join(CONFIG_FILE), config.dump()).unwrap();
+
+    let mut b = ClientMessage::new("B", ACTION_PRE_DUMP, "A").send(port);
+    let mut a = Command::new("target/debug/criu-coordinator")
+        .env(ENV_ACTION, ACTION_PRE_DUMP)
+        .env(ENV_IMAGE_DIR, &images_dir)
+        .spawn()
+        .unwrap();
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    assert_eq!(receive_notification(&listener, Some("ok")), "quiesce\n");
+
+    // A waits at the barrier until B has quiesced too.
+    thread::sleep(Duration::from_millis(500));
+    assert!(a.try_wait().unwrap().is_none(), "A did not wait for B to quiesce");
+    let mut b = ClientMessage::new("B", ACTION_QUIESCE, "A").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    assert!(a.wait().unwrap().success());
+
+    // B fails its dump, so the processes of A are resumed and its
+    // application is told so.
+    let mut b = ClientMessage::new("B", ACTION_ABORT, "").with("failed-action", ACTION_PRE_DUMP).with("reason", "test").send(port);
+    assert_eq!(read_response(&mut b), MESSAGE_ACK);
+    let a = Command::new("target/debug/criu-coordinator")
+        .env(ENV_ACTION, ACTION_POST_DUMP)
+        .env(ENV_IMAGE_DIR, &images_dir)
+        .spawn()
+        .unwrap();
+    assert_eq!(receive_notification(&listener, None), "resumed\n");
+    assert!(!a.wait_with_output().unwrap().status.success());
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&images_dir);
+}
+
+fn run_barrier_command(port: u16, name: &str, id: &str, deps: &str) -> std::process::ExitStatus {
+    Command::new("target/debug/criu-coordinator")
+        .args(["barrier", "--name", name, "--id", id, "--deps", deps, "--port", &port.to_string()])
+        .status()
+        .unwrap()
+}
+
+#[test]
+fn applications_pass_named_barriers_together() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    let a = thread::spawn(move || barrier::Barrier::new("127.0.0.1", port, "A", "B").wait("flush-done"));
+    thread::sleep(Duration::from_millis(500));
+    assert!(!a.is_finished(), "A did not wait for B");
+    assert!(run_barrier_command(port, "flush-done", "B", "A").success());
+    assert_eq!(a.join().unwrap(), Ok(()));
+
+    // A restarted member that reaches the barrier again before the first
+    // dump waits for B to reach it again too.
+    let mut a = barrier::Barrier::new("127.0.0.1", port, "A", "B");
+    a.set_timeout(Some(1));
+    assert_eq!(a.wait("flush-done"), Err(MESSAGE_TIMEOUT.to_string()));
+    let a = thread::spawn(move || barrier::Barrier::new("127.0.0.1", port, "A", "B").wait("flush-done"));
+    thread::sleep(Duration::from_millis(500));
+    assert!(!a.is_finished(), "A did not wait for B");
+    assert!(run_barrier_command(port, "flush-done", "B", "A").success());
+    assert_eq!(a.join().unwrap(), Ok(()));
+
+    // After a dump, A waits for B to reach the barrier again, and a barrier
+    // of another name does not count.
+    let mut a_dump = ClientMessage::new("A", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a_dump), MESSAGE_ACK);
+    let mut a_dump = ClientMessage::new("A", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut a_dump), MESSAGE_ACK);
+    let mut a = barrier::Barrier::new("127.0.0.1", port, "A", "B");
+    a.set_timeout(Some(1));
+    let waiting = thread::spawn(move || a.wait("flush-done"));
+    let mut b = barrier::Barrier::new("127.0.0.1", port, "B", "A");
+    b.set_timeout(Some(1));
+    assert_eq!(b.wait("intake-paused"), Err(MESSAGE_TIMEOUT.to_string()));
+    assert_eq!(waiting.join().unwrap(), Err(MESSAGE_TIMEOUT.to_string()));
+
+    // A failed to pass the barrier, so B waits for it to reach it again.
+    let a = thread::spawn(move || barrier::Barrier::new("127.0.0.1", port, "A", "B").wait("flush-done"));
+    assert!(run_barrier_command(port, "flush-done", "B", "A").success());
+    assert_eq!(a.join().unwrap(), Ok(()));
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn barrier_without_dependencies_waits_for_known_members() {
+    let port = pick_port();
+    let mut server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+
+    // B is known to the server as a member of the last dump of the group.
+    let mut b_dump = ClientMessage::new("B", ACTION_PRE_DUMP, "").send(port);
+    assert_eq!(read_response(&mut b_dump), MESSAGE_ACK);
+    let mut b_dump = ClientMessage::new("B", ACTION_POST_DUMP, "").send(port);
+    assert_eq!(read_response(&mut b_dump), MESSAGE_ACK);
+
+    let barrier_command = |id: &str| {
+        Command::new("target/debug/criu-coordinator")
+            .args(["barrier", "--name", "flush-done", "--id", id, "--port", &port.to_string()])
+            .spawn()
+            .unwrap()
+    };
+    let mut a = barrier_command("A");
+    thread::sleep(Duration::from_millis(500));
+    assert!(a.try_wait().unwrap().is_none(), "A did not wait for B");
+    // A has reached a barrier of the group, so B waits for it too.
+    assert!(barrier_command("B").wait().unwrap().success());
+    assert!(a.wait().unwrap().success());
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
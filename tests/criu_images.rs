@@ -0,0 +1,554 @@
+use std::{
+    convert::TryInto,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use criu_coordinator::criu::*;
+use prost::Message;
+pub mod common;
+use common::*;
+
+const PSTREE_MAGIC: u32 = 0x50273030;
+const CORE_MAGIC: u32 = 0x55053847;
+const IDS_MAGIC: u32 = 0x54432030;
+const MM_MAGIC: u32 = 0x57492820;
+const PAGEMAP_MAGIC: u32 = 0x56084025;
+const FDINFO_MAGIC: u32 = 0x56213732;
+const FILES_MAGIC: u32 = 0x56303138;
+const MNTS_MAGIC: u32 = 0x55563928;
+const PIPES_DATA_MAGIC: u32 = 0x56453709;
+const TCP_STREAM_MAGIC: u32 = 0x51465506;
+const GHOST_FILE_MAGIC: u32 = 0x52583605;
+
+fn ipv4(addr: [u8; 4]) -> Vec<u32> {
+    vec![u32::from_ne_bytes(addr)]
+}
+
+fn core(comm: &str) -> CoreEntry {
+    CoreEntry {
+        tc: Some(TaskCoreEntry { comm: comm.to_string(), ..Default::default() }),
+        ..Default::default()
+    }
+}
+
+/// Write the images of a small process tree: a server with two threads,
+/// a log file and a TCP connection, and a worker child process.
+fn write_checkpoint(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("criu-coordinator-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    fs::write(dir.join("criu-coordinator.json"), r#"{"id": "A", "dependencies": "B:C"}"#).unwrap();
+    write_image(&dir.join("inventory.img"), INVENTORY_MAGIC, &[InventoryEntry { img_version: 2, ..Default::default() }]);
+    write_image(&dir.join("pstree.img"), PSTREE_MAGIC, &[
+        PstreeEntry { pid: 100, ppid: 0, pgid: 100, sid: 100, threads: vec![100, 101] },
+        PstreeEntry { pid: 105, ppid: 100, pgid: 100, sid: 100, threads: vec![105] },
+    ]);
+    write_image(&dir.join("core-100.img"), CORE_MAGIC, &[core("server")]);
+    write_image(&dir.join("core-105.img"), CORE_MAGIC, &[core("worker")]);
+    for pid in [100, 105] {
+        write_image(&dir.join(format!("ids-{pid}.img")), IDS_MAGIC, &[TaskKobjIdsEntry {
+            files_id: pid,
+            net_ns_id: Some(7),
+            mnt_ns_id: Some(8),
+            ..Default::default()
+        }]);
+    }
+
+    write_image(&dir.join("mm-100.img"), MM_MAGIC, &[MmEntry {
+        vmas: vec![
+            VmaEntry { start: 0x1000, end: 0x3000, ..Default::default() },
+            VmaEntry { start: 0x10000, end: 0x11000, ..Default::default() },
+        ],
+        ..Default::default()
+    }]);
+    // A pagemap image starts with a head, followed by the page ranges.
+    write_raw_image(&dir.join("pagemap-100.img"), PAGEMAP_MAGIC, [
+        PagemapHead { pages_id: 1 }.encode_to_vec(),
+        PagemapEntry { vaddr: 0x1000, nr_pages: 2, flags: Some(4), ..Default::default() }.encode_to_vec(),
+        PagemapEntry { vaddr: 0x10000, nr_pages: 1, flags: Some(4), ..Default::default() }.encode_to_vec(),
+    ]);
+
+    write_image(&dir.join("fdinfo-100.img"), FDINFO_MAGIC, &[
+        FdinfoEntry { id: 10, fd: 3, r#type: FdTypes::Reg as i32, ..Default::default() },
+        FdinfoEntry { id: 11, fd: 4, r#type: FdTypes::Inetsk as i32, ..Default::default() },
+    ]);
+    write_image(&dir.join("fdinfo-105.img"), FDINFO_MAGIC, &[
+        FdinfoEntry { id: 10, fd: 1, r#type: FdTypes::Reg as i32, ..Default::default() },
+    ]);
+    write_image(&dir.join("files.img"), FILES_MAGIC, &[
+        FileEntry {
+            id: 10,
+            r#type: FdTypes::Reg as i32,
+            reg: Some(RegFileEntry { id: 10, name: "/var/log/app.log".to_string(), ..Default::default() }),
+            ..Default::default()
+        },
+        FileEntry {
+            id: 11,
+            r#type: FdTypes::Inetsk as i32,
+            isk: Some(InetSkEntry {
+                id: 11,
+                family: 2,
+                proto: 6,
+                state: 1,
+                src_addr: ipv4([10, 0, 0, 1]),
+                src_port: 8080,
+                dst_addr: ipv4([10, 0, 0, 2]),
+                dst_port: 5432,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    ]);
+    dir
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(CRIU_COORDINATOR_PATH).args(args).output().unwrap()
+}
+
+fn run_with_input(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(CRIU_COORDINATOR_PATH)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn decode(path: &Path) -> json::JsonValue {
+    let output = run(&["decode", "-i", path.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    json::parse(std::str::from_utf8(&output.stdout).unwrap()).unwrap()
+}
+
+fn encode(image: &json::JsonValue) -> Vec<u8> {
+    let output = run_with_input(&["encode"], image.dump().as_bytes());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+#[test]
+fn inspect_summarizes_checkpoint() {
+    let dir = write_checkpoint("inspect");
+    let output = run(&["inspect", dir.to_str().unwrap()]);
+    assert!(output.status.success());
+    let summary = String::from_utf8(output.stdout).unwrap();
+
+    for expected in [
+        "id: A",
+        "dependencies: B:C",
+        "Image version: 2",
+        "  100 (server) sid 100 pgid 100, 2 threads",
+        "    105 (worker) sid 100 pgid 100, 1 thread",
+        "100 (server): 2 mappings, 12.0 KiB mapped, 3 pages dumped (12.0 KiB)",
+        "       3  /var/log/app.log",
+        "       4  tcp 10.0.0.1:8080 -> 10.0.0.2:5432 (ESTABLISHED)",
+        "105 (worker): net 7, mnt 8",
+    ] {
+        assert!(summary.contains(expected), "{:?} not found in:\n{}", expected, summary);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn inspect_fails_without_images() {
+    let dir = std::env::temp_dir().join(format!("criu-coordinator-empty-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = run(&["inspect", dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Failed to inspect"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Change the checkpoint written by `write_checkpoint`: the server has a
+/// third thread, writes to another log file and has unmapped a mapping,
+/// and a mount was added.
+fn change_checkpoint(dir: &Path) {
+    write_image(&dir.join("pstree.img"), PSTREE_MAGIC, &[
+        PstreeEntry { pid: 100, ppid: 0, pgid: 100, sid: 100, threads: vec![100, 101, 102] },
+        PstreeEntry { pid: 105, ppid: 100, pgid: 100, sid: 100, threads: vec![105] },
+    ]);
+    write_image(&dir.join("fdinfo-100.img"), FDINFO_MAGIC, &[
+        FdinfoEntry { id: 12, fd: 3, r#type: FdTypes::Reg as i32, ..Default::default() },
+        FdinfoEntry { id: 11, fd: 4, r#type: FdTypes::Inetsk as i32, ..Default::default() },
+    ]);
+    let mut files = read_image::<FileEntry>(&dir.join("files.img"));
+    files.push(FileEntry {
+        id: 12,
+        r#type: FdTypes::Reg as i32,
+        reg: Some(RegFileEntry { id: 12, name: "/var/log/app.log.1".to_string(), ..Default::default() }),
+        ..Default::default()
+    });
+    write_image(&dir.join("files.img"), FILES_MAGIC, &files);
+    write_image(&dir.join("mm-100.img"), MM_MAGIC, &[MmEntry {
+        vmas: vec![VmaEntry { start: 0x1000, end: 0x3000, ..Default::default() }],
+        ..Default::default()
+    }]);
+    write_image(&dir.join("mountpoints-8.img"), MNTS_MAGIC, &[MntEntry {
+        fstype: 5,
+        root: "/".to_string(),
+        mountpoint: "/tmp".to_string(),
+        source: "tmpfs".to_string(),
+        options: "size=64m".to_string(),
+        ..Default::default()
+    }]);
+}
+
+fn read_image<M: Message + Default>(path: &Path) -> Vec<M> {
+    let data = fs::read(path).unwrap();
+    let mut entries = Vec::new();
+    let mut pos = 8;
+    while pos < data.len() {
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        entries.push(M::decode(&data[pos + 4..pos + 4 + len]).unwrap());
+        pos += 4 + len;
+    }
+    entries
+}
+
+#[test]
+fn diff_reports_changes() {
+    let a = write_checkpoint("diff-a");
+    let b = write_checkpoint("diff-b");
+
+    let output = run(&["diff", a.to_str().unwrap(), b.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout).unwrap().contains("No differences"));
+
+    change_checkpoint(&b);
+    let output = run(&["diff", a.to_str().unwrap(), b.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stdout).unwrap();
+    for expected in [
+        "~ 100 (server): server, ppid 0, sid 100, pgid 100, 2 threads -> server, ppid 0, sid 100, pgid 100, 3 threads",
+        "~ 100 (server) fd 3: /var/log/app.log -> /var/log/app.log.1",
+        "+ tmpfs on /tmp type tmpfs (size=64m)",
+        "~ 100 (server): 2 mappings, 12.0 KiB mapped -> 1 mappings, 8.0 KiB mapped",
+    ] {
+        assert!(report.contains(expected), "{:?} not found in:\n{}", expected, report);
+    }
+    // Unchanged objects are not reported.
+    assert!(!report.contains("105 (worker)"), "{}", report);
+    assert!(!report.contains("Sockets:"), "{}", report);
+
+    let _ = fs::remove_dir_all(&a);
+    let _ = fs::remove_dir_all(&b);
+}
+
+#[test]
+fn diff_compares_global_checkpoints() {
+    let global = |name: &str, clients: &[&str]| {
+        let dir = std::env::temp_dir().join(format!("criu-coordinator-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for id in clients {
+            fs::rename(write_checkpoint(&format!("{name}-{id}")), dir.join(id)).unwrap();
+        }
+        dir
+    };
+    let a = global("diff-global-a", &["web", "db"]);
+    let b = global("diff-global-b", &["web", "cache"]);
+    change_checkpoint(&b.join("web"));
+
+    let output = run(&["diff", a.to_str().unwrap(), b.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stdout).unwrap();
+    for expected in [
+        "Client web:",
+        "    ~ 100 (server) fd 3: /var/log/app.log -> /var/log/app.log.1",
+        "- Client db",
+        "+ Client cache",
+    ] {
+        assert!(report.contains(expected), "{:?} not found in:\n{}", expected, report);
+    }
+
+    // A client checkpoint cannot be compared with a global checkpoint.
+    let output = run(&["diff", a.join("web").to_str().unwrap(), b.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&a);
+    let _ = fs::remove_dir_all(&b);
+}
+
+/// Write the images of a client with a TCP connection from `local` to
+/// `remote`, whose queues hold `inq` and `outq`.
+fn write_tcp_client(dir: &Path, local: u32, remote: u32, stream: TcpStreamEntry, inq: &[u8], outq: &[u8]) {
+    fs::create_dir_all(dir).unwrap();
+    write_image(&dir.join("pstree.img"), PSTREE_MAGIC, &[PstreeEntry { pid: 1, threads: vec![1], ..Default::default() }]);
+    write_image(&dir.join("files.img"), FILES_MAGIC, &[FileEntry {
+        id: 1,
+        r#type: FdTypes::Inetsk as i32,
+        isk: Some(InetSkEntry {
+            id: 1,
+            ino: 0xabc,
+            family: 2,
+            proto: 6,
+            state: 1,
+            src_addr: ipv4([10, 0, 0, local as u8]),
+            src_port: 7000 + local,
+            dst_addr: ipv4([10, 0, 0, remote as u8]),
+            dst_port: 7000 + remote,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }]);
+    let stream = TcpStreamEntry { inq_len: inq.len() as u32, outq_len: outq.len() as u32, ..stream };
+    let image = encode_image(TCP_STREAM_MAGIC, &[(stream.encode_to_vec(), &[inq, outq].concat())]);
+    fs::write(dir.join("tcp-stream-abc.img"), image).unwrap();
+}
+
+#[test]
+fn verify_checks_tcp_sequence_numbers() {
+    let dir = std::env::temp_dir().join(format!("criu-coordinator-verify-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    // "hello" was sent by web and received by db, but not acknowledged yet.
+    write_tcp_client(&dir.join("web"), 1, 2, TcpStreamEntry { inq_seq: 2002, outq_seq: 1005, ..Default::default() }, b"", b"hello");
+    write_tcp_client(&dir.join("db"), 2, 1, TcpStreamEntry { inq_seq: 1005, outq_seq: 2002, ..Default::default() }, b"hello", b"");
+    let output = run(&["verify", dir.to_str().unwrap()]);
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", report);
+    assert!(report.contains("Checked 1 connection: consistent"), "{}", report);
+
+    // The data received by db does not match the send queue of web.
+    write_tcp_client(&dir.join("db"), 2, 1, TcpStreamEntry { inq_seq: 1005, outq_seq: 2002, ..Default::default() }, b"hallo", b"");
+    let output = run(&["verify", dir.to_str().unwrap()]);
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", report);
+    assert!(report.contains("10.0.0.2:7002 -> 10.0.0.1:7001: data received by db differs from the data sent by web"), "{}", report);
+
+    // web was dumped before it sent "hello".
+    write_tcp_client(&dir.join("web"), 1, 2, TcpStreamEntry { inq_seq: 2002, outq_seq: 1000, ..Default::default() }, b"", b"");
+    let output = run(&["verify", dir.to_str().unwrap()]);
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", report);
+    assert!(report.contains("db has received 5 bytes that web has not sent"), "{}", report);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rewrite_maps_addresses_of_both_ends() {
+    let dir = std::env::temp_dir().join(format!("criu-coordinator-rewrite-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    write_tcp_client(&dir.join("web"), 1, 2, TcpStreamEntry { inq_seq: 2002, outq_seq: 1005, ..Default::default() }, b"", b"hello");
+    write_tcp_client(&dir.join("db"), 2, 1, TcpStreamEntry { inq_seq: 1005, outq_seq: 2002, ..Default::default() }, b"hello", b"");
+    let tcp_stream = fs::read(dir.join("db/tcp-stream-abc.img")).unwrap();
+    // A field added by a newer CRIU, unknown to this version.
+    let mut entry = read_image::<FileEntry>(&dir.join("web/files.img")).remove(0).encode_to_vec();
+    entry.extend_from_slice(&[0xc0, 0x3e, 0x01]);
+    write_raw_image(&dir.join("web/files.img"), FILES_MAGIC, [entry]);
+
+    // Mapping two hosts to the same address fails without changing the images.
+    let map_path = dir.join("map.json");
+    fs::write(&map_path, r#"{"10.0.0.1": "10.1.0.1", "10.0.0.2": "10.1.0.1"}"#).unwrap();
+    let output = run(&["rewrite", "--map", map_path.to_str().unwrap(), dir.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("mapped to 10.1.0.1"));
+    let isk = read_image::<FileEntry>(&dir.join("web/files.img")).remove(0).isk.unwrap();
+    assert_eq!(isk.src_addr, ipv4([10, 0, 0, 1]));
+
+    fs::write(&map_path, r#"{"10.0.0.1": "10.1.0.1", "10.0.0.2": "10.1.0.2"}"#).unwrap();
+    let output = run(&["rewrite", "--map", map_path.to_str().unwrap(), dir.to_str().unwrap()]);
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(report.contains("web: rewrote 1 socket"), "{}", report);
+    assert!(report.contains("db: rewrote 1 socket"), "{}", report);
+
+    let isk = read_image::<FileEntry>(&dir.join("web/files.img")).remove(0).isk.unwrap();
+    assert_eq!((isk.src_addr, isk.src_port), (ipv4([10, 1, 0, 1]), 7001));
+    assert_eq!((isk.dst_addr, isk.dst_port), (ipv4([10, 1, 0, 2]), 7002));
+    let isk = read_image::<FileEntry>(&dir.join("db/files.img")).remove(0).isk.unwrap();
+    assert_eq!((isk.src_addr, isk.dst_addr), (ipv4([10, 1, 0, 2]), ipv4([10, 1, 0, 1])));
+    assert_eq!(fs::read(dir.join("db/tcp-stream-abc.img")).unwrap(), tcp_stream);
+    assert_eq!(decode(&dir.join("web/files.img"))["entries"][0]["unknown_fields"], "wD4B");
+
+    // Both ends still form the same connection.
+    let output = run(&["verify", dir.to_str().unwrap()]);
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("Checked 1 connection: consistent"), "{}", report);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn decode_encode_round_trip() {
+    let dir = write_checkpoint("crit");
+    for name in ["inventory.img", "pstree.img", "core-100.img", "ids-100.img", "mm-100.img", "pagemap-100.img", "fdinfo-100.img", "files.img"] {
+        let image = decode(&dir.join(name));
+        assert_eq!(encode(&image), fs::read(dir.join(name)).unwrap(), "{}", name);
+    }
+
+    let pstree = decode(&dir.join("pstree.img"));
+    assert_eq!(pstree["magic"], "PSTREE");
+    assert_eq!(pstree["entries"][0]["threads"], json::array![100, 101]);
+    let pagemap = decode(&dir.join("pagemap-100.img"));
+    assert_eq!(pagemap["magic"], "PAGEMAP");
+    assert_eq!(pagemap["entries"][0]["pages_id"], 1);
+    assert_eq!(pagemap["entries"][2]["nr_pages"], 1);
+    let files = decode(&dir.join("files.img"));
+    assert_eq!(files["entries"][0]["type"], "REG");
+    assert_eq!(files["entries"][1]["isk"]["dst_port"], 5432);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn decode_entries_larger_than_a_frame() {
+    let dir = write_checkpoint("crit-large");
+    let name = format!("/{}", "a".repeat(2 * 1024 * 1024));
+    write_image(&dir.join("files.img"), FILES_MAGIC, &[FileEntry {
+        id: 10,
+        r#type: FdTypes::Reg as i32,
+        reg: Some(RegFileEntry { id: 10, name: name.clone(), ..Default::default() }),
+        ..Default::default()
+    }]);
+    assert_eq!(decode(&dir.join("files.img"))["entries"][0]["reg"]["name"], name.as_str());
+
+    // Unknown magics are reported instead of crashing.
+    fs::write(dir.join("unknown.img"), [0x19, 0x43, 0x56, 0x54, 1, 2, 3, 4]).unwrap();
+    let output = run(&["decode", "-i", dir.join("unknown.img").to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Unknown image magic"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn decode_encode_images_with_payload() {
+    let dir = write_checkpoint("crit-payload");
+
+    let pipe = PipeDataEntry { pipe_id: 3, bytes: 5, size: None }.encode_to_vec();
+    fs::write(dir.join("pipes-data.img"), encode_image(PIPES_DATA_MAGIC, &[(pipe, b"hello")])).unwrap();
+    let tcp = TcpStreamEntry { inq_len: 2, outq_len: 3, ..Default::default() }.encode_to_vec();
+    fs::write(dir.join("tcp-stream-1.img"), encode_image(TCP_STREAM_MAGIC, &[(tcp, b"inout")])).unwrap();
+    let ghost = GhostFileEntry { uid: 0, gid: 0, mode: 0o100644, ..Default::default() }.encode_to_vec();
+    fs::write(dir.join("ghost-file-1.img"), encode_image(GHOST_FILE_MAGIC, &[(ghost, b"deleted file")])).unwrap();
+    let chunked = GhostFileEntry { chunks: Some(true), ..Default::default() }.encode_to_vec();
+    let chunk = GhostChunkEntry { len: 4, off: 4096 }.encode_to_vec();
+    fs::write(dir.join("ghost-file-2.img"), encode_image(GHOST_FILE_MAGIC, &[(chunked, b""), (chunk, b"data")])).unwrap();
+
+    for name in ["pipes-data.img", "tcp-stream-1.img", "ghost-file-1.img", "ghost-file-2.img"] {
+        let image = decode(&dir.join(name));
+        assert_eq!(encode(&image), fs::read(dir.join(name)).unwrap(), "{}", name);
+    }
+
+    assert_eq!(decode(&dir.join("pipes-data.img"))["entries"][0]["extra"], "aGVsbG8=");
+    let tcp = decode(&dir.join("tcp-stream-1.img"));
+    assert_eq!(tcp["entries"][0]["extra"], json::object!{ inq: "aW4=", outq: "b3V0" });
+    assert_eq!(decode(&dir.join("ghost-file-1.img"))["entries"][0]["extra"], "ZGVsZXRlZCBmaWxl");
+    assert_eq!(decode(&dir.join("ghost-file-2.img"))["entries"][1]["extra"], "ZGF0YQ==");
+
+    // JSON written by crit splits base64 into lines and may use alias names.
+    let image = json::object!{
+        magic: "FIFO_DATA",
+        entries: [{ pipe_id: 3, bytes: 5, extra: "aGVs\nbG8=\n" }],
+    };
+    assert_eq!(encode(&image), fs::read(dir.join("pipes-data.img")).unwrap());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn encode_edited_image() {
+    let dir = write_checkpoint("crit-edit");
+    let files_img = dir.join("files.img");
+
+    // Rewrite the path of a regular file, then check that it is restored from there.
+    let mut files = decode(&files_img);
+    files["entries"][0]["reg"]["name"] = "/srv/app.log".into();
+    let json_path = dir.join("files.json");
+    fs::write(&json_path, files.pretty(4)).unwrap();
+    let output = run(&["encode", "-i", json_path.to_str().unwrap(), "-o", files_img.to_str().unwrap()]);
+    assert!(output.status.success());
+
+    let summary = String::from_utf8(run(&["inspect", dir.to_str().unwrap()]).stdout).unwrap();
+    assert!(summary.contains("       3  /srv/app.log"), "{}", summary);
+
+    // Unknown fields are rejected rather than dropped.
+    files["entries"][0]["reg"]["path"] = "/srv/app.log".into();
+    let output = run_with_input(&["encode"], files.dump().as_bytes());
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Unknown field path"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn decode_pretty_formats_fields_by_criu_options() {
+    let dir = write_checkpoint("crit-pretty");
+    let decode_pretty = |name: &str| {
+        let output = run(&["decode", "-i", dir.join(name).to_str().unwrap(), "--pretty"]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        json::parse(std::str::from_utf8(&output.stdout).unwrap()).unwrap()
+    };
+
+    let files = decode_pretty("files.img");
+    let isk = &files["entries"][1]["isk"];
+    assert_eq!(isk["family"], "INET");
+    assert_eq!(isk["proto"], "TCP");
+    assert_eq!(isk["state"], "ESTABLISHED");
+    assert_eq!(isk["src_addr"], json::array!["10.0.0.1"]);
+    assert_eq!(isk["dst_addr"], json::array!["10.0.0.2"]);
+    let mm = decode_pretty("mm-100.img");
+    assert_eq!(mm["entries"][0]["vmas"][0]["start"], "0x1000");
+    let pagemap = decode_pretty("pagemap-100.img");
+    assert_eq!(pagemap["entries"][1]["flags"], "PE_PRESENT");
+
+    for name in ["mm-100.img", "pagemap-100.img", "files.img"] {
+        assert_eq!(encode(&decode_pretty(name)), fs::read(dir.join(name)).unwrap(), "{}", name);
+    }
+
+    // Fields that the descriptors do not know are kept.
+    let mut entry = FileEntry {
+        id: 10,
+        r#type: FdTypes::Reg as i32,
+        reg: Some(RegFileEntry { id: 10, name: "/var/log/app.log".to_string(), ..Default::default() }),
+        ..Default::default()
+    }.encode_to_vec();
+    entry.extend_from_slice(&[0xc0, 0x3e, 0x01]);
+    write_raw_image(&dir.join("files.img"), FILES_MAGIC, [entry]);
+    let files = decode(&dir.join("files.img"));
+    assert_eq!(files["entries"][0]["unknown_fields"], "wD4B");
+    assert_eq!(encode(&files), fs::read(dir.join("files.img")).unwrap());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn pack_unpack_round_trip() {
+    let dir = write_checkpoint("pack");
+    let pages: Vec<u8> = (0..2_500_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("pages-1.img"), &pages).unwrap();
+    let archive = dir.with_extension("archive");
+    let restored = dir.with_extension("restored");
+    let _ = fs::remove_dir_all(&restored);
+
+    let output = run(&["pack", dir.to_str().unwrap(), archive.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = run(&["unpack", archive.to_str().unwrap(), restored.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    for entry in fs::read_dir(&dir).unwrap() {
+        let name = entry.unwrap().file_name();
+        assert_eq!(fs::read(restored.join(&name)).unwrap(), fs::read(dir.join(&name)).unwrap(), "{:?}", name);
+    }
+    assert_eq!(fs::read_dir(&restored).unwrap().count(), fs::read_dir(&dir).unwrap().count());
+
+    // An archive that ends before its image_eof marker is rejected.
+    let data = fs::read(&archive).unwrap();
+    fs::write(&archive, &data[..data.len() - 10]).unwrap();
+    let output = run(&["unpack", archive.to_str().unwrap(), restored.to_str().unwrap()]);
+    assert!(!output.status.success());
+
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&restored);
+    let _ = fs::remove_file(&archive);
+}
@@ -0,0 +1,892 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use criu_coordinator::constants::*;
+use criu_coordinator::image::{marker::Body, Marker};
+use prost::Message;
+use sha2::{Digest, Sha256};
+pub mod common;
+use common::*;
+
+const SERVER_IMAGES_DIR: &str = "/tmp/server-images";
+/// Directory where the server keeps the images of a checkpoint in progress.
+const STAGING_DIR: &str = "/tmp/server-images/.incoming";
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) {
+    stream.write_all(&(payload.len() as u32).to_le_bytes()).unwrap();
+    stream.write_all(payload).unwrap();
+}
+
+/// Register with the server using "pre-stream".
+fn register_stream(port: u16, id: &str) -> TcpStream {
+    let mut stream = ClientMessage::new(id, ACTION_PRE_STREAM, "").send(port);
+    assert_eq!(read_response(&mut stream), MESSAGE_ACK);
+    stream
+}
+
+/// Act as a streamer that has finished a local checkpoint: register with
+/// the server using "pre-stream", complete the SYN/ACK handshake and
+/// announce the image files that will be uploaded.
+fn start_stream(port: u16, id: &str, images: &[(&str, &[u8])]) -> TcpStream {
+    let mut stream = register_stream(port, id);
+    announce_images(&mut stream, images);
+    stream
+}
+
+fn announce_images(stream: &mut TcpStream, images: &[(&str, &[u8])]) {
+    stream.write_all(MESSAGE_SYN.as_bytes()).unwrap();
+    assert_eq!(read_response(stream), MESSAGE_ACK);
+
+    let plan: Vec<String> = images.iter()
+        .map(|(name, data)| format!(r#"{{"img_name": "{name}", "img_size": {}, "img_digest": "{}"}}"#, data.len(), sha256_hex(data)))
+        .collect();
+    write_frame(stream, format!(r#"{{"images": [{}]}}"#, plan.join(",")).as_bytes());
+    assert_eq!(read_response(stream), MESSAGE_ACK);
+}
+
+/// Signal the end of the transfer and return the server's verdict.
+fn finish_stream(stream: &mut TcpStream) -> String {
+    write_frame(stream, MESSAGE_SYN.as_bytes());
+    read_response(stream)
+}
+
+fn open_connection(port: u16, id: &str, action: &str) -> TcpStream {
+    let mut conn = ClientMessage::new(id, action, "").send(port);
+    assert_eq!(read_response(&mut conn), MESSAGE_ACK);
+    conn
+}
+
+fn open_data_connection(port: u16, id: &str) -> TcpStream {
+    open_connection(port, id, ACTION_UPLOAD_IMAGE)
+}
+
+/// Request the upload of `name` and return the server's reply.
+fn request_upload_status(conn: &mut TcpStream, name: &str) -> json::JsonValue {
+    write_frame(conn, name.as_bytes());
+    json::parse(&read_response(conn)).unwrap()
+}
+
+/// Request the upload of `name` and return the offset reported by the server.
+fn request_upload(conn: &mut TcpStream, name: &str) -> usize {
+    request_upload_status(conn, name)["offset"].as_usize().unwrap()
+}
+
+/// Writes a marker stream the way the streamer does while CRIU is running.
+struct MarkerStream {
+    conn: TcpStream,
+    seq: u64,
+}
+
+impl MarkerStream {
+    fn open(port: u16, id: &str) -> Self {
+        Self { conn: open_connection(port, id, ACTION_STREAM_IMAGE), seq: 0 }
+    }
+
+    fn marker(&mut self, body: Body) {
+        let marker = Marker { seq: self.seq, body: Some(body) };
+        write_frame(&mut self.conn, &marker.encode_to_vec());
+        self.seq += 1;
+    }
+
+    fn data(&mut self, name: &str, data: &[u8]) {
+        self.marker(Body::Filename(name.to_string()));
+        self.marker(Body::FileData(data.len() as u32));
+        self.conn.write_all(data).unwrap();
+    }
+
+    fn file_eof(&mut self, name: &str) {
+        self.marker(Body::Filename(name.to_string()));
+        self.marker(Body::FileEof(true));
+    }
+
+    fn image_eof(mut self) -> String {
+        self.marker(Body::ImageEof(true));
+        read_response(&mut self.conn)
+    }
+}
+
+fn upload_image(conn: &mut TcpStream, name: &str, data: &[u8]) -> String {
+    let offset = request_upload(conn, name);
+    conn.write_all(&data[offset..]).unwrap();
+    read_response(conn)
+}
+
+/// Directory where the server stored the images of client `id`,
+/// `<images-dir>/<epoch>/<id>`, for the most recent epoch.
+fn stored_images_dir(id: &str) -> Option<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(SERVER_IMAGES_DIR).ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path().join(id))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    dirs.sort();
+    dirs.pop()
+}
+
+fn remove_stored_images(id: &str) {
+    while let Some(dir) = stored_images_dir(id) {
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+fn start_server() -> (u16, std::process::Child) {
+    let port = pick_port();
+    let server = spawn_server(port);
+    assert!(server_ready(&format!("127.0.0.1:{port}"), 20), "server failed to start");
+    (port, server)
+}
+
+#[test]
+fn stream_images_writes_manifest() {
+    let (port, mut server) = start_server();
+    let id = format!("manifest-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let images: [(&str, &[u8]); 2] = [("inventory.img", b"inventory"), ("pages-1.img", &[7u8; 100000])];
+    let mut stream = start_stream(port, &id, &images);
+
+    // Upload both images concurrently over separate data connections.
+    thread::scope(|scope| {
+        for (name, data) in images {
+            let id = &id;
+            scope.spawn(move || {
+                let mut conn = open_data_connection(port, id);
+                assert_eq!(upload_image(&mut conn, name, data), MESSAGE_IMG_ACK);
+            });
+        }
+    });
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+    let images_dir = stored_images_dir(&id).unwrap();
+
+    let manifest = json::parse(&fs::read_to_string(images_dir.join(MANIFEST_FILE)).unwrap()).unwrap();
+    assert_eq!(manifest["id"], id.as_str());
+    assert_eq!(manifest["epoch"], images_dir.parent().unwrap().file_name().unwrap().to_str().unwrap());
+    assert_eq!(manifest["files"].len(), images.len());
+    for ((name, data), entry) in images.iter().zip(manifest["files"].members()) {
+        assert_eq!(entry["name"], *name);
+        assert_eq!(entry["size"].as_usize(), Some(data.len()));
+        assert_eq!(entry["digest"], sha256_hex(data).as_str());
+        assert_eq!(fs::read(images_dir.join(name)).unwrap(), *data);
+    }
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_interrupted_upload_resumes() {
+    let (port, mut server) = start_server();
+    let id = format!("resume-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let data: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+    let mut stream = start_stream(port, &id, &[("pages-1.img", &data)]);
+
+    // Drop the data connection in the middle of the file.
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(request_upload(&mut conn, "pages-1.img"), 0);
+    conn.write_all(&data[..60000]).unwrap();
+    drop(conn);
+    thread::sleep(Duration::from_millis(200));
+
+    // A new data connection resumes from the last received byte.
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(request_upload(&mut conn, "pages-1.img"), 60000);
+    conn.write_all(&data[60000..]).unwrap();
+    assert_eq!(read_response(&mut conn), MESSAGE_IMG_ACK);
+
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+    let images_dir = stored_images_dir(&id).unwrap();
+    assert_eq!(fs::read(images_dir.join("pages-1.img")).unwrap(), data);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_stalled_upload_is_taken_over() {
+    let (port, mut server) = start_server();
+    let id = format!("takeover-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let data: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+    let mut stream = start_stream(port, &id, &[("pages-1.img", &data)]);
+
+    // The first data connection stalls in the middle of the file.
+    let mut stalled = open_data_connection(port, &id);
+    assert_eq!(request_upload(&mut stalled, "pages-1.img"), 0);
+    stalled.write_all(&data[..60000]).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    // A new data connection takes over and resumes from the last received byte.
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(request_upload(&mut conn, "pages-1.img"), 60000);
+    conn.write_all(&data[60000..]).unwrap();
+    assert_eq!(read_response(&mut conn), MESSAGE_IMG_ACK);
+    assert_eq!(stalled.read(&mut [0u8; 16]).unwrap_or(0), 0);
+
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+    let images_dir = stored_images_dir(&id).unwrap();
+    assert_eq!(fs::read(images_dir.join("pages-1.img")).unwrap(), data);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_corrupted_image_fails_checkpoint() {
+    let (port, mut server) = start_server();
+    let id = format!("corrupted-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let mut stream = start_stream(port, &id, &[("pages-1.img", b"original")]);
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(upload_image(&mut conn, "pages-1.img", b"modified"), MESSAGE_IMG_CORRUPTED);
+    assert_eq!(finish_stream(&mut stream), MESSAGE_IMG_CORRUPTED);
+
+    assert!(stored_images_dir(&id).is_none());
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_truncated_image_fails_checkpoint() {
+    let (port, mut server) = start_server();
+    let id = format!("truncated-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let mut stream = start_stream(port, &id, &[("pages-1.img", &[0u8; 1000])]);
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(request_upload(&mut conn, "pages-1.img"), 0);
+    conn.write_all(&[0u8; 10]).unwrap();
+    drop(conn);
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(finish_stream(&mut stream), MESSAGE_IMG_CORRUPTED);
+    assert!(stored_images_dir(&id).is_none());
+    assert!(!PathBuf::from(STAGING_DIR).join(&id).join(".pages-1.img.part").exists());
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_live_markers_interleave_images() {
+    let (port, mut server) = start_server();
+    let id = format!("live-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let pages: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+    let images: [(&str, &[u8]); 3] = [("inventory.img", b"inventory"), ("pages-1.img", &pages), ("stats-dump", b"")];
+    let mut stream = register_stream(port, &id);
+
+    // Chunks of the images arrive interleaved while CRIU is running.
+    let mut live = MarkerStream::open(port, &id);
+    live.data("pages-1.img", &pages[..40000]);
+    live.data("inventory.img", b"inventory");
+    live.file_eof("stats-dump");
+    live.data("pages-1.img", &pages[40000..]);
+    live.file_eof("inventory.img");
+    live.file_eof("pages-1.img");
+    assert_eq!(live.image_eof(), MESSAGE_ACK);
+
+    announce_images(&mut stream, &images);
+
+    // The server reports that it already has every image.
+    let mut conn = open_data_connection(port, &id);
+    for (name, data) in images {
+        let status = request_upload_status(&mut conn, name);
+        assert_eq!(status["verified"].as_bool(), Some(true));
+        assert_eq!(status["offset"].as_usize(), Some(data.len()));
+    }
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+    let images_dir = stored_images_dir(&id).unwrap();
+
+    let manifest = json::parse(&fs::read_to_string(images_dir.join(MANIFEST_FILE)).unwrap()).unwrap();
+    assert_eq!(manifest["files"].len(), images.len());
+    for (name, data) in images {
+        assert_eq!(fs::read(images_dir.join(name)).unwrap(), data);
+    }
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_live_interruption_falls_back_to_upload() {
+    let (port, mut server) = start_server();
+    let id = format!("live-fallback-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let pages: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+    let images: [(&str, &[u8]); 2] = [("inventory.img", b"inventory"), ("pages-1.img", &pages)];
+    let mut stream = register_stream(port, &id);
+
+    // The live stream delivers one image with wrong content, then drops
+    // in the middle of the other one.
+    let mut live = MarkerStream::open(port, &id);
+    live.data("inventory.img", b"modified!");
+    live.file_eof("inventory.img");
+    live.data("pages-1.img", &pages[..50000]);
+    drop(live);
+    thread::sleep(Duration::from_millis(200));
+    assert!(!PathBuf::from(STAGING_DIR).join(&id).join(".pages-1.img.part").exists());
+
+    // Both images are uploaded again from the start.
+    announce_images(&mut stream, &images);
+    let mut conn = open_data_connection(port, &id);
+    for (name, data) in images {
+        assert_eq!(upload_image(&mut conn, name, data), MESSAGE_IMG_ACK);
+    }
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+    let images_dir = stored_images_dir(&id).unwrap();
+    for (name, data) in images {
+        assert_eq!(fs::read(images_dir.join(name)).unwrap(), data);
+    }
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+#[test]
+fn stream_live_rejects_out_of_order_markers() {
+    let (port, mut server) = start_server();
+    let id = format!("live-order-{}", std::process::id());
+    remove_stored_images(&id);
+
+    let _stream = register_stream(port, &id);
+    let mut live = MarkerStream::open(port, &id);
+    live.data("inventory.img", b"inventory");
+    live.seq += 1;
+    live.file_eof("inventory.img");
+    assert_eq!(read_response(&mut live.conn), MESSAGE_IMG_CORRUPTED);
+    assert!(!PathBuf::from(STAGING_DIR).join(&id).join(".inventory.img.part").exists());
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+}
+
+const TEST_KEY: [u8; 32] = [42u8; 32];
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Encrypt `data` as a single-chunk image in the format used by the streamer.
+fn seal(name: &str, data: &[u8], key: &[u8]) -> Vec<u8> {
+    use chacha20poly1305::{aead::{Aead, KeyInit, Payload}, ChaCha20Poly1305, Key, Nonce};
+
+    let prefix = [1u8; 7];
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(&prefix);
+    nonce[11] = 1; // first and last chunk
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let chunk = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: name.as_bytes() }).unwrap();
+
+    let mut sealed = b"CRCOENC1".to_vec();
+    sealed.extend_from_slice(&Sha256::digest(key));
+    sealed.extend_from_slice(&prefix);
+    sealed.push(0);
+    sealed.extend_from_slice(&chunk);
+    sealed
+}
+
+#[test]
+fn stream_rejects_ids_outside_the_staging_area() {
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-ids-{}", std::process::id()));
+    let images_dir = work_dir.join("images");
+    fs::create_dir_all(&images_dir).unwrap();
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, &format!(r#"{{"images-dir": "{}"}}"#, images_dir.display()));
+
+    for id in ["", ".", "..", "../..", "/", "a/b"] {
+        let mut stream = ClientMessage::new(id, ACTION_PRE_STREAM, "").send(port);
+        assert_eq!(read_response(&mut stream), MESSAGE_INVALID_ID, "{:?}", id);
+    }
+    assert!(images_dir.is_dir());
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+/// Start a server that requires the images of the "default" group
+/// to be encrypted with `TEST_KEY`.
+fn spawn_encrypting_server(port: u16, work_dir: &std::path::Path) -> std::process::Child {
+    fs::create_dir_all(work_dir).unwrap();
+    let key_path = work_dir.join("default.key");
+    fs::write(&key_path, hex(&TEST_KEY)).unwrap();
+    spawn_server_with_config(port, &format!(r#"{{"encryption": {{"default": "{}"}}}}"#, key_path.display()))
+}
+
+#[test]
+fn stream_encrypted_images_stay_encrypted() {
+    let port = pick_port();
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-enc-{}", std::process::id()));
+    let mut server = spawn_encrypting_server(port, &work_dir);
+
+    let id = format!("encrypted-{}", std::process::id());
+    remove_stored_images(&id);
+
+    // Plain images are rejected.
+    let plain: &[u8] = &[1u8; 100];
+    let mut stream = start_stream(port, &id, &[("inventory.img", plain)]);
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(upload_image(&mut conn, "inventory.img", plain), MESSAGE_IMG_CORRUPTED);
+    assert_eq!(finish_stream(&mut stream), MESSAGE_IMG_CORRUPTED);
+    assert!(stored_images_dir(&id).is_none());
+
+    // Images encrypted with another key are rejected.
+    let sealed = seal("inventory.img", b"secret", &[7u8; 32]);
+    let mut stream = start_stream(port, &id, &[("inventory.img", &sealed)]);
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(upload_image(&mut conn, "inventory.img", &sealed), MESSAGE_IMG_CORRUPTED);
+    assert_eq!(finish_stream(&mut stream), MESSAGE_IMG_CORRUPTED);
+
+    // Images encrypted with the group key are stored as received.
+    let sealed = seal("inventory.img", b"secret", &TEST_KEY);
+    let mut stream = start_stream(port, &id, &[("inventory.img", &sealed)]);
+    let mut conn = open_data_connection(port, &id);
+    assert_eq!(upload_image(&mut conn, "inventory.img", &sealed), MESSAGE_IMG_ACK);
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+    let images_dir = stored_images_dir(&id).unwrap();
+    assert_eq!(fs::read(images_dir.join("inventory.img")).unwrap(), sealed);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    remove_stored_images(&id);
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+#[test]
+fn restore_decrypts_images() {
+    let port = pick_port();
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-dec-{}", std::process::id()));
+    let mut server = spawn_encrypting_server(port, &work_dir);
+
+    let restore_dir = work_dir.join("images");
+    fs::create_dir_all(&restore_dir).unwrap();
+    fs::write(restore_dir.join("pages-1.img"), seal("pages-1.img", b"memory", &TEST_KEY)).unwrap();
+    fs::write(restore_dir.join("stats-dump"), b"not encrypted").unwrap();
+
+    let output = std::process::Command::new(CRIU_COORDINATOR_PATH)
+        .args([
+            "client",
+            "--id", "A",
+            "--deps", "",
+            "--action", ACTION_PRE_RESTORE,
+            "--images-dir", restore_dir.to_str().unwrap(),
+            "--port", &port.to_string(),
+            "--key-file", work_dir.join("default.key").to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    assert_eq!(fs::read(restore_dir.join("pages-1.img")).unwrap(), b"memory");
+    assert_eq!(fs::read(restore_dir.join("stats-dump")).unwrap(), b"not encrypted");
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+/// Serve a minimal S3-compatible API, like a local MinIO instance,
+/// storing the objects in memory.
+fn spawn_object_store(access_key: &'static str) -> (u16, Objects) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let objects: Objects = Arc::default();
+
+    let store = objects.clone();
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let header = |name: &str| request.headers().iter()
+                .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+                .map(|h| h.value.as_str().to_string());
+            let authorized = header("authorization")
+                .is_some_and(|auth| auth.starts_with(&format!("AWS4-HMAC-SHA256 Credential={access_key}/")))
+                && header("x-amz-date").is_some() && header("x-amz-content-sha256").is_some();
+            if !authorized {
+                let _ = request.respond(tiny_http::Response::from_string("<Error><Code>AccessDenied</Code></Error>").with_status_code(403));
+                continue;
+            }
+
+            let url = request.url().to_string();
+            let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+            let key = percent_decode(path.trim_start_matches('/'));
+            let response = match request.method() {
+                tiny_http::Method::Put => {
+                    let mut data = Vec::new();
+                    request.as_reader().read_to_end(&mut data).unwrap();
+                    store.lock().unwrap().insert(key, data);
+                    tiny_http::Response::from_data(Vec::new())
+                }
+                tiny_http::Method::Get if query.contains("list-type=2") => {
+                    let prefix = query.split('&')
+                        .find_map(|param| param.strip_prefix("prefix="))
+                        .map(percent_decode)
+                        .unwrap_or_default();
+                    let bucket_prefix = format!("{key}/");
+                    let contents: String = store.lock().unwrap().keys()
+                        .filter_map(|k| k.strip_prefix(&bucket_prefix))
+                        .filter(|k| k.starts_with(&prefix))
+                        .map(|k| format!("<Contents><Key>{k}</Key></Contents>"))
+                        .collect();
+                    tiny_http::Response::from_string(format!(
+                        "<ListBucketResult><IsTruncated>false</IsTruncated>{contents}</ListBucketResult>"))
+                }
+                tiny_http::Method::Get => match store.lock().unwrap().get(&key) {
+                    Some(data) => tiny_http::Response::from_data(data.clone()),
+                    None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+                },
+                tiny_http::Method::Delete => {
+                    store.lock().unwrap().remove(&key);
+                    tiny_http::Response::from_data(Vec::new()).with_status_code(204)
+                }
+                _ => tiny_http::Response::from_data(Vec::new()).with_status_code(405),
+            };
+            let _ = request.respond(response);
+        }
+    });
+    (port, objects)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            decoded.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+#[test]
+fn stream_images_to_object_store() {
+    let (store_port, objects) = spawn_object_store("test-access");
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-s3-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, &format!(r#"{{
+        "images-dir": "{}",
+        "storage": {{
+            "type": "s3",
+            "endpoint": "http://127.0.0.1:{store_port}",
+            "bucket": "checkpoints",
+            "access-key": "test-access",
+            "secret-key": "test-secret"
+        }}
+    }}"#, work_dir.display()));
+
+    let id = format!("s3-{}", std::process::id());
+    let images: [(&str, &[u8]); 2] = [("inventory.img", b"inventory"), ("pages 1.img", &[3u8; 70000])];
+    let mut stream = start_stream(port, &id, &images);
+    let mut conn = open_data_connection(port, &id);
+    for (name, data) in images {
+        assert_eq!(upload_image(&mut conn, name, data), MESSAGE_IMG_ACK);
+    }
+    assert_eq!(finish_stream(&mut stream), MESSAGE_ACK);
+
+    let objects = objects.lock().unwrap();
+    let manifest_key = objects.keys().find(|key| key.ends_with(&format!("/{id}/{MANIFEST_FILE}"))).unwrap();
+    let manifest = json::parse(std::str::from_utf8(&objects[manifest_key]).unwrap()).unwrap();
+    assert_eq!(manifest["files"].len(), images.len());
+    let epoch = manifest["epoch"].as_str().unwrap();
+    assert_eq!(
+        objects.keys().cloned().collect::<Vec<_>>(),
+        [
+            format!("checkpoints/{epoch}/{id}/{MANIFEST_FILE}"),
+            format!("checkpoints/{epoch}/{id}/inventory.img"),
+            format!("checkpoints/{epoch}/{id}/pages 1.img"),
+            format!("checkpoints/catalog/{epoch}.json"),
+        ]
+    );
+    for (name, data) in images {
+        assert_eq!(objects[&format!("checkpoints/{epoch}/{id}/{name}")], data);
+    }
+    let entry = json::parse(std::str::from_utf8(&objects[&format!("checkpoints/catalog/{epoch}.json")]).unwrap()).unwrap();
+    assert_eq!(entry["members"][0], id.as_str());
+    assert_eq!(entry["size"].as_usize(), Some(images.iter().map(|(_, data)| data.len()).sum()));
+
+    // Nothing is left in the images directory once the checkpoint is stored.
+    assert!(!work_dir.join(".incoming").join(&id).exists());
+    assert!(!work_dir.join(&id).exists());
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+fn run_catalog_command(port: u16, args: &[&str]) -> std::process::Output {
+    std::process::Command::new(CRIU_COORDINATOR_PATH)
+        .args(args)
+        .args(["--port", &port.to_string()])
+        .output()
+        .unwrap()
+}
+
+/// Image names and contents of a client.
+type Images<'a> = &'a [(&'a str, &'a [u8])];
+
+/// Stream the images of every client in `clients` as one global checkpoint.
+fn stream_global_checkpoint(port: u16, clients: &[(&str, Images)]) -> Vec<String> {
+    let mut streams: Vec<_> = clients.iter().map(|(id, images)| start_stream(port, id, images)).collect();
+    for (id, images) in clients {
+        let mut conn = open_data_connection(port, id);
+        for (name, data) in images.iter() {
+            assert_eq!(upload_image(&mut conn, name, data), MESSAGE_IMG_ACK);
+        }
+    }
+    streams.iter_mut().map(finish_stream).collect()
+}
+
+#[test]
+fn catalog_lists_tags_and_deletes_checkpoints() {
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-catalog-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, &format!(r#"{{"images-dir": "{}"}}"#, work_dir.display()));
+
+    let (a, b) = (format!("catalog-a-{}", std::process::id()), format!("catalog-b-{}", std::process::id()));
+    let replies = stream_global_checkpoint(port, &[
+        (&a, &[("pages-1.img", b"memory of a")]),
+        (&b, &[("pages-1.img", b"memory of b")]),
+    ]);
+    assert_eq!(replies, [MESSAGE_ACK, MESSAGE_ACK]);
+
+    let output = run_catalog_command(port, &["list"]);
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout).unwrap();
+    let epoch = listing.lines().nth(1).unwrap().split_whitespace().next().unwrap().to_string();
+    assert!(listing.contains(&format!("{a},{b}")), "{}", listing);
+    assert!(work_dir.join(&epoch).join(&a).join("pages-1.img").is_file());
+
+    let output = run_catalog_command(port, &["show", &epoch]);
+    assert!(output.status.success());
+    let entry = json::parse(&String::from_utf8(output.stdout).unwrap()).unwrap();
+    assert_eq!(entry["group"], "default");
+    assert_eq!(entry["size"].as_usize(), Some(22));
+    assert_eq!(entry["manifests"][a.as_str()]["files"][0]["name"], "pages-1.img");
+    assert_eq!(entry["manifests"][b.as_str()]["files"][0]["digest"], sha256_hex(b"memory of b").as_str());
+
+    assert!(run_catalog_command(port, &["tag", &epoch, "nightly", "golden"]).status.success());
+    assert!(run_catalog_command(port, &["tag", "--remove", &epoch, "golden"]).status.success());
+    let tagged = String::from_utf8(run_catalog_command(port, &["list", "--tag", "nightly"]).stdout).unwrap();
+    assert!(tagged.contains(&epoch));
+    let tagged = String::from_utf8(run_catalog_command(port, &["list", "--tag", "golden"]).stdout).unwrap();
+    assert!(!tagged.contains(&epoch));
+
+    assert!(run_catalog_command(port, &["delete", &epoch]).status.success());
+    assert!(!work_dir.join(&epoch).exists());
+    let output = run_catalog_command(port, &["show", &epoch]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("does not exist"));
+
+    // A global checkpoint is not recorded if one of its members fails.
+    let mut stream_a = start_stream(port, &a, &[("pages-1.img", b"memory of a")]);
+    let mut stream_b = start_stream(port, &b, &[("pages-1.img", b"memory of b")]);
+    assert_eq!(upload_image(&mut open_data_connection(port, &a), "pages-1.img", b"memory of a"), MESSAGE_IMG_ACK);
+    assert_eq!(upload_image(&mut open_data_connection(port, &b), "pages-1.img", b"memory of c"), MESSAGE_IMG_CORRUPTED);
+    assert_eq!(finish_stream(&mut stream_a), MESSAGE_ACK);
+    assert_eq!(finish_stream(&mut stream_b), MESSAGE_IMG_CORRUPTED);
+    let listing = String::from_utf8(run_catalog_command(port, &["list"]).stdout).unwrap();
+    assert_eq!(listing.lines().count(), 1, "{}", listing);
+    assert!(fs::read_dir(&work_dir).unwrap().all(|entry| !entry.unwrap().path().join(&a).exists()));
+
+    // A member that starts its dump after the others have committed joins their checkpoint.
+    assert_eq!(stream_global_checkpoint(port, &[(&a, &[("pages-1.img", b"memory of a")])]), [MESSAGE_ACK]);
+    assert_eq!(stream_global_checkpoint(port, &[(&b, &[("pages-1.img", b"memory of b")])]), [MESSAGE_ACK]);
+    let listing = String::from_utf8(run_catalog_command(port, &["list"]).stdout).unwrap();
+    assert_eq!(listing.lines().count(), 2, "{}", listing);
+    assert!(listing.contains(&format!("{a},{b}")), "{}", listing);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+/// Images of a client with an established connection from `local_port` to
+/// `remote_port` on 127.0.0.1, whose queues are empty.
+fn tcp_connection_images(local_port: u32, remote_port: u32, inq_seq: u32, outq_seq: u32) -> [(&'static str, Vec<u8>); 2] {
+    use criu_coordinator::criu::{FdTypes, FileEntry, InetSkEntry, TcpStreamEntry};
+    let loopback = vec![u32::from_ne_bytes([127, 0, 0, 1])];
+    let file = FileEntry {
+        id: 1,
+        r#type: FdTypes::Inetsk as i32,
+        isk: Some(InetSkEntry {
+            id: 1,
+            ino: 0x1234,
+            family: 2,
+            proto: 6,
+            state: 1,
+            src_addr: loopback.clone(),
+            src_port: local_port,
+            dst_addr: loopback,
+            dst_port: remote_port,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let stream = TcpStreamEntry { inq_seq, outq_seq, ..Default::default() };
+    [
+        ("files.img", encode_image(0x56303138, &[(file.encode_to_vec(), &[])])),
+        ("tcp-stream-1234.img", encode_image(0x51465506, &[(stream.encode_to_vec(), &[])])),
+    ]
+}
+
+#[test]
+fn catalog_flags_inconsistent_checkpoints() {
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-consistency-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, &format!(r#"{{"images-dir": "{}"}}"#, work_dir.display()));
+
+    let (a, b) = (format!("tcp-a-{}", std::process::id()), format!("tcp-b-{}", std::process::id()));
+    let stream_and_show = |b_outq_seq: u32| {
+        let images_a = tcp_connection_images(8080, 9090, 500, 100);
+        let images_b = tcp_connection_images(9090, 8080, 100, b_outq_seq);
+        let images_a: Vec<(&str, &[u8])> = images_a.iter().map(|(name, data)| (*name, &data[..])).collect();
+        let images_b: Vec<(&str, &[u8])> = images_b.iter().map(|(name, data)| (*name, &data[..])).collect();
+        let replies = stream_global_checkpoint(port, &[(&a, &images_a), (&b, &images_b)]);
+        assert_eq!(replies, [MESSAGE_ACK, MESSAGE_ACK]);
+
+        let listing = String::from_utf8(run_catalog_command(port, &["list"]).stdout).unwrap();
+        let line = listing.lines().last().unwrap().to_string();
+        let epoch = line.split_whitespace().next().unwrap();
+        let output = run_catalog_command(port, &["show", epoch]);
+        (line, json::parse(&String::from_utf8(output.stdout).unwrap()).unwrap())
+    };
+
+    let (line, entry) = stream_and_show(500);
+    assert_eq!(entry["consistent"], true, "{}", entry.pretty(4));
+    assert!(!line.contains("inconsistent"), "{}", line);
+
+    // B's image was taken before it sent the last 5 bytes that A has received.
+    let (line, entry) = stream_and_show(495);
+    assert_eq!(entry["consistent"], false, "{}", entry.pretty(4));
+    assert_eq!(entry["inconsistencies"][0], format!("127.0.0.1:8080 -> 127.0.0.1:9090: {a} has received 5 bytes that {b} has not sent").as_str());
+    assert!(line.contains("(inconsistent)"), "{}", line);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+#[test]
+fn catalog_leaves_encrypted_checkpoints_unverified() {
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-consistency-enc-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let key_path = work_dir.join("default.key");
+    fs::write(&key_path, hex(&TEST_KEY)).unwrap();
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, &format!(
+        r#"{{"images-dir": "{}", "encryption": {{"default": "{}"}}}}"#, work_dir.display(), key_path.display()));
+
+    // The server cannot read the sealed images, even though they are inconsistent.
+    let (a, b) = (format!("tcp-enc-a-{}", std::process::id()), format!("tcp-enc-b-{}", std::process::id()));
+    let seal_all = |images: [(&'static str, Vec<u8>); 2]| images.map(|(name, data)| (name, seal(name, &data, &TEST_KEY)));
+    let images_a = seal_all(tcp_connection_images(8080, 9090, 500, 100));
+    let images_b = seal_all(tcp_connection_images(9090, 8080, 100, 495));
+    let images_a: Vec<(&str, &[u8])> = images_a.iter().map(|(name, data)| (*name, &data[..])).collect();
+    let images_b: Vec<(&str, &[u8])> = images_b.iter().map(|(name, data)| (*name, &data[..])).collect();
+    assert_eq!(stream_global_checkpoint(port, &[(&a, &images_a), (&b, &images_b)]), [MESSAGE_ACK, MESSAGE_ACK]);
+
+    let listing = String::from_utf8(run_catalog_command(port, &["list"]).stdout).unwrap();
+    let line = listing.lines().last().unwrap();
+    let epoch = line.split_whitespace().next().unwrap();
+    let entry = json::parse(&String::from_utf8(run_catalog_command(port, &["show", epoch]).stdout).unwrap()).unwrap();
+    assert!(entry["consistent"].is_null(), "{}", entry.pretty(4));
+    assert!(line.contains("(unverified)"), "{}", line);
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+#[test]
+fn timeline_records_phases_of_global_checkpoint() {
+    let work_dir = std::env::temp_dir().join(format!("criu-coordinator-timeline-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let port = pick_port();
+    let mut server = spawn_server_with_config(port, &format!(r#"{{"images-dir": "{}"}}"#, work_dir.display()));
+
+    let (a, b) = (format!("timeline-a-{}", std::process::id()), format!("timeline-b-{}", std::process::id()));
+    let mut streams: Vec<_> = [&a, &b].iter().map(|id| start_stream(port, id, &[("pages-1.img", b"memory")])).collect();
+    for id in [&a, &b] {
+        assert_eq!(upload_image(&mut open_data_connection(port, id), "pages-1.img", b"memory"), MESSAGE_IMG_ACK);
+    }
+    for stream in streams.iter_mut() {
+        assert_eq!(finish_stream(stream), MESSAGE_ACK);
+        // The span of pre-stream ends when the server closes the connection.
+        stream.read_to_end(&mut Vec::new()).unwrap();
+    }
+    // The members of the group pass post-dump together.
+    let conns: Vec<_> = [&a, &b].iter().map(|id| ClientMessage::new(id, ACTION_POST_DUMP, "").send(port)).collect();
+    for mut conn in conns {
+        assert_eq!(read_response(&mut conn), MESSAGE_ACK);
+        conn.read_to_end(&mut Vec::new()).unwrap();
+    }
+
+    // The timeline is stored under the epoch of the global checkpoint.
+    let listing = String::from_utf8(run_catalog_command(port, &["list"]).stdout).unwrap();
+    let epoch = listing.lines().nth(1).unwrap().split_whitespace().next().unwrap().to_string();
+    let listing = String::from_utf8(run_catalog_command(port, &["timeline"]).stdout).unwrap();
+    assert!(listing.lines().nth(1).unwrap().starts_with(&epoch), "{}", listing);
+    assert!(listing.contains(&format!("{a},{b}")), "{}", listing);
+
+    let output = run_catalog_command(port, &["timeline", &epoch]);
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains(&format!("Timeline {epoch} of group default")), "{}", report);
+    assert_eq!(report.lines().filter(|line| line.starts_with(ACTION_PRE_STREAM)).count(), 2, "{}", report);
+    assert_eq!(report.lines().filter(|line| line.starts_with(ACTION_POST_DUMP)).count(), 2, "{}", report);
+    assert!(report.contains("Critical path: pre-stream ("), "{}", report);
+
+    let output = run_catalog_command(port, &["timeline", &epoch, "--trace", "-"]);
+    assert!(output.status.success());
+    let trace = json::parse(&String::from_utf8(output.stdout).unwrap()).unwrap();
+    let spans: Vec<_> = trace["traceEvents"].members().filter(|event| event["ph"] == "X").collect();
+    assert_eq!(spans.len(), 4);
+    // One straggler per phase is on the critical path.
+    assert_eq!(spans.iter().filter(|event| event["args"]["critical"] == true).count(), 2);
+
+    let output = run_catalog_command(port, &["timeline", "0-0000"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("No timeline was recorded"));
+
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_dir_all(&work_dir);
+}